@@ -1,6 +1,4 @@
-use blake3;
 use criterion::{Criterion, black_box, criterion_group, criterion_main};
-use getrandom;
 use tub::chaos::DefaultName;
 
 pub fn hash_blake3(data: &[u8]) -> DefaultName {
@@ -29,7 +27,7 @@ fn bm_hash2(c: &mut Criterion) {
 
 fn bm_dalek_s(c: &mut Criterion) {
     let buf = [7_u8; 30];
-    use ed25519_dalek::{Signature, Signer, SigningKey};
+    use ed25519_dalek::{Signer, SigningKey};
     use rand::rngs::OsRng;
     let mut csprng = OsRng;
     let sk = SigningKey::generate(&mut csprng);
@@ -40,7 +38,7 @@ fn bm_dalek_s(c: &mut Criterion) {
 
 fn bm_dalek_v(c: &mut Criterion) {
     let buf = [7_u8; 30];
-    use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+    use ed25519_dalek::{Signer, SigningKey, Verifier};
     use rand::rngs::OsRng;
     let mut csprng = OsRng;
     let sk = SigningKey::generate(&mut csprng);
@@ -53,7 +51,7 @@ fn bm_dalek_v(c: &mut Criterion) {
 
 fn bm_dalek_v_strict(c: &mut Criterion) {
     let buf = [7_u8; 30];
-    use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+    use ed25519_dalek::{Signer, SigningKey};
     use rand::rngs::OsRng;
     let mut csprng = OsRng;
     let sk = SigningKey::generate(&mut csprng);
@@ -65,7 +63,7 @@ fn bm_dalek_v_strict(c: &mut Criterion) {
 }
 
 fn bm_db32enc(c: &mut Criterion) {
-    let mut src = DefaultName::new();
+    let src = DefaultName::new();
     c.bench_function("db32enc: Name.to_string()", |b| {
         b.iter(|| black_box(src.to_string()))
     });
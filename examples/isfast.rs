@@ -14,7 +14,7 @@ fn main() -> io::Result<()> {
     let mut obj = DefaultObject::new();
 
     println!("🤔 Is Tub 🛁 fast? 🚀");
-    println!("");
+    println!();
 
     println!("🛁 Saving {} random {} byte sized objects...", COUNT, SIZE);
     obj.reset(SIZE, 1);
@@ -24,12 +24,12 @@ fn main() -> io::Result<()> {
         //obj.fast_randomize();
         obj.as_mut_data()[0..8].copy_from_slice(&(i as u64).to_le_bytes());
         obj.finalize();
-        store.save(&mut obj)?;
+        store.save(&obj)?;
     }
     let elapsed = start.elapsed().as_secs_f64();
     let rate = COUNT as f64 / elapsed;
     println!("🚀 {} Store.save() calls per second", rate as u64);
-    println!("");
+    println!();
 
     // Store.load()
     println!("🛁 Requesting all objects in random order...");
@@ -38,13 +38,13 @@ fn main() -> io::Result<()> {
     let start = Instant::now();
     for _ in 0..LOOPS {
         for hash in keys.iter() {
-            assert!(store.load(&hash, &mut obj)?);
+            assert!(store.load(hash, &mut obj)?);
         }
     }
     let elapsed = start.elapsed().as_secs_f64();
     let rate = (COUNT * LOOPS) as f64 / elapsed;
     println!("🚀 {} Store.load() validated reads per second", rate as u64);
-    println!("");
+    println!();
 
     // Store.load_unchecked()
     println!("🛁 Requesting all objects in random order, UNCHECKED...");
@@ -53,13 +53,13 @@ fn main() -> io::Result<()> {
     let start = Instant::now();
     for _ in 0..LOOPS {
         for hash in keys.iter() {
-            assert!(store.load_unchecked(&hash, &mut obj)?);
+            assert!(store.load_unchecked(hash, &mut obj)?);
         }
     }
     let elapsed = start.elapsed().as_secs_f64();
     let rate = (COUNT * LOOPS) as f64 / elapsed;
     println!("🚀 {} Store.load_unchecked() reads per second", rate as u64);
-    println!("");
+    println!();
 
     println!("🛁 Reindexing objects...");
     let start = Instant::now();
@@ -72,7 +72,7 @@ fn main() -> io::Result<()> {
         "🚀 {} objects indexed plus validated per second",
         rate as u64
     );
-    println!("");
+    println!();
     assert_eq!(store.len(), COUNT);
 
     println!("😎 Yes, Tub 🛁 is fast. 🚀");
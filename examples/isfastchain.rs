@@ -1,5 +1,4 @@
-use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
-use getrandom;
+use ed25519_dalek::SigningKey;
 use rand::rngs::OsRng;
 use std::io;
 use std::time::Instant;
@@ -27,7 +26,7 @@ fn main() -> io::Result<()> {
     let rate = COUNT as f64 / elapsed;
     println!("🚀 {} blocks signed per second", rate as u64);
 
-    println!("");
+    println!();
 
     println!("🛁 Veriying {} times...", COUNT);
     let start = Instant::now();
@@ -11,6 +11,12 @@ pub const OBJECTDIR: &str = "objects";
 pub const TMPDIR: &str = "tmp";
 pub const README: &str = "REAMDE.txt"; // The REAMDE file
 pub const BRANCHES: &str = "blockchain";
+pub const CURRENT_BRANCH: &str = "branch";
+pub const DEFAULT_BRANCH: &str = "main";
+pub const ENCRYPTION: &str = "encryption";
+pub const DICTIONARY: &str = "dictionary.tub";
+pub const MERGE_STATE: &str = "merge.tub";
+pub const META_OPTIONS: &str = "meta";
 
 pub static README_CONTENTS: &[u8] = b"Hello from Bathtub DB!
 
@@ -29,6 +35,7 @@ pub enum ObjKind {
     Commit,
     Fanout,
     Unknown,
+    Dictionary,
 }
 
 impl From<u8> for ObjKind {
@@ -43,6 +50,7 @@ impl From<u8> for ObjKind {
             6 => Self::Tree,
             7 => Self::Commit,
             8 => Self::Fanout,
+            10 => Self::Dictionary,
             _ => Self::Unknown,
         }
     }
@@ -74,6 +82,9 @@ mod tests {
             let kind: ObjKind = k.into();
             if k < 9 {
                 assert_eq!(kind as u8, k);
+            } else if k == 10 {
+                assert_eq!(kind, ObjKind::Dictionary);
+                assert_eq!(kind as u8, 10);
             } else {
                 assert_eq!(kind as u8, 9);
             }
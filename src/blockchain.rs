@@ -33,6 +33,7 @@ fn compute_hash(payload: &[u8]) -> DefaultName {
     hash
 }
 
+#[cfg(test)]
 fn gen_signing_key() -> SigningKey {
     let mut csprng = OsRng;
     SigningKey::generate(&mut csprng)
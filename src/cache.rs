@@ -0,0 +1,216 @@
+//! Bounded LRU cache of verified objects. 🗃️
+//!
+//! Walking trees loads the same small objects over and over (`Tree`s when
+//! flattening and diffing, `Fanout` buckets on every lookup).  With the cache
+//! enabled (see `Store::enable_cache()`), repeat loads skip both the `pread64()`
+//! and the hash check, since only objects that were verified get cached.
+//!
+//! The capacity is in bytes.  Objects larger than `1 / MAX_FRACTION` of it are
+//! never cached so a few big leaves can't flush out all the little trees.
+
+use crate::chaos::Name;
+use std::collections::{BTreeMap, HashMap};
+
+/// Default cache capacity (64 MiB).
+pub const DEFAULT_CACHE_CAPACITY: usize = 1 << 26;
+
+/// Objects larger than `capacity / MAX_FRACTION` are not cached.
+pub const MAX_FRACTION: usize = 16;
+
+/// Hit and miss counters.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStats {
+    pub fn lookups(&self) -> u64 {
+        self.hits + self.misses
+    }
+
+    pub fn hit_rate(&self) -> f64 {
+        match self.lookups() {
+            0 => 0.0,
+            n => self.hits as f64 / n as f64,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ObjectCache<const N: usize> {
+    capacity: usize,
+    size: usize,
+    tick: u64,
+    map: HashMap<Name<N>, (u64, Vec<u8>)>,
+    order: BTreeMap<u64, Name<N>>, // Least recently used first
+    stats: CacheStats,
+}
+
+impl<const N: usize> ObjectCache<N> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            size: 0,
+            tick: 0,
+            map: HashMap::new(),
+            order: BTreeMap::new(),
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Total bytes of the cached objects.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = CacheStats::default();
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear();
+        self.order.clear();
+        self.size = 0;
+    }
+
+    pub fn contains(&self, hash: &Name<N>) -> bool {
+        self.map.contains_key(hash)
+    }
+
+    /// The cached object buffer (counting a hit or a miss).
+    pub fn get(&mut self, hash: &Name<N>) -> Option<&[u8]> {
+        match self.map.get_mut(hash) {
+            Some((tick, buf)) => {
+                self.stats.hits += 1;
+                self.order.remove(tick);
+                self.tick += 1;
+                *tick = self.tick;
+                self.order.insert(self.tick, *hash);
+                Some(buf)
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Cache the object buffer `buf`, evicting the least recently used objects
+    /// to make room.  Returns `false` if it's too big to cache.
+    pub fn insert(&mut self, hash: Name<N>, buf: &[u8]) -> bool {
+        if buf.len() > self.capacity / MAX_FRACTION {
+            return false;
+        }
+        self.remove(&hash);
+        while self.size + buf.len() > self.capacity {
+            match self.order.pop_first() {
+                Some((_, old)) => {
+                    let (_, old) = self.map.remove(&old).unwrap();
+                    self.size -= old.len();
+                }
+                None => break,
+            }
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, hash);
+        self.map.insert(hash, (self.tick, buf.to_vec()));
+        self.size += buf.len();
+        true
+    }
+
+    pub fn remove(&mut self, hash: &Name<N>) -> bool {
+        match self.map.remove(hash) {
+            Some((tick, buf)) => {
+                self.order.remove(&tick);
+                self.size -= buf.len();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chaos::DefaultName;
+
+    fn name(i: u8) -> DefaultName {
+        let mut hash = DefaultName::new();
+        hash.as_mut_buf().fill(i);
+        hash
+    }
+
+    #[test]
+    fn test_cache_stats() {
+        let mut stats = CacheStats::default();
+        assert_eq!(stats.lookups(), 0);
+        assert_eq!(stats.hit_rate(), 0.0);
+        stats.hits = 3;
+        stats.misses = 1;
+        assert_eq!(stats.lookups(), 4);
+        assert_eq!(stats.hit_rate(), 0.75);
+    }
+
+    #[test]
+    fn test_object_cache() {
+        let mut cache: ObjectCache<30> = ObjectCache::new(1600);
+        assert!(cache.is_empty());
+        assert_eq!(cache.capacity(), 1600);
+        assert!(cache.get(&name(1)).is_none());
+        assert_eq!(cache.stats(), CacheStats { hits: 0, misses: 1 });
+
+        // Too big
+        assert!(!cache.insert(name(1), &[1; 101]));
+        assert!(cache.is_empty());
+
+        for i in 0..16 {
+            assert!(cache.insert(name(i), &[i; 100]));
+        }
+        assert_eq!(cache.len(), 16);
+        assert_eq!(cache.size(), 1600);
+        assert_eq!(cache.get(&name(0)).unwrap(), &[0; 100]);
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 1 });
+
+        // 0 was just used, so 1 is the least recently used
+        assert!(cache.insert(name(16), &[16; 50]));
+        assert!(!cache.contains(&name(1)));
+        assert!(cache.contains(&name(0)));
+        assert_eq!(cache.size(), 1550);
+        assert!(cache.insert(name(17), &[17; 100]));
+        assert!(!cache.contains(&name(2)));
+        assert_eq!(cache.size(), 1550);
+        assert_eq!(cache.len(), 16);
+
+        // Replacing doesn't double count
+        assert!(cache.insert(name(17), &[17; 100]));
+        assert_eq!(cache.size(), 1550);
+        assert!(cache.remove(&name(17)));
+        assert!(!cache.remove(&name(17)));
+        assert_eq!(cache.size(), 1450);
+
+        cache.reset_stats();
+        assert_eq!(cache.stats(), CacheStats::default());
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(cache.size(), 0);
+        assert!(cache.get(&name(0)).is_none());
+    }
+}
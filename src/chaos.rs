@@ -26,7 +26,9 @@
 //! 1.  A single system call to `write()` or `pread64()`
 //! 2.  Zero heap allocations
 //!
-//! If we stick to the above, this should stay fast!
+//! If we stick to the above, this should stay fast!  The optional object cache
+//! (see `tub::cache`) is the one exception: inserting a freshly verified object
+//! allocates, but every later load of it then skips the system call entirely.
 //!
 //! We can get a bit more performance by replacing HashMap with something
 //! custom... we already have a hash!  Maybe hash the Tub hash with aHash?
@@ -39,9 +41,11 @@ use std::io::prelude::*;
 use std::io::{BufReader, BufWriter, SeekFrom};
 use std::marker::PhantomData;
 use std::os::unix::fs::FileExt;
-use std::{cmp, fmt};
+use std::sync::Arc;
+use std::{cmp, fmt, io};
 
 use crate::base::*;
+use crate::cache::{CacheStats, ObjectCache};
 use crate::dbase32::{db32dec_into, db32enc};
 use crate::protocol::{Blake3, Hasher};
 
@@ -116,7 +120,7 @@ impl<const N: usize> Default for Name<N> {
 }
 
 /// Packs 24-bit `size` and 8-bit `kind` into a `u32`.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub struct Info {
     val: u32,
 }
@@ -396,12 +400,53 @@ impl<'a, R: Read, H: Hasher, const N: usize> ObjectReader<'a, R, H, N> {
     }
 }
 
+/// Encodes objects on their way into a `Store`, and decodes them on the way out.
+///
+/// This module only stores and verifies bytes, the actual encodings (delta,
+/// compression, encryption) live in `tub::inception`.  An encoded object is
+/// stored inside of a container object (always an `ObjKind::Stream` object),
+/// and the `Store` indexes the container under the `Name` of the object it
+/// holds, so `Store.load()` works exactly the same either way.
+pub trait Codec<H: Hasher, const N: usize>: Send + Sync {
+    /// Bytes at the start of the container data that `held()` needs.
+    fn held_len(&self) -> usize;
+
+    /// The `Name` of the object held by the container with this data.
+    fn held(&self, data: &[u8]) -> Option<Name<N>>;
+
+    /// Encode the (finalized) `obj` into a container in `tmp`, maybe as a delta
+    /// against `base`.  Returns `false` when `obj` should be stored as is.
+    fn encode(
+        &self,
+        store: &mut Store<H, N>,
+        obj: &Object<H, N>,
+        base: Option<&Name<N>>,
+        tmp: &mut Object<H, N>,
+    ) -> IoResult<bool>;
+
+    /// Decode the object `hash` out of the container in `obj` (in place).
+    ///
+    /// `depth` is how many containers deep we already are, so a codec that
+    /// loads other objects should do so with `Store.load_at(.., depth + 1)`.
+    fn decode(
+        &self,
+        store: &mut Store<H, N>,
+        hash: &Name<N>,
+        obj: &mut Object<H, N>,
+        depth: u8,
+    ) -> IoResult<()>;
+}
+
 /// Organizes objects in an append-only file.
 pub struct Store<H: Hasher, const N: usize> {
     file: File,
     _hasher: H,
     map: HashMap<Name<N>, Entry>,
     offset: u64,
+    cache: Option<ObjectCache<N>>,
+    codec: Option<Arc<dyn Codec<H, N>>>,
+    located: HashMap<Name<N>, Name<N>>, // Held object => container
+    tmp: Option<Object<H, N>>,
 }
 
 impl<H: Hasher, const N: usize> Store<H, N> {
@@ -411,9 +456,32 @@ impl<H: Hasher, const N: usize> Store<H, N> {
             _hasher: H::new(),
             map: HashMap::new(),
             offset: 0,
+            cache: None,
+            codec: None,
+            located: HashMap::new(),
+            tmp: None,
         }
     }
 
+    /// Encode and decode objects with `codec` (set it before `reindex()`).
+    pub fn set_codec(&mut self, codec: Arc<dyn Codec<H, N>>) {
+        self.codec = Some(codec);
+    }
+
+    /// Cache up to `capacity` bytes of recently loaded objects.
+    pub fn enable_cache(&mut self, capacity: usize) {
+        self.cache = Some(ObjectCache::new(capacity));
+    }
+
+    pub fn disable_cache(&mut self) {
+        self.cache = None;
+    }
+
+    /// Cache hits and misses (`None` if the cache isn't enabled).
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(|cache| cache.stats())
+    }
+
     pub fn new_object(&self) -> Object<H, N> {
         Object::new()
     }
@@ -430,12 +498,46 @@ impl<H: Hasher, const N: usize> Store<H, N> {
         self.offset
     }
 
+    /// Whether we have `hash`, either as is or inside of a container.
+    pub fn contains(&self, hash: &Name<N>) -> bool {
+        self.map.contains_key(hash) || self.located.contains_key(hash)
+    }
+
+    /// The container holding `hash` (`None` when it's stored as is).
+    pub fn container_of(&self, hash: &Name<N>) -> Option<Name<N>> {
+        if self.map.contains_key(hash) {
+            None
+        } else {
+            self.located.get(hash).copied()
+        }
+    }
+
+    // Index the container (if it is one) with data `data` at `offset`.
+    fn locate(&mut self, hash: Name<N>, info: Info, data: &[u8]) {
+        if let Some(codec) = &self.codec {
+            if info.kind() == ObjKind::Stream as u8 && data.len() >= codec.held_len() {
+                if let Some(held) = codec.held(data) {
+                    self.located.insert(held, hash);
+                }
+            }
+        }
+    }
+
     pub fn keys(&self) -> Vec<Name<N>> {
         Vec::from_iter(self.map.keys().cloned())
     }
 
+    /// Names of the objects held inside of containers (see `Codec`).
+    pub fn held_keys(&self) -> Vec<Name<N>> {
+        Vec::from_iter(self.located.keys().cloned())
+    }
+
     pub fn reindex(&mut self, obj: &mut Object<H, N>) -> IoResult<()> {
         self.map.clear();
+        self.located.clear();
+        if let Some(cache) = &mut self.cache {
+            cache.clear();
+        }
         self.offset = 0;
         self.file.rewind()?;
         let mut br = BufReader::new(self.file.try_clone()?);
@@ -443,6 +545,7 @@ impl<H: Hasher, const N: usize> Store<H, N> {
         while reader.read_next(obj)? {
             self.map
                 .insert(obj.hash(), Entry::new(obj.info(), self.offset));
+            self.locate(obj.hash(), obj.info(), obj.as_data());
             self.offset += obj.len() as u64;
         }
         // Truncate to end of valid object stream, discarding any partial object
@@ -454,13 +557,26 @@ impl<H: Hasher, const N: usize> Store<H, N> {
 
     pub fn reindex_from(&mut self, obj: &mut Object<H, N>, idx: File) -> IoResult<()> {
         self.map.clear();
+        self.located.clear();
+        if let Some(cache) = &mut self.cache {
+            cache.clear();
+        }
         self.offset = 0;
 
         // Load entries from the saved index file
+        let held_len = self.codec.as_ref().map_or(0, |codec| codec.held_len());
+        let mut held = vec![0; held_len];
         let mut idx = BufReader::new(idx);
         while idx.read_exact(obj.as_mut_header()).is_ok() {
-            self.map
-                .insert(obj.hash(), Entry::new(obj.info(), self.offset));
+            let info = obj.info();
+            self.map.insert(obj.hash(), Entry::new(info, self.offset));
+            if info.kind() == ObjKind::Stream as u8 && info.size() >= held_len {
+                // The index only has headers, so read what the codec needs
+                let offset = self.offset + (N + INFO_LEN) as u64;
+                if self.file.read_exact_at(&mut held, offset).is_ok() {
+                    self.locate(obj.hash(), info, &held);
+                }
+            }
             self.offset += (N + 4 + obj.info().size()) as u64;
         }
         // FIXME: truncate if needed based on OFFSET % HEADER_LEN
@@ -473,6 +589,7 @@ impl<H: Hasher, const N: usize> Store<H, N> {
         while reader.read_next(obj)? {
             self.map
                 .insert(obj.hash(), Entry::new(obj.info(), self.offset));
+            self.locate(obj.hash(), obj.info(), obj.as_data());
             idx.write_all(obj.as_header())?;
             self.offset += (N + 4 + obj.info().size()) as u64;
         }
@@ -484,7 +601,34 @@ impl<H: Hasher, const N: usize> Store<H, N> {
         Ok(())
     }
 
+    // Copy the object from the cache, if it's there.  This is the one cache
+    // lookup per load (so one hit or one miss), and objects we don't have at
+    // all aren't looked up.
+    fn load_cached(&mut self, hash: &Name<N>, obj: &mut Object<H, N>) -> bool {
+        if !self.contains(hash) {
+            return false;
+        }
+        if let Some(cache) = &mut self.cache {
+            if let Some(buf) = cache.get(hash) {
+                let vec = obj.as_mut_vec();
+                vec.clear();
+                vec.extend_from_slice(buf);
+                return true;
+            }
+        }
+        false
+    }
+
     pub fn load_unchecked(&mut self, hash: &Name<N>, obj: &mut Object<H, N>) -> IoResult<bool> {
+        if self.load_cached(hash, obj) || self.load_raw(hash, obj)? {
+            Ok(true)
+        } else {
+            self.load_held(hash, obj, 0)
+        }
+    }
+
+    // Read the object as stored (without verifying it).
+    fn load_raw(&mut self, hash: &Name<N>, obj: &mut Object<H, N>) -> IoResult<bool> {
         if let Some(entry) = self.map.get(hash) {
             obj.reset(entry.info.size(), entry.info.kind());
             self.file.read_exact_at(obj.as_mut_buf(), entry.offset)?;
@@ -499,17 +643,76 @@ impl<H: Hasher, const N: usize> Store<H, N> {
     }
 
     pub fn load(&mut self, hash: &Name<N>, obj: &mut Object<H, N>) -> IoResult<bool> {
-        if self.load_unchecked(hash, obj)? {
+        self.load_at(hash, obj, 0)
+    }
+
+    /// Like `load()`, but when `depth` containers deep (see `Codec.decode()`).
+    pub fn load_at(&mut self, hash: &Name<N>, obj: &mut Object<H, N>, depth: u8) -> IoResult<bool> {
+        if self.load_cached(hash, obj) {
+            return Ok(true);
+        }
+        if self.load_raw(hash, obj)? {
             if !obj.validate_against(hash) {
                 panic!("{} hash does not match", hash);
             }
+            if let Some(cache) = &mut self.cache {
+                cache.insert(*hash, obj.as_buf());
+            }
             Ok(true)
         } else {
-            Ok(false)
+            self.load_held(hash, obj, depth)
+        }
+    }
+
+    // Decode `hash` out of the container holding it.
+    fn load_held(&mut self, hash: &Name<N>, obj: &mut Object<H, N>, depth: u8) -> IoResult<bool> {
+        let (Some(container), Some(codec)) = (self.container_of(hash), self.codec.clone()) else {
+            return Ok(false);
+        };
+        if !self.load_at(&container, obj, depth)? {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Cannot find container {} for {}", container, hash),
+            ));
         }
+        codec.decode(self, hash, obj, depth)?;
+        if !obj.validate_against(hash) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} decoded from {} does not match", hash, container),
+            ));
+        }
+        if let Some(cache) = &mut self.cache {
+            cache.insert(*hash, obj.as_buf());
+        }
+        Ok(true)
     }
 
     pub fn save(&mut self, obj: &Object<H, N>) -> IoResult<bool> {
+        self.save_with_base(obj, None)
+    }
+
+    /// Save `obj`, as a delta against `base` if the codec thinks that's worth it.
+    pub fn save_with_base(&mut self, obj: &Object<H, N>, base: Option<&Name<N>>) -> IoResult<bool> {
+        if self.contains(&obj.hash()) {
+            return Ok(false);
+        }
+        if let Some(codec) = self.codec.clone() {
+            if obj.kind() != ObjKind::Stream {
+                let mut tmp = self.tmp.take().unwrap_or_else(Object::new);
+                let saved = match codec.encode(self, obj, base, &mut tmp) {
+                    Ok(true) => self.save_raw(&tmp),
+                    Ok(false) => self.save_raw(obj),
+                    Err(err) => Err(err),
+                };
+                self.tmp = Some(tmp);
+                return saved;
+            }
+        }
+        self.save_raw(obj)
+    }
+
+    fn save_raw(&mut self, obj: &Object<H, N>) -> IoResult<bool> {
         let hash = obj.hash();
         let info = obj.info();
         if let Some(_entry) = self.map.get(&hash) {
@@ -517,6 +720,7 @@ impl<H: Hasher, const N: usize> Store<H, N> {
         } else {
             self.file.write_all(obj.as_buf())?;
             self.map.insert(hash, Entry::new(info, self.offset));
+            self.locate(hash, info, obj.as_data());
             self.offset += obj.len() as u64;
             Ok(true)
         }
@@ -571,7 +775,7 @@ mod tests {
         let mut name = DefaultName::new();
         for _ in 0..777 {
             name.randomize();
-            set.insert(name.clone());
+            set.insert(name);
         }
         assert_eq!(set.len(), 777);
     }
@@ -706,12 +910,72 @@ mod tests {
 
         let keys = store.keys();
         for key in keys.iter() {
-            assert!(store.load(&key, &mut obj1).unwrap());
+            assert!(store.load(key, &mut obj1).unwrap());
         }
         store.reindex(&mut obj1).unwrap();
         assert_eq!(store.len(), keys.len());
         for key in keys.iter() {
-            assert!(store.load(&key, &mut obj1).unwrap());
+            assert!(store.load(key, &mut obj1).unwrap());
         }
     }
+
+    #[test]
+    fn test_store_cache() {
+        let tmp = TestTempDir::new();
+        let file = tmp.create(&["foo"]);
+        let mut store = Store::<Blake3, 30>::new(file);
+        let mut obj = store.new_object();
+        assert_eq!(store.cache_stats(), None);
+        store.enable_cache(1 << 20);
+        assert_eq!(store.cache_stats(), Some(CacheStats::default()));
+
+        let mut hashes = Vec::new();
+        let mut bufs = Vec::new();
+        for _ in 0..16 {
+            hashes.push(obj.randomize(true));
+            bufs.push(obj.as_buf().to_vec());
+            store.save(&obj).unwrap();
+        }
+        for _ in 0..3 {
+            for (hash, buf) in hashes.iter().zip(bufs.iter()) {
+                assert!(store.load(hash, &mut obj).unwrap());
+                assert_eq!(obj.as_buf(), buf);
+                assert!(store.load_unchecked(hash, &mut obj).unwrap());
+                assert_eq!(obj.as_buf(), buf);
+            }
+        }
+        let stats = store.cache_stats().unwrap();
+        assert_eq!(
+            stats,
+            CacheStats {
+                hits: 80,
+                misses: 16
+            }
+        );
+
+        // Objects we don't have don't count
+        let mut missing = DefaultName::new();
+        missing.randomize();
+        assert!(!store.load(&missing, &mut obj).unwrap());
+        assert_eq!(store.cache_stats().unwrap(), stats);
+
+        // Too big to cache
+        obj.clear();
+        obj.extend(&vec![42; (1 << 20) / crate::cache::MAX_FRACTION + 1]);
+        let big = obj.finalize_with_kind(ObjKind::Data as u8);
+        store.save(&obj).unwrap();
+        assert!(store.load(&big, &mut obj).unwrap());
+        assert!(store.load_unchecked(&big, &mut obj).unwrap());
+        assert!(store.load(&big, &mut obj).unwrap());
+        assert_eq!(store.cache_stats().unwrap().misses, 19);
+        assert_eq!(store.cache_stats().unwrap().hits, 80);
+
+        // Reindexing starts over
+        store.reindex(&mut obj).unwrap();
+        assert!(store.load(&hashes[0], &mut obj).unwrap());
+        assert_eq!(obj.as_buf(), bufs[0]);
+        store.disable_cache();
+        assert_eq!(store.cache_stats(), None);
+        assert!(store.load(&hashes[0], &mut obj).unwrap());
+    }
 }
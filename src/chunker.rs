@@ -0,0 +1,282 @@
+//! Content defined chunking for BigData leaves. ✂️
+//!
+//! With fixed size leaves, inserting a single byte near the start of a file
+//! shifts (and so changes) every leaf after it.  With content defined chunking
+//! the content itself picks the cut points, using a rolling "gear" hash as in
+//! FastCDC.  After an edit the cut points quickly fall back into step, so
+//! everything but the changed chunk dedupes.
+//!
+//! We use FastCDC's normalized chunking: a harder mask before the average size
+//! and an easier one after it, which keeps chunk sizes close to the average.
+//!
+//! The chunking used is recorded in the BigData root object, see
+//! `inception::LeafHashes`.
+
+use crate::base::OBJECT_MAX_SIZE;
+use std::io;
+use std::io::prelude::*;
+
+pub const CDC_MIN_SIZE: usize = 1 << 20; // 1 MiB
+pub const CDC_AVG_SIZE: usize = 1 << 22; // 4 MiB
+pub const CDC_MAX_SIZE: usize = OBJECT_MAX_SIZE; // 16 MiB
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Chunking {
+    Fixed,
+    #[default]
+    FastCdc,
+    Unknown,
+}
+
+impl From<u8> for Chunking {
+    fn from(item: u8) -> Self {
+        match item {
+            0 => Self::Fixed,
+            1 => Self::FastCdc,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+// SplitMix64, so the gear table is reproducible without shipping 2 KiB of hex
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0_u64; 256];
+    let mut state: u64 = 0x7475622043444321; // "tub CDC!"
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+static GEAR: [u64; 256] = gear_table();
+
+// Mask with the top `bits` bits set (the top bits of a gear hash depend on the
+// most bytes).
+const fn top_mask(bits: u32) -> u64 {
+    !0 << (64 - bits)
+}
+
+/// Finds content defined cut points.
+#[derive(Debug, Clone)]
+pub struct Chunker {
+    min: usize,
+    avg: usize,
+    max: usize,
+    mask_s: u64,
+    mask_l: u64,
+}
+
+impl Default for Chunker {
+    fn default() -> Self {
+        Self::new(CDC_MIN_SIZE, CDC_AVG_SIZE, CDC_MAX_SIZE)
+    }
+}
+
+impl Chunker {
+    pub fn new(min: usize, avg: usize, max: usize) -> Self {
+        assert!(avg.is_power_of_two());
+        assert!(0 < min && min <= avg && avg <= max && max <= OBJECT_MAX_SIZE);
+        let bits = avg.trailing_zeros();
+        assert!(bits > 2);
+        Self {
+            min,
+            avg,
+            max,
+            mask_s: top_mask(bits + 2),
+            mask_l: top_mask(bits - 2),
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Length of the chunk at the start of `buf`.
+    ///
+    /// Unless at EOF, `buf` must hold at least `max()` bytes or the cut point
+    /// depends on how much was read.
+    pub fn cut(&self, buf: &[u8]) -> usize {
+        if buf.len() <= self.min {
+            return buf.len();
+        }
+        let end = buf.len().min(self.max);
+        let normal = self.avg.min(end);
+        let mut h: u64 = 0;
+        let mut i = self.min;
+        while i < normal {
+            h = (h << 1).wrapping_add(GEAR[buf[i] as usize]);
+            if h & self.mask_s == 0 {
+                return i + 1;
+            }
+            i += 1;
+        }
+        while i < end {
+            h = (h << 1).wrapping_add(GEAR[buf[i] as usize]);
+            if h & self.mask_l == 0 {
+                return i + 1;
+            }
+            i += 1;
+        }
+        end
+    }
+}
+
+/// Reads content defined chunks out of a `Read`.
+pub struct ChunkReader<R: Read> {
+    inner: R,
+    chunker: Chunker,
+    buf: Vec<u8>,
+    start: usize,
+    end: usize,
+    eof: bool,
+}
+
+impl<R: Read> ChunkReader<R> {
+    pub fn new(inner: R, chunker: Chunker) -> Self {
+        // Twice max so we only move the leftovers once per max bytes
+        let buf = vec![0; chunker.max() * 2];
+        Self {
+            inner,
+            chunker,
+            buf,
+            start: 0,
+            end: 0,
+            eof: false,
+        }
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        self.buf.copy_within(self.start..self.end, 0);
+        self.end -= self.start;
+        self.start = 0;
+        while self.end < self.buf.len() {
+            match self.inner.read(&mut self.buf[self.end..]) {
+                Ok(0) => {
+                    self.eof = true;
+                    break;
+                }
+                Ok(n) => self.end += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// The next chunk, or `None` at EOF.
+    pub fn next_chunk(&mut self) -> io::Result<Option<&[u8]>> {
+        if !self.eof && self.end - self.start < self.chunker.max() {
+            self.fill()?;
+        }
+        if self.start == self.end {
+            return Ok(None);
+        }
+        let size = self.chunker.cut(&self.buf[self.start..self.end]);
+        let chunk = &self.buf[self.start..self.start + size];
+        self.start += size;
+        Ok(Some(chunk))
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn small() -> Chunker {
+        Chunker::new(256, 1024, 4096)
+    }
+
+    fn chunk_all(data: &[u8]) -> Vec<Vec<u8>> {
+        let mut reader = ChunkReader::new(data, small());
+        let mut chunks = Vec::new();
+        while let Some(chunk) = reader.next_chunk().unwrap() {
+            chunks.push(chunk.to_vec());
+        }
+        chunks
+    }
+
+    #[test]
+    fn test_chunking() {
+        for k in 0_u8..=255 {
+            let chunking: Chunking = k.into();
+            if k < 2 {
+                assert_eq!(chunking as u8, k);
+            } else {
+                assert_eq!(chunking, Chunking::Unknown);
+            }
+        }
+        assert_eq!(Chunking::default(), Chunking::FastCdc);
+    }
+
+    #[test]
+    fn test_gear_table() {
+        assert_eq!(GEAR, gear_table());
+        for (i, a) in GEAR.iter().enumerate() {
+            assert!(!GEAR[..i].contains(a));
+        }
+    }
+
+    #[test]
+    fn test_cut() {
+        let chunker = small();
+        assert_eq!(chunker.cut(b""), 0);
+        assert_eq!(chunker.cut(&[7; 256]), 256);
+        // No cut point in constant data, so we hit max
+        assert_eq!(chunker.cut(&[0; 10000]), 4096);
+        assert_eq!(chunker.cut(&[0; 3000]), 3000);
+
+        let data = noise(65536, 7);
+        let size = chunker.cut(&data);
+        assert!((256..=4096).contains(&size));
+        assert_eq!(chunker.cut(&data[..size]), size);
+    }
+
+    #[test]
+    fn test_chunk_reader() {
+        assert!(chunk_all(b"").is_empty());
+        let data = noise(200000, 42);
+        let chunks = chunk_all(&data);
+        assert_eq!(chunks.concat(), data);
+        for chunk in &chunks[..chunks.len() - 1] {
+            assert!((256..=4096).contains(&chunk.len()));
+        }
+        let avg = data.len() / chunks.len();
+        assert!(avg > 512 && avg < 2048);
+    }
+
+    // Reproducible "random" data (SplitMix64), so chunk boundaries are stable
+    fn noise(size: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        let mut data = Vec::with_capacity(size + 8);
+        while data.len() < size {
+            state = state.wrapping_add(0x9e3779b97f4a7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            data.extend_from_slice(&(z ^ (z >> 31)).to_le_bytes());
+        }
+        data.truncate(size);
+        data
+    }
+
+    #[test]
+    fn test_resync() {
+        let mut data = noise(200000, 69);
+        let before = chunk_all(&data);
+        data.insert(1000, 69);
+        let after = chunk_all(&data);
+        assert_eq!(after.concat(), data);
+        let changed = after.iter().filter(|c| !before.contains(c)).count();
+        assert!(changed <= 3);
+    }
+}
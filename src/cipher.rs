@@ -0,0 +1,330 @@
+//! Authenticated encryption for container objects. 🔐
+//!
+//! This is the third (and last) stage of the container encoding pipeline, see
+//! `tub::inception`.  The Encrypt byte in the container header picks the AEAD:
+//!
+//! | Byte | Cipher             | Nonce | Tag |
+//! |------|--------------------|-------|-----|
+//! |    0 | None (passthrough) |     0 |   0 |
+//! |    1 | XChaCha20-Poly1305 |    24 |  16 |
+//! |    2 | AES-256-GCM        |    12 |  16 |
+//!
+//! Each repository has a single random 32 byte `Secret`.  Per-cipher keys are
+//! derived from it with `blake3::derive_key()`, so the two ciphers never share
+//! key material.  The secret is kept in the user's config directory, not in the
+//! repository, and is found by its `Secret::id()` (see `tub::Tub::encryption()`).
+//!
+//! Nonces are never random and never counters.  The nonce is a keyed hash of
+//! the length-prefixed AAD followed by the plaintext (a synthetic nonce, as in
+//! SIV).  A nonce only repeats when both the AAD and the plaintext repeat, in
+//! which case the ciphertext is identical too, so the only thing leaked is
+//! equality... which content addressing leaks anyway.
+//! As a bonus, encrypting the same container twice gives the same `Name`.
+
+use crate::dbase32::db32enc;
+use aes_gcm::Aes256Gcm;
+use aes_gcm::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::XChaCha20Poly1305;
+use std::io;
+
+pub const SECRET_LEN: usize = 32;
+pub const TAG_LEN: usize = 16;
+
+const XCHACHA_KEY_CONTEXT: &str = "tub 2025 cipher XChaCha20-Poly1305 key";
+const XCHACHA_NONCE_CONTEXT: &str = "tub 2025 cipher XChaCha20-Poly1305 nonce";
+const AESGCM_KEY_CONTEXT: &str = "tub 2025 cipher AES-256-GCM key";
+const AESGCM_NONCE_CONTEXT: &str = "tub 2025 cipher AES-256-GCM nonce";
+const SECRET_ID_CONTEXT: &str = "tub 2025 cipher secret id";
+
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub enum Cipher {
+    #[default]
+    None,
+    XChaCha20Poly1305,
+    Aes256Gcm,
+    Unknown,
+}
+
+impl From<u8> for Cipher {
+    fn from(item: u8) -> Self {
+        match item {
+            0 => Self::None,
+            1 => Self::XChaCha20Poly1305,
+            2 => Self::Aes256Gcm,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+impl Cipher {
+    pub fn nonce_len(&self) -> usize {
+        match self {
+            Self::XChaCha20Poly1305 => 24,
+            Self::Aes256Gcm => 12,
+            _ => 0,
+        }
+    }
+
+    pub fn tag_len(&self) -> usize {
+        match self {
+            Self::XChaCha20Poly1305 | Self::Aes256Gcm => TAG_LEN,
+            _ => 0,
+        }
+    }
+
+    /// Bytes added to the container data by this cipher (nonce plus tag).
+    pub fn overhead(&self) -> usize {
+        self.nonce_len() + self.tag_len()
+    }
+
+    /// Parse the name used in settings and on the command line.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Self::None),
+            "xchacha20-poly1305" => Some(Self::XChaCha20Poly1305),
+            "aes-256-gcm" => Some(Self::Aes256Gcm),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::XChaCha20Poly1305 => "xchacha20-poly1305",
+            Self::Aes256Gcm => "aes-256-gcm",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
+/// Per-repository secret from which all encryption keys are derived.
+#[derive(Clone)]
+pub struct Secret {
+    buf: [u8; SECRET_LEN],
+}
+
+impl Secret {
+    pub fn generate() -> Self {
+        let mut buf = [0_u8; SECRET_LEN];
+        getrandom::fill(&mut buf).unwrap();
+        Self { buf }
+    }
+
+    pub fn from(src: &[u8]) -> io::Result<Self> {
+        match src.try_into() {
+            Ok(buf) => Ok(Self { buf }),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("secret must be {} bytes, got {}", SECRET_LEN, src.len()),
+            )),
+        }
+    }
+
+    /// Public identifier of this secret (Dbase32, safe to put in a repository).
+    pub fn id(&self) -> String {
+        let id = blake3::derive_key(SECRET_ID_CONTEXT, &self.buf);
+        db32enc(&id[..15])
+    }
+
+    pub fn as_buf(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn derive(&self, cipher: Cipher) -> Keys {
+        let (kctx, nctx) = match cipher {
+            Cipher::XChaCha20Poly1305 => (XCHACHA_KEY_CONTEXT, XCHACHA_NONCE_CONTEXT),
+            Cipher::Aes256Gcm => (AESGCM_KEY_CONTEXT, AESGCM_NONCE_CONTEXT),
+            _ => panic!("Cannot derive keys for {:?}", cipher),
+        };
+        Keys {
+            cipher,
+            key: blake3::derive_key(kctx, &self.buf),
+            nonce_key: blake3::derive_key(nctx, &self.buf),
+        }
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.buf.fill(0);
+    }
+}
+
+/// Encryption key plus nonce derivation key for a single `Cipher`.
+pub struct Keys {
+    cipher: Cipher,
+    key: [u8; 32],
+    nonce_key: [u8; 32],
+}
+
+impl Keys {
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    /// Same AAD and plaintext gives the same nonce, anything else doesn't.
+    pub fn derive_nonce(&self, aad: &[u8], plaintext: &[u8], nonce: &mut [u8]) {
+        assert_eq!(nonce.len(), self.cipher.nonce_len());
+        let mut h = blake3::Hasher::new_keyed(&self.nonce_key);
+        h.update(&(aad.len() as u64).to_le_bytes());
+        h.update(aad);
+        h.update(plaintext);
+        h.finalize_xof().fill(nonce);
+    }
+
+    /// Encrypt `buf` in place, returning the tag.  `nonce` gets filled in.
+    pub fn encrypt(
+        &self,
+        aad: &[u8],
+        nonce: &mut [u8],
+        buf: &mut [u8],
+    ) -> io::Result<[u8; TAG_LEN]> {
+        self.derive_nonce(aad, buf, nonce);
+        let tag = match self.cipher {
+            Cipher::XChaCha20Poly1305 => XChaCha20Poly1305::new(&self.key.into())
+                .encrypt_in_place_detached(nonce[..].into(), aad, buf),
+            Cipher::Aes256Gcm => Aes256Gcm::new(&self.key.into()).encrypt_in_place_detached(
+                nonce[..].into(),
+                aad,
+                buf,
+            ),
+            _ => panic!("Cannot encrypt with {:?}", self.cipher),
+        };
+        match tag {
+            Ok(tag) => Ok(tag.into()),
+            _ => Err(io::Error::other("encryption failed")),
+        }
+    }
+
+    /// Decrypt `buf` in place, failing if the tag does not authenticate.
+    pub fn decrypt(&self, aad: &[u8], nonce: &[u8], buf: &mut [u8], tag: &[u8]) -> io::Result<()> {
+        assert_eq!(nonce.len(), self.cipher.nonce_len());
+        assert_eq!(tag.len(), TAG_LEN);
+        let r = match self.cipher {
+            Cipher::XChaCha20Poly1305 => XChaCha20Poly1305::new(&self.key.into())
+                .decrypt_in_place_detached(nonce.into(), aad, buf, tag.into()),
+            Cipher::Aes256Gcm => Aes256Gcm::new(&self.key.into()).decrypt_in_place_detached(
+                nonce.into(),
+                aad,
+                buf,
+                tag.into(),
+            ),
+            _ => panic!("Cannot decrypt with {:?}", self.cipher),
+        };
+        match r {
+            Ok(_) => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "container failed authentication",
+            )),
+        }
+    }
+}
+
+impl Drop for Keys {
+    fn drop(&mut self) {
+        self.key.fill(0);
+        self.nonce_key.fill(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::flip_bit_in;
+
+    #[test]
+    fn test_cipher() {
+        for k in 0_u8..=255 {
+            let cipher: Cipher = k.into();
+            if k < 3 {
+                assert_eq!(cipher as u8, k);
+            } else {
+                assert_eq!(cipher, Cipher::Unknown);
+            }
+        }
+        assert_eq!(Cipher::None.overhead(), 0);
+        assert_eq!(Cipher::XChaCha20Poly1305.overhead(), 40);
+        assert_eq!(Cipher::Aes256Gcm.overhead(), 28);
+        for cipher in [Cipher::None, Cipher::XChaCha20Poly1305, Cipher::Aes256Gcm] {
+            assert_eq!(Cipher::parse(cipher.name()), Some(cipher));
+        }
+        assert_eq!(Cipher::parse("unknown"), None);
+        assert_eq!(Cipher::parse("AES-256-GCM"), None);
+    }
+
+    #[test]
+    fn test_secret() {
+        let secret = Secret::generate();
+        let again = Secret::from(secret.as_buf()).unwrap();
+        assert_eq!(again.as_buf(), secret.as_buf());
+        assert_eq!(again.id(), secret.id());
+        assert_eq!(secret.id().len(), 24);
+        assert_ne!(Secret::generate().id(), secret.id());
+        assert!(Secret::from(&[0; SECRET_LEN - 1]).is_err());
+        assert!(Secret::from(&[0; SECRET_LEN + 1]).is_err());
+        assert!(Secret::from(&[]).is_err());
+    }
+
+    #[test]
+    fn test_derive() {
+        let secret = Secret::generate();
+        let a = secret.derive(Cipher::XChaCha20Poly1305);
+        let b = secret.derive(Cipher::Aes256Gcm);
+        assert_ne!(a.key, b.key);
+        assert_ne!(a.nonce_key, b.nonce_key);
+        assert_ne!(a.key, a.nonce_key);
+        let again = Secret::from(secret.as_buf())
+            .unwrap()
+            .derive(Cipher::Aes256Gcm);
+        assert_eq!(again.key, b.key);
+        assert_eq!(again.nonce_key, b.nonce_key);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let secret = Secret::generate();
+        for cipher in [Cipher::XChaCha20Poly1305, Cipher::Aes256Gcm] {
+            let keys = secret.derive(cipher);
+            let mut plaintext = vec![0_u8; 69];
+            getrandom::fill(&mut plaintext).unwrap();
+            let mut buf = plaintext.clone();
+            let mut nonce = vec![0_u8; cipher.nonce_len()];
+            let tag = keys.encrypt(b"aad", &mut nonce, &mut buf).unwrap();
+            assert_ne!(buf, plaintext);
+
+            // Synthetic nonce means encryption is deterministic
+            let mut buf2 = plaintext.clone();
+            let mut nonce2 = vec![0_u8; cipher.nonce_len()];
+            assert_eq!(keys.encrypt(b"aad", &mut nonce2, &mut buf2).unwrap(), tag);
+            assert_eq!((&buf, &nonce), (&buf2, &nonce2));
+
+            // Same plaintext with different AAD must never reuse the nonce
+            let mut buf3 = plaintext.clone();
+            let mut nonce3 = vec![0_u8; cipher.nonce_len()];
+            keys.encrypt(b"aae", &mut nonce3, &mut buf3).unwrap();
+            assert_ne!(nonce3, nonce);
+            let mut nonce4 = vec![0_u8; cipher.nonce_len()];
+            keys.derive_nonce(b"aa", &plaintext, &mut nonce4);
+            let mut nonce5 = vec![0_u8; cipher.nonce_len()];
+            let mut shifted = b"d".to_vec();
+            shifted.extend_from_slice(&plaintext);
+            keys.derive_nonce(b"a", &shifted, &mut nonce5);
+            assert_ne!(nonce4, nonce5);
+
+            for bit in 0..buf.len() * 8 {
+                let mut copy = buf.clone();
+                flip_bit_in(&mut copy, bit);
+                assert!(keys.decrypt(b"aad", &nonce, &mut copy, &tag).is_err());
+            }
+            let mut copy = buf.clone();
+            assert!(keys.decrypt(b"aaa", &nonce, &mut copy, &tag).is_err());
+            let other = Secret::generate().derive(cipher);
+            let mut copy = buf.clone();
+            assert!(other.decrypt(b"aad", &nonce, &mut copy, &tag).is_err());
+
+            keys.decrypt(b"aad", &nonce, &mut buf, &tag).unwrap();
+            assert_eq!(buf, plaintext);
+        }
+    }
+}
@@ -1,5 +1,6 @@
 //! CLI commands for WIP version control tool `tub`.
 
+use std::collections::HashMap;
 use std::env;
 use std::fs;
 use std::io;
@@ -8,14 +9,24 @@ use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::time::Instant;
 
-use clap::{Parser, Subcommand};
-use yansi::Paint;
+use clap::{Args, Parser, Subcommand};
 
-use crate::base::ObjKind;
+use crate::base::{DEFAULT_BRANCH, ObjKind};
+use crate::blockchain::Chain;
 use crate::chaos::{DefaultName, DefaultObject};
-use crate::dvcs::{DefaultCommit, DefaultTree};
-use crate::inception::hash_file;
-use crate::tub::{DefaultTub, find_dotdir};
+use crate::chunker::Chunking;
+use crate::cipher::Cipher;
+use crate::dbase32::isdb32;
+use crate::dictionary::DICT_MAX_SIZE;
+use crate::diff::{Blob, Content, DEFAULT_CONTEXT, FileDiff, Hunk, LineKind, diff_words};
+use crate::dvcs::{
+    DefaultCommit, DefaultTree, ItemMap, MetaMap, Person, TrackedItem, TrackingList, compare_trees,
+    display_relpath, overwritten, relpath_to_os,
+};
+use crate::inception::{hash_file, import_reader};
+use crate::merge::{ConflictKind, MergeState, merge_base, merge_flat, merge_metas};
+use crate::meta::MetaOptions;
+use crate::tub::{DefaultTub, find_dotdir, is_branch_name, tree_relpath};
 
 type OptPath = Option<PathBuf>;
 
@@ -36,7 +47,32 @@ enum Commands {
     },
 
     #[command(about = "👷 Fork 🥄 history into a new indpendent branch 🪛")]
-    Branch {},
+    Branch {
+        #[arg(short, long, value_name = "DIR")]
+        #[arg(help = "Path of Tub control directory (defaults to CWD)")]
+        tub: Option<PathBuf>,
+
+        #[arg(help = "Name of branch to create (lists branches when omitted)")]
+        name: Option<String>,
+
+        #[arg(short, long, value_name = "BRANCH_OR_HASH")]
+        #[arg(help = "Fork from this branch or commit (defaults to current branch)")]
+        from: Option<String>,
+
+        #[arg(short, long)]
+        #[arg(help = "Delete the branch instead")]
+        delete: bool,
+    },
+
+    #[command(about = "🔀 Switch the working tree to another branch")]
+    Switch {
+        #[arg(short, long, value_name = "DIR")]
+        #[arg(help = "Path of Tub control directory (defaults to CWD)")]
+        tub: Option<PathBuf>,
+
+        #[arg(help = "Name of branch")]
+        name: String,
+    },
 
     #[command(about = "🔴 Remove paths from tracking list")]
     Rm {
@@ -71,24 +107,52 @@ enum Commands {
         tub: Option<PathBuf>,
     },
 
-    #[command(about = "🚫 Add paths to ignore list")]
+    #[command(about = "🚫 Add patterns to ignore list (.gitignore style)")]
     Ignore {
         #[arg(short, long, value_name = "DIR")]
         #[arg(help = "Path of Tub control directory (defaults to CWD)")]
         tub: Option<PathBuf>,
 
-        #[arg(help = "path names to ignore (or unignore)")]
+        #[arg(help = "patterns to ignore (or unignore), like *.o or /build/")]
         paths: Vec<String>,
 
-        #[arg(short, long, help = "Remove paths from ignore list")]
+        #[arg(short, long, help = "Remove patterns from ignore list")]
         remove: bool,
     },
 
+    #[command(about = "🙈 Explain which ignore pattern matches a path")]
+    CheckIgnore {
+        #[arg(short, long, value_name = "DIR")]
+        #[arg(help = "Path of Tub control directory (defaults to CWD)")]
+        tub: Option<PathBuf>,
+
+        #[arg(help = "paths to check (relative to the tree)")]
+        #[arg(required = true)]
+        paths: Vec<String>,
+    },
+
     #[command(about = "🔎 Examine changes in working tree")]
     Diff {
         #[arg(short, long, value_name = "DIR")]
         #[arg(help = "Path of Tub control directory (defaults to CWD)")]
         tub: Option<PathBuf>,
+
+        #[arg(help = "Branch, commit, or tree to compare from (defaults to last commit)")]
+        a: Option<String>,
+
+        #[arg(help = "Branch, commit, or tree to compare to (defaults to working tree)")]
+        b: Option<String>,
+
+        #[arg(long, conflicts_with = "b")]
+        #[arg(help = "Only the changes `tub commit` would take")]
+        staged: bool,
+
+        #[arg(long)]
+        #[arg(help = "Don't look for renamed and copied files")]
+        no_renames: bool,
+
+        #[command(flatten)]
+        format: DiffFormat,
     },
 
     #[command(about = "🤔 Sumarize changes in working tree")]
@@ -96,6 +160,14 @@ enum Commands {
         #[arg(short, long, value_name = "DIR")]
         #[arg(help = "Path of Tub control directory (defaults to CWD)")]
         tub: Option<PathBuf>,
+
+        #[arg(long)]
+        #[arg(help = "Print object cache hits and misses")]
+        cache_stats: bool,
+
+        #[arg(long)]
+        #[arg(help = "Don't look for renamed and copied files")]
+        no_renames: bool,
     },
 
     #[command(about = "💖 Take a snapshot 📸 of your work 🤓")]
@@ -110,7 +182,14 @@ enum Commands {
     },
 
     #[command(about = "🧬 Bring changes from one branch into another 😍")]
-    Merge {},
+    Merge {
+        #[arg(short, long, value_name = "DIR")]
+        #[arg(help = "Path of Tub control directory (defaults to CWD)")]
+        tub: Option<PathBuf>,
+
+        #[arg(help = "Branch name or Dbase32-encoded commit hash to merge")]
+        target: String,
+    },
 
     #[command(about = "🚽 Undo 💩 changes in working tree")]
     Revert {
@@ -122,6 +201,26 @@ enum Commands {
         hash: String,
     },
 
+    #[command(about = "🏷️ Choose which file metadata commits keep")]
+    Meta {
+        #[arg(short, long, value_name = "DIR")]
+        #[arg(help = "Path of Tub control directory (defaults to CWD)")]
+        tub: Option<PathBuf>,
+
+        #[arg(help = "Comma separated: mtime, mode, owner, xattrs (or all, none)")]
+        fields: Option<String>,
+    },
+
+    #[command(about = "🔐 Choose which cipher encrypts new objects")]
+    Encrypt {
+        #[arg(short, long, value_name = "DIR")]
+        #[arg(help = "Path of Tub control directory (defaults to CWD)")]
+        tub: Option<PathBuf>,
+
+        #[arg(help = "xchacha20-poly1305, aes-256-gcm, or none")]
+        cipher: Option<String>,
+    },
+
     #[command(about = "📜 View commit history")]
     Log {
         #[arg(short, long, value_name = "DIR")]
@@ -136,6 +235,23 @@ enum Commands {
         tub: Option<PathBuf>,
     },
 
+    #[command(about = "📥 Import a file (or - for stdin) into the object store")]
+    Import {
+        #[arg(short, long, value_name = "DIR")]
+        #[arg(help = "Path of Tub control directory (defaults to CWD)")]
+        tub: Option<PathBuf>,
+
+        #[arg(help = "Path of input file, or - to read stdin")]
+        path: PathBuf,
+    },
+
+    #[command(about = "📖 Train a compression dictionary from small objects")]
+    Train {
+        #[arg(short, long, value_name = "DIR")]
+        #[arg(help = "Path of Tub control directory (defaults to CWD)")]
+        tub: Option<PathBuf>,
+    },
+
     #[command(about = "🚀 Compare 🛁 hashing performance with git hash-object! 😜")]
     Hash {
         #[arg(help = "Path of input file")]
@@ -143,22 +259,63 @@ enum Commands {
     },
 }
 
+#[derive(Debug, Args)]
+struct DiffFormat {
+    #[arg(long)]
+    #[arg(help = "Summarize the changes to each file")]
+    stat: bool,
+
+    #[arg(long, conflicts_with = "stat")]
+    #[arg(help = "Lines added and removed in each file, tab separated")]
+    numstat: bool,
+
+    #[arg(long)]
+    #[arg(help = "Show changed words instead of changed lines")]
+    word_diff: bool,
+
+    #[arg(short = 'U', long, value_name = "LINES", default_value_t = DEFAULT_CONTEXT)]
+    #[arg(help = "Lines of context around each change")]
+    unified: u32,
+}
+
 pub fn run() -> IoResult<()> {
     let args = Cli::parse();
     match args.command {
         Commands::Init { target } => cmd_init(target),
-        Commands::Branch {} => not_yet(),
-        Commands::Merge {} => not_yet(),
+        Commands::Branch {
+            tub,
+            name,
+            from,
+            delete,
+        } => cmd_branch(tub, name, from, delete),
+        Commands::Switch { tub, name } => cmd_switch(tub, name),
+        Commands::Merge { tub, target } => cmd_merge(tub, target),
         Commands::Add { tub, paths } => cmd_add(tub, paths),
         Commands::Mv { tub, src, dst } => cmd_mov(tub, src, dst),
         Commands::Rm { tub, paths } => cmd_rem(tub, paths),
         Commands::Ignore { tub, paths, remove } => cmd_ignore(tub, paths, remove),
-        Commands::Diff { tub } => cmd_dif(tub),
-        Commands::Status { tub } => cmd_status(tub),
+        Commands::CheckIgnore { tub, paths } => cmd_check_ignore(tub, paths),
+        Commands::Diff {
+            tub,
+            a,
+            b,
+            staged,
+            no_renames,
+            format,
+        } => cmd_dif(tub, a, b, staged, no_renames, format),
+        Commands::Status {
+            tub,
+            cache_stats,
+            no_renames,
+        } => cmd_status(tub, cache_stats, no_renames),
         Commands::Commit { tub, msg } => cmd_commit(tub, msg),
         Commands::Revert { tub, hash } => cmd_revert(tub, hash),
+        Commands::Meta { tub, fields } => cmd_meta(tub, fields),
+        Commands::Encrypt { tub, cipher } => cmd_encrypt(tub, cipher),
         Commands::Log { tub } => cmd_log(tub),
         Commands::Check { tub } => cmd_check(tub),
+        Commands::Import { tub, path } => cmd_import(tub, &path),
+        Commands::Train { tub } => cmd_train(tub),
         Commands::Hash { path } => cmd_hash(&path),
     }
 }
@@ -195,6 +352,10 @@ fn get_tub(target: &Path) -> IoResult<DefaultTub> {
 fn get_tub_exit(target: &Path) -> IoResult<DefaultTub> {
     match get_tub(target) {
         Ok(tub) => Ok(tub),
+        Err(err) if find_dotdir(target).is_some() => {
+            eprintln!("🛁❗ Could not open Tub in {:?}: {}", &target, err);
+            exit(42);
+        }
         _ => {
             eprintln!("🛁❗ Could not find Tub in {:?}", &target);
             exit(42);
@@ -202,9 +363,87 @@ fn get_tub_exit(target: &Path) -> IoResult<DefaultTub> {
     }
 }
 
-fn not_yet() -> IoResult<()> {
-    eprintln!("🛁❗ Yo dawg, this command hasn't been implemented yet! 🤪");
-    Ok(())
+// The current branch, with its key so we can sign commits.
+fn open_signing_branch(tub: &DefaultTub) -> IoResult<Chain> {
+    let name = tub.current_branch()?;
+    let mut chain = tub.open_branch(&name)?;
+    if !tub.load_branch_seckey(&name, &mut chain)? {
+        eprintln!(
+            "🛁❗ Cannot find key for {} ({})",
+            chain.header.hash(),
+            name
+        );
+        exit(42);
+    }
+    Ok(chain)
+}
+
+// Latest commit on branch `name` (`None` if it has no commits yet).
+fn branch_head(tub: &DefaultTub, name: &str) -> IoResult<Option<DefaultName>> {
+    let mut chain = tub.open_branch(name)?;
+    if chain.load_last_block()? {
+        Ok(Some(chain.block.payload()))
+    } else {
+        Ok(None)
+    }
+}
+
+// A branch name or commit hash given on the command line.
+fn resolve_commit(tub: &mut DefaultTub, txt: &str) -> IoResult<DefaultName> {
+    let hash = if tub.has_branch(txt) {
+        match branch_head(tub, txt)? {
+            Some(hash) => hash,
+            None => {
+                eprintln!("🛁❗ Branch {} has no commits yet", txt);
+                exit(42);
+            }
+        }
+    } else if txt.len() == 48 && isdb32(txt.as_bytes()) {
+        DefaultName::from_dbase32(txt)
+    } else {
+        eprintln!("🛁❗ Not a branch or commit hash: {:?}", txt);
+        exit(42);
+    };
+    let mut obj = tub.store.new_object();
+    if !tub.store.load(&hash, &mut obj)? || obj.kind() != ObjKind::Commit {
+        eprintln!("🛁❗ Cannot find commit {}", hash);
+        exit(42);
+    }
+    Ok(hash)
+}
+
+// Refuse to touch the working tree when it has changes that aren't committed.
+// Returns the untracked items, see `exit_if_overwritten()`.
+fn exit_if_dirty(tree: &mut DefaultTree, flat: &ItemMap<30>, what: &str) -> IoResult<ItemMap<30>> {
+    tree.scan_tree()?;
+    let status = tree.compare_with_flatmap(flat);
+    if !status.changed.is_empty() || !status.removed.is_empty() {
+        eprintln!("🛁❗ Commit or revert your changes before {}", what);
+        exit(42);
+    }
+    let flatmap = tree.flatmap();
+    Ok(status
+        .unknown
+        .into_iter()
+        .map(|path| {
+            let item = flatmap[&path].clone();
+            (path, item)
+        })
+        .collect())
+}
+
+fn exit_if_overwritten(untracked: &ItemMap<30>, new: &ItemMap<30>, what: &str) {
+    let paths = overwritten(untracked, new);
+    if !paths.is_empty() {
+        eprintln!(
+            "🛁❗ Move these untracked paths out of the way before {}:",
+            what
+        );
+        for path in paths {
+            eprintln!("  {}", display_relpath(&path));
+        }
+        exit(42);
+    }
 }
 
 fn cmd_init(target: OptPath) -> IoResult<()> {
@@ -216,7 +455,8 @@ fn cmd_init(target: OptPath) -> IoResult<()> {
         }
         _ => {
             let tub = DefaultTub::create(&target)?;
-            tub.create_branch()?;
+            tub.create_branch(DEFAULT_BRANCH)?;
+            tub.set_current_branch(DEFAULT_BRANCH)?;
             eprintln!("🛁 Created new Tub repository: {:?}", tub.dotdir());
             eprintln!("🛁 Excellent first step, now reward yourself with two cookies! 🍪🍪");
             Ok(())
@@ -233,7 +473,7 @@ fn cmd_add(tub: OptPath, paths: Vec<PathBuf>) -> IoResult<()> {
             eprintln!("🛁❗Path does not exists: {:?}", p);
             exit(42);
         }
-        tl.add(p.to_str().unwrap().to_owned());
+        tl.add(tree_relpath(tub.treedir(), &p)?);
     }
     tub.save_tracking_list(&mut obj, &tl)
 }
@@ -242,9 +482,17 @@ fn cmd_mov(tub: OptPath, old: PathBuf, new: PathBuf) -> IoResult<()> {
     let tub = get_tub_exit(&dir_or_cwd(tub)?)?;
     let mut obj = tub.store.new_object();
     let mut tl = tub.load_tracking_list(&mut obj)?;
-    let old = old.to_str().unwrap().to_owned();
-    let new = new.to_str().unwrap().to_owned();
-    tl.rename(old, new);
+    // Both must be inside the tree before anything gets moved
+    let old_relpath = tree_relpath(tub.treedir(), &old)?;
+    let new_relpath = tree_relpath(tub.treedir(), &new)?;
+    // Like `git mv`, also rename it in the working tree (unless that's done)
+    if old.symlink_metadata().is_ok() && new.symlink_metadata().is_err() {
+        fs::rename(&old, &new)?;
+    } else if new.symlink_metadata().is_err() {
+        eprintln!("🛁❗Path does not exists: {:?}", old);
+        exit(42);
+    }
+    tl.rename(old_relpath, new_relpath);
     for (key, item) in tl.as_sorted_vec().iter() {
         println!("{} {:+?}", key, item);
     }
@@ -260,7 +508,7 @@ fn cmd_rem(tub: OptPath, paths: Vec<PathBuf>) -> IoResult<()> {
             eprintln!("🛁❗Path does not exists: {:?}", p);
             exit(42);
         }
-        tl.remove(p.to_str().unwrap().to_owned());
+        tl.remove(tree_relpath(tub.treedir(), &p)?);
     }
     tub.save_tracking_list(&mut obj, &tl)
 }
@@ -268,70 +516,571 @@ fn cmd_rem(tub: OptPath, paths: Vec<PathBuf>) -> IoResult<()> {
 fn cmd_commit(tub: OptPath, msg: Option<String>) -> IoResult<()> {
     let mut tub = get_tub_exit(&dir_or_cwd(tub)?)?;
     let source = tub.treedir().to_owned();
-    let mut chain = tub.open_branch()?;
-    if !tub.load_branch_seckey(&mut chain)? {
-        eprintln!("🛁❗ Cannot find key for {}", chain.header.hash());
-        exit(42);
-    }
+    let mut chain = open_signing_branch(&tub)?;
     let mut obj = tub.store.new_object();
+    let tl = tub.load_tracking_list(&mut obj)?;
+    let merging = tub.load_merge_state()?;
+    if let Some(state) = &merging {
+        let unresolved = unresolved_conflicts(&source, state);
+        if !unresolved.is_empty() {
+            eprintln!("🛁❗ Fix the conflict markers in these first:");
+            for path in unresolved {
+                eprintln!("  {}", display_relpath(&path));
+            }
+            exit(42);
+        }
+    }
+    let mut previous = None;
+    let mut parents = Vec::new();
+    if chain.load_last_block()? && tub.store.load(&chain.block.payload(), &mut obj)? {
+        previous = Some(DefaultCommit::deserialize(obj.as_data()).tree);
+        parents.push(chain.block.payload());
+    }
+    if let Some(state) = &merging {
+        parents.push(state.theirs);
+    }
+    let meta = tub.load_meta_options()?;
     let mut scanner = DefaultTree::new(&mut tub.store, &source);
+    let mut bases = match previous {
+        Some(tree) => scanner.flatten_tree(&tree)?,
+        None => HashMap::new(),
+    };
+    scanner.set_tracked(Some(tl.apply(&bases)));
+    // A renamed file is still a good delta base for its new self
+    for (old, item) in tl.as_sorted_vec() {
+        if let (TrackedItem::Renamed(new), Some(base)) = (item, bases.get(old)) {
+            bases.insert(new.to_owned(), base.to_owned());
+        }
+    }
+    scanner.set_delta_bases(bases);
+    scanner.set_meta_options(meta);
     scanner.load_ignore()?;
     scanner.enable_import();
     eprintln!("🛁 Writing commit...");
-    if let Some(root) = scanner.scan_tree()? {
-        let msg = msg.unwrap_or_default();
-        let commit = DefaultCommit::new(root, msg);
-        obj.clear();
-        commit.serialize(obj.as_mut_vec());
-        obj.finalize_with_kind(ObjKind::Commit as u8);
-        tub.store.save(&obj)?;
-        chain.sign_next(&obj.hash())?;
-        println!("{}", &obj.hash());
-    }
+    let root = scanner.scan_tree()?;
+    let Some(root) = root else {
+        eprintln!("🛁❗ Nothing to commit, add some paths with `tub add`");
+        exit(42);
+    };
+    let msg = match (msg, &merging) {
+        (Some(msg), _) => msg,
+        (None, Some(state)) => format!("Merge {}", state.theirs),
+        (None, None) => String::new(),
+    };
+    let mut commit = DefaultCommit::new(root, msg);
+    commit.parents = parents;
+    let (name, email) = whoami();
+    commit.author = Person::now(&name, &email, tub.user_key()?.verifying_key().to_bytes());
+    commit.committer = commit.author.clone();
+    obj.clear();
+    commit.serialize(obj.as_mut_vec())?;
+    obj.finalize_with_kind(ObjKind::Commit as u8);
+    tub.store.save(&obj)?;
+    chain.sign_next(&obj.hash())?;
+    println!("{}", &obj.hash());
+    tub.save_tracking_list(&mut obj, &TrackingList::new())?;
+    tub.clear_merge_state()?;
     eprintln!("🛁 Wow, great job on that one! 💋");
     Ok(())
 }
 
-fn cmd_dif(tub: OptPath) -> IoResult<()> {
+// Content conflicts whose file still has conflict markers.
+fn unresolved_conflicts(treedir: &Path, state: &MergeState<30>) -> Vec<String> {
+    state
+        .conflicts
+        .iter()
+        .filter(|c| c.kind == ConflictKind::Content)
+        .filter(
+            |c| match fs::read_to_string(treedir.join(relpath_to_os(&c.path))) {
+                Ok(txt) => txt.lines().any(|line| line.starts_with("<<<<<<< ")),
+                Err(_) => false,
+            },
+        )
+        .map(|c| c.path.to_owned())
+        .collect()
+}
+
+fn cmd_merge(tub: OptPath, target: String) -> IoResult<()> {
     let mut tub = get_tub_exit(&dir_or_cwd(tub)?)?;
+    let theirs = resolve_commit(&mut tub, &target)?;
+    if let Some(state) = tub.load_merge_state()? {
+        eprintln!(
+            "🛁❗ Already merging {}, fix the conflicts then `tub commit`",
+            state.theirs
+        );
+        exit(42);
+    }
     let source = tub.treedir().to_owned();
-    let mut chain = tub.open_branch()?;
-    if chain.load_last_block()? {
+    let mut chain = open_signing_branch(&tub)?;
+    if !chain.load_last_block()? {
+        eprintln!("🛁❗ Nothing to merge into, make a commit first");
+        exit(42);
+    }
+    let ours = chain.block.payload();
+    let user_key = tub.user_key()?;
+    let meta = tub.load_meta_options()?;
+    let mut obj = tub.store.new_object();
+    let mut tl = tub.load_tracking_list(&mut obj)?;
+
+    let mut tree = DefaultTree::new(&mut tub.store, &source);
+    tree.load_ignore()?;
+    tree.set_meta_options(meta);
+    let their_commit = tree.load_commit(&theirs)?.unwrap();
+    let Some(our_commit) = tree.load_commit(&ours)? else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Could not find commit {}", ours),
+        ));
+    };
+    let base = merge_base(&mut tree, &ours, &theirs)?;
+    if base == Some(theirs) {
+        eprintln!("🛁 Already up to date! 😎");
+        return Ok(());
+    }
+
+    let (our_flat, our_metas) = tree.flatten_tree_with_meta(&our_commit.tree)?;
+    let untracked = exit_if_dirty(&mut tree, &our_flat, "merging")?;
+
+    let (base_flat, base_metas) = match base {
+        Some(base) => {
+            let commit = tree.load_commit(&base)?.unwrap();
+            tree.flatten_tree_with_meta(&commit.tree)?
+        }
+        None => (HashMap::new(), MetaMap::new()),
+    };
+    let (their_flat, their_metas) = tree.flatten_tree_with_meta(&their_commit.tree)?;
+    let merged = merge_flat(
+        &mut tree,
+        &base_flat,
+        &our_flat,
+        &their_flat,
+        ("HEAD", &target),
+    )?;
+    let merged_metas = merge_metas(&base_metas, &our_metas, &their_metas, &merged.flat);
+
+    // Update the working tree
+    exit_if_overwritten(&untracked, &merged.flat, "merging");
+    let status =
+        tree.checkout_flat_with_meta(&our_flat, &merged.flat, &our_metas, &merged_metas)?;
+    for path in status.removed {
+        tl.remove(path);
+    }
+    for path in status.unknown {
+        tl.add(path);
+    }
+    for (path, text) in merged.marked.iter() {
+        fs::write(source.join(relpath_to_os(path)), text)?;
+    }
+
+    if !merged.is_clean() {
+        eprintln!("🛁❗ Conflicts:");
+        for conflict in merged.conflicts.iter() {
+            let path = display_relpath(&conflict.path);
+            println!("  {:<13} {}", conflict.kind.as_str(), path);
+        }
+        tub.save_tracking_list(&mut obj, &tl)?;
+        tub.save_merge_state(&MergeState::new(theirs, merged.conflicts))?;
+        eprintln!("🛁 Fix them up, then `tub commit` to finish the merge 🧬");
+        exit(1);
+    }
+
+    let Some(root) = tree.save_flat_tree_with_meta(&merged.flat, &merged_metas)? else {
+        eprintln!("🛁❗ Nothing left after merging");
+        exit(42);
+    };
+    let mut commit = DefaultCommit::new(root, format!("Merge {}", target));
+    commit.parents = vec![ours, theirs];
+    let (name, email) = whoami();
+    commit.author = Person::now(&name, &email, user_key.verifying_key().to_bytes());
+    commit.committer = commit.author.clone();
+    let hash = tree.save_commit(&commit)?;
+    chain.sign_next(&hash)?;
+    println!("{}", hash);
+    eprintln!("🛁 Merged! 🧬😍");
+    Ok(())
+}
+
+// Who's committing, from `TUB_AUTHOR_NAME` and `TUB_AUTHOR_EMAIL` (or `USER`).
+fn whoami() -> (String, String) {
+    let name = env::var("TUB_AUTHOR_NAME")
+        .or_else(|_| env::var("USER"))
+        .unwrap_or_default();
+    let email = env::var("TUB_AUTHOR_EMAIL").unwrap_or_default();
+    (name, email)
+}
+
+fn cmd_branch(
+    tub: OptPath,
+    name: Option<String>,
+    from: Option<String>,
+    delete: bool,
+) -> IoResult<()> {
+    let mut tub = get_tub_exit(&dir_or_cwd(tub)?)?;
+    let current = tub.current_branch()?;
+    let Some(name) = name else {
+        for name in tub.list_branches()? {
+            if name == current {
+                println!("* {}", yansi::Paint::green(&name));
+            } else {
+                println!("  {}", name);
+            }
+        }
+        return Ok(());
+    };
+    if !is_branch_name(&name) {
+        eprintln!("🛁❗ Branch names can only use A-Z, a-z, 0-9, '.', '_', and '-'");
+        exit(42);
+    }
+    if delete {
+        if name == current {
+            eprintln!("🛁❗ Cannot delete the current branch {}", name);
+            exit(42);
+        }
+        if !tub.has_branch(&name) {
+            eprintln!("🛁❗ No such branch: {}", name);
+            exit(42);
+        }
+        tub.delete_branch(&name)?;
+        eprintln!("🛁 Deleted branch {} 🪓", name);
+        return Ok(());
+    }
+    if tub.has_branch(&name) {
+        eprintln!("🛁❗ Branch already exists: {}", name);
+        exit(42);
+    }
+    let start = match from {
+        Some(txt) => Some(resolve_commit(&mut tub, &txt)?),
+        None => branch_head(&tub, &current)?,
+    };
+    let mut chain = tub.create_branch(&name)?;
+    if let Some(start) = start {
+        chain.sign_next(&start)?;
+    }
+    println!("{}", chain.header.hash());
+    eprintln!("🛁 Created branch {}, now `tub switch {}` 🌱", name, name);
+    Ok(())
+}
+
+fn cmd_switch(tub: OptPath, name: String) -> IoResult<()> {
+    let mut tub = get_tub_exit(&dir_or_cwd(tub)?)?;
+    if !tub.has_branch(&name) {
+        eprintln!("🛁❗ No such branch: {}", name);
+        exit(42);
+    }
+    let current = tub.current_branch()?;
+    if name == current {
+        eprintln!("🛁 Already on {} 😎", name);
+        return Ok(());
+    }
+    if tub.load_merge_state()?.is_some() {
+        eprintln!("🛁❗ Finish the merge with `tub commit` first");
+        exit(42);
+    }
+    let mut obj = tub.store.new_object();
+    if !tub.load_tracking_list(&mut obj)?.is_empty() {
+        eprintln!("🛁❗ Commit your staged changes before switching");
+        exit(42);
+    }
+    let old = branch_head(&tub, &current)?;
+    let new = branch_head(&tub, &name)?;
+    let source = tub.treedir().to_owned();
+    let meta = tub.load_meta_options()?;
+    let mut tree = DefaultTree::new(&mut tub.store, &source);
+    tree.load_ignore()?;
+    tree.set_meta_options(meta);
+    let flatten = |tree: &mut DefaultTree, head: Option<DefaultName>| match head {
+        Some(hash) => {
+            let commit = tree.load_commit(&hash)?.unwrap();
+            tree.flatten_tree_with_meta(&commit.tree)
+        }
+        None => Ok((ItemMap::new(), MetaMap::new())),
+    };
+    let (old_flat, old_metas) = flatten(&mut tree, old)?;
+    let (new_flat, new_metas) = flatten(&mut tree, new)?;
+    let untracked = exit_if_dirty(&mut tree, &old_flat, "switching")?;
+    exit_if_overwritten(&untracked, &new_flat, "switching");
+    tree.checkout_flat_with_meta(&old_flat, &new_flat, &old_metas, &new_metas)?;
+    tub.set_current_branch(&name)?;
+    eprintln!("🛁 Switched to branch {} 🔀", name);
+    Ok(())
+}
+
+fn cmd_dif(
+    tub: OptPath,
+    a: Option<String>,
+    b: Option<String>,
+    staged: bool,
+    no_renames: bool,
+    format: DiffFormat,
+) -> IoResult<()> {
+    let mut tub = get_tub_exit(&dir_or_cwd(tub)?)?;
+    let source = tub.treedir().to_owned();
+    let a = match a {
+        Some(txt) => Some(resolve_tree(&mut tub, &txt)?),
+        None => match branch_head(&tub, &tub.current_branch()?)? {
+            Some(head) => Some(commit_tree(&mut tub, &head)?),
+            None => None,
+        },
+    };
+    let b = match b {
+        Some(txt) => Some(resolve_tree(&mut tub, &txt)?),
+        None => None,
+    };
+    let meta = tub.load_meta_options()?;
+    let mut obj = tub.store.new_object();
+    let tl = tub.load_tracking_list(&mut obj)?;
+    let mut tree = DefaultTree::new(&mut tub.store, &source);
+    tree.load_ignore()?;
+    tree.set_meta_options(meta);
+
+    let (old, new) = if b.is_some() {
+        tree.diff_trees(a.as_ref(), b.as_ref())?
+    } else {
+        // Against the working tree (or just what `tub commit` would take)
+        if staged {
+            let old = match a {
+                Some(a) => tree.flatten_tree(&a)?,
+                None => ItemMap::new(),
+            };
+            tree.set_tracked(Some(tl.apply(&old)));
+        }
+        let root = tree.scan_tree()?;
+        tree.diff_trees(a.as_ref(), root.as_ref())?
+    };
+
+    let mut status = compare_trees(&old, &new);
+    if !no_renames {
+        tree.find_renames(&mut status, &old, &new)?;
+    }
+    let context = format.unified;
+    let mut diffs = Vec::new();
+    for path in status.changed.iter() {
+        let (a, b) = (
+            Some((path.as_str(), &old[path])),
+            Some((path.as_str(), &new[path])),
+        );
+        diffs.push((None, tree.diff_items(a, b, context)?));
+    }
+    for path in status.removed.iter() {
+        let a = Some((path.as_str(), &old[path]));
+        diffs.push((None, tree.diff_items(a, None, context)?));
+    }
+    let moved = status.renamed.iter().map(|m| ("rename", m));
+    for (what, m) in moved.chain(status.copied.iter().map(|m| ("copy", m))) {
+        let (a, b) = (
+            Some((m.old.as_str(), &old[&m.old])),
+            Some((m.new.as_str(), &new[&m.new])),
+        );
+        let mut fd = tree.diff_items(a, b, context)?;
+        if m.similarity == 100 && fd.numstat() == Some((0, 0)) {
+            fd.content = Content::Text(Vec::new());
+        }
+        diffs.push((Some(format!("{} {}", what, m)), fd));
+    }
+    // Untracked files aren't part of the diff (unless renamed or copied)
+    if b.is_some() || staged {
+        for path in status.unknown.iter() {
+            let b = Some((path.as_str(), &new[path]));
+            diffs.push((None, tree.diff_items(None, b, context)?));
+        }
+    }
+
+    if format.numstat {
+        for (_, fd) in diffs.iter() {
+            match fd.numstat() {
+                Some((added, removed)) => println!("{}\t{}\t{}", added, removed, fd.display_path()),
+                None => println!("-\t-\t{}", fd.display_path()),
+            }
+        }
+    } else if format.stat {
+        print_stat(diffs.iter().map(|(_, fd)| fd));
+    } else {
+        for (header, fd) in diffs.iter() {
+            if let Some(header) = header {
+                println!("{}", header);
+            }
+            print_file_diff(fd, format.word_diff);
+        }
+    }
+    Ok(())
+}
+
+// Tree of the branch, commit, or tree named on the command line.
+fn resolve_tree(tub: &mut DefaultTub, txt: &str) -> IoResult<DefaultName> {
+    if !tub.has_branch(txt) && txt.len() == 48 && isdb32(txt.as_bytes()) {
+        let hash = DefaultName::from_dbase32(txt);
         let mut obj = tub.store.new_object();
+        if tub.store.load(&hash, &mut obj)? && obj.kind() == ObjKind::Tree {
+            return Ok(hash);
+        }
+    }
+    let hash = resolve_commit(tub, txt)?;
+    commit_tree(tub, &hash)
+}
 
-        if tub.store.load(&chain.block.payload(), &mut obj)? {
-            let commit = DefaultCommit::deserialize(obj.as_data());
-            eprintln!(" block: {}", chain.block.hash());
-            eprintln!("commit: {}", chain.block.payload());
-            eprintln!("   old: {}", commit.tree);
+fn commit_tree(tub: &mut DefaultTub, hash: &DefaultName) -> IoResult<DefaultName> {
+    let mut obj = tub.store.new_object();
+    if !tub.store.load(hash, &mut obj)? {
+        panic!("could not find commit {}", hash);
+    }
+    Ok(DefaultCommit::deserialize(obj.as_data()).tree)
+}
 
-            let mut scanner = DefaultTree::new(&mut tub.store, &source);
-            scanner.load_ignore()?;
-            let a = scanner.diff(&commit.tree)?;
-            let mut items = Vec::from_iter(a.iter());
-            items.sort_by(|a, b| a.0.cmp(b.0));
-            for (k, v) in items.iter() {
-                println!("--- a/{}", k);
-                println!("+++ b/{}", k);
-                for line in v.lines() {
-                    if line.starts_with('-') {
-                        println!("{}", Paint::red(line));
-                    } else if line.starts_with('+') {
-                        println!("{}", Paint::green(line));
-                    } else {
-                        println!("{}", line);
-                    }
+// `diffstat` style summary, one line per file and then the totals.
+fn print_stat<'a>(diffs: impl Iterator<Item = &'a FileDiff<30>>) {
+    const BAR: usize = 40;
+    let diffs = Vec::from_iter(diffs);
+    let paths = Vec::from_iter(diffs.iter().map(|fd| fd.display_path()));
+    let width = paths.iter().map(|p| p.chars().count()).max().unwrap_or(0);
+    let most = diffs
+        .iter()
+        .filter_map(|fd| fd.numstat())
+        .map(|(a, r)| a + r)
+        .max()
+        .unwrap_or(0);
+    let (mut insertions, mut deletions) = (0, 0);
+    for (fd, path) in diffs.iter().zip(paths.iter()) {
+        match (fd.numstat(), &fd.content) {
+            (Some((added, removed)), _) => {
+                insertions += added;
+                deletions += removed;
+                let scale = |n: usize| match most > BAR {
+                    true => (n * BAR).div_ceil(most),
+                    false => n,
+                };
+                // No escape codes at all for an empty run
+                let mut bar = String::new();
+                if added > 0 {
+                    bar.push_str(&yansi::Paint::green(&"+".repeat(scale(added))).to_string());
                 }
+                if removed > 0 {
+                    bar.push_str(&yansi::Paint::red(&"-".repeat(scale(removed))).to_string());
+                }
+                println!(" {:<width$} | {:>5} {}", path, added + removed, bar);
+            }
+            (None, Content::Binary { old, new }) => {
+                let size = |b: &Option<Blob<30>>| b.as_ref().map_or(0, |b| b.size);
+                println!(
+                    " {:<width$} | Bin {} -> {} bytes",
+                    path,
+                    size(old),
+                    size(new)
+                );
             }
+            _ => {}
         }
     }
-    Ok(())
+    println!(
+        " {} file{} changed, {} insertion{}(+), {} deletion{}(-)",
+        diffs.len(),
+        if diffs.len() == 1 { "" } else { "s" },
+        insertions,
+        if insertions == 1 { "" } else { "s" },
+        deletions,
+        if deletions == 1 { "" } else { "s" },
+    );
 }
 
-fn cmd_status(tub: OptPath) -> IoResult<()> {
+fn print_file_diff(fd: &FileDiff<30>, word_diff: bool) {
+    if let Content::Text(hunks) = &fd.content {
+        if hunks.is_empty() {
+            return;
+        }
+    }
+    match &fd.old_path {
+        Some(path) => println!("--- a/{}", display_relpath(path)),
+        None => println!("--- /dev/null"),
+    }
+    match &fd.new_path {
+        Some(path) => println!("+++ b/{}", display_relpath(path)),
+        None => println!("+++ /dev/null"),
+    }
+    match &fd.content {
+        Content::Text(hunks) => {
+            for hunk in hunks.iter() {
+                println!("{}", yansi::Paint::cyan(&hunk.header()));
+                if word_diff {
+                    print_word_hunk(hunk);
+                } else {
+                    print_hunk(hunk);
+                }
+            }
+        }
+        Content::Binary { old, new } => {
+            let side = |blob: &Option<Blob<30>>| match blob {
+                Some(Blob {
+                    hash: Some(hash),
+                    size,
+                }) => format!("{} ({} bytes)", hash, size),
+                Some(Blob { hash: None, .. }) => "empty".to_string(),
+                None => "/dev/null".to_string(),
+            };
+            println!("Binary files differ");
+            println!("  old: {}", side(old));
+            println!("  new: {}", side(new));
+        }
+    }
+}
+
+fn print_hunk(hunk: &Hunk) {
+    for line in hunk.lines.iter() {
+        let text = line.text.strip_suffix('\n').unwrap_or(&line.text);
+        match line.kind {
+            LineKind::Context => println!(" {}", text),
+            LineKind::Removed => println!("{}", yansi::Paint::red(&format!("-{}", text))),
+            LineKind::Added => println!("{}", yansi::Paint::green(&format!("+{}", text))),
+        }
+        if !line.text.ends_with('\n') {
+            println!("\\ No newline at end of file");
+        }
+    }
+}
+
+// Like `git diff --word-diff=plain`, changed words as `[-old-]{+new+}`.
+fn print_word_hunk(hunk: &Hunk) {
+    let mut out = String::new();
+    let mut lines = hunk.lines.iter().peekable();
+    while let Some(line) = lines.next() {
+        if line.kind == LineKind::Context {
+            out.push_str(&line.text);
+            continue;
+        }
+        let (mut before, mut after) = (String::new(), String::new());
+        let mut line = Some(line);
+        while let Some(l) = line {
+            match l.kind {
+                LineKind::Removed => before.push_str(&l.text),
+                LineKind::Added => after.push_str(&l.text),
+                LineKind::Context => unreachable!(),
+            }
+            line = lines.next_if(|l| l.kind != LineKind::Context);
+        }
+        for (kind, text) in diff_words(&before, &after) {
+            match kind {
+                LineKind::Context => out.push_str(&text),
+                LineKind::Removed => {
+                    let txt = format!("[-{}-]", text);
+                    out.push_str(&yansi::Paint::red(&txt).to_string());
+                }
+                LineKind::Added => {
+                    let txt = format!("{{+{}+}}", text);
+                    out.push_str(&yansi::Paint::green(&txt).to_string());
+                }
+            }
+        }
+    }
+    print!("{}", out);
+    if !out.ends_with('\n') {
+        println!();
+    }
+}
+
+fn cmd_status(tub: OptPath, cache_stats: bool, no_renames: bool) -> IoResult<()> {
     let mut tub = get_tub_exit(&dir_or_cwd(tub)?)?;
     let source = tub.treedir().to_owned();
-    let mut chain = tub.open_branch()?;
+    let branch = tub.current_branch()?;
+    eprintln!("🛁 On branch {}", branch);
+    let mut chain = tub.open_branch(&branch)?;
     if chain.load_last_block()? {
         let mut obj = tub.store.new_object();
 
@@ -341,28 +1090,53 @@ fn cmd_status(tub: OptPath) -> IoResult<()> {
             eprintln!("commit: {}", chain.block.payload());
             eprintln!("   old: {}", commit.tree);
 
+            let meta = tub.load_meta_options()?;
             let mut scanner = DefaultTree::new(&mut tub.store, &source);
             scanner.load_ignore()?;
+            scanner.set_meta_options(meta);
             let a = scanner.flatten_tree(&commit.tree)?;
             let root = scanner.scan_tree()?.unwrap();
             eprintln!("   new: {}", root);
-            let status = scanner.compare_with_flatmap(&a);
+            let status = if no_renames {
+                scanner.compare_with_flatmap(&a)
+            } else {
+                scanner.compare_with_renames(&a)?
+            };
             if !status.removed.is_empty() {
                 println!("Removed:");
                 for relname in status.removed.iter() {
-                    println!("  {}", relname);
+                    println!("  {}", display_relpath(relname));
                 }
             }
             if !status.changed.is_empty() {
                 println!("Changed:");
                 for relname in status.changed.iter() {
-                    println!("  {}", relname);
+                    println!("  {}", display_relpath(relname));
+                }
+            }
+            if !status.renamed.is_empty() {
+                println!("Renamed:");
+                for moved in status.renamed.iter() {
+                    println!("  {}", moved);
+                }
+            }
+            if !status.copied.is_empty() {
+                println!("Copied:");
+                for moved in status.copied.iter() {
+                    println!("  {}", moved);
                 }
             }
             if !status.unknown.is_empty() {
                 println!("Unknown:");
                 for relname in status.unknown.iter() {
-                    println!("  {}", relname);
+                    println!("  {}", display_relpath(relname));
+                }
+            }
+            if let Some(state) = tub.load_merge_state()? {
+                println!("Merging {}:", state.theirs);
+                for conflict in state.conflicts.iter() {
+                    let path = display_relpath(&conflict.path);
+                    println!("  {:<13} {}", conflict.kind.as_str(), path);
                 }
             }
         }
@@ -370,10 +1144,19 @@ fn cmd_status(tub: OptPath) -> IoResult<()> {
         eprintln!("🛁 Status: it's complicated! 🤣");
         eprintln!("🛁 Status: empty project, get to work, yo!");
     }
+    if cache_stats {
+        if let Some(stats) = tub.store.cache_stats() {
+            eprintln!(
+                "🛁 Cache: {} hits, {} misses ({:.1}% hit rate)",
+                stats.hits,
+                stats.misses,
+                stats.hit_rate() * 100.0
+            );
+        }
+    }
     Ok(())
 }
 
-// FIXME: Use this - https://docs.rs/glob/latest/glob/struct.Pattern.html
 fn cmd_ignore(tub: OptPath, paths: Vec<String>, remove: bool) -> IoResult<()> {
     let mut tub = get_tub_exit(&dir_or_cwd(tub)?)?;
     let source = tub.treedir().to_owned();
@@ -393,9 +1176,27 @@ fn cmd_ignore(tub: OptPath, paths: Vec<String>, remove: bool) -> IoResult<()> {
         tree.save_ignore()?;
     }
 
-    eprintln!("🚫 Ignored paths:");
-    for relpath in tree.sorted_ignore_vec() {
-        println!("{}", relpath);
+    eprintln!("🚫 Ignore patterns:");
+    for pattern in tree.ignore_patterns() {
+        println!("{}", pattern);
+    }
+    Ok(())
+}
+
+fn cmd_check_ignore(tub: OptPath, paths: Vec<String>) -> IoResult<()> {
+    let mut tub = get_tub_exit(&dir_or_cwd(tub)?)?;
+    let source = tub.treedir().to_owned();
+    let mut tree = DefaultTree::new(&mut tub.store, &source);
+
+    tree.load_ignore()?;
+    for relpath in paths.iter() {
+        match tree.check_ignore(relpath)? {
+            Some(m) if m.ignored => {
+                println!("🚫 {}  ({}:{}: {})", relpath, m.source, m.lineno, m.pattern)
+            }
+            Some(m) => println!("✅ {}  ({}:{}: {})", relpath, m.source, m.lineno, m.pattern),
+            None => println!("✅ {}", relpath),
+        }
     }
     Ok(())
 }
@@ -410,9 +1211,47 @@ fn cmd_revert(tub: OptPath, txt: String) -> IoResult<()> {
     Ok(())
 }
 
+fn cmd_meta(tub: OptPath, fields: Option<String>) -> IoResult<()> {
+    let tub = get_tub_exit(&dir_or_cwd(tub)?)?;
+    if let Some(fields) = fields {
+        let Some(opts) = MetaOptions::parse(&fields) else {
+            eprintln!("🛁❗ Not sure what metadata that is: {:?}", fields);
+            exit(42);
+        };
+        tub.save_meta_options(&opts)?;
+    }
+    println!("{}", tub.load_meta_options()?);
+    Ok(())
+}
+
+fn cmd_encrypt(tub: OptPath, cipher: Option<String>) -> IoResult<()> {
+    let mut tub = get_tub_exit(&dir_or_cwd(tub)?)?;
+    if let Some(name) = cipher {
+        let Some(cipher) = Cipher::parse(&name) else {
+            eprintln!("🛁❗ Not sure what cipher that is: {:?}", name);
+            exit(42);
+        };
+        tub.set_encryption(cipher)?;
+    }
+    match tub.load_encryption()? {
+        Some((cipher, id)) => {
+            println!("{}", cipher.name());
+            if let Some(config) = tub.config() {
+                eprintln!(
+                    "🔐 Key: {:?}",
+                    config.join("keys").join(format!("{}.key", id))
+                );
+                eprintln!("🔐 Back it up!  Without it encrypted objects are gone for good.");
+            }
+        }
+        None => println!("{}", Cipher::None.name()),
+    }
+    Ok(())
+}
+
 fn cmd_log(tub: OptPath) -> IoResult<()> {
     let mut tub = get_tub_exit(&dir_or_cwd(tub)?)?;
-    match tub.open_branch() {
+    match tub.open_branch(&tub.current_branch()?) {
         Ok(mut chain) => {
             let mut obj = tub.store.new_object();
             chain.seek_to_beyond();
@@ -422,6 +1261,18 @@ fn cmd_log(tub: OptPath) -> IoResult<()> {
                 if tub.store.load(&chain.block.payload(), &mut obj)? {
                     let commit = DefaultCommit::deserialize(obj.as_data());
                     println!("  tree: {}", commit.tree);
+                    if commit.is_merge() {
+                        for parent in commit.parents.iter() {
+                            println!(" merge: {}", parent);
+                        }
+                    }
+                    if commit.author.timestamp != 0 {
+                        println!("author: {}", commit.author);
+                        println!("  date: {}", commit.author.date());
+                    }
+                    for (key, val) in commit.headers.iter() {
+                        println!("{:>6}: {}", key, val);
+                    }
                     println!("📜 {}", commit.msg);
                 }
                 println!();
@@ -449,6 +1300,48 @@ fn cmd_check(tub: OptPath) -> IoResult<()> {
     Ok(())
 }
 
+fn cmd_import(tub: OptPath, path: &Path) -> IoResult<()> {
+    let mut tub = get_tub_exit(&dir_or_cwd(tub)?)?;
+    let mut obj = tub.store.new_object();
+    let hash = if path == Path::new("-") {
+        import_reader(
+            &mut tub.store,
+            &mut obj,
+            io::stdin().lock(),
+            Chunking::default(),
+        )?
+    } else {
+        let file = fs::File::open(path)?;
+        import_reader(&mut tub.store, &mut obj, file, Chunking::default())?
+    };
+    match hash {
+        Some(hash) => {
+            println!("{}", hash);
+            Ok(())
+        }
+        None => {
+            eprintln!("🛁❗ Nothing to import, input is empty");
+            exit(42);
+        }
+    }
+}
+
+fn cmd_train(tub: OptPath) -> IoResult<()> {
+    let mut tub = get_tub_exit(&dir_or_cwd(tub)?)?;
+    let mut obj = tub.store.new_object();
+    match tub.train_dictionary(&mut obj, DICT_MAX_SIZE)? {
+        Some(dict) => {
+            eprintln!("🛁 Trained {} byte dictionary", dict.as_buf().len());
+            eprintln!("🛁 New small objects will be compressed with it 📖");
+            println!("{}", dict.hash());
+        }
+        None => {
+            eprintln!("🛁 Not enough small objects to train a dictionary yet");
+        }
+    }
+    Ok(())
+}
+
 fn cmd_hash(path: &Path) -> IoResult<()> {
     let start = Instant::now();
     let pb = path.canonicalize()?;
@@ -324,7 +324,7 @@ mod tests {
         // Should contain 32 unique values
         let mut set: HashSet<u8> = HashSet::new();
         for v in FORWARD.iter() {
-            assert!(set.insert(v.clone()))
+            assert!(set.insert(*v))
         }
         assert_eq!(set.len(), FORWARD.len());
 
@@ -341,7 +341,7 @@ mod tests {
         // Should contain 33 unique values
         let mut set: HashSet<u8> = HashSet::new();
         for v in REVERSE.iter() {
-            let v = v.clone();
+            let v = *v;
             let new = set.insert(v);
             if v < 32 {
                 assert!(new);
@@ -462,26 +462,26 @@ mod tests {
 
     #[test]
     fn test_isdb32() {
-        assert_eq!(isdb32(b""), false);
-        assert_eq!(isdb32(b"A"), false);
-        assert_eq!(isdb32(b"AA"), false);
-        assert_eq!(isdb32(b"AAA"), false);
-        assert_eq!(isdb32(b"AAAA"), false);
-        assert_eq!(isdb32(b"AAAAA"), false);
-        assert_eq!(isdb32(b"AAAAAA"), false);
-        assert_eq!(isdb32(b"AAAAAAA"), false);
-        assert_eq!(isdb32(b"AAAAAAAA"), true);
-        assert_eq!(isdb32(b"AAAAAAAAA"), false);
-
-        assert_eq!(isdb32(b"ABCDEFGH"), true);
-        assert_eq!(isdb32(b"ZBCDEFGH"), false);
-        assert_eq!(isdb32(b"AZCDEFGH"), false);
-        assert_eq!(isdb32(b"ABZDEFGH"), false);
-        assert_eq!(isdb32(b"ABCZEFGH"), false);
-        assert_eq!(isdb32(b"ABCDZFGH"), false);
-        assert_eq!(isdb32(b"ABCDEZGH"), false);
-        assert_eq!(isdb32(b"ABCDEFZH"), false);
-        assert_eq!(isdb32(b"ABCDEFGZ"), false);
+        assert!(!isdb32(b""));
+        assert!(!isdb32(b"A"));
+        assert!(!isdb32(b"AA"));
+        assert!(!isdb32(b"AAA"));
+        assert!(!isdb32(b"AAAA"));
+        assert!(!isdb32(b"AAAAA"));
+        assert!(!isdb32(b"AAAAAA"));
+        assert!(!isdb32(b"AAAAAAA"));
+        assert!(isdb32(b"AAAAAAAA"));
+        assert!(!isdb32(b"AAAAAAAAA"));
+
+        assert!(isdb32(b"ABCDEFGH"));
+        assert!(!isdb32(b"ZBCDEFGH"));
+        assert!(!isdb32(b"AZCDEFGH"));
+        assert!(!isdb32(b"ABZDEFGH"));
+        assert!(!isdb32(b"ABCZEFGH"));
+        assert!(!isdb32(b"ABCDZFGH"));
+        assert!(!isdb32(b"ABCDEZGH"));
+        assert!(!isdb32(b"ABCDEFZH"));
+        assert!(!isdb32(b"ABCDEFGZ"));
     }
 
     #[test]
@@ -490,9 +490,9 @@ mod tests {
         let mut set: HashSet<[u8; 15]> = HashSet::new();
         for _ in 0..4269 {
             getrandom::fill(&mut bin).unwrap();
-            set.insert(bin.clone());
+            set.insert(bin);
             let txt = db32enc(&bin);
-            let bin2 = db32dec(&txt.as_bytes()).unwrap();
+            let bin2 = db32dec(txt.as_bytes()).unwrap();
             assert_eq!(&bin, &bin2[..]);
         }
         assert_eq!(set.len(), 4269);
@@ -504,7 +504,7 @@ mod tests {
         getrandom::fill(&mut bin).unwrap();
         let bin = bin;
         let txt = db32enc(&bin);
-        assert_eq!(isdb32(&txt.as_bytes()), true);
+        assert!(isdb32(txt.as_bytes()));
         for i in 0..txt.len() {
             for v in 0..=255 {
                 let mut copy = txt.clone();
@@ -512,15 +512,15 @@ mod tests {
                     copy.as_mut_vec()[i] = v;
                 }
                 if FORWARD.contains(&v) {
-                    assert_eq!(isdb32(&copy.as_bytes()), true);
+                    assert!(isdb32(copy.as_bytes()));
                     if copy == txt {
-                        assert_eq!(db32dec(&copy.as_bytes()).unwrap(), bin);
+                        assert_eq!(db32dec(copy.as_bytes()).unwrap(), bin);
                     } else {
-                        assert_ne!(db32dec(&copy.as_bytes()).unwrap(), bin);
+                        assert_ne!(db32dec(copy.as_bytes()).unwrap(), bin);
                     }
                 } else {
-                    assert_eq!(isdb32(&copy.as_bytes()), false);
-                    assert_eq!(db32dec(&copy.as_bytes()), None);
+                    assert!(!isdb32(copy.as_bytes()));
+                    assert_eq!(db32dec(copy.as_bytes()), None);
                 }
             }
         }
@@ -530,7 +530,7 @@ mod tests {
     fn test_db32dec_into() {
         let txt = b"FCNPVRELI7J9FUUI";
         let mut bin = [0_u8; 10];
-        assert_eq!(db32dec_into(txt, &mut bin), true);
+        assert!(db32dec_into(txt, &mut bin));
         assert_eq!(&bin, b"binary foo");
     }
 
@@ -0,0 +1,241 @@
+//! Trained zstd dictionaries for small objects. 📖
+//!
+//! zstd needs to see some data before it compresses well, and a tiny `Tree`,
+//! `Commit`, or config file is over before that happens.  A dictionary trained
+//! on a sample of a repository's own small objects primes the compressor, which
+//! makes a big difference for repositories with millions of tiny files.
+//!
+//! A dictionary is stored as an ordinary object (`ObjKind::Dictionary`) whose
+//! data is the raw zstd dictionary.  Containers compressed with a dictionary
+//! name it in their header (see `inception::Encoder` and
+//! `inception::ContainerCodec`), so a container can always be decoded no matter
+//! which dictionary is the repository's current one.
+
+use crate::base::*;
+use crate::chaos::{Name, Object, Store};
+use crate::protocol::Hasher;
+use std::io;
+
+/// Default maximum dictionary size (112 KiB, same as the zstd CLI).
+pub const DICT_MAX_SIZE: usize = 112640;
+
+/// Only objects up to this size are sampled.
+pub const DICT_SAMPLE_MAX_SIZE: usize = 65536;
+
+/// Training needs a reasonable number of samples (otherwise we skip it).
+pub const DICT_MIN_SAMPLES: usize = 64;
+
+/// Stop sampling after this many objects.
+pub const DICT_MAX_SAMPLES: usize = 100000;
+
+pub type DefaultDictionary = Dictionary<30>;
+
+/// A zstd dictionary and the `Name` of the object storing it.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Dictionary<const N: usize> {
+    hash: Name<N>,
+    data: Vec<u8>,
+}
+
+impl<const N: usize> Dictionary<N> {
+    pub fn hash(&self) -> &Name<N> {
+        &self.hash
+    }
+
+    pub fn as_buf(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Train a dictionary from the given samples.
+    ///
+    /// Returns `None` when there are fewer than `DICT_MIN_SAMPLES`.
+    pub fn train<H: Hasher>(
+        samples: &[&[u8]],
+        max_size: usize,
+        obj: &mut Object<H, N>,
+    ) -> io::Result<Option<Self>> {
+        if samples.len() < DICT_MIN_SAMPLES {
+            return Ok(None);
+        }
+        let data = zstd::dict::from_samples(samples, max_size)?;
+        Ok(Some(Self::from_data(data, obj)))
+    }
+
+    /// Train a dictionary from a sample of the small objects in `store`.
+    ///
+    /// Data, Tree, and Commit objects up to `DICT_SAMPLE_MAX_SIZE` are sampled,
+    /// including those held in containers (so encrypted ones count too).
+    /// Objects are visited in `Name` order, which is effectively a random sample
+    /// (and is deterministic, so training twice gives the same dictionary).
+    pub fn train_from_store<H: Hasher>(
+        store: &mut Store<H, N>,
+        obj: &mut Object<H, N>,
+        max_size: usize,
+    ) -> io::Result<Option<Self>> {
+        let mut keys = store.keys();
+        keys.extend(store.held_keys());
+        keys.sort();
+        keys.dedup();
+        let mut buf: Vec<u8> = Vec::new();
+        let mut sizes: Vec<usize> = Vec::new();
+        for hash in keys.iter() {
+            if sizes.len() >= DICT_MAX_SAMPLES {
+                break;
+            }
+            if store.load(hash, obj)? && is_sample(obj) {
+                buf.extend_from_slice(obj.as_data());
+                sizes.push(obj.as_data().len());
+            }
+        }
+        if sizes.len() < DICT_MIN_SAMPLES {
+            return Ok(None);
+        }
+        let data = zstd::dict::from_continuous(&buf, &sizes, max_size)?;
+        Ok(Some(Self::from_data(data, obj)))
+    }
+
+    fn from_data<H: Hasher>(data: Vec<u8>, obj: &mut Object<H, N>) -> Self {
+        obj.clear();
+        obj.extend(&data);
+        let hash = obj.finalize_with_kind(ObjKind::Dictionary as u8);
+        Self { hash, data }
+    }
+
+    /// Save the dictionary object in `store`.
+    pub fn save<H: Hasher>(
+        &self,
+        store: &mut Store<H, N>,
+        obj: &mut Object<H, N>,
+    ) -> io::Result<bool> {
+        obj.clear();
+        obj.extend(&self.data);
+        obj.finalize_with_kind(ObjKind::Dictionary as u8);
+        assert_eq!(obj.hash(), self.hash);
+        store.save(obj)
+    }
+
+    /// Load the dictionary object `hash` from `store`.
+    pub fn load<H: Hasher>(
+        store: &mut Store<H, N>,
+        hash: &Name<N>,
+        obj: &mut Object<H, N>,
+    ) -> io::Result<Option<Self>> {
+        if !store.load(hash, obj)? {
+            return Ok(None);
+        }
+        if obj.kind() != ObjKind::Dictionary {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not a dictionary", hash),
+            ));
+        }
+        Ok(Some(Self {
+            hash: *hash,
+            data: obj.as_data().to_vec(),
+        }))
+    }
+}
+
+/// Whether `obj` is the kind of small object a dictionary is for.
+pub fn is_sample<H: Hasher, const N: usize>(obj: &Object<H, N>) -> bool {
+    matches!(obj.kind(), ObjKind::Data | ObjKind::Tree | ObjKind::Commit)
+        && obj.as_data().len() <= DICT_SAMPLE_MAX_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chaos::{DefaultName, DefaultObject, DefaultStore};
+    use crate::helpers::TestTempDir;
+
+    fn config_file(i: usize) -> Vec<u8> {
+        format!(
+            "[server]\nname = \"host-{i}\"\nport = {}\nworkers = {}\n\n\
+             [logging]\nlevel = \"info\"\npath = \"/var/log/service-{i}.log\"\n\
+             rotate = true\n",
+            8000 + i % 97,
+            i % 13
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn test_train() {
+        let mut obj = DefaultObject::new();
+        let files: Vec<Vec<u8>> = (0..1000).map(config_file).collect();
+        let samples: Vec<&[u8]> = files.iter().map(|f| f.as_slice()).collect();
+
+        assert!(
+            DefaultDictionary::train(&samples[..DICT_MIN_SAMPLES - 1], 4096, &mut obj)
+                .unwrap()
+                .is_none()
+        );
+        let dict = DefaultDictionary::train(&samples, 4096, &mut obj)
+            .unwrap()
+            .unwrap();
+        assert!(!dict.as_buf().is_empty() && dict.as_buf().len() <= 4096);
+        assert_eq!(obj.kind(), ObjKind::Dictionary);
+        assert_eq!(&obj.hash(), dict.hash());
+        assert_eq!(obj.as_data(), dict.as_buf());
+
+        // The whole point:
+        let file = config_file(1234);
+        let plain = zstd::bulk::compress(&file, 3).unwrap();
+        let mut comp = zstd::bulk::Compressor::with_dictionary(3, dict.as_buf()).unwrap();
+        let primed = comp.compress(&file).unwrap();
+        assert!(primed.len() * 2 < plain.len());
+    }
+
+    #[test]
+    fn test_train_from_store() {
+        let tmp = TestTempDir::new();
+        let mut store = DefaultStore::new(tmp.create(&["some_file.store"]));
+        let mut obj = DefaultObject::new();
+        assert!(
+            DefaultDictionary::train_from_store(&mut store, &mut obj, 4096)
+                .unwrap()
+                .is_none()
+        );
+        for i in 0..500 {
+            obj.clear();
+            obj.extend(&config_file(i));
+            obj.finalize_with_kind(ObjKind::Data as u8);
+            store.save(&obj).unwrap();
+            // Not sampled:
+            obj.randomize(true);
+            obj.finalize_with_kind(ObjKind::Stream as u8);
+            store.save(&obj).unwrap();
+        }
+        let dict = DefaultDictionary::train_from_store(&mut store, &mut obj, 4096)
+            .unwrap()
+            .unwrap();
+        let again = DefaultDictionary::train_from_store(&mut store, &mut obj, 4096)
+            .unwrap()
+            .unwrap();
+        assert_eq!(dict, again);
+
+        assert!(
+            DefaultDictionary::load(&mut store, dict.hash(), &mut obj)
+                .unwrap()
+                .is_none()
+        );
+        assert!(dict.save(&mut store, &mut obj).unwrap());
+        assert!(!dict.save(&mut store, &mut obj).unwrap());
+        let loaded = DefaultDictionary::load(&mut store, dict.hash(), &mut obj)
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded, dict);
+
+        // Not a dictionary object
+        obj.clear();
+        obj.extend(&config_file(0));
+        let hash = obj.finalize_with_kind(ObjKind::Data as u8);
+        let err = DefaultDictionary::load(&mut store, &hash, &mut obj).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(
+            DefaultDictionary::load(&mut store, &DefaultName::new(), &mut obj)
+                .unwrap()
+                .is_none()
+        );
+    }
+}
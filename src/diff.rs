@@ -0,0 +1,483 @@
+//! Structured diffs, for `tub diff` and anything else that wants to render
+//! changes its own way. 🔍
+//!
+//! A `FileDiff` is what changed in one file: line `Hunk`s when both sides are
+//! text, otherwise just the size and hash of each side.  Text means UTF-8
+//! without any NUL bytes.
+
+use crate::chaos::Name;
+use crate::dvcs::display_relpath;
+use imara_diff::intern::InternedInput;
+use imara_diff::sources::lines_with_terminator;
+use imara_diff::{Algorithm, diff};
+use std::ops::Range;
+
+/// Lines of context around each change (like `diff -u`).
+pub const DEFAULT_CONTEXT: u32 = 3;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LineKind {
+    Context,
+    Removed,
+    Added,
+}
+
+/// One line of a hunk (with its line terminator, if it had one).
+#[derive(Debug, PartialEq, Clone)]
+pub struct Line {
+    pub kind: LineKind,
+    pub text: String,
+}
+
+impl Line {
+    pub fn new(kind: LineKind, text: &str) -> Self {
+        Self {
+            kind,
+            text: text.to_owned(),
+        }
+    }
+}
+
+/// A run of changes with the context lines around them.
+///
+/// The starts are 1-based line numbers, except that an empty side starts at
+/// the line before it (0 at the top), same as unified diffs.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Hunk {
+    pub old_start: u32,
+    pub old_len: u32,
+    pub new_start: u32,
+    pub new_len: u32,
+    pub lines: Vec<Line>,
+}
+
+impl Hunk {
+    /// The `@@ -1,3 +1,4 @@` line.
+    pub fn header(&self) -> String {
+        format!(
+            "@@ -{},{} +{},{} @@",
+            self.old_start, self.old_len, self.new_start, self.new_len
+        )
+    }
+
+    /// The old side as 0-based line indexes.
+    pub fn old_range(&self) -> Range<usize> {
+        zero_based(self.old_start, self.old_len)
+    }
+
+    /// The new side as 0-based line indexes.
+    pub fn new_range(&self) -> Range<usize> {
+        zero_based(self.new_start, self.new_len)
+    }
+
+    /// As unified diff text, `header()` then the lines.
+    pub fn unified(&self) -> String {
+        let mut text = self.header();
+        text.push('\n');
+        for line in self.lines.iter() {
+            text.push(match line.kind {
+                LineKind::Context => ' ',
+                LineKind::Removed => '-',
+                LineKind::Added => '+',
+            });
+            text.push_str(&line.text);
+            if !line.text.ends_with('\n') {
+                text.push('\n');
+            }
+        }
+        text
+    }
+}
+
+fn zero_based(start: u32, len: u32) -> Range<usize> {
+    let start = if len == 0 { start } else { start - 1 } as usize;
+    start..start + len as usize
+}
+
+/// One side of a binary diff (`hash` is `None` for an empty file).
+#[derive(Debug, PartialEq, Clone)]
+pub struct Blob<const N: usize> {
+    pub hash: Option<Name<N>>,
+    pub size: u64,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Content<const N: usize> {
+    Text(Vec<Hunk>),
+    /// Either side isn't text (`None` when the file doesn't exist on that side)
+    Binary {
+        old: Option<Blob<N>>,
+        new: Option<Blob<N>>,
+    },
+}
+
+/// Changes to one file (a path is `None` when it doesn't exist on that side).
+#[derive(Debug, PartialEq, Clone)]
+pub struct FileDiff<const N: usize> {
+    pub old_path: Option<String>,
+    pub new_path: Option<String>,
+    pub content: Content<N>,
+}
+
+impl<const N: usize> FileDiff<N> {
+    pub fn is_binary(&self) -> bool {
+        matches!(self.content, Content::Binary { .. })
+    }
+
+    /// Lines `(added, removed)`, or `None` for a binary diff.
+    pub fn numstat(&self) -> Option<(usize, usize)> {
+        match &self.content {
+            Content::Text(hunks) => {
+                let mut added = 0;
+                let mut removed = 0;
+                for line in hunks.iter().flat_map(|h| h.lines.iter()) {
+                    match line.kind {
+                        LineKind::Added => added += 1,
+                        LineKind::Removed => removed += 1,
+                        LineKind::Context => {}
+                    }
+                }
+                Some((added, removed))
+            }
+            Content::Binary { .. } => None,
+        }
+    }
+
+    /// `path` (lossy, for humans), or `old -> new` when it was renamed or copied.
+    pub fn display_path(&self) -> String {
+        match (&self.old_path, &self.new_path) {
+            (Some(old), Some(new)) if old != new => {
+                format!("{} -> {}", display_relpath(old), display_relpath(new))
+            }
+            (_, Some(path)) | (Some(path), None) => display_relpath(path).into_owned(),
+            (None, None) => String::new(),
+        }
+    }
+}
+
+pub fn is_text(data: &[u8]) -> bool {
+    !data.contains(&0) && std::str::from_utf8(data).is_ok()
+}
+
+/// Diff `old` against `new`, text or not.
+pub fn diff_content<const N: usize>(
+    old: Option<(&[u8], Blob<N>)>,
+    new: Option<(&[u8], Blob<N>)>,
+    context: u32,
+) -> Content<N> {
+    fn text<'a, const N: usize>(side: &Option<(&'a [u8], Blob<N>)>) -> Option<&'a str> {
+        match side {
+            Some((data, _)) => is_text(data).then(|| std::str::from_utf8(data).unwrap()),
+            None => Some(""),
+        }
+    }
+    match (text(&old), text(&new)) {
+        (Some(before), Some(after)) => Content::Text(diff_hunks(before, after, context)),
+        _ => Content::Binary {
+            old: old.map(|(_, blob)| blob),
+            new: new.map(|(_, blob)| blob),
+        },
+    }
+}
+
+/// Line diff of `before` and `after`, with `context` lines around each change.
+pub fn diff_hunks(before: &str, after: &str, context: u32) -> Vec<Hunk> {
+    let input = InternedInput::new(lines_with_terminator(before), lines_with_terminator(after));
+    let mut changes: Vec<(Range<u32>, Range<u32>)> = Vec::new();
+    diff(
+        Algorithm::Histogram,
+        &input,
+        |a: Range<u32>, b: Range<u32>| {
+            changes.push((a, b));
+        },
+    );
+    let old_lines: Vec<&str> = lines_with_terminator(before).collect();
+    let new_lines: Vec<&str> = lines_with_terminator(after).collect();
+    let old_total = old_lines.len() as u32;
+
+    let mut hunks = Vec::new();
+    let mut i = 0;
+    while i < changes.len() {
+        // Changes closer than 2 * context share a hunk
+        let mut j = i;
+        while j + 1 < changes.len() && changes[j + 1].0.start - changes[j].0.end <= 2 * context {
+            j += 1;
+        }
+        let (first, last) = (&changes[i], &changes[j]);
+        let old_start = first.0.start.saturating_sub(context);
+        let new_start = first.1.start - (first.0.start - old_start);
+        let old_end = (last.0.end + context).min(old_total);
+        let new_end = last.1.end + (old_end - last.0.end);
+
+        let mut lines = Vec::new();
+        let mut pos = old_start;
+        for (a, b) in changes[i..=j].iter() {
+            for k in pos..a.start {
+                lines.push(Line::new(LineKind::Context, old_lines[k as usize]));
+            }
+            for k in a.clone() {
+                lines.push(Line::new(LineKind::Removed, old_lines[k as usize]));
+            }
+            for k in b.clone() {
+                lines.push(Line::new(LineKind::Added, new_lines[k as usize]));
+            }
+            pos = a.end;
+        }
+        for k in pos..old_end {
+            lines.push(Line::new(LineKind::Context, old_lines[k as usize]));
+        }
+        let start = |start: u32, len: u32| if len == 0 { start } else { start + 1 };
+        hunks.push(Hunk {
+            old_start: start(old_start, old_end - old_start),
+            old_len: old_end - old_start,
+            new_start: start(new_start, new_end - new_start),
+            new_len: new_end - new_start,
+            lines,
+        });
+        i = j + 1;
+    }
+    hunks
+}
+
+// Runs of word characters, runs of whitespace, and single other characters.
+fn split_words(text: &str) -> Vec<&str> {
+    let class = |c: char| {
+        if c.is_alphanumeric() || c == '_' {
+            0
+        } else if c.is_whitespace() {
+            1
+        } else {
+            2
+        }
+    };
+    let mut words = Vec::new();
+    let mut start = 0;
+    let mut prev = None;
+    for (i, c) in text.char_indices() {
+        let cls = class(c);
+        if i > start && (prev != Some(cls) || cls == 2) {
+            words.push(&text[start..i]);
+            start = i;
+        }
+        prev = Some(cls);
+    }
+    if start < text.len() {
+        words.push(&text[start..]);
+    }
+    words
+}
+
+/// Word level diff of `before` and `after`, as runs of unchanged, removed,
+/// and added text.
+pub fn diff_words(before: &str, after: &str) -> Vec<(LineKind, String)> {
+    let old_words = split_words(before);
+    let new_words = split_words(after);
+    let mut input = InternedInput::default();
+    input.update_before(old_words.iter().copied());
+    input.update_after(new_words.iter().copied());
+    let mut runs: Vec<(LineKind, String)> = Vec::new();
+    let mut push = |kind: LineKind, words: &[&str]| {
+        if words.is_empty() {
+            return;
+        }
+        match runs.last_mut() {
+            Some((last, text)) if *last == kind => text.push_str(&words.concat()),
+            _ => runs.push((kind, words.concat())),
+        }
+    };
+    let mut pos = 0;
+    let mut changes = Vec::new();
+    diff(
+        Algorithm::Histogram,
+        &input,
+        |a: Range<u32>, b: Range<u32>| {
+            changes.push((a, b));
+        },
+    );
+    for (a, b) in changes {
+        push(LineKind::Context, &old_words[pos..a.start as usize]);
+        push(
+            LineKind::Removed,
+            &old_words[a.start as usize..a.end as usize],
+        );
+        push(
+            LineKind::Added,
+            &new_words[b.start as usize..b.end as usize],
+        );
+        pos = a.end as usize;
+    }
+    push(LineKind::Context, &old_words[pos..]);
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chaos::DefaultName;
+
+    fn lines(hunk: &Hunk) -> String {
+        let mut txt = String::new();
+        for line in hunk.lines.iter() {
+            txt.push(match line.kind {
+                LineKind::Context => ' ',
+                LineKind::Removed => '-',
+                LineKind::Added => '+',
+            });
+            txt.push_str(&line.text);
+        }
+        txt
+    }
+
+    #[test]
+    fn test_is_text() {
+        assert!(is_text(b""));
+        assert!(is_text(b"hello\n"));
+        assert!(is_text("ünïcödé".as_bytes()));
+        assert!(!is_text(b"nul\0byte"));
+        assert!(!is_text(&[0xff, 0xfe]));
+    }
+
+    #[test]
+    fn test_diff_hunks() {
+        assert_eq!(diff_hunks("", "", 3), vec![]);
+        assert_eq!(diff_hunks("a\nb\n", "a\nb\n", 3), vec![]);
+
+        let before = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n13\n14\n15\n";
+        let after = "1\n2\nthree\n4\n5\n6\n7\n8\n9\n10\n11\n12\n13\n14\nfifteen\n16\n";
+        let hunks = diff_hunks(before, after, 3);
+        assert_eq!(hunks.len(), 2);
+        assert_eq!(hunks[0].header(), "@@ -1,6 +1,6 @@");
+        assert_eq!(lines(&hunks[0]), " 1\n 2\n-3\n+three\n 4\n 5\n 6\n");
+        assert_eq!(hunks[1].header(), "@@ -12,4 +12,5 @@");
+        assert_eq!(lines(&hunks[1]), " 12\n 13\n 14\n-15\n+fifteen\n+16\n");
+
+        // More context merges them
+        let hunks = diff_hunks(before, after, 6);
+        assert_eq!(hunks.len(), 1);
+        assert_eq!(hunks[0].header(), "@@ -1,15 +1,16 @@");
+
+        // No context
+        let hunks = diff_hunks(before, after, 0);
+        assert_eq!(hunks[0].header(), "@@ -3,1 +3,1 @@");
+        assert_eq!(lines(&hunks[0]), "-3\n+three\n");
+        assert_eq!(hunks[1].header(), "@@ -15,1 +15,2 @@");
+
+        // Empty sides
+        let hunks = diff_hunks("", "a\nb", 3);
+        assert_eq!(hunks[0].header(), "@@ -0,0 +1,2 @@");
+        assert_eq!(lines(&hunks[0]), "+a\n+b");
+        let hunks = diff_hunks("a\n", "", 3);
+        assert_eq!(hunks[0].header(), "@@ -1,1 +0,0 @@");
+        let hunks = diff_hunks("a\nb\n", "a\nx\nb\n", 0);
+        assert_eq!(hunks[0].header(), "@@ -1,0 +2,1 @@");
+        assert_eq!((hunks[0].old_range(), hunks[0].new_range()), (1..1, 1..2));
+        let hunks = diff_hunks(before, after, 0);
+        assert_eq!((hunks[0].old_range(), hunks[0].new_range()), (2..3, 2..3));
+        assert_eq!(
+            (hunks[1].old_range(), hunks[1].new_range()),
+            (14..15, 14..16)
+        );
+        let hunks = diff_hunks("", "a\nb", 0);
+        assert_eq!((hunks[0].old_range(), hunks[0].new_range()), (0..0, 0..2));
+
+        // No newline at the end still ends the line
+        assert_eq!(hunks[0].unified(), "@@ -0,0 +1,2 @@\n+a\n+b\n");
+        let hunks = diff_hunks(before, after, 1);
+        assert_eq!(hunks[0].unified(), "@@ -2,3 +2,3 @@\n 2\n-3\n+three\n 4\n");
+    }
+
+    #[test]
+    fn test_diff_content() {
+        let blob = |size| Blob::<30> {
+            hash: Some(DefaultName::new()),
+            size,
+        };
+        let content = diff_content(Some((b"a\n", blob(2))), Some((b"b\n", blob(2))), 3);
+        let Content::Text(hunks) = &content else {
+            panic!("not text");
+        };
+        assert_eq!(lines(&hunks[0]), "-a\n+b\n");
+
+        let content = diff_content(None, Some((b"a\n", blob(2))), 3);
+        assert!(matches!(content, Content::Text(_)));
+
+        let content = diff_content(Some((b"a\n", blob(2))), Some((b"\0\x01", blob(2))), 3);
+        assert_eq!(
+            content,
+            Content::Binary {
+                old: Some(blob(2)),
+                new: Some(blob(2))
+            }
+        );
+        let content = diff_content(None, Some((b"\0", blob(1))), 3);
+        assert_eq!(
+            content,
+            Content::Binary {
+                old: None,
+                new: Some(blob(1))
+            }
+        );
+    }
+
+    #[test]
+    fn test_file_diff() {
+        let fd: FileDiff<30> = FileDiff {
+            old_path: Some("a".to_string()),
+            new_path: Some("a".to_string()),
+            content: Content::Text(diff_hunks("1\n2\n3\n", "1\nB\n3\n4\n", 3)),
+        };
+        assert!(!fd.is_binary());
+        assert_eq!(fd.numstat(), Some((2, 1)));
+        assert_eq!(fd.display_path(), "a");
+
+        let fd: FileDiff<30> = FileDiff {
+            old_path: Some("a".to_string()),
+            new_path: Some("b".to_string()),
+            content: Content::Binary {
+                old: None,
+                new: None,
+            },
+        };
+        assert!(fd.is_binary());
+        assert_eq!(fd.numstat(), None);
+        assert_eq!(fd.display_path(), "a -> b");
+    }
+
+    #[test]
+    fn test_split_words() {
+        assert_eq!(split_words(""), Vec::<&str>::new());
+        assert_eq!(
+            split_words("let x_1 = foo(a, b);\n"),
+            [
+                "let", " ", "x_1", " ", "=", " ", "foo", "(", "a", ",", " ", "b", ")", ";", "\n"
+            ]
+        );
+        assert_eq!(split_words("  ünï  "), ["  ", "ünï", "  "]);
+    }
+
+    #[test]
+    fn test_diff_words() {
+        assert_eq!(diff_words("", ""), vec![]);
+        assert_eq!(
+            diff_words("same words\n", "same words\n"),
+            [(LineKind::Context, "same words\n".to_string())]
+        );
+        assert_eq!(
+            diff_words("let x = 1;\n", "let y = 1;\n"),
+            [
+                (LineKind::Context, "let ".to_string()),
+                (LineKind::Removed, "x".to_string()),
+                (LineKind::Added, "y".to_string()),
+                (LineKind::Context, " = 1;\n".to_string()),
+            ]
+        );
+        assert_eq!(
+            diff_words("a b\n", "a b c\n"),
+            [
+                (LineKind::Context, "a b".to_string()),
+                (LineKind::Added, " c".to_string()),
+                (LineKind::Context, "\n".to_string()),
+            ]
+        );
+    }
+}
@@ -1,20 +1,38 @@
 //! Doodles on version control software built on Bathtub DB
 
-use std::collections::{HashMap, HashSet};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::convert::Into;
-use std::fs::{File, Permissions, create_dir_all, metadata, read_dir, read_link};
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs::{
+    File, Permissions, create_dir_all, metadata, read_dir, read_link, remove_dir, remove_dir_all,
+    remove_file,
+};
 use std::io::Result as IoResult;
 use std::io::prelude::*;
-use std::io::{BufReader, BufWriter};
+use std::ops::Bound;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::os::unix::fs::{PermissionsExt, symlink};
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
-use crate::base::{DOTDIR, DOTIGNORE, ObjKind};
+use crate::base::{DOTDIR, DOTIGNORE, OBJECT_MAX_SIZE, ObjKind};
 use crate::chaos::{Name, Object, Store};
-use crate::inception::{hash_file, import_file, restore_file};
+use crate::chunker::Chunking;
+use crate::diff::{Blob, Content, DEFAULT_CONTEXT, FileDiff, diff_content, diff_hunks};
+use crate::ignore::{IgnoreFile, IgnoreMatch, IgnoreStack, global_ignore_path};
+use crate::inception::{
+    LeafWalker, RestoreOptions, hash_file, import_file, import_file_delta, import_reader, reflink,
+    restore_file_with,
+};
+use crate::meta::{Meta, MetaOptions};
 use crate::protocol::{Blake3, Hasher};
 
 const MAX_DEPTH: usize = 32;
+
+// Decoded `Dir`s a `Tree` keeps around (it starts over when full).
+const DIR_CACHE_MAX: usize = 4096;
 pub type DefaultTree<'a> = Tree<'a, Blake3, 30>;
 pub type DefaultCommit = Commit<30>;
 
@@ -49,11 +67,78 @@ pub enum Item<const N: usize> {
     Dir(Name<N>),
     File(Name<N>),
     ExeFile(Name<N>),
-    SymLink(String),
+    SymLink(OsString),
 }
 
+/// Flattened tree, keyed by relative path (see `os_to_relpath()`).
 pub type ItemMap<const N: usize> = HashMap<String, Item<N>>;
 
+/// Metadata that goes with an `ItemMap`, keyed the same way (only the entries
+/// that have any).
+pub type MetaMap = HashMap<String, Meta>;
+
+/// Entries in one `Dir`, keyed by the raw bytes of their names.
+pub type DirMap<const N: usize> = HashMap<OsString, Item<N>>;
+
+// Bytes that aren't valid UTF-8 get the last 128 code points of plane 16
+// (private use), 0x80 => U+10FF80 and so on.  ASCII is always valid UTF-8.
+const ESCAPE_BASE: u32 = 0x10FF00;
+
+fn is_escape(c: char) -> bool {
+    c as u32 >= ESCAPE_BASE + 0x80
+}
+
+fn push_escaped(out: &mut String, bytes: &[u8]) {
+    for byte in bytes {
+        out.push(char::from_u32(ESCAPE_BASE + *byte as u32).unwrap());
+    }
+}
+
+/// Relative path used in flattened trees for a name or path on disk.
+///
+/// Names that aren't valid UTF-8 are escaped so they round-trip exactly
+/// through `relpath_to_os()`.  Valid UTF-8 comes through unchanged (except
+/// for the 128 private use code points we borrow, which get escaped too).
+pub fn os_to_relpath(path: &OsStr) -> String {
+    let mut out = String::new();
+    for chunk in path.as_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if is_escape(c) {
+                push_escaped(&mut out, c.encode_utf8(&mut [0; 4]).as_bytes());
+            } else {
+                out.push(c);
+            }
+        }
+        push_escaped(&mut out, chunk.invalid());
+    }
+    out
+}
+
+/// The exact name or path on disk for a relative path from `os_to_relpath()`.
+pub fn relpath_to_os(relpath: &str) -> OsString {
+    if !relpath.chars().any(is_escape) {
+        return OsString::from(relpath);
+    }
+    let mut out = Vec::with_capacity(relpath.len());
+    for c in relpath.chars() {
+        if is_escape(c) {
+            out.push((c as u32 - ESCAPE_BASE) as u8);
+        } else {
+            out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+        }
+    }
+    OsString::from_vec(out)
+}
+
+/// Lossy form of a relative path for showing to humans 🤓
+pub fn display_relpath(relpath: &str) -> Cow<'_, str> {
+    if relpath.chars().any(is_escape) {
+        Cow::Owned(relpath_to_os(relpath).to_string_lossy().into_owned())
+    } else {
+        Cow::Borrowed(relpath)
+    }
+}
+
 #[inline]
 fn item_to_kind<const N: usize>(item: &Item<N>) -> Kind {
     match item {
@@ -66,19 +151,63 @@ fn item_to_kind<const N: usize>(item: &Item<N>) -> Kind {
     }
 }
 
+/// Current `Dir` format version, used only when some name or symlink target
+/// doesn't fit the legacy format (so existing trees hash the same as always).
+///
+/// Legacy `Dir`s start right in with a `Kind` (0 to 5), so the high bit is set
+/// to tell them apart.
+pub const DIR_VERSION: u8 = 0x81;
+
+/// `DIR_VERSION` plus a `Meta` after each entry, used only when some entry
+/// has metadata (so trees without any hash the same as always).
+pub const DIR_META_VERSION: u8 = 0x82;
+
+/// Longest name in a `Dir`, in bytes.
+pub const MAX_NAME_LEN: usize = u16::MAX as usize;
+
+/// Longest symlink target in a `Dir`, in bytes (the format has room for more).
+pub const MAX_TARGET_LEN: usize = 1 << 20;
+
 /// Stores entries in a directory
+///
+/// ```text
+/// | Version 1 | (Kind 1 | NameLen 2 | Name | Hash N or TargetLen 4 | Target) * |
+/// ```
+///
+/// Entries are sorted by name, and only `Dir`, `File`, and `ExeFile` have a
+/// `Hash`, only `SymLink` a `Target`.  Legacy `Dir`s (no `Version`, u8 name
+/// lengths, and u16 target lengths) are still written whenever everything
+/// fits, and read by `deserialize()`.
+///
+/// With `DIR_META_VERSION` each entry is followed by its `Meta`.
 #[derive(Debug, PartialEq, Default)]
 pub struct Dir<const N: usize> {
-    map: ItemMap<N>,
+    map: DirMap<N>,
+    meta: HashMap<OsString, Meta>,
 }
 
 impl<const N: usize> Dir<N> {
     pub fn new() -> Self {
         Self {
             map: HashMap::new(),
+            meta: HashMap::new(),
+        }
+    }
+
+    /// Metadata for the entry `name` (empty metadata is dropped).
+    pub fn set_meta(&mut self, name: impl Into<OsString>, meta: Meta) {
+        let name = name.into();
+        if meta.is_empty() {
+            self.meta.remove(&name);
+        } else {
+            self.meta.insert(name, meta);
         }
     }
 
+    pub fn get_meta(&self, name: &OsStr) -> Option<&Meta> {
+        self.meta.get(name)
+    }
+
     pub fn len(&self) -> usize {
         self.map.len()
     }
@@ -87,63 +216,130 @@ impl<const N: usize> Dir<N> {
         self.map.is_empty()
     }
 
-    pub fn as_map(&self) -> &HashMap<String, Item<N>> {
+    pub fn as_map(&self) -> &DirMap<N> {
         &self.map
     }
 
-    pub fn deserialize(buf: &[u8]) -> Self {
-        let mut map = HashMap::new();
-        let mut offset = 0;
-        while offset < buf.len() {
-            let kind: Kind = buf[offset].into();
-            offset += 1;
-            let size = buf[offset] as usize;
-            assert!(size > 0);
-            offset += 1;
-
-            let key = String::from_utf8(buf[offset..offset + size].to_vec()).unwrap();
-            offset += size;
+    /// Parse any of the three encodings, or an `InvalidData` error.
+    pub fn deserialize(buf: &[u8]) -> IoResult<Self> {
+        let dir = match buf.first() {
+            Some(&DIR_VERSION) => Self::deserialize_current(&buf[1..], false),
+            Some(&DIR_META_VERSION) => Self::deserialize_current(&buf[1..], true),
+            _ => Self::deserialize_legacy(buf),
+        };
+        dir.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Could not parse Dir of {} bytes", buf.len()),
+            )
+        })
+    }
 
-            let val: Item<N> = match kind {
+    fn deserialize_current(buf: &[u8], with_meta: bool) -> Option<Self> {
+        let mut dir = Self::new();
+        let mut rd = Reader::new(buf);
+        while !rd.is_done() {
+            let kind = rd.u8()?;
+            if kind > Kind::SymLink as u8 {
+                return None;
+            }
+            let size = rd.u16()? as usize;
+            if size == 0 {
+                return None;
+            }
+            let key = OsString::from_vec(rd.take(size)?.to_vec());
+            let val = match kind.into() {
                 Kind::EmptyDir => Item::EmptyDir,
                 Kind::EmptyFile => Item::EmptyFile,
-                Kind::Dir | Kind::File | Kind::ExeFile => {
-                    let hash = Name::from(&buf[offset..offset + N]);
-                    offset += N;
-                    match kind {
-                        Kind::Dir => Item::Dir(hash),
-                        Kind::File => Item::File(hash),
-                        Kind::ExeFile => Item::ExeFile(hash),
-                        _ => {
-                            panic!("nope")
-                        }
+                Kind::Dir => Item::Dir(Name::from(rd.take(N)?)),
+                Kind::File => Item::File(Name::from(rd.take(N)?)),
+                Kind::ExeFile => Item::ExeFile(Name::from(rd.take(N)?)),
+                Kind::SymLink => {
+                    let size = rd.u32()? as usize;
+                    if size > MAX_TARGET_LEN {
+                        return None;
                     }
+                    Item::SymLink(OsString::from_vec(rd.take(size)?.to_vec()))
                 }
+            };
+            if with_meta {
+                dir.set_meta(key.clone(), Meta::deserialize(&mut rd)?);
+            }
+            if dir.map.insert(key, val).is_some() {
+                return None;
+            }
+        }
+        Some(dir)
+    }
+
+    // Before `DIR_VERSION`, with a u8 name length and a u16 target length.
+    fn deserialize_legacy(buf: &[u8]) -> Option<Self> {
+        let mut map = HashMap::new();
+        let mut rd = Reader::new(buf);
+        while !rd.is_done() {
+            let kind = rd.u8()?;
+            if kind > Kind::SymLink as u8 {
+                return None;
+            }
+            let size = rd.u8()? as usize;
+            if size == 0 {
+                return None;
+            }
+            let key = OsString::from_vec(rd.take(size)?.to_vec());
+            let val = match kind.into() {
+                Kind::EmptyDir => Item::EmptyDir,
+                Kind::EmptyFile => Item::EmptyFile,
+                Kind::Dir => Item::Dir(Name::from(rd.take(N)?)),
+                Kind::File => Item::File(Name::from(rd.take(N)?)),
+                Kind::ExeFile => Item::ExeFile(Name::from(rd.take(N)?)),
                 Kind::SymLink => {
-                    let size =
-                        u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap()) as usize;
-                    offset += 2;
-                    let target = String::from_utf8(buf[offset..offset + size].to_vec()).unwrap();
-                    offset += size;
-                    Item::SymLink(target)
+                    let size = rd.u16()? as usize;
+                    Item::SymLink(OsString::from_vec(rd.take(size)?.to_vec()))
                 }
             };
-            map.insert(key, val);
+            if map.insert(key, val).is_some() {
+                return None;
+            }
         }
-        assert_eq!(offset, buf.len());
-        Self { map }
+        Some(Self {
+            map,
+            meta: HashMap::new(),
+        })
     }
 
-    pub fn serialize(&self, buf: &mut Vec<u8>) {
+    /// Serialize into `buf`, or an `InvalidInput` error when a name or symlink
+    /// target is too long (or a name is empty) for the format.
+    pub fn serialize(&self, buf: &mut Vec<u8>) -> IoResult<()> {
         let mut pairs = Vec::from_iter(self.map.iter());
         pairs.sort_by(|a, b| a.0.cmp(b.0));
+        let with_meta = self.meta.keys().any(|name| self.map.contains_key(name));
+        let legacy = !with_meta
+            && pairs.iter().all(|(name, item)| {
+                name.len() <= u8::MAX as usize
+                    && match item {
+                        Item::SymLink(target) => target.len() <= u16::MAX as usize,
+                        _ => true,
+                    }
+            });
+        if with_meta {
+            buf.push(DIR_META_VERSION);
+        } else if !legacy {
+            buf.push(DIR_VERSION);
+        }
         for (name, item) in pairs.iter() {
-            let kind = item_to_kind(item);
-            let name = name.as_bytes();
-            let size = name.len() as u8;
-            buf.push(kind as u8);
-            buf.push(size);
-            buf.extend_from_slice(name);
+            if name.is_empty() || name.len() > MAX_NAME_LEN {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Name of {} bytes is not allowed: {:?}", name.len(), name),
+                ));
+            }
+            buf.push(item_to_kind(item) as u8);
+            if legacy {
+                buf.push(name.len() as u8);
+            } else {
+                buf.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            }
+            buf.extend_from_slice(name.as_bytes());
             match item {
                 Item::EmptyDir | Item::EmptyFile => {
                     // Nothing to do
@@ -152,43 +348,63 @@ impl<const N: usize> Dir<N> {
                     buf.extend_from_slice(hash.as_buf());
                 }
                 Item::SymLink(target) => {
-                    let tsize = target.len() as u16;
-                    buf.extend_from_slice(&tsize.to_le_bytes());
+                    if target.len() > MAX_TARGET_LEN {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!("Symlink target of {} bytes in {:?}", target.len(), name),
+                        ));
+                    }
+                    if legacy {
+                        buf.extend_from_slice(&(target.len() as u16).to_le_bytes());
+                    } else {
+                        buf.extend_from_slice(&(target.len() as u32).to_le_bytes());
+                    }
                     buf.extend_from_slice(target.as_bytes());
                 }
             }
+            if with_meta {
+                match self.meta.get(*name) {
+                    Some(meta) => meta.serialize(buf)?,
+                    None => Meta::default().serialize(buf)?,
+                }
+            }
         }
+        Ok(())
     }
 
     #[inline]
-    fn add(&mut self, name: String, item: Item<N>) -> Item<N> {
+    fn add(&mut self, name: impl Into<OsString>, item: Item<N>) -> Item<N> {
         let copy = item.clone();
-        self.map.insert(name, item);
+        self.map.insert(name.into(), item);
         copy
     }
 
-    pub fn add_empty_dir(&mut self, name: String) -> Item<N> {
+    pub fn add_empty_dir(&mut self, name: impl Into<OsString>) -> Item<N> {
         self.add(name, Item::EmptyDir)
     }
 
-    pub fn add_empty_file(&mut self, name: String) -> Item<N> {
+    pub fn add_empty_file(&mut self, name: impl Into<OsString>) -> Item<N> {
         self.add(name, Item::EmptyFile)
     }
 
-    pub fn add_dir(&mut self, name: String, hash: Name<N>) -> Item<N> {
+    pub fn add_dir(&mut self, name: impl Into<OsString>, hash: Name<N>) -> Item<N> {
         self.add(name, Item::Dir(hash))
     }
 
-    pub fn add_file(&mut self, name: String, hash: Name<N>) -> Item<N> {
+    pub fn add_file(&mut self, name: impl Into<OsString>, hash: Name<N>) -> Item<N> {
         self.add(name, Item::File(hash))
     }
 
-    pub fn add_exefile(&mut self, name: String, hash: Name<N>) -> Item<N> {
+    pub fn add_exefile(&mut self, name: impl Into<OsString>, hash: Name<N>) -> Item<N> {
         self.add(name, Item::ExeFile(hash))
     }
 
-    pub fn add_symlink(&mut self, name: String, target: String) -> Item<N> {
-        self.add(name, Item::SymLink(target))
+    pub fn add_symlink(
+        &mut self,
+        name: impl Into<OsString>,
+        target: impl Into<OsString>,
+    ) -> Item<N> {
+        self.add(name, Item::SymLink(target.into()))
     }
 }
 
@@ -274,20 +490,28 @@ impl TrackingList {
         Self { map }
     }
 
-    pub fn serialize(&self, buf: &mut Vec<u8>) {
+    /// Serialize into `buf`, or an `InvalidInput` error for a path longer than
+    /// `u16::MAX` bytes.
+    pub fn serialize(&self, buf: &mut Vec<u8>) -> IoResult<()> {
+        fn push_path(buf: &mut Vec<u8>, path: &str) -> IoResult<()> {
+            let size: u16 = path.len().try_into().map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Path of {} bytes is too long to track", path.len()),
+                )
+            })?;
+            buf.extend_from_slice(&size.to_le_bytes());
+            buf.extend_from_slice(path.as_bytes());
+            Ok(())
+        }
         for (key, item) in self.as_sorted_vec() {
-            let path = key.as_bytes();
-            let size = key.len() as u16;
             buf.push(item_to_tracked(item) as u8);
-            buf.extend_from_slice(&size.to_le_bytes());
-            buf.extend_from_slice(path);
+            push_path(buf, key)?;
             if let TrackedItem::Renamed(new) = item {
-                let new = new.as_bytes();
-                let size = new.len() as u16;
-                buf.extend_from_slice(&size.to_le_bytes());
-                buf.extend_from_slice(new);
+                push_path(buf, new)?;
             }
         }
+        Ok(())
     }
 
     pub fn as_sorted_vec(&self) -> Vec<(&String, &TrackedItem)> {
@@ -324,29 +548,395 @@ impl TrackingList {
         let item = TrackedItem::Renamed(new);
         self.map.insert(old, item)
     }
+
+    /// The paths to commit: those in the `previous` commit with the staged
+    /// changes applied.
+    ///
+    /// Removing or renaming a directory applies to everything under it.
+    pub fn apply<const N: usize>(&self, previous: &ItemMap<N>) -> TrackedPaths {
+        let mut tracked = TrackedPaths::new();
+        for (relpath, item) in previous.iter() {
+            if !matches!(item, Item::Dir(_)) {
+                tracked.insert(relpath.to_owned());
+            }
+        }
+        for (relpath, item) in self.as_sorted_vec() {
+            match item {
+                TrackedItem::Added => {
+                    tracked.insert(relpath.to_owned());
+                }
+                TrackedItem::Removed => {
+                    tracked.remove_tree(relpath);
+                }
+                TrackedItem::Renamed(new) => {
+                    let removed = tracked.remove_tree(relpath);
+                    if removed.is_empty() {
+                        // Say added then renamed before committing
+                        tracked.insert(new.to_owned());
+                    }
+                    for old in removed {
+                        tracked.insert(format!("{}{}", new, &old[relpath.len()..]));
+                    }
+                }
+            }
+        }
+        tracked
+    }
+}
+
+/// Set of relative paths to commit (see `TrackingList::apply()`)
+///
+/// A path covers everything under it, and `""` covers the whole tree.
+#[derive(Debug, PartialEq, Default, Clone)]
+pub struct TrackedPaths {
+    set: BTreeSet<String>,
+}
+
+impl TrackedPaths {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.set.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty()
+    }
+
+    pub fn insert(&mut self, relpath: String) -> bool {
+        self.set.insert(relpath)
+    }
+
+    /// Explicitly tracked (not just covered by a parent).
+    pub fn contains(&self, relpath: &str) -> bool {
+        self.set.contains(relpath)
+    }
+
+    /// Remove `relpath` and everything under it, returning what was removed.
+    pub fn remove_tree(&mut self, relpath: &str) -> Vec<String> {
+        let mut removed = Vec::new();
+        if self.set.remove(relpath) {
+            removed.push(relpath.to_owned());
+        }
+        let prefix = format!("{}/", relpath);
+        while let Some(next) = self.first_under(&prefix) {
+            self.set.remove(&next);
+            removed.push(next);
+        }
+        removed
+    }
+
+    fn first_under(&self, prefix: &str) -> Option<String> {
+        let mut range = self
+            .set
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded));
+        range.next().filter(|p| p.starts_with(prefix)).cloned()
+    }
+
+    /// Tracked itself or by one of its parent directories.
+    pub fn covers(&self, relpath: &str) -> bool {
+        self.set.contains("")
+            || self.set.contains(relpath)
+            || relpath
+                .match_indices('/')
+                .any(|(i, _)| self.set.contains(&relpath[..i]))
+    }
+
+    /// Something under the directory `relpath` is tracked.
+    pub fn has_descendant(&self, relpath: &str) -> bool {
+        self.first_under(&format!("{}/", relpath)).is_some()
+    }
+
+    pub fn as_sorted_vec(&self) -> Vec<&String> {
+        self.set.iter().collect()
+    }
+}
+
+// Bounds checked reading of length-prefixed fields.
+pub(crate) struct Reader<'a> {
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Self {
+        Self { buf, offset: 0 }
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        self.offset == self.buf.len()
+    }
+
+    pub(crate) fn take(&mut self, size: usize) -> Option<&'a [u8]> {
+        let end = self.offset.checked_add(size)?;
+        let val = self.buf.get(self.offset..end)?;
+        self.offset = end;
+        Some(val)
+    }
+
+    pub(crate) fn array<const S: usize>(&mut self) -> Option<[u8; S]> {
+        self.take(S)?.try_into().ok()
+    }
+
+    pub(crate) fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.array()?))
+    }
+
+    fn string(&mut self, size: usize) -> Option<String> {
+        String::from_utf8(self.take(size)?.to_vec()).ok()
+    }
+}
+
+/// Current `Commit` format version.
+pub const COMMIT_VERSION: u8 = 1;
+
+/// Author or committer of a commit (and when they did their thing).
+///
+/// ```text
+/// | PubKey 32 | Time 8 | TZ 2 | NameLen 2 | Name | EmailLen 2 | Email |
+/// ```
+///
+/// `PubKey` is their ed25519 public key, `Time` is seconds since the Unix epoch
+/// (`i64`), and `TZ` is their UTC offset in minutes (`i16`).
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Person {
+    pub name: String,
+    pub email: String,
+    pub pubkey: [u8; 32],
+    pub timestamp: i64,
+    pub tz_offset: i16,
+}
+
+impl Person {
+    /// Right now, in the local timezone.
+    pub fn now(name: &str, email: &str, pubkey: [u8; 32]) -> Self {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        Self {
+            name: name.to_owned(),
+            email: email.to_owned(),
+            pubkey,
+            timestamp,
+            tz_offset: local_tz_offset(timestamp),
+        }
+    }
+
+    fn deserialize(rd: &mut Reader) -> Option<Self> {
+        let pubkey = rd.array()?;
+        let timestamp = i64::from_le_bytes(rd.array()?);
+        let tz_offset = i16::from_le_bytes(rd.array()?);
+        let size = rd.u16()? as usize;
+        let name = rd.string(size)?;
+        let size = rd.u16()? as usize;
+        let email = rd.string(size)?;
+        Some(Self {
+            name,
+            email,
+            pubkey,
+            timestamp,
+            tz_offset,
+        })
+    }
+
+    fn serialize(&self, buf: &mut Vec<u8>) -> IoResult<()> {
+        buf.extend_from_slice(&self.pubkey);
+        buf.extend_from_slice(&self.timestamp.to_le_bytes());
+        buf.extend_from_slice(&self.tz_offset.to_le_bytes());
+        for val in [&self.name, &self.email] {
+            let size: u16 = size_for(val.len(), "Name or email")?;
+            buf.extend_from_slice(&size.to_le_bytes());
+            buf.extend_from_slice(val.as_bytes());
+        }
+        Ok(())
+    }
+
+    /// Like `2022-11-06 20:15:00 -0700`
+    pub fn date(&self) -> String {
+        format_timestamp(self.timestamp, self.tz_offset)
+    }
+}
+
+impl std::fmt::Display for Person {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} <{}>", self.name, self.email)
+    }
+}
+
+// The length prefix for a field of `len` bytes, or an `InvalidInput` error
+// when it doesn't fit in a `T`.
+fn size_for<T: TryFrom<usize>>(len: usize, what: &str) -> IoResult<T> {
+    T::try_from(len).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} of {} bytes is too long", what, len),
+        )
+    })
+}
+
+// UTC offset (in minutes) of the local timezone at `timestamp`.
+fn local_tz_offset(timestamp: i64) -> i16 {
+    let time = timestamp as libc::time_t;
+    // SAFETY: `tm` is plain old data so all zeroes is a valid value.
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    // SAFETY: Both pointers come from live references, and `localtime_r()` is
+    // the reentrant one (it only writes to our `tm`).
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return 0;
+    }
+    (tm.tm_gmtoff / 60) as i16
+}
+
+/// Format `timestamp` in the timezone `tz_offset` (minutes east of UTC).
+pub fn format_timestamp(timestamp: i64, tz_offset: i16) -> String {
+    let local = timestamp + tz_offset as i64 * 60;
+    let (days, secs) = (local.div_euclid(86400), local.rem_euclid(86400));
+    // Days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    let sign = if tz_offset < 0 { '-' } else { '+' };
+    let tz = tz_offset.unsigned_abs();
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} {}{:02}{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        sign,
+        tz / 60,
+        tz % 60
+    )
 }
 
-#[derive(Debug)]
+/// A commit: the root `Tree`, its parent commits, who, when, and why.
+///
+/// ```text
+/// | Version 1 | Tree N | Count 1 | Parents N * Count | Author | Committer |
+/// | Count 2 | (KeyLen 2 | Key | ValLen 4 | Val) * Count | MsgLen 4 | Msg |
+/// ```
+///
+/// A commit with no parents is the first on its branch, and one with two or
+/// more is a merge.  The headers are for whatever else we think of later.
+///
+/// Commits from before this format were just `| Tree N | Msg |`, which
+/// `deserialize()` still reads (with no parents and default people).
+#[derive(Debug, PartialEq, Clone)]
 pub struct Commit<const N: usize> {
     pub tree: Name<N>,
+    pub parents: Vec<Name<N>>,
+    pub author: Person,
+    pub committer: Person,
+    pub headers: BTreeMap<String, String>,
     pub msg: String,
 }
 
 impl<const N: usize> Commit<N> {
     pub fn new(tree: Name<N>, msg: String) -> Self {
-        Self { tree, msg }
+        Self {
+            tree,
+            parents: Vec::new(),
+            author: Person::default(),
+            committer: Person::default(),
+            headers: BTreeMap::new(),
+            msg,
+        }
+    }
+
+    pub fn is_merge(&self) -> bool {
+        self.parents.len() > 1
     }
 
     pub fn deserialize(buf: &[u8]) -> Self {
-        Self {
-            tree: Name::from(&buf[0..N]),
-            msg: String::from_utf8(buf[N..].to_vec()).unwrap(),
+        match Self::deserialize_current(buf) {
+            Some(commit) => commit,
+            None => Self::new(
+                Name::from(&buf[0..N]),
+                String::from_utf8(buf[N..].to_vec()).unwrap(),
+            ),
+        }
+    }
+
+    fn deserialize_current(buf: &[u8]) -> Option<Self> {
+        let mut rd = Reader::new(buf);
+        if rd.u8()? != COMMIT_VERSION {
+            return None;
+        }
+        let tree = Name::from(rd.take(N)?);
+        let count = rd.u8()? as usize;
+        let mut parents = Vec::with_capacity(count);
+        for _ in 0..count {
+            parents.push(Name::from(rd.take(N)?));
+        }
+        let author = Person::deserialize(&mut rd)?;
+        let committer = Person::deserialize(&mut rd)?;
+        let mut headers = BTreeMap::new();
+        for _ in 0..rd.u16()? {
+            let size = rd.u16()? as usize;
+            let key = rd.string(size)?;
+            let size = rd.u32()? as usize;
+            headers.insert(key, rd.string(size)?);
         }
+        let size = rd.u32()? as usize;
+        let msg = rd.string(size)?;
+        if !rd.is_done() {
+            return None;
+        }
+        Some(Self {
+            tree,
+            parents,
+            author,
+            committer,
+            headers,
+            msg,
+        })
     }
 
-    pub fn serialize(&self, buf: &mut Vec<u8>) {
+    /// Serialize into `buf`, or an `InvalidInput` error when something is too
+    /// big for the format.
+    pub fn serialize(&self, buf: &mut Vec<u8>) -> IoResult<()> {
+        buf.push(COMMIT_VERSION);
         buf.extend_from_slice(self.tree.as_buf());
+        let count: u8 = size_for(self.parents.len(), "Parents list")?;
+        buf.push(count);
+        for parent in self.parents.iter() {
+            buf.extend_from_slice(parent.as_buf());
+        }
+        self.author.serialize(buf)?;
+        self.committer.serialize(buf)?;
+        let count: u16 = size_for(self.headers.len(), "Headers list")?;
+        buf.extend_from_slice(&count.to_le_bytes());
+        for (key, val) in self.headers.iter() {
+            let size: u16 = size_for(key.len(), "Header key")?;
+            buf.extend_from_slice(&size.to_le_bytes());
+            buf.extend_from_slice(key.as_bytes());
+            let size: u32 = size_for(val.len(), "Header value")?;
+            buf.extend_from_slice(&size.to_le_bytes());
+            buf.extend_from_slice(val.as_bytes());
+        }
+        let size: u32 = size_for(self.msg.len(), "Message")?;
+        buf.extend_from_slice(&size.to_le_bytes());
         buf.extend_from_slice(self.msg.as_bytes());
+        Ok(())
     }
 }
 
@@ -361,8 +951,15 @@ pub struct Tree<'a, H: Hasher, const N: usize> {
     obj: Object<H, N>,
     store: &'a mut Store<H, N>,
     flatmap: ItemMap<N>,
-    ignore: HashSet<String>,
+    ignore: IgnoreStack,
+    tracked: Option<TrackedPaths>,
     dir: PathBuf,
+    bases: ItemMap<N>,
+    restore: RestoreOptions,
+    restored: HashMap<Name<N>, PathBuf>,
+    meta: MetaOptions,
+    dirs: HashMap<Name<N>, Rc<Dir<N>>>,
+    scanned: HashMap<Name<N>, Rc<Dir<N>>>, // `Dir`s from the last scan (not saved)
 }
 
 impl<'a, H: Hasher, const N: usize> Tree<'a, H, N> {
@@ -372,82 +969,190 @@ impl<'a, H: Hasher, const N: usize> Tree<'a, H, N> {
             mode: ScanMode::Scan,
             obj: Object::<H, N>::new(),
             flatmap: ItemMap::new(),
-            ignore: HashSet::new(),
+            ignore: IgnoreStack::new(),
+            tracked: None,
             dir: dir.to_path_buf(),
+            bases: ItemMap::new(),
+            restore: RestoreOptions {
+                sparse: true,
+                sync: false,
+            },
+            restored: HashMap::new(),
+            meta: MetaOptions::default(),
+            dirs: HashMap::new(),
+            scanned: HashMap::new(),
         }
     }
 
-    pub fn ignore(&mut self, relpath: String) -> bool {
-        self.ignore.insert(relpath)
+    /// Which metadata `scan_tree()` saves (none by default).
+    ///
+    /// `restore_tree()` puts back whatever metadata a tree has regardless.
+    /// Flattened trees don't carry metadata, so `save_flat_tree()` (merges)
+    /// and `restore_item()` leave it out.
+    pub fn set_meta_options(&mut self, opts: MetaOptions) {
+        self.meta = opts;
+    }
+
+    /// How `restore_tree()` writes files (sparse by default, no fsync).
+    pub fn set_restore_options(&mut self, opts: RestoreOptions) {
+        self.restore = opts;
+    }
+
+    /// Delta bases for import, usually the flattened tree of the last commit.
+    ///
+    /// When a file at the same relative path was previously committed with
+    /// different content, the new version is stored as a delta against it.
+    pub fn set_delta_bases(&mut self, bases: ItemMap<N>) {
+        self.bases = bases;
+    }
+
+    /// Append an ignore pattern (see `ignore::Pattern` for the syntax).
+    pub fn ignore(&mut self, pattern: String) -> bool {
+        self.ignore.root_mut().add(&pattern)
+    }
+
+    pub fn unignore(&mut self, pattern: &str) -> bool {
+        self.ignore.root_mut().remove(pattern)
+    }
+
+    pub fn is_ignored(&self, relpath: &str, is_dir: bool) -> bool {
+        self.ignore.is_ignored(relpath, is_dir)
     }
 
-    pub fn unignore(&mut self, relpath: &String) -> bool {
-        self.ignore.remove(relpath)
+    /// Only scan the `tracked` paths (by default everything not ignored is).
+    ///
+    /// Explicitly tracked paths are scanned even when they match an ignore
+    /// pattern.
+    pub fn set_tracked(&mut self, tracked: Option<TrackedPaths>) {
+        self.tracked = tracked;
     }
 
     pub fn enable_import(&mut self) {
         self.mode = ScanMode::Import;
     }
 
+    /// Load the top `.tubignore` and the global ignore file.
+    ///
+    /// Returns `false` if there's no top `.tubignore`.  Nested `.tubignore`
+    /// files are loaded as `scan_tree()` walks into their directories.
     pub fn load_ignore(&mut self) -> IoResult<bool> {
+        self.load_ignore_with(global_ignore_path().as_deref())
+    }
+
+    /// Like `load_ignore()` but with the global ignore file at `global`.
+    pub fn load_ignore_with(&mut self, global: Option<&Path>) -> IoResult<bool> {
+        let global = match global {
+            Some(path) => IgnoreFile::load(path, &path.to_string_lossy(), 0)?,
+            None => None,
+        };
+        self.ignore = IgnoreStack::new();
+        self.ignore.set_global(global);
         let mut filename = self.dir.clone();
         filename.push(DOTIGNORE);
-        self.ignore.clear();
-        self.ignore.insert(".git".to_string());
-        self.ignore.insert(DOTDIR.to_string());
-        match File::open(&filename) {
-            Ok(file) => {
-                let file = BufReader::new(file);
-                for relpath in file.lines() {
-                    let relpath = relpath?;
-                    self.ignore.insert(relpath);
-                }
+        match IgnoreFile::load(&filename, DOTIGNORE, 0)? {
+            Some(file) => {
+                self.ignore.set_root(file);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// The top `.tubignore` patterns, in order (order matters because of `!`).
+    pub fn ignore_patterns(&self) -> Vec<&str> {
+        self.ignore.root().lines()
+    }
+
+    // Push the `.tubignore` in `dir` (if any), returns `true` if there was one.
+    fn push_ignore(&mut self, dir: &Path, depth: usize) -> IoResult<bool> {
+        let relpath = dir.strip_prefix(&self.dir).unwrap().join(DOTIGNORE);
+        match IgnoreFile::load(
+            &dir.join(DOTIGNORE),
+            &os_to_relpath(relpath.as_os_str()),
+            depth,
+        )? {
+            Some(file) => {
+                self.ignore.push(file);
                 Ok(true)
             }
-            _ => Ok(false),
+            None => Ok(false),
         }
     }
 
-    pub fn sorted_ignore_vec(&self) -> Vec<&String> {
-        let mut vec = Vec::from_iter(self.ignore.iter().to_owned());
-        vec.sort();
-        vec
+    /// The pattern deciding whether `relpath` gets scanned (if any).
+    ///
+    /// Like the scan, an ignored parent directory wins, so its pattern is the one
+    /// returned for anything inside it.
+    pub fn check_ignore(&mut self, relpath: &str) -> IoResult<Option<IgnoreMatch>> {
+        let comps: Vec<&str> = relpath
+            .split('/')
+            .filter(|c| !c.is_empty() && *c != ".")
+            .collect();
+        if comps.first() == Some(&DOTDIR) {
+            return Ok(self.ignore.check_builtin(&comps[..1], true));
+        }
+        self.ignore.clear_nested();
+        let mut found = None;
+        for i in 0..comps.len() {
+            let path = self.dir.join(comps[..=i].join("/"));
+            let is_dir = i + 1 < comps.len() || path.symlink_metadata().is_ok_and(|m| m.is_dir());
+            found = self.ignore.check(&comps[..=i], is_dir);
+            if found.as_ref().is_some_and(|m| m.ignored) {
+                break;
+            }
+            if i + 1 < comps.len() {
+                self.push_ignore(&path, i + 1)?;
+            }
+        }
+        self.ignore.clear_nested();
+        Ok(found)
     }
 
     pub fn save_ignore(&mut self) -> IoResult<()> {
         let mut filename = self.dir.clone();
         filename.push(DOTIGNORE);
-        let file = File::create(&filename)?;
-        let mut file = BufWriter::new(file);
-        for relpath in self.sorted_ignore_vec() {
-            file.write_all(relpath.as_bytes())?;
-            file.write_all(b"\n")?;
-        }
-        file.flush()?;
-        Ok(())
+        // Edit the lines in place so comments and blank lines survive
+        let text = match std::fs::read_to_string(&filename) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+        std::fs::write(&filename, self.ignore.root().rewrite(&text))
     }
 
     fn scan_tree_inner(&mut self, dir: &Path, depth: usize) -> IoResult<Option<Name<N>>> {
         if depth >= MAX_DEPTH {
             panic!("Depth {} is >= MAX_DEPTH {}", depth, MAX_DEPTH);
         }
+        let pushed = depth > 0 && self.push_ignore(dir, depth)?;
         let mut tree = Dir::new();
         for entry in read_dir(dir)? {
             let entry = entry?;
             let ft = entry.file_type()?;
             let path = entry.path();
-            let relpath = path
-                .strip_prefix(&self.dir)
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_string();
-            if self.ignore.contains(&relpath) {
+            let relpath = os_to_relpath(path.strip_prefix(&self.dir).unwrap().as_os_str());
+            let name = entry.file_name();
+            if depth == 0 && name == DOTDIR {
+                continue;
+            }
+            let mut partial = false; // Only some of a directory is tracked
+            if let Some(tracked) = &self.tracked {
+                if tracked.contains(&relpath) {
+                    // Tracked, so scanned even if ignored
+                } else if tracked.covers(&relpath) {
+                    if self.ignore.is_ignored(&relpath, ft.is_dir()) {
+                        continue;
+                    }
+                } else if ft.is_dir() && tracked.has_descendant(&relpath) {
+                    partial = true;
+                } else {
+                    continue;
+                }
+            } else if self.ignore.is_ignored(&relpath, ft.is_dir()) {
                 continue;
             }
-            let name = path.file_name().unwrap().to_str().unwrap().to_string();
             let item = if ft.is_symlink() {
-                let target = read_link(&path)?.to_str().unwrap().to_string();
+                let target = read_link(&path)?.into_os_string();
                 //println!("S {:?} {}", path, target);
                 tree.add_symlink(name, target)
             } else if ft.is_file() {
@@ -455,9 +1160,22 @@ impl<'a, H: Hasher, const N: usize> Tree<'a, H, N> {
                 let size = meta.len();
                 if size > 0 {
                     let file = File::open(&path)?;
-                    let hash = match self.mode {
-                        ScanMode::Scan => hash_file(&mut self.obj, file, size)?,
-                        ScanMode::Import => import_file(self.store, &mut self.obj, file, size)?,
+                    let base = match self.bases.get(&relpath) {
+                        Some(Item::File(base)) | Some(Item::ExeFile(base))
+                            if size <= OBJECT_MAX_SIZE as u64 =>
+                        {
+                            Some(*base)
+                        }
+                        _ => None,
+                    };
+                    let hash = match (&self.mode, base) {
+                        (ScanMode::Scan, _) => hash_file(&mut self.obj, file, size)?,
+                        (ScanMode::Import, Some(base)) => {
+                            import_file_delta(self.store, &mut self.obj, file, size, &base)?
+                        }
+                        (ScanMode::Import, None) => {
+                            import_file(self.store, &mut self.obj, file, size)?
+                        }
                     };
                     if meta.permissions().mode() & 0o111 != 0 {
                         // Executable?
@@ -472,33 +1190,39 @@ impl<'a, H: Hasher, const N: usize> Tree<'a, H, N> {
                     tree.add_empty_file(name)
                 }
             } else if ft.is_dir() {
-                /*
-                if name == DOTDIR || name == ".git" {
-                    eprintln!("Skipping {}", name);
-                    continue;
-                }
-                */
-                if let Some(hash) = self.scan_tree_inner(&path, depth + 1)? {
-                    //println!("D {} {:?}", hash, path);
-                    tree.add_dir(name, hash)
-                } else {
-                    //println!("ED {:?}", path);
-                    tree.add_empty_dir(name)
+                match self.scan_tree_inner(&path, depth + 1)? {
+                    Some(hash) => {
+                        //println!("D {} {:?}", hash, path);
+                        tree.add_dir(name, hash)
+                    }
+                    None if partial => continue,
+                    None => {
+                        //println!("ED {:?}", path);
+                        tree.add_empty_dir(name)
+                    }
                 }
             } else {
                 panic!("nope");
             };
 
+            if !self.meta.is_empty() {
+                tree.set_meta(entry.file_name(), Meta::read(&path, &self.meta)?);
+            }
             if self.mode == ScanMode::Scan {
                 self.flatmap.insert(relpath, item);
             }
         }
+        if pushed {
+            self.ignore.pop();
+        }
         if !tree.is_empty() {
             self.obj.clear();
-            tree.serialize(self.obj.as_mut_vec());
+            tree.serialize(self.obj.as_mut_vec())?;
             let hash = self.obj.finalize_with_kind(ObjKind::Tree as u8);
             if self.mode == ScanMode::Import {
                 self.store.save(&self.obj)?;
+            } else {
+                self.scanned.insert(hash, Rc::new(tree));
             }
             Ok(Some(hash))
         } else {
@@ -508,97 +1232,120 @@ impl<'a, H: Hasher, const N: usize> Tree<'a, H, N> {
 
     pub fn scan_tree(&mut self) -> IoResult<Option<Name<N>>> {
         let dir = self.dir.clone();
+        self.ignore.clear_nested();
+        self.scanned.clear();
         self.scan_tree_inner(&dir, 0)
     }
 
-    fn restore_tree_inner(&mut self, root: &Name<N>, path: &Path, depth: usize) -> IoResult<()> {
-        if depth >= MAX_DEPTH {
-            panic!("Depth {} is >= MAX_DEPTH {}", depth, MAX_DEPTH);
-        }
-        if self.store.load(root, &mut self.obj)? {
-            let tree = Dir::deserialize(self.obj.as_data());
-            create_dir_all(path)?;
-            for (name, entry) in tree.as_map() {
-                let mut pb = path.to_path_buf();
-                pb.push(name);
-                match entry {
-                    Item::EmptyDir => {
-                        create_dir_all(&pb)?;
-                    }
-                    Item::EmptyFile => {
-                        File::create(&pb)?;
-                    }
-                    Item::Dir(hash) => {
-                        self.restore_tree_inner(hash, &pb, depth + 1)?;
-                    }
-                    Item::File(hash) | Item::ExeFile(hash) => {
-                        if self.store.load(hash, &mut self.obj)? {
-                            let mut file = File::create(&pb)?;
-                            if let Item::ExeFile(_) = entry {
-                                file.set_permissions(Permissions::from_mode(0o755))?;
-                            }
-                            restore_file(self.store, &mut self.obj, &mut file, hash)?;
-                        } else {
-                            panic!("could not find object {}", hash);
-                        }
-                    }
-                    Item::SymLink(target) => {
-                        let target = PathBuf::from(target);
-                        symlink(&target, &pb)?;
-                    }
-                }
-            }
-        } else {
-            panic!("Could not find tree object {}", root);
-        }
-        Ok(())
-    }
-
-    pub fn restore_tree(&mut self, root: &Name<N>) -> IoResult<()> {
-        let dir = self.dir.clone();
-        self.restore_tree_inner(root, &dir, 0)
-    }
-
-    fn flatten_tree_inner(
+    // Metadata goes into `metas` rather than on the files right away, as a
+    // restored mode (say 0o200) could stop `clone_restored()` reading them.
+    fn restore_tree_inner(
         &mut self,
-        flat: &mut ItemMap<N>,
         root: &Name<N>,
-        parent: &Path,
+        path: &Path,
+        metas: &mut Vec<(PathBuf, Meta)>,
         depth: usize,
     ) -> IoResult<()> {
         if depth >= MAX_DEPTH {
             panic!("Depth {} is >= MAX_DEPTH {}", depth, MAX_DEPTH);
         }
-        if self.store.load(root, &mut self.obj)? {
-            let tree: Dir<N> = Dir::deserialize(self.obj.as_data());
-            for (key, val) in tree.as_map().iter() {
-                let mut dir = parent.to_path_buf();
-                dir.push(key);
-                if let Item::Dir(hash) = val {
-                    self.flatten_tree_inner(flat, hash, &dir, depth + 1)?;
-                }
-                flat.insert(dir.to_str().unwrap().to_owned(), val.to_owned());
+        let tree = self.load_dir(root)?;
+        create_dir_all(path)?;
+        for (name, entry) in tree.as_map() {
+            let mut pb = path.to_path_buf();
+            pb.push(name);
+            self.restore_item_inner(entry, &pb, metas, depth)?;
+            // After the item so a directory's mtime isn't bumped by its contents
+            if let Some(meta) = tree.get_meta(name) {
+                metas.push((pb, meta.clone()));
             }
-        } else {
-            panic!("Could not find tree object {}", root);
         }
         Ok(())
     }
 
-    pub fn flatten_tree(&mut self, root: &Name<N>) -> IoResult<ItemMap<N>> {
-        let parent = PathBuf::from("");
-        let mut flat: ItemMap<N> = HashMap::new();
-        self.flatten_tree_inner(&mut flat, root, &parent, 0)?;
-        Ok(flat)
+    fn restore_item_inner(
+        &mut self,
+        item: &Item<N>,
+        path: &Path,
+        metas: &mut Vec<(PathBuf, Meta)>,
+        depth: usize,
+    ) -> IoResult<()> {
+        match item {
+            Item::EmptyDir => {
+                create_dir_all(path)?;
+            }
+            Item::EmptyFile => {
+                File::create(path)?;
+            }
+            Item::Dir(hash) => {
+                self.restore_tree_inner(hash, path, metas, depth + 1)?;
+            }
+            Item::File(hash) | Item::ExeFile(hash) => {
+                // Before File::create(), so a missing object doesn't leave an
+                // empty (or truncated) file behind
+                if !self.store.contains(hash) {
+                    return Err(missing_object(hash));
+                }
+                let mut file = File::create(path)?;
+                if let Item::ExeFile(_) = item {
+                    file.set_permissions(Permissions::from_mode(0o755))?;
+                }
+                if self.clone_restored(hash, &file)? {
+                    return Ok(());
+                }
+                if self.store.load(hash, &mut self.obj)? {
+                    if self.obj.kind() == ObjKind::BigData {
+                        restore_file_with(
+                            self.store,
+                            &mut self.obj,
+                            &mut file,
+                            hash,
+                            self.restore,
+                        )?;
+                    } else {
+                        file.write_all(self.obj.as_data())?;
+                        if self.restore.sync {
+                            file.sync_all()?;
+                        }
+                    }
+                    self.restored.insert(*hash, path.to_path_buf());
+                } else {
+                    return Err(missing_object(hash));
+                }
+            }
+            Item::SymLink(target) => {
+                let target = PathBuf::from(target);
+                symlink(&target, path)?;
+            }
+        }
+        Ok(())
     }
 
-    pub fn compare_with_flatmap(&self, other: &ItemMap<N>) -> Status<N> {
-        compare_trees(other, &self.flatmap)
+    // Reflink a file with the same content restored earlier (when we can).
+    fn clone_restored(&self, hash: &Name<N>, dst: &File) -> IoResult<bool> {
+        if let Some(src) = self.restored.get(hash) {
+            if reflink(&File::open(src)?, dst)? {
+                if self.restore.sync {
+                    dst.sync_all()?;
+                }
+                return Ok(true);
+            }
+        }
+        Ok(false)
     }
 
-    fn diff_inner(
+    pub fn restore_tree(&mut self, root: &Name<N>) -> IoResult<()> {
+        let dir = self.dir.clone();
+        self.restored.clear();
+        let mut metas = Vec::new();
+        self.restore_tree_inner(root, &dir, &mut metas, 0)?;
+        apply_metas(&metas)
+    }
+
+    fn flatten_tree_inner(
         &mut self,
-        flat: &mut HashMap<String, String>,
+        flat: &mut ItemMap<N>,
+        metas: &mut MetaMap,
         root: &Name<N>,
         parent: &Path,
         depth: usize,
@@ -606,46 +1353,691 @@ impl<'a, H: Hasher, const N: usize> Tree<'a, H, N> {
         if depth >= MAX_DEPTH {
             panic!("Depth {} is >= MAX_DEPTH {}", depth, MAX_DEPTH);
         }
-        if self.store.load(root, &mut self.obj)? {
-            let tree: Dir<N> = Dir::deserialize(self.obj.as_data());
-            for (key, val) in tree.as_map().iter() {
-                let mut dir = parent.to_path_buf();
-                dir.push(key);
-                match val {
-                    Item::Dir(hash) => {
-                        self.diff_inner(flat, hash, &dir, depth + 1)?;
+        let tree = self.load_dir(root)?;
+        for (key, val) in tree.as_map().iter() {
+            let mut dir = parent.to_path_buf();
+            dir.push(key);
+            if let Item::Dir(hash) = val {
+                self.flatten_tree_inner(flat, metas, hash, &dir, depth + 1)?;
+            }
+            let relpath = os_to_relpath(dir.as_os_str());
+            if let Some(meta) = tree.get_meta(key) {
+                metas.insert(relpath.clone(), meta.clone());
+            }
+            flat.insert(relpath, val.to_owned());
+        }
+        Ok(())
+    }
+
+    pub fn flatten_tree(&mut self, root: &Name<N>) -> IoResult<ItemMap<N>> {
+        Ok(self.flatten_tree_with_meta(root)?.0)
+    }
+
+    /// Like `flatten_tree()`, plus the metadata saved with the entries.
+    pub fn flatten_tree_with_meta(&mut self, root: &Name<N>) -> IoResult<(ItemMap<N>, MetaMap)> {
+        let parent = PathBuf::from("");
+        let mut flat: ItemMap<N> = HashMap::new();
+        let mut metas = MetaMap::new();
+        self.flatten_tree_inner(&mut flat, &mut metas, root, &parent, 0)?;
+        Ok((flat, metas))
+    }
+
+    /// The leaves that differ between the stored trees `a` and `b`, as the
+    /// `a` side and the `b` side (`None` is an empty tree).
+    ///
+    /// Both trees are walked together and subtrees with the same hash are
+    /// skipped without loading them, so this only touches what changed.
+    ///
+    /// Either side can be the root from the last `scan_tree()`, even when the
+    /// scan didn't save anything, to compare against the working tree.
+    pub fn diff_trees(
+        &mut self,
+        a: Option<&Name<N>>,
+        b: Option<&Name<N>>,
+    ) -> IoResult<(ItemMap<N>, ItemMap<N>)> {
+        let mut old = ItemMap::new();
+        let mut new = ItemMap::new();
+        self.diff_trees_inner(&mut old, &mut new, a, b, "", 0)?;
+        Ok((old, new))
+    }
+
+    // The decoded `Dir` with `hash`.  Flattening and diffing load the same
+    // subtrees over and over (a merge flattens three trees that are mostly the
+    // same), so these are cached.
+    fn load_dir(&mut self, hash: &Name<N>) -> IoResult<Rc<Dir<N>>> {
+        if let Some(dir) = self.dirs.get(hash).or_else(|| self.scanned.get(hash)) {
+            return Ok(dir.clone());
+        }
+        if !self.store.load(hash, &mut self.obj)? {
+            return Err(missing_object(hash));
+        }
+        let dir = Rc::new(Dir::deserialize(self.obj.as_data())?);
+        if self.dirs.len() >= DIR_CACHE_MAX {
+            self.dirs.clear();
+        }
+        self.dirs.insert(*hash, dir.clone());
+        Ok(dir)
+    }
+
+    fn load_dir_or_empty(&mut self, hash: Option<&Name<N>>) -> IoResult<Rc<Dir<N>>> {
+        match hash {
+            Some(hash) => self.load_dir(hash),
+            None => Ok(Rc::new(Dir::new())),
+        }
+    }
+
+    fn diff_trees_inner(
+        &mut self,
+        old: &mut ItemMap<N>,
+        new: &mut ItemMap<N>,
+        a: Option<&Name<N>>,
+        b: Option<&Name<N>>,
+        parent: &str,
+        depth: usize,
+    ) -> IoResult<()> {
+        if a == b {
+            return Ok(());
+        }
+        if depth >= MAX_DEPTH {
+            panic!("Depth {} is >= MAX_DEPTH {}", depth, MAX_DEPTH);
+        }
+        let a = self.load_dir_or_empty(a)?;
+        let b = self.load_dir_or_empty(b)?;
+        let (a, b) = (a.as_map(), b.as_map());
+        let mut names = BTreeSet::from_iter(a.keys());
+        names.extend(b.keys());
+        for name in names {
+            let path = if parent.is_empty() {
+                os_to_relpath(name)
+            } else {
+                format!("{}/{}", parent, os_to_relpath(name))
+            };
+            let (x, y) = (a.get(name), b.get(name));
+            if x == y {
+                continue;
+            }
+            if let (Some(Item::Dir(x)), Some(Item::Dir(y))) = (x, y) {
+                self.diff_trees_inner(old, new, Some(x), Some(y), &path, depth + 1)?;
+                continue;
+            }
+            match x {
+                Some(Item::Dir(x)) => {
+                    self.diff_trees_inner(old, new, Some(x), None, &path, depth + 1)?
+                }
+                Some(item) => {
+                    old.insert(path.clone(), item.to_owned());
+                }
+                None => {}
+            }
+            match y {
+                Some(Item::Dir(y)) => {
+                    self.diff_trees_inner(old, new, None, Some(y), &path, depth + 1)?
+                }
+                Some(item) => {
+                    new.insert(path, item.to_owned());
+                }
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Save the `Dir` objects for a flattened tree, returning the root.
+    ///
+    /// Only the leaves are used (`Item::Dir` entries are skipped), so this builds
+    /// the same tree as scanning a directory with those leaves would.  Returns
+    /// `None` when there's nothing in it.
+    pub fn save_flat_tree(&mut self, flat: &ItemMap<N>) -> IoResult<Option<Name<N>>> {
+        self.save_flat_tree_with_meta(flat, &MetaMap::new())
+    }
+
+    /// Like `save_flat_tree()`, with `metas` saved for the entries (files and
+    /// directories) that have any.
+    pub fn save_flat_tree_with_meta(
+        &mut self,
+        flat: &ItemMap<N>,
+        metas: &MetaMap,
+    ) -> IoResult<Option<Name<N>>> {
+        let mut root: BTreeMap<String, Node<N>> = BTreeMap::new();
+        for (relpath, item) in flat.iter() {
+            if matches!(item, Item::Dir(_)) {
+                continue;
+            }
+            let mut parts: Vec<&str> = relpath.split('/').collect();
+            let name = parts.pop().unwrap().to_owned();
+            let mut node = &mut root;
+            for part in parts {
+                let entry = node
+                    .entry(part.to_owned())
+                    .or_insert_with(|| Node::Dir(BTreeMap::new()));
+                node = match entry {
+                    Node::Dir(children) => children,
+                    Node::Leaf(_) => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("{:?} is both a file and a directory", part),
+                        ));
                     }
-                    Item::File(hash) | Item::ExeFile(hash) => {
-                        let mut pb = self.dir.clone();
-                        pb.push(&dir);
-                        if pb.is_file() {
-                            let size = metadata(&pb)?.len();
-                            let file = File::open(&pb)?;
-                            let newhash = hash_file(&mut self.obj, file, size)?;
-                            if &newhash != hash {
-                                let after = self.obj.as_data().to_vec();
-                                assert!(self.store.load(hash, &mut self.obj)?);
-                                if let Some(diff) = compute_diff(self.obj.as_data(), after.as_ref())
-                                {
-                                    flat.insert(dir.to_str().unwrap().to_owned(), diff);
-                                }
-                            }
-                        }
+                };
+            }
+            node.insert(name, Node::Leaf(item.to_owned()));
+        }
+        self.save_node(&root, metas, "")
+    }
+
+    fn save_node(
+        &mut self,
+        children: &BTreeMap<String, Node<N>>,
+        metas: &MetaMap,
+        parent: &str,
+    ) -> IoResult<Option<Name<N>>> {
+        let mut tree = Dir::new();
+        for (name, node) in children.iter() {
+            let relpath = if parent.is_empty() {
+                name.to_owned()
+            } else {
+                format!("{}/{}", parent, name)
+            };
+            match node {
+                Node::Leaf(item) => tree.add(relpath_to_os(name), item.to_owned()),
+                Node::Dir(children) => match self.save_node(children, metas, &relpath)? {
+                    Some(hash) => tree.add_dir(relpath_to_os(name), hash),
+                    None => continue,
+                },
+            };
+            if let Some(meta) = metas.get(&relpath) {
+                tree.set_meta(relpath_to_os(name), meta.clone());
+            }
+        }
+        if tree.is_empty() {
+            return Ok(None);
+        }
+        self.obj.clear();
+        tree.serialize(self.obj.as_mut_vec())?;
+        let hash = self.obj.finalize_with_kind(ObjKind::Tree as u8);
+        self.store.save(&self.obj)?;
+        Ok(Some(hash))
+    }
+
+    /// Load the contents of a file (`None` for a `BigData` file).
+    pub fn load_data(&mut self, hash: &Name<N>) -> IoResult<Option<Vec<u8>>> {
+        if !self.store.load(hash, &mut self.obj)? {
+            return Err(missing_object(hash));
+        }
+        if self.obj.kind() == ObjKind::BigData {
+            Ok(None)
+        } else {
+            Ok(Some(self.obj.as_data().to_vec()))
+        }
+    }
+
+    /// Save `data` as a file, returning its `Item`.
+    pub fn save_data(&mut self, data: &[u8], exe: bool) -> IoResult<Item<N>> {
+        let hash = if data.is_empty() {
+            return Ok(Item::EmptyFile);
+        } else if data.len() <= OBJECT_MAX_SIZE {
+            self.obj.clear();
+            self.obj.extend(data);
+            let hash = self.obj.finalize_with_kind(ObjKind::Data as u8);
+            self.store.save(&self.obj)?;
+            hash
+        } else {
+            import_reader(self.store, &mut self.obj, data, Chunking::default())?.unwrap()
+        };
+        Ok(if exe {
+            Item::ExeFile(hash)
+        } else {
+            Item::File(hash)
+        })
+    }
+
+    /// Load a commit (`None` if it's not in the store or isn't a commit).
+    pub fn load_commit(&mut self, hash: &Name<N>) -> IoResult<Option<Commit<N>>> {
+        if self.store.load(hash, &mut self.obj)? && self.obj.kind() == ObjKind::Commit {
+            Ok(Some(Commit::deserialize(self.obj.as_data())))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn save_commit(&mut self, commit: &Commit<N>) -> IoResult<Name<N>> {
+        self.obj.clear();
+        commit.serialize(self.obj.as_mut_vec())?;
+        let hash = self.obj.finalize_with_kind(ObjKind::Commit as u8);
+        self.store.save(&self.obj)?;
+        Ok(hash)
+    }
+
+    /// Write `item` at `relpath` in the working tree, replacing what's there.
+    pub fn restore_item(&mut self, relpath: &str, item: &Item<N>) -> IoResult<()> {
+        if let Item::File(hash) | Item::ExeFile(hash) | Item::Dir(hash) = item {
+            if !self.store.contains(hash) {
+                return Err(missing_object(hash));
+            }
+        }
+        let path = self.dir.join(relpath_to_os(relpath));
+        remove_path(&path)?;
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        self.restored.clear();
+        let mut metas = Vec::new();
+        self.restore_item_inner(item, &path, &mut metas, 0)?;
+        apply_metas(&metas)
+    }
+
+    /// Remove `relpath` from the working tree, along with any parent
+    /// directories left empty.
+    pub fn remove_item(&mut self, relpath: &str) -> IoResult<()> {
+        let mut path = self.dir.join(relpath_to_os(relpath));
+        remove_path(&path)?;
+        while path.pop() && path != self.dir {
+            if remove_dir(&path).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Change the working tree from the flattened tree `old` to `new`,
+    /// touching only the leaves that differ.
+    pub fn checkout_flat(&mut self, old: &ItemMap<N>, new: &ItemMap<N>) -> IoResult<Status<N>> {
+        let status = compare_trees(&leaves(old), &leaves(new));
+        for relpath in status.removed.iter() {
+            self.remove_item(relpath)?;
+        }
+        for (relpath, _, item) in status.newch.iter() {
+            self.restore_item(relpath, item)?;
+        }
+        let mut added = status.unknown.clone();
+        added.sort();
+        for relpath in added.iter() {
+            self.restore_item(relpath, new.get(relpath).unwrap())?;
+        }
+        Ok(status)
+    }
+
+    /// Like `checkout_flat()`, then puts `new_metas` back on what was restored
+    /// or has different metadata than in `old_metas`.
+    ///
+    /// Directories are done after their contents (and get theirs again when
+    /// anything inside changed), so their mtimes stick.
+    pub fn checkout_flat_with_meta(
+        &mut self,
+        old: &ItemMap<N>,
+        new: &ItemMap<N>,
+        old_metas: &MetaMap,
+        new_metas: &MetaMap,
+    ) -> IoResult<Status<N>> {
+        let status = self.checkout_flat(old, new)?;
+        let mut touched: BTreeSet<&str> = BTreeSet::new();
+        let changed = status.newch.iter().map(|(path, _, _)| path);
+        for path in changed
+            .chain(status.removed.iter())
+            .chain(status.unknown.iter())
+        {
+            touched.insert(path);
+            for (i, _) in path.match_indices('/') {
+                touched.insert(&path[..i]);
+            }
+        }
+        let mut paths = Vec::from_iter(new_metas.iter().filter(|(path, meta)| {
+            touched.contains(path.as_str()) || old_metas.get(*path) != Some(meta)
+        }));
+        paths.sort_by(|a, b| b.0.cmp(a.0));
+        for (path, meta) in paths {
+            let path = self.dir.join(relpath_to_os(path));
+            if path.symlink_metadata().is_ok() {
+                meta.apply(&path)?;
+            }
+        }
+        Ok(status)
+    }
+
+    /// Find the renames and copies between `old` and `new` in `status`, first
+    /// the exact ones, then those at least `RENAME_THRESHOLD` similar.
+    ///
+    /// Files in `new` that aren't in the store are read from the working tree.
+    pub fn find_renames(
+        &mut self,
+        status: &mut Status<N>,
+        old: &ItemMap<N>,
+        new: &ItemMap<N>,
+    ) -> IoResult<()> {
+        status.find_exact_renames(old, new);
+        let removed = Vec::from_iter(
+            status
+                .removed
+                .iter()
+                .filter(|p| content_hash(old.get(*p)).is_some()),
+        );
+        let unknown = Vec::from_iter(
+            status
+                .unknown
+                .iter()
+                .filter(|p| content_hash(new.get(*p)).is_some()),
+        );
+        if unknown.is_empty() || removed.len() > RENAME_LIMIT || unknown.len() > RENAME_LIMIT {
+            return Ok(());
+        }
+        let mut budget = RENAME_MAX_BYTES;
+        let Some(before) = self.rename_candidates(&removed, old, false, &mut budget)? else {
+            return Ok(());
+        };
+        let Some(after) = self.rename_candidates(&unknown, new, true, &mut budget)? else {
+            return Ok(());
+        };
+
+        // Best matches first, each path used at most once
+        let mut scores = Vec::new();
+        for (i, a) in before.iter().enumerate() {
+            for (j, b) in after.iter().enumerate() {
+                if let (Some(a), Some(b)) = (a, b) {
+                    if !may_be_similar(a.len(), b.len()) {
+                        continue;
+                    }
+                    let score = similarity(a, b);
+                    if score >= RENAME_THRESHOLD {
+                        scores.push((100 - score, removed[i], unknown[j], i, j));
                     }
-                    _ => {}
                 }
             }
+        }
+        scores.sort();
+        let mut used_before = vec![false; before.len()];
+        let mut used_after = vec![false; after.len()];
+        let mut renamed = Vec::new();
+        for (score, src, dst, i, j) in scores {
+            if !used_before[i] && !used_after[j] {
+                used_before[i] = true;
+                used_after[j] = true;
+                renamed.push(Moved::new(src, dst, 100 - score));
+            }
+        }
+
+        // Then the rest might be copies of a changed or renamed file
+        let mut sources = Vec::new();
+        for path in status.changed.iter() {
+            if content_hash(old.get(path)).is_some() {
+                sources.push(path.to_owned());
+            }
+        }
+        sources.extend(status.renamed.iter().map(|m| m.old.to_owned()));
+        sources.extend(renamed.iter().map(|m| m.old.to_owned()));
+        sources.sort();
+        let mut copied = Vec::new();
+        let paths = Vec::from_iter(sources.iter());
+        let data = if paths.len() <= RENAME_LIMIT {
+            self.rename_candidates(&paths, old, false, &mut budget)?
         } else {
-            panic!("Could not find tree object {}", root);
+            None
+        };
+        if let Some(data) = data {
+            for (j, b) in after.iter().enumerate() {
+                let Some(b) = b else { continue };
+                if used_after[j] {
+                    continue;
+                }
+                let mut best: Option<(u8, &String)> = None;
+                for (path, a) in sources.iter().zip(data.iter()) {
+                    if let Some(a) = a {
+                        if !may_be_similar(a.len(), b.len()) {
+                            continue;
+                        }
+                        let score = similarity(a, b);
+                        if score >= RENAME_THRESHOLD && best.is_none_or(|(s, _)| score > s) {
+                            best = Some((score, path));
+                        }
+                    }
+                }
+                if let Some((score, path)) = best {
+                    used_after[j] = true;
+                    copied.push(Moved::new(path, unknown[j], score));
+                }
+            }
         }
+
+        let gone: HashSet<&String> = renamed.iter().map(|m| &m.old).collect();
+        status.removed.retain(|path| !gone.contains(path));
+        let found: HashSet<&String> = renamed
+            .iter()
+            .chain(copied.iter())
+            .map(|m| &m.new)
+            .collect();
+        status.unknown.retain(|path| !found.contains(path));
+        status.renamed.extend(renamed);
+        status.renamed.sort_by(|a, b| a.new.cmp(&b.new));
+        status.copied.extend(copied);
+        status.copied.sort_by(|a, b| a.new.cmp(&b.new));
         Ok(())
     }
 
-    pub fn diff(&mut self, root: &Name<N>) -> IoResult<HashMap<String, String>> {
-        let parent = PathBuf::from("");
-        let mut flat = HashMap::new();
-        self.diff_inner(&mut flat, root, &parent, 0)?;
-        Ok(flat)
+    // Contents of `paths` in `flat` for `find_renames()`, or `None` once they
+    // add up to more than is left in `budget`.
+    fn rename_candidates(
+        &mut self,
+        paths: &[&String],
+        flat: &ItemMap<N>,
+        disk: bool,
+        budget: &mut usize,
+    ) -> IoResult<Option<Vec<Option<Vec<u8>>>>> {
+        let mut data = Vec::with_capacity(paths.len());
+        for path in paths.iter() {
+            let buf = self.item_data(path, flat.get(*path).unwrap(), disk)?;
+            let size = buf.as_ref().map_or(0, |buf| buf.len());
+            if size > *budget {
+                return Ok(None);
+            }
+            *budget -= size;
+            data.push(buf);
+        }
+        Ok(Some(data))
+    }
+
+    /// Contents of the file `item` at `relpath`, from the store, or (when
+    /// `disk` is true and it isn't in the store) from the working tree.
+    ///
+    /// Returns `None` for files too big to diff.
+    pub fn item_data(
+        &mut self,
+        relpath: &str,
+        item: &Item<N>,
+        disk: bool,
+    ) -> IoResult<Option<Vec<u8>>> {
+        Ok(self.load_blob(relpath, item, disk)?.1)
+    }
+
+    // Like `item_data()`, plus the hash and size (a symlink is its target).
+    fn load_blob(
+        &mut self,
+        relpath: &str,
+        item: &Item<N>,
+        disk: bool,
+    ) -> IoResult<(Blob<N>, Option<Vec<u8>>)> {
+        let hash = match item {
+            Item::File(hash) | Item::ExeFile(hash) => *hash,
+            Item::SymLink(target) => {
+                let blob = Blob {
+                    hash: None,
+                    size: target.len() as u64,
+                };
+                return Ok((blob, Some(target.as_bytes().to_vec())));
+            }
+            _ => {
+                return Ok((
+                    Blob {
+                        hash: None,
+                        size: 0,
+                    },
+                    Some(Vec::new()),
+                ));
+            }
+        };
+        if disk && !self.store.contains(&hash) {
+            let path = self.dir.join(relpath_to_os(relpath));
+            let size = metadata(&path)?.len();
+            let blob = Blob {
+                hash: Some(hash),
+                size,
+            };
+            if size > OBJECT_MAX_SIZE as u64 {
+                return Ok((blob, None));
+            }
+            return Ok((blob, Some(std::fs::read(&path)?)));
+        }
+        match self.load_data(&hash)? {
+            Some(data) => {
+                let blob = Blob {
+                    hash: Some(hash),
+                    size: data.len() as u64,
+                };
+                Ok((blob, Some(data)))
+            }
+            None => {
+                let walker = LeafWalker::<H, N>::new(self.store, &hash)?;
+                let blob = Blob {
+                    hash: Some(hash),
+                    size: walker.map_or(0, |w| w.total()),
+                };
+                Ok((blob, None))
+            }
+        }
+    }
+
+    /// Diff the file `old` against `new` (`None` when it doesn't exist on
+    /// that side), with `context` lines around each change.
+    ///
+    /// Files in `new` that aren't in the store are read from the working tree,
+    /// and files too big to load get a binary diff.
+    pub fn diff_items(
+        &mut self,
+        old: Option<(&str, &Item<N>)>,
+        new: Option<(&str, &Item<N>)>,
+        context: u32,
+    ) -> IoResult<FileDiff<N>> {
+        let a = match old {
+            Some((relpath, item)) => Some(self.load_blob(relpath, item, false)?),
+            None => None,
+        };
+        let b = match new {
+            Some((relpath, item)) => Some(self.load_blob(relpath, item, true)?),
+            None => None,
+        };
+        let loaded = a.iter().chain(b.iter()).all(|(_, data)| data.is_some());
+        let content = if loaded {
+            let a = a
+                .as_ref()
+                .map(|(blob, data)| (data.as_deref().unwrap(), blob.clone()));
+            let b = b
+                .as_ref()
+                .map(|(blob, data)| (data.as_deref().unwrap(), blob.clone()));
+            diff_content(a, b, context)
+        } else {
+            Content::Binary {
+                old: a.map(|(blob, _)| blob),
+                new: b.map(|(blob, _)| blob),
+            }
+        };
+        Ok(FileDiff {
+            old_path: old.map(|(relpath, _)| relpath.to_owned()),
+            new_path: new.map(|(relpath, _)| relpath.to_owned()),
+            content,
+        })
+    }
+
+    pub fn compare_with_flatmap(&self, other: &ItemMap<N>) -> Status<N> {
+        compare_trees(other, &self.flatmap)
+    }
+
+    /// Like `compare_with_flatmap()`, but also finding renames and copies.
+    pub fn compare_with_renames(&mut self, other: &ItemMap<N>) -> IoResult<Status<N>> {
+        let flatmap = std::mem::take(&mut self.flatmap);
+        let mut status = compare_trees(other, &flatmap);
+        let result = self.find_renames(&mut status, other, &flatmap);
+        self.flatmap = flatmap;
+        result.map(|_| status)
+    }
+
+    /// Items found by the last scan.
+    pub fn flatmap(&self) -> &ItemMap<N> {
+        &self.flatmap
+    }
+}
+
+// For building a tree from flattened leaves
+enum Node<const N: usize> {
+    Leaf(Item<N>),
+    Dir(BTreeMap<String, Node<N>>),
+}
+
+fn missing_object<const N: usize>(hash: &Name<N>) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("Could not find object {}", hash),
+    )
+}
+
+// Remove whatever is at `path` (if anything).
+// In order, so directories (which come after their contents) keep their mtime.
+fn apply_metas(metas: &[(PathBuf, Meta)]) -> IoResult<()> {
+    for (path, meta) in metas.iter() {
+        meta.apply(path)?;
+    }
+    Ok(())
+}
+
+fn remove_path(path: &Path) -> IoResult<()> {
+    match path.symlink_metadata() {
+        Ok(meta) if meta.is_dir() => remove_dir_all(path),
+        Ok(_) => remove_file(path),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Minimum similarity (percent) for a rename or copy that isn't exact.
+pub const RENAME_THRESHOLD: u8 = 50;
+
+/// Only exact renames are found when there are more candidates than this.
+pub const RENAME_LIMIT: usize = 400;
+
+/// Or when the candidates add up to more bytes than this.
+pub const RENAME_MAX_BYTES: usize = 32 * 1024 * 1024;
+
+// Whether files of sizes `a` and `b` could reach `RENAME_THRESHOLD` (like
+// `similarity()` but guessing the lines from the bytes), so most pairs get
+// skipped without diffing them.
+fn may_be_similar(a: usize, b: usize) -> bool {
+    let (min, max) = if a < b { (a, b) } else { (b, a) };
+    min * 2 * 100 >= (min + max) * RENAME_THRESHOLD as usize
+}
+
+/// A path that was renamed or copied.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Moved {
+    pub old: String,
+    pub new: String,
+    /// Percent of lines in common (100 when the content is identical)
+    pub similarity: u8,
+}
+
+impl Moved {
+    pub fn new(old: &str, new: &str, similarity: u8) -> Self {
+        Self {
+            old: old.to_owned(),
+            new: new.to_owned(),
+            similarity,
+        }
+    }
+}
+
+impl fmt::Display for Moved {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (old, new) = (display_relpath(&self.old), display_relpath(&self.new));
+        if self.similarity == 100 {
+            write!(f, "{} -> {}", old, new)
+        } else {
+            write!(f, "{} -> {} ({}%)", old, new, self.similarity)
+        }
     }
 }
 
@@ -655,6 +2047,8 @@ pub struct Status<const N: usize> {
     pub changed: Vec<String>,
     pub unknown: Vec<String>,
     pub newch: Vec<(String, Item<N>, Item<N>)>,
+    pub renamed: Vec<Moved>,
+    pub copied: Vec<Moved>,
 }
 
 impl<const N: usize> Status<N> {
@@ -664,10 +2058,106 @@ impl<const N: usize> Status<N> {
             changed: Vec::new(),
             unknown: Vec::new(),
             newch: Vec::new(),
+            renamed: Vec::new(),
+            copied: Vec::new(),
+        }
+    }
+
+    /// Pair up removed and unknown paths with identical content as renames,
+    /// then unknown paths with the same content as a path in `old` as copies.
+    ///
+    /// Paths paired up are taken out of `removed` and `unknown`.
+    pub fn find_exact_renames(&mut self, old: &ItemMap<N>, new: &ItemMap<N>) {
+        self.unknown.sort();
+        let mut removed: HashMap<Name<N>, Vec<&String>> = HashMap::new();
+        for path in self.removed.iter() {
+            if let Some(hash) = content_hash(old.get(path)) {
+                removed.entry(hash).or_default().push(path);
+            }
+        }
+        for paths in removed.values_mut() {
+            paths.sort();
+            paths.reverse();
+        }
+        let mut unknown = Vec::new();
+        for path in self.unknown.iter() {
+            let src = content_hash(new.get(path)).and_then(|h| removed.get_mut(&h)?.pop());
+            match src {
+                Some(src) => self.renamed.push(Moved::new(src, path, 100)),
+                None => unknown.push(path.to_owned()),
+            }
+        }
+        let gone: HashSet<&String> = self.renamed.iter().map(|m| &m.old).collect();
+        self.removed.retain(|path| !gone.contains(path));
+
+        let removed: HashSet<&String> = self.removed.iter().collect();
+        let mut sources: HashMap<Name<N>, &String> = HashMap::new();
+        let mut paths = Vec::from_iter(old.keys());
+        paths.sort();
+        for path in paths.into_iter().rev() {
+            if !removed.contains(path) {
+                if let Some(hash) = content_hash(old.get(path)) {
+                    sources.insert(hash, path);
+                }
+            }
+        }
+        self.unknown.clear();
+        for path in unknown {
+            match content_hash(new.get(&path)).and_then(|h| sources.get(&h)) {
+                Some(src) => self.copied.push(Moved::new(src, &path, 100)),
+                None => self.unknown.push(path),
+            }
         }
     }
 }
 
+/// Just the leaves (everything but `Item::Dir`) of a flattened tree.
+pub fn leaves<const N: usize>(flat: &ItemMap<N>) -> ItemMap<N> {
+    flat.iter()
+        .filter(|(_, item)| !matches!(item, Item::Dir(_)))
+        .map(|(path, item)| (path.to_owned(), item.to_owned()))
+        .collect()
+}
+
+// Content hash of a file (empty files are too common to pair up).
+fn content_hash<const N: usize>(item: Option<&Item<N>>) -> Option<Name<N>> {
+    match item {
+        Some(Item::File(hash)) | Some(Item::ExeFile(hash)) => Some(*hash),
+        _ => None,
+    }
+}
+
+/// The `untracked` paths that checking out `new` would clobber, sorted.
+///
+/// An untracked directory is fine to merge into, but anything else in the
+/// way gets replaced (or removed) by `Tree::checkout_flat()`.
+pub fn overwritten<const N: usize>(untracked: &ItemMap<N>, new: &ItemMap<N>) -> Vec<String> {
+    let mut paths = Vec::from_iter(
+        untracked
+            .iter()
+            .filter(|(path, item)| match new.get(*path) {
+                Some(Item::Dir(_)) => !matches!(item, Item::Dir(_)),
+                Some(_) => true,
+                None => false,
+            })
+            .map(|(path, _)| path.to_owned()),
+    );
+    paths.sort();
+    paths
+}
+
+/// Like `Tree::diff_trees()` but for flattened trees, the leaves that differ
+/// as the `a` side and the `b` side.
+pub fn diff_flat<const N: usize>(a: &ItemMap<N>, b: &ItemMap<N>) -> (ItemMap<N>, ItemMap<N>) {
+    let differ = |x: &ItemMap<N>, y: &ItemMap<N>| -> ItemMap<N> {
+        x.iter()
+            .filter(|(path, item)| !matches!(item, Item::Dir(_)) && y.get(*path) != Some(item))
+            .map(|(path, item)| (path.to_owned(), item.to_owned()))
+            .collect()
+    };
+    (differ(a, b), differ(b, a))
+}
+
 pub fn compare_trees<const N: usize>(a: &ItemMap<N>, b: &ItemMap<N>) -> Status<N> {
     let mut status = Status::new();
     let mut keys = Vec::from_iter(a.keys());
@@ -696,14 +2186,34 @@ pub fn compare_trees<const N: usize>(a: &ItemMap<N>, b: &ItemMap<N>) -> Status<N
 }
 
 fn compute_diff_inner(before: &str, after: &str) -> String {
+    diff_hunks(before, after, DEFAULT_CONTEXT)
+        .iter()
+        .map(|hunk| hunk.unified())
+        .collect()
+}
+
+/// Percent of lines `before` and `after` have in common.
+pub fn similarity(before: &[u8], after: &[u8]) -> u8 {
     use imara_diff::intern::InternedInput;
-    use imara_diff::{Algorithm, UnifiedDiffBuilder, diff};
-    let input = InternedInput::new(before, after);
+    use imara_diff::sources::byte_lines_with_terminator;
+    use imara_diff::{Algorithm, diff};
+    let input = InternedInput::new(
+        byte_lines_with_terminator(before),
+        byte_lines_with_terminator(after),
+    );
+    let total = input.before.len() + input.after.len();
+    if total == 0 {
+        return 100;
+    }
+    let mut changed = 0;
     diff(
         Algorithm::Histogram,
         &input,
-        UnifiedDiffBuilder::new(&input),
-    )
+        |a: std::ops::Range<u32>, b: std::ops::Range<u32>| {
+            changed += a.len() + b.len();
+        },
+    );
+    ((total - changed) * 100 / total) as u8
 }
 
 pub fn compute_diff(before: &[u8], after: &[u8]) -> Option<String> {
@@ -719,6 +2229,7 @@ pub fn compute_diff(before: &[u8], after: &[u8]) -> Option<String> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::chaos::DefaultName;
 
     #[test]
     fn test_compare() {
@@ -756,72 +2267,312 @@ mod tests {
         assert_eq!(status.unknown, vec!["baz".to_string()]);
     }
 
+    #[test]
+    fn test_find_exact_renames() {
+        let name = |i: u8| {
+            let mut hash = DefaultName::new();
+            hash.as_mut_buf().fill(i);
+            hash
+        };
+        let mut a: ItemMap<30> = ItemMap::new();
+        let mut b: ItemMap<30> = ItemMap::new();
+        a.insert("same".to_string(), Item::File(name(1)));
+        b.insert("same".to_string(), Item::File(name(1)));
+        a.insert("old".to_string(), Item::File(name(2)));
+        b.insert("new".to_string(), Item::ExeFile(name(2)));
+        a.insert("changed".to_string(), Item::File(name(3)));
+        b.insert("changed".to_string(), Item::File(name(4)));
+        b.insert("copy".to_string(), Item::File(name(1)));
+        b.insert("copy2".to_string(), Item::File(name(3)));
+        a.insert("empty".to_string(), Item::EmptyFile);
+        b.insert("empty2".to_string(), Item::EmptyFile);
+        b.insert("unknown".to_string(), Item::File(name(5)));
+
+        // Two removed with the same content
+        a.insert("x1".to_string(), Item::File(name(6)));
+        a.insert("x2".to_string(), Item::File(name(6)));
+        b.insert("y".to_string(), Item::File(name(6)));
+
+        let mut status = compare_trees(&a, &b);
+        status.find_exact_renames(&a, &b);
+        assert_eq!(
+            status.renamed,
+            [Moved::new("old", "new", 100), Moved::new("x1", "y", 100)]
+        );
+        assert_eq!(
+            status.copied,
+            [
+                Moved::new("same", "copy", 100),
+                Moved::new("changed", "copy2", 100)
+            ]
+        );
+        assert_eq!(status.removed, ["empty", "x2"]);
+        assert_eq!(status.changed, ["changed"]);
+        assert_eq!(status.unknown, ["empty2", "unknown"]);
+        assert_eq!(Moved::new("a", "b", 100).to_string(), "a -> b");
+        assert_eq!(Moved::new("a", "b", 75).to_string(), "a -> b (75%)");
+    }
+
+    #[test]
+    fn test_similarity() {
+        assert_eq!(similarity(b"", b""), 100);
+        assert_eq!(similarity(b"a\n", b""), 0);
+        assert_eq!(similarity(b"a\nb\n", b"a\nb\n"), 100);
+        assert_eq!(similarity(b"a\nb\n", b"a\nc\n"), 50);
+        assert_eq!(similarity(b"a\nb\nc\nd\n", b"a\nb\nc\nd\ne\n"), 88);
+        assert_eq!(similarity(b"a\nb\n", b"c\nd\n"), 0);
+
+        assert!(may_be_similar(0, 0));
+        assert!(may_be_similar(100, 100));
+        assert!(may_be_similar(100, 300));
+        assert!(may_be_similar(300, 100));
+        assert!(!may_be_similar(100, 301));
+        assert!(!may_be_similar(0, 1));
+    }
+
+    #[test]
+    fn test_find_renames() {
+        use crate::chaos::DefaultStore;
+        use crate::helpers::TestTempDir;
+
+        let tmp = TestTempDir::new();
+        let mut store = DefaultStore::new(tmp.create(&["some_file.store"]));
+        tmp.makedirs(&["tree"]);
+        let text = b"one\ntwo\nthree\nfour\nfive\nsix\nseven\neight\n";
+        tmp.write(&["tree", "moved"], text);
+        tmp.write(&["tree", "edited"], b"1\n2\n3\n4\n5\n6\n7\n8\n");
+        tmp.write(&["tree", "gone"], b"nothing like the others\n");
+        tmp.write(&["tree", "base"], b"a\nb\nc\nd\n");
+        let dir = tmp.build(&["tree"]);
+        let mut tree: DefaultTree = Tree::new(&mut store, &dir);
+        tree.load_ignore_with(None).unwrap();
+        tree.enable_import();
+        let root = tree.scan_tree().unwrap().unwrap();
+        let old = tree.flatten_tree(&root).unwrap();
+
+        // Not imported, so the new files get read from the working tree
+        let mut tree: DefaultTree = Tree::new(&mut store, &dir);
+        tree.load_ignore_with(None).unwrap();
+        std::fs::rename(
+            tmp.build(&["tree", "moved"]),
+            tmp.build(&["tree", "renamed"]),
+        )
+        .unwrap();
+        std::fs::remove_file(tmp.build(&["tree", "edited"])).unwrap();
+        tmp.write(&["tree", "edited2"], b"1\n2\n3\n4\n5\n6\n7\nEIGHT\n");
+        std::fs::remove_file(tmp.build(&["tree", "gone"])).unwrap();
+        tmp.write(&["tree", "base"], b"a\nb\nc\nD\n");
+        tmp.write(&["tree", "copied"], b"a\nb\nc\nd\ne\n");
+        tmp.write(&["tree", "new"], b"brand new\n");
+        tree.scan_tree().unwrap();
+
+        let status = tree.compare_with_renames(&old).unwrap();
+        assert_eq!(
+            status.renamed,
+            [
+                Moved::new("edited", "edited2", 87),
+                Moved::new("moved", "renamed", 100)
+            ]
+        );
+        assert_eq!(status.copied, [Moved::new("base", "copied", 88)]);
+        assert_eq!(status.removed, ["gone"]);
+        assert_eq!(status.changed, ["base"]);
+        assert_eq!(status.unknown, ["new"]);
+
+        let status = tree.compare_with_flatmap(&old);
+        assert!(status.renamed.is_empty());
+        assert_eq!(status.removed.len(), 3);
+    }
+
+    #[test]
+    fn test_diff_items() {
+        use crate::chaos::DefaultStore;
+        use crate::diff::LineKind;
+        use crate::helpers::TestTempDir;
+
+        let tmp = TestTempDir::new();
+        let mut store = DefaultStore::new(tmp.create(&["some_file.store"]));
+        tmp.makedirs(&["tree"]);
+        tmp.write(&["tree", "text"], b"one\ntwo\nthree\n");
+        tmp.write(&["tree", "binary"], b"\x00\x01\x02");
+        let dir = tmp.build(&["tree"]);
+        let mut tree: DefaultTree = Tree::new(&mut store, &dir);
+        tree.load_ignore_with(None).unwrap();
+        tree.enable_import();
+        let root = tree.scan_tree().unwrap().unwrap();
+        let old = tree.flatten_tree(&root).unwrap();
+
+        // Not imported, so the new side gets read from the working tree
+        let mut tree: DefaultTree = Tree::new(&mut store, &dir);
+        tree.load_ignore_with(None).unwrap();
+        tmp.write(&["tree", "text"], b"one\nTWO\nthree\n");
+        tmp.write(&["tree", "binary"], b"\x00\x01\x02\x03");
+        tmp.write(&["tree", "new"], b"hello\n");
+        tree.scan_tree().unwrap();
+        let new = tree.flatmap().clone();
+
+        let fd = tree
+            .diff_items(
+                Some(("text", &old["text"])),
+                Some(("text", &new["text"])),
+                0,
+            )
+            .unwrap();
+        assert_eq!(fd.old_path.as_deref(), Some("text"));
+        assert_eq!(fd.new_path.as_deref(), Some("text"));
+        assert_eq!(fd.numstat(), Some((1, 1)));
+        match &fd.content {
+            Content::Text(hunks) => {
+                assert_eq!(hunks.len(), 1);
+                assert_eq!(hunks[0].header(), "@@ -2,1 +2,1 @@");
+                assert_eq!(hunks[0].lines[0].kind, LineKind::Removed);
+                assert_eq!(hunks[0].lines[0].text, "two\n");
+                assert_eq!(hunks[0].lines[1].kind, LineKind::Added);
+                assert_eq!(hunks[0].lines[1].text, "TWO\n");
+            }
+            _ => panic!("expected a text diff"),
+        }
+
+        let fd = tree
+            .diff_items(
+                Some(("binary", &old["binary"])),
+                Some(("binary", &new["binary"])),
+                3,
+            )
+            .unwrap();
+        assert!(fd.is_binary());
+        assert_eq!(fd.numstat(), None);
+        match &fd.content {
+            Content::Binary {
+                old: Some(a),
+                new: Some(b),
+            } => {
+                assert_eq!(a.size, 3);
+                assert_eq!(b.size, 4);
+                assert_eq!(
+                    Some(Item::File(a.hash.unwrap())),
+                    Some(old["binary"].clone())
+                );
+                assert_eq!(
+                    Some(Item::File(b.hash.unwrap())),
+                    Some(new["binary"].clone())
+                );
+            }
+            _ => panic!("expected a binary diff"),
+        }
+
+        let fd = tree
+            .diff_items(None, Some(("new", &new["new"])), 3)
+            .unwrap();
+        assert_eq!(fd.old_path, None);
+        assert_eq!(fd.display_path(), "new");
+        assert_eq!(fd.numstat(), Some((1, 0)));
+
+        let fd = tree
+            .diff_items(Some(("text", &old["text"])), None, 3)
+            .unwrap();
+        assert_eq!(fd.new_path, None);
+        assert_eq!(fd.numstat(), Some((0, 3)));
+    }
+
+    #[test]
+    fn test_tree_raw_names() {
+        use std::os::unix::ffi::OsStrExt;
+        let mut tree: Dir<15> = Dir::new();
+        tree.add_empty_file(OsStr::from_bytes(b"\xff"));
+        tree.add_symlink("a", OsStr::from_bytes(b"b\xfe"));
+        let mut buf = Vec::new();
+        tree.serialize(&mut buf).unwrap();
+        assert_eq!(buf, b"\x05\x01a\x02\x00b\xfe\x01\x01\xff");
+        assert_eq!(Dir::deserialize(&buf).unwrap(), tree);
+        let versioned = b"\x81\x05\x01\x00a\x02\x00\x00\x00b\xfe\x01\x01\x00\xff";
+        assert_eq!(Dir::deserialize(versioned).unwrap(), tree);
+    }
+
     #[test]
     fn test_tree() {
         let mut hash = Name::<15>::new();
         let tree: Dir<15> = Dir::new();
         let mut buf = Vec::new();
-        tree.serialize(&mut buf);
-        assert_eq!(buf, vec![]);
+        tree.serialize(&mut buf).unwrap();
+        assert!(buf.is_empty());
+        assert_eq!(Dir::deserialize(&buf).unwrap(), tree);
+        assert_eq!(Dir::deserialize(&[DIR_VERSION]).unwrap(), tree);
 
-        // Test each add method, tree with a sigle item
+        // Test each add method, tree with a sigle item (written in the legacy
+        // format, and that the versioned format reads the same)
 
         // EmptyDir
         let mut tree: Dir<15> = Dir::new();
         tree.add_empty_dir("a".to_string());
         let mut buf = Vec::new();
-        tree.serialize(&mut buf);
+        tree.serialize(&mut buf).unwrap();
         assert_eq!(buf, [0, 1, 97]);
-        assert_eq!(Dir::deserialize(&buf), tree);
+        assert_eq!(Dir::deserialize(&buf).unwrap(), tree);
+        assert_eq!(Dir::deserialize(&[0x81, 0, 1, 0, 97]).unwrap(), tree);
 
         // EmptyFile
         let mut tree: Dir<15> = Dir::new();
         tree.add_empty_file("bb".to_string());
         let mut buf = Vec::new();
-        tree.serialize(&mut buf);
+        tree.serialize(&mut buf).unwrap();
         assert_eq!(buf, [1, 2, 98, 98]);
-        assert_eq!(Dir::deserialize(&buf), tree);
+        assert_eq!(Dir::deserialize(&buf).unwrap(), tree);
+        assert_eq!(Dir::deserialize(&[0x81, 1, 2, 0, 98, 98]).unwrap(), tree);
 
         // Dir
         let mut tree: Dir<15> = Dir::new();
         hash.as_mut_buf().fill(7);
-        tree.add_dir("c".to_string(), hash.clone());
+        tree.add_dir("c".to_string(), hash);
         let mut buf = Vec::new();
-        tree.serialize(&mut buf);
-        assert_eq!(buf, [2, 1, 99, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7]);
-        assert_eq!(Dir::deserialize(&buf), tree);
+        tree.serialize(&mut buf).unwrap();
+        assert_eq!(buf[0..3], [2, 1, 99]);
+        assert_eq!(buf[3..], [7; 15]);
+        assert_eq!(Dir::deserialize(&buf).unwrap(), tree);
+        let versioned = [
+            0x81, 2, 1, 0, 99, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7,
+        ];
+        assert_eq!(Dir::deserialize(&versioned).unwrap(), tree);
 
         // File
         let mut tree: Dir<15> = Dir::new();
         hash.as_mut_buf().fill(5);
-        tree.add_file("d".to_string(), hash.clone());
+        tree.add_file("d".to_string(), hash);
         let mut buf = Vec::new();
-        tree.serialize(&mut buf);
-        assert_eq!(
-            buf,
-            [3, 1, 100, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5]
-        );
-        assert_eq!(Dir::deserialize(&buf), tree);
+        tree.serialize(&mut buf).unwrap();
+        assert_eq!(buf[0..3], [3, 1, 100]);
+        assert_eq!(buf[3..], [5; 15]);
+        assert_eq!(Dir::deserialize(&buf).unwrap(), tree);
+        let versioned = [
+            0x81, 3, 1, 0, 100, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5,
+        ];
+        assert_eq!(Dir::deserialize(&versioned).unwrap(), tree);
 
         // ExeFile
         let mut tree: Dir<15> = Dir::new();
         hash.as_mut_buf().fill(3);
-        tree.add_exefile("e".to_string(), hash.clone());
+        tree.add_exefile("e".to_string(), hash);
         let mut buf = Vec::new();
-        tree.serialize(&mut buf);
-        assert_eq!(
-            buf,
-            [4, 1, 101, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3]
-        );
-        assert_eq!(Dir::deserialize(&buf), tree);
+        tree.serialize(&mut buf).unwrap();
+        assert_eq!(buf[0..3], [4, 1, 101]);
+        assert_eq!(buf[3..], [3; 15]);
+        assert_eq!(Dir::deserialize(&buf).unwrap(), tree);
+        let versioned = [
+            0x81, 4, 1, 0, 101, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3,
+        ];
+        assert_eq!(Dir::deserialize(&versioned).unwrap(), tree);
 
         // SymLink
         let mut tree: Dir<15> = Dir::new();
         tree.add_symlink("f".to_string(), "g".to_string());
         let mut buf = Vec::new();
-        tree.serialize(&mut buf);
+        tree.serialize(&mut buf).unwrap();
         assert_eq!(buf, [5, 1, 102, 1, 0, 103]);
-        assert_eq!(Dir::deserialize(&buf), tree);
+        assert_eq!(Dir::deserialize(&buf).unwrap(), tree);
+        assert_eq!(
+            Dir::deserialize(&[0x81, 5, 1, 0, 102, 1, 0, 0, 0, 103]).unwrap(),
+            tree
+        );
     }
 
     #[test]
@@ -834,31 +2585,238 @@ mod tests {
         tree.add_empty_file("E".to_string());
 
         hash.as_mut_buf().fill(7);
-        tree.add_dir("D".to_string(), hash.clone());
+        tree.add_dir("D".to_string(), hash);
 
         hash.as_mut_buf().fill(5);
-        tree.add_file("C".to_string(), hash.clone());
+        tree.add_file("C".to_string(), hash);
 
         hash.as_mut_buf().fill(3);
-        tree.add_exefile("B".to_string(), hash.clone());
+        tree.add_exefile("B".to_string(), hash);
 
         tree.add_symlink("A".to_string(), "foo/bar".to_string());
 
         let mut buf = Vec::new();
-        tree.serialize(&mut buf);
-        assert_eq!(Dir::deserialize(&buf), tree);
+        tree.serialize(&mut buf).unwrap();
+        assert_eq!(Dir::deserialize(&buf).unwrap(), tree);
+        let versioned = [
+            0x81, // Version
+            // "A" SymLink
+            5, 1, 0, 65, 7, 0, 0, 0, 102, 111, 111, 47, 98, 97, 114, // "B" ExeFile
+            4, 1, 0, 66, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, // "C" File
+            3, 1, 0, 67, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, // "D" Dir
+            2, 1, 0, 68, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, // "E" EmptyFile
+            1, 1, 0, 69, // "F" EmptyDir
+            0, 1, 0, 70,
+        ];
+        assert_eq!(Dir::deserialize(&versioned).unwrap(), tree);
+        let legacy = [
+            5, 1, 65, 7, 0, 102, 111, 111, 47, 98, 97, 114, // "B" ExeFile
+            4, 1, 66, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, // "C" File
+            3, 1, 67, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, // "D" Dir
+            2, 1, 68, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, // "E" EmptyFile
+            1, 1, 69, // "F" EmptyDir
+            0, 1, 70,
+        ];
+        assert_eq!(buf, legacy);
+    }
+
+    #[test]
+    fn test_tree_limits() {
+        // Longest name, and one byte too long
+        let mut tree: Dir<15> = Dir::new();
+        let name = "n".repeat(MAX_NAME_LEN);
+        tree.add_empty_file(name.as_str());
+        let mut buf = Vec::new();
+        tree.serialize(&mut buf).unwrap();
+        assert_eq!(buf.len(), 1 + 1 + 2 + MAX_NAME_LEN);
+        assert_eq!(buf[2..4], [0xff, 0xff]);
+        assert_eq!(Dir::deserialize(&buf).unwrap(), tree);
+
+        let mut tree: Dir<15> = Dir::new();
+        tree.add_empty_file("n".repeat(MAX_NAME_LEN + 1));
+        let err = tree.serialize(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        // Legacy up to 255 byte names, versioned past that
+        let mut tree: Dir<15> = Dir::new();
+        tree.add_empty_dir("d".repeat(255));
+        let mut buf = Vec::new();
+        tree.serialize(&mut buf).unwrap();
+        assert_eq!(buf[..2], [0, 255]);
+        assert_eq!(Dir::deserialize(&buf).unwrap(), tree);
+        tree.add_empty_dir("d".repeat(256));
+        let mut buf = Vec::new();
+        tree.serialize(&mut buf).unwrap();
+        assert_eq!(buf[0], DIR_VERSION);
+        assert_eq!(Dir::deserialize(&buf).unwrap(), tree);
+
+        // Empty names aren't a thing
+        let mut tree: Dir<15> = Dir::new();
+        tree.add_empty_file("");
+        let err = tree.serialize(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        // Longest symlink target, and one byte too long
+        let mut tree: Dir<15> = Dir::new();
+        tree.add_symlink("s", "t".repeat(MAX_TARGET_LEN));
+        let mut buf = Vec::new();
+        tree.serialize(&mut buf).unwrap();
+        assert_eq!(buf.len(), 1 + 1 + 2 + 1 + 4 + MAX_TARGET_LEN);
+        assert_eq!(Dir::deserialize(&buf).unwrap(), tree);
+
+        let mut tree: Dir<15> = Dir::new();
+        tree.add_symlink("s", "t".repeat(MAX_TARGET_LEN + 1));
+        let err = tree.serialize(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        // Legacy up to 64 KiB targets, versioned past that
+        let mut tree: Dir<15> = Dir::new();
+        tree.add_symlink("s", "t".repeat(u16::MAX as usize));
+        let mut buf = Vec::new();
+        tree.serialize(&mut buf).unwrap();
+        assert_eq!(buf.len(), 1 + 1 + 1 + 2 + u16::MAX as usize);
+        assert_eq!(Dir::deserialize(&buf).unwrap(), tree);
+        tree.add_symlink("s", "t".repeat(u16::MAX as usize + 1));
+        let mut buf = Vec::new();
+        tree.serialize(&mut buf).unwrap();
+        assert_eq!(buf[0], DIR_VERSION);
+        assert_eq!(Dir::deserialize(&buf).unwrap(), tree);
+    }
+
+    #[test]
+    fn test_tree_deserialize_bad() {
+        let bad: [&[u8]; 7] = [
+            &[0x81, 6, 1, 0, 97],                  // Unknown kind
+            &[0x81, 0, 0, 0],                      // Empty name
+            &[0x81, 0, 2, 0, 97],                  // Short name
+            &[0x81, 2, 1, 0, 97, 7, 7],            // Short hash
+            &[0x81, 5, 1, 0, 97, 2, 0, 0, 0, 103], // Short target
+            &[0x81, 0, 1, 0, 97, 1, 1, 0, 97],     // Duplicate name
+            &[0x81, 5, 1, 0, 97, 1, 0, 16, 0],     // Target too long
+        ];
+        for buf in bad {
+            let err = Dir::<15>::deserialize(buf).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData, "{:?}", buf);
+        }
+        // Legacy and with metadata too
+        let bad: [&[u8]; 5] = [
+            &[6, 1, 97],                // Unknown kind
+            &[0, 0],                    // Empty name
+            &[2, 1, 97, 7, 7],          // Short hash
+            &[0, 1, 97, 1, 1, 97],      // Duplicate name
+            &[0x82, 0, 1, 0, 97, 0x10], // Bad metadata flags
+        ];
+        for buf in bad {
+            let err = Dir::<15>::deserialize(buf).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData, "{:?}", buf);
+        }
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0, 0), "1970-01-01 00:00:00 +0000");
+        assert_eq!(format_timestamp(-1, 0), "1969-12-31 23:59:59 +0000");
         assert_eq!(
-            buf,
-            [
-                // "A" SymLink
-                5, 1, 65, 7, 0, 102, 111, 111, 47, 98, 97, 114, // "D" ExeFile
-                4, 1, 66, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, // "C" File
-                3, 1, 67, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, // "D" Dir
-                2, 1, 68, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, // "E" EmptyDir
-                1, 1, 69, // "F" EmptyDir
-                0, 1, 70,
-            ]
+            format_timestamp(1667790900, -420),
+            "2022-11-06 20:15:00 -0700"
         );
+        assert_eq!(
+            format_timestamp(1709208000, 330),
+            "2024-02-29 17:30:00 +0530"
+        );
+        assert_eq!(format_timestamp(951782400, 0), "2000-02-29 00:00:00 +0000");
+    }
+
+    #[test]
+    fn test_person() {
+        let person = Person::now("Tubby", "tubby@example.com", [5; 32]);
+        assert!(person.timestamp > 1667790900);
+        assert!(person.tz_offset.abs() <= 14 * 60);
+        assert_eq!(person.to_string(), "Tubby <tubby@example.com>");
+        assert_eq!(person.date().len(), 25);
+
+        let mut buf = Vec::new();
+        person.serialize(&mut buf).unwrap();
+        assert_eq!(buf.len(), 32 + 8 + 2 + 2 + 5 + 2 + 17);
+        assert_eq!(Person::deserialize(&mut Reader::new(&buf)), Some(person));
+        for size in 0..buf.len() {
+            assert!(Person::deserialize(&mut Reader::new(&buf[..size])).is_none());
+        }
+
+        let person = Person {
+            name: "x".repeat(u16::MAX as usize + 1),
+            ..Default::default()
+        };
+        let err = person.serialize(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_commit() {
+        let tree = DefaultName::from(&[1; 30][..]);
+        let mut commit = DefaultCommit::new(tree, "Hello".to_string());
+        assert!(commit.parents.is_empty());
+        assert!(!commit.is_merge());
+        let mut buf = Vec::new();
+        commit.serialize(&mut buf).unwrap();
+        assert_eq!(buf[0], COMMIT_VERSION);
+        assert_eq!(buf.len(), 1 + 30 + 1 + 2 * 46 + 2 + 4 + 5);
+        assert_eq!(DefaultCommit::deserialize(&buf), commit);
+
+        commit.parents.push(DefaultName::from(&[2; 30][..]));
+        commit.parents.push(DefaultName::from(&[3; 30][..]));
+        assert!(commit.is_merge());
+        commit.author = Person {
+            name: "Author".to_string(),
+            email: "author@example.com".to_string(),
+            pubkey: [4; 32],
+            timestamp: 1667790900,
+            tz_offset: -420,
+        };
+        commit.committer = Person::now("Committer", "", [6; 32]);
+        commit
+            .headers
+            .insert("branch".to_string(), "main".to_string());
+        commit
+            .headers
+            .insert("encoding".to_string(), "utf-8".to_string());
+        commit.msg = "Merge 🛁 into main\n\nWith a body".to_string();
+        let mut buf = Vec::new();
+        commit.serialize(&mut buf).unwrap();
+        assert_eq!(DefaultCommit::deserialize(&buf), commit);
+        assert_eq!(
+            DefaultCommit::deserialize_current(&buf),
+            Some(commit.clone())
+        );
+
+        // Truncated or trailing junk isn't the current format
+        for size in 0..buf.len() {
+            assert!(DefaultCommit::deserialize_current(&buf[..size]).is_none());
+        }
+        let mut bad = buf.clone();
+        bad.push(0);
+        assert!(DefaultCommit::deserialize_current(&bad).is_none());
+        let mut bad = buf.clone();
+        bad[0] = COMMIT_VERSION + 1;
+        assert!(DefaultCommit::deserialize_current(&bad).is_none());
+
+        // Too big for the format is an error, not a panic
+        commit.parents = vec![DefaultName::from(&[2; 30][..]); 256];
+        let err = commit.serialize(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_commit_legacy() {
+        // | Tree N | Msg |
+        let mut buf = vec![7; 30];
+        buf.extend_from_slice(b"Old school");
+        let commit = DefaultCommit::deserialize(&buf);
+        assert_eq!(commit.tree, DefaultName::from(&[7; 30][..]));
+        assert_eq!(commit.msg, "Old school");
+        assert!(commit.parents.is_empty());
+        assert_eq!(commit.author, Person::default());
+        assert!(commit.headers.is_empty());
     }
 
     #[test]
@@ -898,7 +2856,7 @@ mod tests {
         let mut tl = TrackingList::new();
         assert_eq!(tl.len(), 0);
         let mut buf = Vec::new();
-        tl.serialize(&mut buf);
+        tl.serialize(&mut buf).unwrap();
         assert_eq!(buf, vec![]);
         assert_eq!(TrackingList::deserialize(&buf), tl);
 
@@ -911,7 +2869,7 @@ mod tests {
             tl.as_sorted_vec(),
             vec![(&String::from("test"), &TrackedItem::Added)]
         );
-        tl.serialize(&mut buf);
+        tl.serialize(&mut buf).unwrap();
         assert_eq!(buf, vec![1, 4, 0, 116, 101, 115, 116]);
         assert_eq!(TrackingList::deserialize(&buf), tl);
 
@@ -931,7 +2889,7 @@ mod tests {
             ]
         );
         buf.clear();
-        tl.serialize(&mut buf);
+        tl.serialize(&mut buf).unwrap();
         assert_eq!(
             buf,
             vec![
@@ -957,7 +2915,7 @@ mod tests {
             ]
         );
         buf.clear();
-        tl.serialize(&mut buf);
+        tl.serialize(&mut buf).unwrap();
         assert_eq!(
             buf,
             vec![
@@ -968,6 +2926,714 @@ mod tests {
         assert_eq!(TrackingList::deserialize(&buf), tl);
     }
 
+    #[test]
+    fn test_tracking_list_limits() {
+        let mut tl = TrackingList::new();
+        tl.add("p".repeat(u16::MAX as usize));
+        let mut buf = Vec::new();
+        tl.serialize(&mut buf).unwrap();
+        assert_eq!(buf[1..3], [0xff, 0xff]);
+        assert_eq!(TrackingList::deserialize(&buf), tl);
+
+        let mut tl = TrackingList::new();
+        tl.add("p".repeat(u16::MAX as usize + 1));
+        let err = tl.serialize(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        let mut tl = TrackingList::new();
+        tl.rename("old".to_owned(), "n".repeat(u16::MAX as usize + 1));
+        let err = tl.serialize(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_tracked_paths() {
+        let mut tp = TrackedPaths::new();
+        assert!(tp.is_empty());
+        assert!(!tp.covers("a"));
+        assert!(tp.insert("a/b".to_string()));
+        assert!(!tp.insert("a/b".to_string()));
+        tp.insert("a/b/c".to_string());
+        tp.insert("a/bc".to_string());
+        tp.insert("a.txt".to_string());
+        assert_eq!(tp.len(), 4);
+        assert!(tp.contains("a/b"));
+        assert!(!tp.contains("a"));
+        assert!(tp.covers("a/b"));
+        assert!(tp.covers("a/b/x/y"));
+        assert!(!tp.covers("a"));
+        assert!(!tp.covers("a/x"));
+        assert!(tp.has_descendant("a"));
+        assert!(tp.has_descendant("a/b"));
+        assert!(!tp.has_descendant("a/bc"));
+        assert!(!tp.has_descendant("a.txt"));
+
+        assert_eq!(tp.remove_tree("a/b"), ["a/b", "a/b/c"]);
+        assert_eq!(tp.as_sorted_vec(), ["a.txt", "a/bc"]);
+        assert!(tp.remove_tree("nope").is_empty());
+
+        tp.insert(String::new());
+        assert!(tp.covers("anything/at/all"));
+    }
+
+    #[test]
+    fn test_tracking_apply() {
+        let mut previous: ItemMap<30> = HashMap::new();
+        let hash = Name::from(&[7; 30][..]);
+        previous.insert("src".to_string(), Item::Dir(hash));
+        previous.insert("src/main.rs".to_string(), Item::File(hash));
+        previous.insert("src/old".to_string(), Item::Dir(hash));
+        previous.insert("src/old/a.rs".to_string(), Item::File(hash));
+        previous.insert("src/old/b.rs".to_string(), Item::File(hash));
+        previous.insert("README".to_string(), Item::File(hash));
+        previous.insert("empty".to_string(), Item::EmptyDir);
+        previous.insert("LICENSE".to_string(), Item::EmptyFile);
+
+        let tl = TrackingList::new();
+        assert_eq!(
+            tl.apply(&previous).as_sorted_vec(),
+            [
+                "LICENSE",
+                "README",
+                "empty",
+                "src/main.rs",
+                "src/old/a.rs",
+                "src/old/b.rs"
+            ]
+        );
+
+        let mut tl = TrackingList::new();
+        tl.add("docs".to_string());
+        tl.remove("LICENSE".to_string());
+        tl.remove("empty".to_string());
+        tl.rename("src/old".to_string(), "src/new".to_string());
+        tl.rename("README".to_string(), "README.md".to_string());
+        assert_eq!(
+            tl.apply(&previous).as_sorted_vec(),
+            [
+                "README.md",
+                "docs",
+                "src/main.rs",
+                "src/new/a.rs",
+                "src/new/b.rs"
+            ]
+        );
+        assert_eq!(
+            tl.apply(&HashMap::<String, Item<30>>::new())
+                .as_sorted_vec(),
+            ["README.md", "docs", "src/new"]
+        );
+    }
+
+    #[test]
+    fn test_scan_tracked() {
+        use crate::chaos::DefaultStore;
+        use crate::helpers::TestTempDir;
+
+        let tmp = TestTempDir::new();
+        let mut store = DefaultStore::new(tmp.create(&["some_file.store"]));
+        tmp.makedirs(&["tree", "a", "b"]);
+        tmp.makedirs(&["tree", "c"]);
+        tmp.makedirs(&["tree", "d", "e"]);
+        tmp.write(&["tree", "a", "b", "one"], b"1");
+        tmp.write(&["tree", "a", "b", "two.o"], b"2");
+        tmp.write(&["tree", "a", "three"], b"3");
+        tmp.write(&["tree", "c", "four"], b"4");
+        tmp.write(&["tree", "five.o"], b"5");
+        tmp.write(&["tree", DOTIGNORE], b"*.o\n");
+        let dir = tmp.build(&["tree"]);
+
+        let scan = |tree: &mut DefaultTree| {
+            tree.flatmap.clear();
+            let root = tree.scan_tree().unwrap();
+            let mut relpaths: Vec<String> = tree.flatmap.keys().cloned().collect();
+            relpaths.sort();
+            (root, relpaths)
+        };
+        let mut tree: DefaultTree = Tree::new(&mut store, &dir);
+        tree.load_ignore_with(None).unwrap();
+        let (all, relpaths) = scan(&mut tree);
+        assert_eq!(relpaths.len(), 9);
+
+        let mut tracked = TrackedPaths::new();
+        tracked.insert(String::new());
+        tree.set_tracked(Some(tracked));
+        assert_eq!(scan(&mut tree), (all, relpaths));
+
+        tree.set_tracked(Some(TrackedPaths::new()));
+        assert_eq!(scan(&mut tree), (None, vec![]));
+
+        // Partially tracked directories only get the tracked entries, explicitly
+        // tracked paths are scanned even if ignored, empty directories only if
+        // they're tracked
+        let mut tracked = TrackedPaths::new();
+        tracked.insert("a/b".to_string());
+        tracked.insert("five.o".to_string());
+        tracked.insert("d/e".to_string());
+        tracked.insert("c/nope".to_string());
+        tree.set_tracked(Some(tracked));
+        let (root, relpaths) = scan(&mut tree);
+        assert!(root.is_some());
+        assert_eq!(relpaths, ["a", "a/b", "a/b/one", "d", "d/e", "five.o"]);
+    }
+
+    #[test]
+    fn test_save_flat_tree() {
+        use crate::chaos::DefaultStore;
+        use crate::helpers::TestTempDir;
+
+        let tmp = TestTempDir::new();
+        let mut store = DefaultStore::new(tmp.create(&["some_file.store"]));
+        tmp.makedirs(&["tree", "a", "b"]);
+        tmp.makedirs(&["tree", "empty"]);
+        tmp.write(&["tree", "a", "b", "one"], b"1");
+        tmp.write(&["tree", "a", "two"], b"2");
+        tmp.touch(&["tree", "three"]);
+        let dir = tmp.build(&["tree"]);
+
+        let mut tree: DefaultTree = Tree::new(&mut store, &dir);
+        tree.load_ignore_with(None).unwrap();
+        tree.enable_import();
+        let root = tree.scan_tree().unwrap().unwrap();
+        let flat = tree.flatten_tree(&root).unwrap();
+        assert_eq!(tree.save_flat_tree(&flat).unwrap(), Some(root));
+        assert_eq!(tree.save_flat_tree(&ItemMap::new()).unwrap(), None);
+
+        // Just the leaves is enough, `Dir` items get rebuilt
+        let mut leaves: ItemMap<30> = flat
+            .iter()
+            .filter(|(_, item)| !matches!(item, Item::Dir(_)))
+            .map(|(path, item)| (path.to_owned(), item.to_owned()))
+            .collect();
+        assert_eq!(tree.save_flat_tree(&leaves).unwrap(), Some(root));
+
+        let item = tree.save_data(b"4", true).unwrap();
+        assert!(matches!(item, Item::ExeFile(_)));
+        assert_eq!(tree.save_data(b"", false).unwrap(), Item::EmptyFile);
+        leaves.insert("c/d/four".to_string(), item.clone());
+        let root2 = tree.save_flat_tree(&leaves).unwrap().unwrap();
+        let flat2 = tree.flatten_tree(&root2).unwrap();
+        assert_eq!(flat2.get("c/d/four"), Some(&item));
+        assert!(matches!(flat2.get("c/d"), Some(Item::Dir(_))));
+        assert_eq!(flat2.len(), flat.len() + 3);
+        if let Item::ExeFile(hash) = item {
+            assert_eq!(tree.load_data(&hash).unwrap(), Some(b"4".to_vec()));
+        }
+        let mut missing = DefaultName::new();
+        missing.randomize();
+        let err = tree.load_data(&missing).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        // Decoded `Dir`s are cached, so flattening again doesn't load them
+        tree.store.enable_cache(1 << 20);
+        assert_eq!(tree.flatten_tree(&root2).unwrap(), flat2);
+        assert_eq!(tree.store.cache_stats().unwrap().lookups(), 0);
+        tree.dirs.clear();
+        assert_eq!(tree.flatten_tree(&root2).unwrap(), flat2);
+        assert_eq!(tree.store.cache_stats().unwrap().lookups(), 5);
+    }
+
+    #[test]
+    fn test_os_to_relpath() {
+        use std::os::unix::ffi::OsStrExt;
+        let cases: [&[u8]; 7] = [
+            b"",
+            b"foo/bar.txt",
+            "caf\u{e9}/\u{1f6c1}".as_bytes(),
+            b"caf\xe9",
+            b"\xff\xfe/\x80",
+            b"\xf0\x9f\x9b",
+            "\u{10ff80}\u{10ffff}".as_bytes(),
+        ];
+        for raw in cases {
+            let relpath = os_to_relpath(OsStr::from_bytes(raw));
+            assert_eq!(relpath_to_os(&relpath).as_bytes(), raw);
+        }
+        assert_eq!(os_to_relpath(OsStr::new("foo/bar.txt")), "foo/bar.txt");
+        assert_eq!(os_to_relpath(OsStr::from_bytes(b"a\xffb")), "a\u{10ffff}b");
+        assert_ne!(
+            os_to_relpath(OsStr::from_bytes(b"\xff")),
+            os_to_relpath(OsStr::new("\u{10ffff}"))
+        );
+        assert_eq!(display_relpath("foo"), "foo");
+        assert!(matches!(display_relpath("foo"), Cow::Borrowed(_)));
+        let relpath = os_to_relpath(OsStr::from_bytes(b"caf\xe9/x"));
+        assert_eq!(display_relpath(&relpath), "caf\u{fffd}/x");
+    }
+
+    #[test]
+    fn test_non_utf8_names() {
+        use crate::chaos::DefaultStore;
+        use crate::helpers::TestTempDir;
+        use std::os::unix::ffi::OsStrExt;
+
+        let tmp = TestTempDir::new();
+        let mut store = DefaultStore::new(tmp.create(&["some_file.store"]));
+        tmp.makedirs(&["tree"]);
+        let dir = tmp.build(&["tree"]);
+        let sub = dir.join(OsStr::from_bytes(b"d\xefr"));
+        create_dir_all(&sub).unwrap();
+        std::fs::write(sub.join(OsStr::from_bytes(b"f\xfcle")), b"data").unwrap();
+        symlink(OsStr::from_bytes(b"t\xe0rget"), dir.join("link")).unwrap();
+
+        let mut tree: DefaultTree = Tree::new(&mut store, &dir);
+        tree.load_ignore_with(None).unwrap();
+        tree.enable_import();
+        let root = tree.scan_tree().unwrap().unwrap();
+        let flat = tree.flatten_tree(&root).unwrap();
+        let relpath = os_to_relpath(OsStr::from_bytes(b"d\xefr/f\xfcle"));
+        assert!(matches!(flat.get(&relpath), Some(Item::File(_))));
+        assert_eq!(
+            flat.get("link"),
+            Some(&Item::SymLink(OsStr::from_bytes(b"t\xe0rget").to_owned()))
+        );
+        assert_eq!(tree.save_flat_tree(&flat).unwrap(), Some(root));
+
+        // Restored with the exact same names
+        let dir2 = tmp.makedirs(&["tree2"]);
+        let mut tree: DefaultTree = Tree::new(&mut store, &dir2);
+        tree.restore_tree(&root).unwrap();
+        let path = dir2.join(OsStr::from_bytes(b"d\xefr/f\xfcle"));
+        assert_eq!(std::fs::read(&path).unwrap(), b"data");
+        assert_eq!(
+            read_link(dir2.join("link")).unwrap().as_os_str().as_bytes(),
+            b"t\xe0rget"
+        );
+        tree.remove_item(&relpath).unwrap();
+        assert!(!dir2.join(OsStr::from_bytes(b"d\xefr")).exists());
+        tree.restore_item(&relpath, &flat[&relpath]).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"data");
+    }
+
+    #[test]
+    fn test_tree_meta() {
+        let mut tree: Dir<15> = Dir::new();
+        tree.add_empty_file("a");
+        tree.add_empty_dir("b");
+        tree.set_meta("b", Meta::default());
+        let mut buf = Vec::new();
+        tree.serialize(&mut buf).unwrap();
+        assert_eq!(buf, [1, 1, 97, 0, 1, 98]);
+
+        let meta = Meta {
+            mode: Some(0o750),
+            ..Meta::default()
+        };
+        tree.set_meta("b", meta.clone());
+        assert_eq!(tree.get_meta(OsStr::new("b")), Some(&meta));
+        let mut buf = Vec::new();
+        tree.serialize(&mut buf).unwrap();
+        assert_eq!(buf[0], DIR_META_VERSION);
+        assert_eq!(buf[1..6], [1, 1, 0, 97, 0]); // "a" EmptyFile, no metadata
+        assert_eq!(buf[6..], [0, 1, 0, 98, 2, 0xe8, 1, 0, 0]); // "b" EmptyDir, mode
+        assert_eq!(Dir::deserialize(&buf).unwrap(), tree);
+
+        // Bad or missing metadata
+        assert!(Dir::<15>::deserialize(&buf[..6]).is_ok());
+        assert!(Dir::<15>::deserialize(&buf[..5]).is_err());
+        let mut bad = buf.clone();
+        bad[5] = 0x80;
+        assert!(Dir::<15>::deserialize(&bad).is_err());
+    }
+
+    #[test]
+    fn test_scan_restore_meta() {
+        use crate::chaos::DefaultStore;
+        use crate::helpers::TestTempDir;
+
+        let tmp = TestTempDir::new();
+        let mut store = DefaultStore::new(tmp.create(&["some_file.store"]));
+        tmp.makedirs(&["tree", "sub"]);
+        tmp.write(&["tree", "sub", "file"], b"data");
+        let dir = tmp.build(&["tree"]);
+        let (sub, file) = (dir.join("sub"), dir.join("sub/file"));
+        std::fs::set_permissions(&file, Permissions::from_mode(0o604)).unwrap();
+        std::fs::set_permissions(&sub, Permissions::from_mode(0o750)).unwrap();
+        let then = Meta {
+            mtime: Some((1667790900, 5)),
+            ..Meta::default()
+        };
+        then.apply(&file).unwrap();
+        then.apply(&sub).unwrap();
+
+        let mut tree: DefaultTree = Tree::new(&mut store, &dir);
+        tree.load_ignore_with(None).unwrap();
+        tree.enable_import();
+        let plain = tree.scan_tree().unwrap().unwrap();
+        let opts = MetaOptions::parse("mtime,mode").unwrap();
+        tree.set_meta_options(opts);
+        let root = tree.scan_tree().unwrap().unwrap();
+        assert_ne!(root, plain);
+        // Same content, so the same flattened tree
+        assert_eq!(
+            tree.flatten_tree(&root).unwrap().get("sub/file"),
+            tree.flatten_tree(&plain).unwrap().get("sub/file")
+        );
+
+        let dir2 = tmp.makedirs(&["tree2"]);
+        let mut tree: DefaultTree = Tree::new(&mut store, &dir2);
+        tree.restore_tree(&root).unwrap();
+        let (sub2, file2) = (dir2.join("sub"), dir2.join("sub/file"));
+        assert_eq!(std::fs::read(&file2).unwrap(), b"data");
+        assert_eq!(
+            Meta::read(&file2, &opts).unwrap(),
+            Meta {
+                mtime: Some((1667790900, 5)),
+                mode: Some(0o604),
+                ..Meta::default()
+            }
+        );
+        assert_eq!(
+            Meta::read(&sub2, &opts).unwrap(),
+            Meta {
+                mtime: Some((1667790900, 5)),
+                mode: Some(0o750),
+                ..Meta::default()
+            }
+        );
+
+        // Trees without metadata restore like always
+        let dir3 = tmp.makedirs(&["tree3"]);
+        let mut tree: DefaultTree = Tree::new(&mut store, &dir3);
+        tree.restore_tree(&plain).unwrap();
+        let meta = Meta::read(&dir3.join("sub/file"), &opts).unwrap();
+        assert_ne!(meta.mtime, Some((1667790900, 5)));
+
+        // Same content restored first with a mode that can't be read back
+        let dir4 = tmp.makedirs(&["tree4"]);
+        let mut tree: DefaultTree = Tree::new(&mut store, &dir4);
+        let item = tree.save_data(b"data", false).unwrap();
+        let flat = ItemMap::from([("a".to_string(), item.clone()), ("b".to_string(), item)]);
+        let wronly = Meta {
+            mode: Some(0o200),
+            ..Meta::default()
+        };
+        let metas = MetaMap::from([("a".to_string(), wronly.clone())]);
+        let root = tree
+            .save_flat_tree_with_meta(&flat, &metas)
+            .unwrap()
+            .unwrap();
+        tree.restore_tree(&root).unwrap();
+        assert_eq!(
+            Meta::read(&dir4.join("a"), &opts).unwrap().mode,
+            wronly.mode
+        );
+        assert_eq!(std::fs::read(dir4.join("b")).unwrap(), b"data");
+    }
+
+    #[test]
+    fn test_restore_remove_item() {
+        use crate::chaos::DefaultStore;
+        use crate::helpers::TestTempDir;
+
+        let tmp = TestTempDir::new();
+        let mut store = DefaultStore::new(tmp.create(&["some_file.store"]));
+        tmp.makedirs(&["tree", "a"]);
+        tmp.write(&["tree", "a", "one"], b"1");
+        let dir = tmp.build(&["tree"]);
+        let mut tree: DefaultTree = Tree::new(&mut store, &dir);
+
+        let item = tree.save_data(b"hello", false).unwrap();
+        tree.restore_item("x/y/z", &item).unwrap();
+        assert_eq!(tmp.read(&["tree", "x", "y", "z"]), b"hello");
+
+        // Replaces what's there, even a directory
+        tree.restore_item("a", &item).unwrap();
+        assert_eq!(tmp.read(&["tree", "a"]), b"hello");
+
+        // Empty parents get pruned, but never the tree itself
+        tree.remove_item("x/y/z").unwrap();
+        assert!(!tmp.build(&["tree", "x"]).exists());
+        tree.remove_item("a").unwrap();
+        assert!(dir.is_dir());
+        assert_eq!(tmp.list_dir(&["tree"]), Vec::<String>::new());
+        tree.remove_item("nope").unwrap();
+
+        // A missing object is an error, and leaves what's there alone
+        tmp.write(&["tree", "keep"], b"precious");
+        let missing = Item::File(DefaultName::new());
+        let err = tree.restore_item("keep", &missing).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(tmp.read(&["tree", "keep"]), b"precious");
+        assert!(tree.restore_item("new", &missing).is_err());
+        assert!(!tmp.build(&["tree", "new"]).exists());
+        let err = tree
+            .restore_item("d", &Item::Dir(DefaultName::new()))
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_checkout_flat() {
+        use crate::chaos::DefaultStore;
+        use crate::helpers::TestTempDir;
+
+        let tmp = TestTempDir::new();
+        let mut store = DefaultStore::new(tmp.create(&["some_file.store"]));
+        tmp.makedirs(&["tree", "a", "b"]);
+        tmp.write(&["tree", "a", "b", "one"], b"1");
+        tmp.write(&["tree", "a", "two"], b"2");
+        tmp.write(&["tree", "three"], b"3");
+        let dir = tmp.build(&["tree"]);
+        let mut tree: DefaultTree = Tree::new(&mut store, &dir);
+        tree.load_ignore_with(None).unwrap();
+        tree.enable_import();
+        let root1 = tree.scan_tree().unwrap().unwrap();
+        let flat1 = tree.flatten_tree(&root1).unwrap();
+
+        tmp.write(&["tree", "a", "two"], b"two");
+        tmp.write(&["tree", "four"], b"4");
+        std::fs::remove_file(tmp.build(&["tree", "three"])).unwrap();
+        tmp.makedirs(&["tree", "three"]);
+        tmp.write(&["tree", "three", "five"], b"5");
+        let root2 = tree.scan_tree().unwrap().unwrap();
+        let flat2 = tree.flatten_tree(&root2).unwrap();
+
+        let status = tree.checkout_flat(&flat2, &flat1).unwrap();
+        assert_eq!(status.removed, ["four", "three/five"]);
+        assert_eq!(status.changed, ["a/two"]);
+        assert_eq!(status.unknown, ["three"]);
+        assert_eq!(tmp.read(&["tree", "three"]), b"3");
+        assert_eq!(tree.scan_tree().unwrap(), Some(root1));
+
+        tree.checkout_flat(&flat1, &flat2).unwrap();
+        assert_eq!(tree.scan_tree().unwrap(), Some(root2));
+        tree.checkout_flat(&flat2, &flat2).unwrap();
+        assert_eq!(tree.scan_tree().unwrap(), Some(root2));
+    }
+
+    #[test]
+    fn test_checkout_flat_with_meta() {
+        use crate::chaos::DefaultStore;
+        use crate::helpers::TestTempDir;
+
+        let tmp = TestTempDir::new();
+        let mut store = DefaultStore::new(tmp.create(&["some_file.store"]));
+        tmp.makedirs(&["tree", "a", "b"]);
+        tmp.write(&["tree", "a", "b", "one"], b"1");
+        tmp.write(&["tree", "a", "two"], b"2");
+        let dir = tmp.build(&["tree"]);
+        let mut tree: DefaultTree = Tree::new(&mut store, &dir);
+        tree.load_ignore_with(None).unwrap();
+        tree.set_meta_options(MetaOptions::parse("mtime,mode").unwrap());
+        tree.enable_import();
+        let past = Meta {
+            mtime: Some((1667790900, 0)),
+            ..Meta::default()
+        };
+        past.apply(&tmp.build(&["tree", "a", "two"])).unwrap();
+        past.apply(&tmp.build(&["tree", "a"])).unwrap();
+        let root1 = tree.scan_tree().unwrap().unwrap();
+        let (flat1, metas1) = tree.flatten_tree_with_meta(&root1).unwrap();
+        let mut keys = Vec::from_iter(metas1.keys());
+        keys.sort();
+        assert_eq!(keys, ["a", "a/b", "a/b/one", "a/two"]);
+        assert_eq!(metas1["a/two"].mtime, past.mtime);
+
+        // The metadata makes it through a flattened tree
+        assert_eq!(tree.flatten_tree(&root1).unwrap(), flat1);
+        assert_eq!(
+            tree.save_flat_tree_with_meta(&flat1, &metas1).unwrap(),
+            Some(root1)
+        );
+        assert_ne!(tree.save_flat_tree(&flat1).unwrap(), Some(root1));
+
+        tmp.write(&["tree", "a", "two"], b"two");
+        let path = tmp.build(&["tree", "a", "b", "one"]);
+        std::fs::set_permissions(&path, Permissions::from_mode(0o600)).unwrap();
+        let root2 = tree.scan_tree().unwrap().unwrap();
+        let (flat2, metas2) = tree.flatten_tree_with_meta(&root2).unwrap();
+        assert_ne!(metas1["a/b/one"], metas2["a/b/one"]);
+
+        // Both the changed file and the metadata only change (and the mtime of
+        // the directory they're in) get put back
+        let status = tree
+            .checkout_flat_with_meta(&flat2, &flat1, &metas2, &metas1)
+            .unwrap();
+        assert_eq!(status.changed, ["a/two"]);
+        assert_eq!(tree.scan_tree().unwrap(), Some(root1));
+        tree.checkout_flat_with_meta(&flat1, &flat2, &metas1, &metas2)
+            .unwrap();
+        assert_eq!(tree.scan_tree().unwrap(), Some(root2));
+    }
+
+    #[test]
+    fn test_diff_trees() {
+        use crate::chaos::DefaultStore;
+        use crate::helpers::TestTempDir;
+
+        let tmp = TestTempDir::new();
+        let mut store = DefaultStore::new(tmp.create(&["some_file.store"]));
+        let dir = tmp.makedirs(&["tree"]);
+        let mut tree: DefaultTree = Tree::new(&mut store, &dir);
+        let file = |tree: &mut DefaultTree, data: &[u8]| tree.save_data(data, false).unwrap();
+        let flat = |items: &[(&str, &Item<30>)]| -> ItemMap<30> {
+            items
+                .iter()
+                .map(|(path, item)| (path.to_string(), (*item).clone()))
+                .collect()
+        };
+
+        // A subtree that isn't in the store, but is the same on both sides
+        let mut missing = DefaultName::new();
+        missing.randomize();
+        let with_missing = |tree: &mut DefaultTree, root: Option<DefaultName>| {
+            let mut dir = Dir::new();
+            for (name, item) in tree.load_dir_or_empty(root.as_ref()).unwrap().as_map() {
+                dir.add(name.clone(), item.clone());
+            }
+            dir.add_dir("missing".to_string(), missing);
+            tree.obj.clear();
+            dir.serialize(tree.obj.as_mut_vec()).unwrap();
+            let hash = tree.obj.finalize_with_kind(ObjKind::Tree as u8);
+            tree.store.save(&tree.obj).unwrap();
+            Some(hash)
+        };
+
+        let (one, two, three) = (
+            file(&mut tree, b"1"),
+            file(&mut tree, b"2"),
+            file(&mut tree, b"3"),
+        );
+        let a = flat(&[
+            ("same", &one),
+            ("x/changed", &one),
+            ("x/y/removed", &two),
+            ("becomes_dir", &three),
+        ]);
+        let b = flat(&[
+            ("same", &one),
+            ("x/changed", &two),
+            ("x/added", &three),
+            ("becomes_dir/z", &three),
+        ]);
+        let ra = tree.save_flat_tree(&a).unwrap();
+        let ra = with_missing(&mut tree, ra);
+        let rb = tree.save_flat_tree(&b).unwrap();
+        let rb = with_missing(&mut tree, rb);
+
+        let (old, new) = tree.diff_trees(ra.as_ref(), rb.as_ref()).unwrap();
+        let expected_old = flat(&[
+            ("x/changed", &one),
+            ("x/y/removed", &two),
+            ("becomes_dir", &three),
+        ]);
+        let expected_new = flat(&[
+            ("x/changed", &two),
+            ("x/added", &three),
+            ("becomes_dir/z", &three),
+        ]);
+        assert_eq!(old, expected_old);
+        assert_eq!(new, expected_new);
+
+        let (new2, old2) = tree.diff_trees(rb.as_ref(), ra.as_ref()).unwrap();
+        assert_eq!((old2, new2), (expected_old, expected_new));
+        assert_eq!(
+            tree.diff_trees(ra.as_ref(), ra.as_ref()).unwrap(),
+            (ItemMap::new(), ItemMap::new())
+        );
+        assert_eq!(
+            tree.diff_trees(None, None).unwrap(),
+            (ItemMap::new(), ItemMap::new())
+        );
+
+        // Against nothing it's everything
+        let a = flat(&[("x/changed", &one), ("x/y/removed", &two)]);
+        let ra = tree.save_flat_tree(&a).unwrap();
+        assert_eq!(
+            tree.diff_trees(ra.as_ref(), None).unwrap(),
+            (a.clone(), ItemMap::new())
+        );
+        assert_eq!(
+            tree.diff_trees(None, ra.as_ref()).unwrap(),
+            (ItemMap::new(), a)
+        );
+    }
+
+    #[test]
+    fn test_diff_trees_scanned() {
+        use crate::chaos::DefaultStore;
+        use crate::helpers::TestTempDir;
+
+        let tmp = TestTempDir::new();
+        let mut store = DefaultStore::new(tmp.create(&["some_file.store"]));
+        let dir = tmp.makedirs(&["tree", "x", "y"]);
+        let dir = dir.parent().unwrap().parent().unwrap();
+        tmp.write(&["tree", "a"], b"a");
+        tmp.write(&["tree", "x", "b"], b"b");
+        tmp.write(&["tree", "x", "y", "c"], b"c");
+        let mut tree: DefaultTree = Tree::new(&mut store, dir);
+        tree.load_ignore_with(None).unwrap();
+        tree.enable_import();
+        let stored = tree.scan_tree().unwrap();
+        let old = tree.flatten_tree(stored.as_ref().unwrap()).unwrap();
+
+        // Nothing gets saved, the scanned `Dir`s are only kept in the `Tree`
+        let mut tree: DefaultTree = Tree::new(&mut store, dir);
+        tree.load_ignore_with(None).unwrap();
+        tmp.write(&["tree", "x", "b"], b"B");
+        tmp.write(&["tree", "x", "new"], b"new");
+        let scanned = tree.scan_tree().unwrap();
+        assert!(!tree.store.contains(scanned.as_ref().unwrap()));
+        tree.store.enable_cache(1 << 20);
+        let (a, b) = tree.diff_trees(stored.as_ref(), scanned.as_ref()).unwrap();
+        assert_eq!(Vec::from_iter(a.keys()), ["x/b"]);
+        assert_eq!(a["x/b"], old["x/b"]);
+        let mut keys = Vec::from_iter(b.keys());
+        keys.sort();
+        assert_eq!(keys, ["x/b", "x/new"]);
+        assert_eq!(b["x/new"], tree.flatmap()["x/new"]);
+        // Only the stored root and `x` get loaded, `x/y` is the same hash
+        assert_eq!(tree.store.cache_stats().unwrap().lookups(), 2);
+    }
+
+    #[test]
+    fn test_diff_flat() {
+        let mut hash = DefaultName::new();
+        hash.randomize();
+        let mut a: ItemMap<30> = ItemMap::new();
+        let mut b: ItemMap<30> = ItemMap::new();
+        assert_eq!(diff_flat(&a, &b), (ItemMap::new(), ItemMap::new()));
+
+        a.insert("same".to_string(), Item::EmptyFile);
+        b.insert("same".to_string(), Item::EmptyFile);
+        a.insert("dir".to_string(), Item::Dir(hash));
+        a.insert("dir/changed".to_string(), Item::EmptyFile);
+        b.insert("dir".to_string(), Item::EmptyDir);
+        b.insert("new".to_string(), Item::File(hash));
+        let (old, new) = diff_flat(&a, &b);
+        assert_eq!(Vec::from_iter(old.keys()), ["dir/changed"]);
+        let mut keys = Vec::from_iter(new.keys());
+        keys.sort();
+        assert_eq!(keys, ["dir", "new"]);
+    }
+
+    #[test]
+    fn test_overwritten() {
+        let mut hash = DefaultName::new();
+        hash.randomize();
+        let mut untracked: ItemMap<30> = ItemMap::new();
+        let mut new: ItemMap<30> = ItemMap::new();
+        assert!(overwritten(&untracked, &new).is_empty());
+
+        untracked.insert("notes".to_string(), Item::File(hash));
+        untracked.insert("docs".to_string(), Item::Dir(hash));
+        untracked.insert("docs/todo".to_string(), Item::EmptyFile);
+        untracked.insert("build".to_string(), Item::EmptyFile);
+        untracked.insert("alone".to_string(), Item::EmptyFile);
+        assert!(overwritten(&untracked, &new).is_empty());
+
+        new.insert("notes".to_string(), Item::File(hash));
+        new.insert("docs".to_string(), Item::Dir(hash));
+        new.insert("docs/readme".to_string(), Item::File(hash));
+        new.insert("build".to_string(), Item::Dir(hash));
+        assert_eq!(overwritten(&untracked, &new), ["build", "notes"]);
+        new.insert("docs/todo".to_string(), Item::File(hash));
+        assert_eq!(
+            overwritten(&untracked, &new),
+            ["build", "docs/todo", "notes"]
+        );
+    }
+
     #[test]
     fn test_imara() {
         use imara_diff::intern::InternedInput;
@@ -1002,4 +3668,156 @@ mod tests {
         let expected = "@@ -1,3 +1,3 @@\n foo\n-bar\n baz\n+bar\n";
         assert_eq!(compute_diff(a, b), Some(expected.to_owned()));
     }
+
+    #[test]
+    fn test_scan_ignore() {
+        use crate::chaos::DefaultStore;
+        use crate::helpers::TestTempDir;
+
+        let tmp = TestTempDir::new();
+        let mut store = DefaultStore::new(tmp.create(&["some_file.store"]));
+        let dir = tmp.makedirs(&["tree", "src", "target"]);
+        tmp.makedirs(&["tree", "logs"]);
+        tmp.makedirs(&["tree", ".tub"]);
+        tmp.makedirs(&["tree", "docs", ".tub"]);
+        tmp.write(&["tree", "main.o"], b"o");
+        tmp.write(&["tree", "keep.o"], b"o");
+        tmp.write(&["tree", "src", "lib.o"], b"o");
+        tmp.write(&["tree", "src", "lib.rs"], b"rs");
+        tmp.write(&["tree", "src", "target", "x"], b"x");
+        tmp.write(&["tree", "logs", "today"], b"log");
+        tmp.write(&["tree", ".tub", "x"], b"x");
+        tmp.write(&["tree", "docs", ".tub", "x"], b"x");
+        tmp.write(
+            &["tree", DOTIGNORE],
+            b"# Build output\n*.o\n!keep.o\n/src/target/\nlogs/**\n",
+        );
+        let dir = dir.parent().unwrap().parent().unwrap();
+
+        let mut tree: DefaultTree = Tree::new(&mut store, dir);
+        assert!(tree.load_ignore().unwrap());
+        assert_eq!(
+            tree.ignore_patterns(),
+            ["*.o", "!keep.o", "/src/target/", "logs/**"]
+        );
+        assert!(tree.is_ignored("a/b.o", false));
+        assert!(!tree.is_ignored("a/keep.o", false));
+        tree.scan_tree().unwrap().unwrap();
+        let mut relpaths: Vec<&String> = tree.flatmap.keys().collect();
+        relpaths.sort();
+        assert_eq!(
+            relpaths,
+            [
+                ".tubignore",
+                "docs",
+                "docs/.tub",
+                "docs/.tub/x",
+                "keep.o",
+                "logs",
+                "src",
+                "src/lib.rs",
+            ]
+        );
+
+        // Nested .tubignore files apply to their subtree, the global file everywhere
+        tmp.makedirs(&["tree", "src", "gen"]);
+        tmp.write(&["tree", "src", "gen", "out.rs"], b"rs");
+        tmp.write(&["tree", "src", "gen", "notes.md"], b"md");
+        tmp.write(&["tree", "src", "gen", "lib.o"], b"o");
+        tmp.write(&["tree", "src", "gen", DOTIGNORE], b"*.rs\n!lib.o\n");
+        tmp.write(&["tree", "logs", DOTIGNORE], b"!today\n");
+        tmp.write(&["global"], b"*.md\n");
+        let global = tmp.build(&["global"]);
+        assert!(tree.load_ignore_with(Some(&global)).unwrap());
+        tree.flatmap.clear();
+        tree.scan_tree().unwrap().unwrap();
+        let mut relpaths: Vec<&String> = tree.flatmap.keys().collect();
+        relpaths.sort();
+        assert_eq!(
+            relpaths,
+            [
+                ".tubignore",
+                "docs",
+                "docs/.tub",
+                "docs/.tub/x",
+                "keep.o",
+                "logs",
+                "logs/today",
+                "src",
+                "src/gen",
+                "src/gen/.tubignore",
+                "src/gen/lib.o",
+                "src/lib.rs",
+            ]
+        );
+        let check = |tree: &mut DefaultTree, relpath: &str| {
+            tree.check_ignore(relpath)
+                .unwrap()
+                .map(|m| (m.source, m.lineno, m.pattern, m.ignored))
+        };
+        assert_eq!(check(&mut tree, "src/lib.rs"), None);
+        assert_eq!(
+            check(&mut tree, "src/gen/out.rs"),
+            Some((
+                "src/gen/.tubignore".to_string(),
+                1,
+                "*.rs".to_string(),
+                true
+            ))
+        );
+        assert_eq!(
+            check(&mut tree, "./src/gen/lib.o"),
+            Some((
+                "src/gen/.tubignore".to_string(),
+                2,
+                "!lib.o".to_string(),
+                false
+            ))
+        );
+        assert_eq!(
+            check(&mut tree, "src/gen/notes.md"),
+            Some((
+                global.to_str().unwrap().to_string(),
+                1,
+                "*.md".to_string(),
+                true
+            ))
+        );
+        // The ignored parent directory decides
+        assert_eq!(
+            check(&mut tree, "src/target/x"),
+            Some((DOTIGNORE.to_string(), 4, "/src/target/".to_string(), true))
+        );
+        assert_eq!(
+            check(&mut tree, "logs/today"),
+            Some((
+                "logs/.tubignore".to_string(),
+                1,
+                "!today".to_string(),
+                false
+            ))
+        );
+        assert_eq!(
+            check(&mut tree, ".tub/x"),
+            Some(("<built-in>".to_string(), 2, "/.tub".to_string(), true))
+        );
+        assert_eq!(
+            check(&mut tree, "a/.git"),
+            Some(("<built-in>".to_string(), 1, ".git".to_string(), true))
+        );
+
+        // Round trip through the file keeps the order
+        assert!(tree.unignore("!keep.o"));
+        assert!(tree.ignore("!src/lib.o".to_string()));
+        tree.save_ignore().unwrap();
+        assert!(tree.load_ignore().unwrap());
+        assert_eq!(
+            tree.ignore_patterns(),
+            ["*.o", "/src/target/", "logs/**", "!src/lib.o"]
+        );
+        assert_eq!(
+            tmp.read(&["tree", DOTIGNORE]),
+            b"# Build output\n*.o\n/src/target/\nlogs/**\n!src/lib.o\n"
+        );
+    }
 }
@@ -0,0 +1,893 @@
+//! `.gitignore` style patterns for `.tubignore` files. 🙈
+//!
+//! Each line is a pattern, and the last pattern that matches a path decides
+//! whether it's ignored:
+//!
+//! | Pattern     | Matches                                               |
+//! |-------------|-------------------------------------------------------|
+//! | `*.o`       | Any path whose last component matches (at any depth)  |
+//! | `/build`    | Only `build` at the top (a leading `/` anchors)       |
+//! | `doc/*.txt` | Relative to the top (any `/` but a trailing one does) |
+//! | `target/`   | Only directories (a trailing `/`)                     |
+//! | `**/tmp`    | `tmp` at any depth                                    |
+//! | `a/**/b`    | `a/b`, `a/x/b`, `a/x/y/b`, ...                        |
+//! | `logs/**`   | Everything inside `logs`                              |
+//! | `!keep.o`   | Un-ignore a path an earlier pattern ignored           |
+//!
+//! Within a path component, `*` matches anything, `?` matches any one
+//! character, and `[a-z]` (or `[!a-z]`) matches a character class.  Blank lines
+//! and lines starting with `#` are skipped, and `\` escapes a special character.
+//!
+//! Patterns are parsed once, and the walk in `dvcs::Tree` never descends into
+//! an ignored directory, so like git, a file can't be un-ignored when one of
+//! its parent directories is ignored.
+//!
+//! Also like git, patterns come in layers (see `IgnoreStack`).  A `.tubignore`
+//! in a subdirectory applies to that subtree (with patterns relative to it) and
+//! beats the ones above it, and the global file (see `global_ignore_path()`)
+//! applies to every tree but loses to all of them.
+
+use crate::base::DOTIGNORE;
+use crate::tub::config_dir;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Always ignored, unless a pattern says otherwise (`.tub` is ignored no matter what).
+pub const BUILTIN_IGNORE: [&str; 2] = [".git", "/.tub"];
+
+/// Shown as the source of the `BUILTIN_IGNORE` patterns.
+pub const BUILTIN_SOURCE: &str = "<built-in>";
+
+#[derive(Debug, PartialEq, Clone)]
+enum Token {
+    Char(char),
+    Any,  // ?
+    Star, // *
+    Class(bool, Vec<(char, char)>),
+}
+
+impl Token {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Self::Char(a) => *a == c,
+            Self::Any => true,
+            Self::Star => false,
+            Self::Class(negate, ranges) => {
+                ranges.iter().any(|(lo, hi)| *lo <= c && c <= *hi) != *negate
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Part {
+    AnyDirs, // **
+    Glob(Vec<Token>),
+}
+
+// Matches a single path component against a glob.
+fn glob_match(tokens: &[Token], text: &str) -> bool {
+    let mut ti = 0;
+    let mut si = 0;
+    let mut star: Option<(usize, usize)> = None;
+    while si < text.len() {
+        let c = text[si..].chars().next().unwrap();
+        match tokens.get(ti) {
+            Some(Token::Star) => {
+                star = Some((ti, si));
+                ti += 1;
+                continue;
+            }
+            Some(token) if token.matches(c) => {
+                ti += 1;
+                si += c.len_utf8();
+                continue;
+            }
+            _ => {}
+        }
+        // Backtrack: let the last star eat one more character
+        match star {
+            Some((sti, ssi)) => {
+                let next = ssi + text[ssi..].chars().next().unwrap().len_utf8();
+                star = Some((sti, next));
+                ti = sti + 1;
+                si = next;
+            }
+            None => return false,
+        }
+    }
+    tokens[ti..].iter().all(|t| *t == Token::Star)
+}
+
+fn parts_match(parts: &[Part], comps: &[&str]) -> bool {
+    match parts.first() {
+        None => comps.is_empty(),
+        Some(Part::AnyDirs) => (0..=comps.len()).any(|i| parts_match(&parts[1..], &comps[i..])),
+        Some(Part::Glob(tokens)) => {
+            !comps.is_empty()
+                && glob_match(tokens, comps[0])
+                && parts_match(&parts[1..], &comps[1..])
+        }
+    }
+}
+
+fn parse_glob(src: &str) -> Vec<Token> {
+    let chars: Vec<char> = src.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if i + 1 < chars.len() => {
+                tokens.push(Token::Char(chars[i + 1]));
+                i += 2;
+                continue;
+            }
+            '*' => {
+                if tokens.last() != Some(&Token::Star) {
+                    tokens.push(Token::Star);
+                }
+            }
+            '?' => tokens.push(Token::Any),
+            '[' => {
+                if let Some((token, end)) = parse_class(&chars, i) {
+                    tokens.push(token);
+                    i = end;
+                    continue;
+                }
+                tokens.push(Token::Char('['));
+            }
+            c => tokens.push(Token::Char(c)),
+        }
+        i += 1;
+    }
+    tokens
+}
+
+// Parses the class starting at `chars[start] == '['`, returning the token and
+// the index just past the closing `]` (or `None` if there isn't one).
+fn parse_class(chars: &[char], start: usize) -> Option<(Token, usize)> {
+    let mut i = start + 1;
+    let negate = matches!(chars.get(i), Some('!') | Some('^'));
+    if negate {
+        i += 1;
+    }
+    let mut ranges = Vec::new();
+    let mut first = true;
+    while i < chars.len() {
+        let mut c = chars[i];
+        if c == ']' && !first {
+            return Some((Token::Class(negate, ranges), i + 1));
+        }
+        first = false;
+        if c == '\\' && i + 1 < chars.len() {
+            i += 1;
+            c = chars[i];
+        }
+        if i + 2 < chars.len() && chars[i + 1] == '-' && chars[i + 2] != ']' {
+            ranges.push((c, chars[i + 2]));
+            i += 3;
+        } else {
+            ranges.push((c, c));
+            i += 1;
+        }
+    }
+    None
+}
+
+/// A single compiled ignore pattern.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Pattern {
+    line: String,
+    negate: bool,
+    dir_only: bool,
+    basename: bool, // No `/`, so only match the last component
+    parts: Vec<Part>,
+    lineno: usize,
+}
+
+impl Pattern {
+    /// Parse one line (`None` for blank lines and comments).
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut src = trim_trailing_spaces(line);
+        if src.is_empty() || src.starts_with('#') {
+            return None;
+        }
+        let negate = src.starts_with('!');
+        if negate || src.starts_with("\\!") || src.starts_with("\\#") {
+            src = &src[1..];
+        }
+        let dir_only = src.ends_with('/') && !src.ends_with("\\/");
+        if dir_only {
+            src = src.trim_end_matches('/');
+        }
+        let anchored = src.contains('/');
+        let src = src.trim_start_matches('/');
+        if src.is_empty() {
+            return None;
+        }
+        let mut parts = Vec::new();
+        for comp in src.split('/').filter(|c| !c.is_empty()) {
+            if comp == "**" {
+                if parts.last() != Some(&Part::AnyDirs) {
+                    parts.push(Part::AnyDirs);
+                }
+            } else {
+                parts.push(Part::Glob(parse_glob(comp)));
+            }
+        }
+        // A trailing `**` matches everything inside, but not the directory itself
+        if parts.last() == Some(&Part::AnyDirs) {
+            parts.push(Part::Glob(vec![Token::Star]));
+        }
+        Some(Self {
+            line: line.to_string(),
+            negate,
+            dir_only,
+            basename: !anchored,
+            parts,
+            lineno: 0,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.line
+    }
+
+    /// Line number in the file it came from (starting at 1).
+    pub fn lineno(&self) -> usize {
+        self.lineno
+    }
+
+    pub fn is_negated(&self) -> bool {
+        self.negate
+    }
+
+    /// Whether the `/` separated components `comps` match (ignoring negation).
+    pub fn matches(&self, comps: &[&str], is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        match (self.basename, comps.last()) {
+            (true, Some(name)) => parts_match(&self.parts, &[name]),
+            (true, None) => false,
+            (false, _) => parts_match(&self.parts, comps),
+        }
+    }
+}
+
+fn trim_trailing_spaces(line: &str) -> &str {
+    let mut end = line.len();
+    while end > 0 && line.as_bytes()[end - 1] == b' ' {
+        if end > 1 && line.as_bytes()[end - 2] == b'\\' {
+            break;
+        }
+        end -= 1;
+    }
+    &line[..end]
+}
+
+/// An ordered list of patterns, like one `.tubignore` file.
+#[derive(Debug, Default, Clone)]
+pub struct IgnoreList {
+    patterns: Vec<Pattern>,
+}
+
+impl IgnoreList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn parse(text: &str) -> Self {
+        let mut list = Self::new();
+        for (i, line) in text.lines().enumerate() {
+            list.add_at(line, i + 1);
+        }
+        list
+    }
+
+    pub fn len(&self) -> usize {
+        self.patterns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.patterns.clear();
+    }
+
+    /// Append the pattern `line` (`false` if it's blank or already the last one).
+    ///
+    /// Duplicates are kept on purpose: the last match wins, so adding `*.o`
+    /// after `!*.o` has to land after it to mean anything.
+    pub fn add(&mut self, line: &str) -> bool {
+        if self.patterns.last().is_some_and(|p| p.line == line) {
+            return false;
+        }
+        let lineno = self.patterns.last().map_or(0, |p| p.lineno) + 1;
+        self.add_at(line, lineno)
+    }
+
+    fn add_at(&mut self, line: &str, lineno: usize) -> bool {
+        match Pattern::parse(line) {
+            Some(mut pattern) => {
+                pattern.lineno = lineno;
+                self.patterns.push(pattern);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn remove(&mut self, line: &str) -> bool {
+        let before = self.patterns.len();
+        self.patterns.retain(|p| p.line != line);
+        self.patterns.len() != before
+    }
+
+    /// The pattern lines, in order.
+    pub fn lines(&self) -> Vec<&str> {
+        self.patterns.iter().map(|p| p.as_str()).collect()
+    }
+
+    /// Rewrite `text` (the file this list was parsed from) to match the list.
+    ///
+    /// Comments, blank lines and surviving patterns stay where they were,
+    /// removed patterns are dropped and new ones go at the end.
+    pub fn rewrite(&self, text: &str) -> String {
+        let mut out = String::new();
+        let mut next = self.patterns.iter().peekable();
+        for line in text.lines() {
+            if Pattern::parse(line).is_some() {
+                if next.peek().is_none_or(|p| p.line != line) {
+                    continue; // Removed
+                }
+                next.next();
+            }
+            out.push_str(line);
+            out.push('\n');
+        }
+        for pattern in next {
+            out.push_str(&pattern.line);
+            out.push('\n');
+        }
+        out
+    }
+
+    /// The last pattern matching `comps`, if any.
+    pub fn last_match(&self, comps: &[&str], is_dir: bool) -> Option<&Pattern> {
+        self.patterns
+            .iter()
+            .rev()
+            .find(|p| p.matches(comps, is_dir))
+    }
+
+    /// Whether the relative path `relpath` is ignored.
+    pub fn is_ignored(&self, relpath: &str, is_dir: bool) -> bool {
+        let comps: Vec<&str> = relpath.split('/').filter(|c| !c.is_empty()).collect();
+        match self.last_match(&comps, is_dir) {
+            Some(pattern) => !pattern.negate,
+            None => false,
+        }
+    }
+}
+
+/// The global ignore file, `$XDG_CONFIG_HOME/tub/ignore` (which defaults to
+/// `~/.config/tub/ignore`).
+pub fn global_ignore_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("ignore"))
+}
+
+/// One layer of patterns and where they came from.
+#[derive(Debug, Default, Clone)]
+pub struct IgnoreFile {
+    source: String,
+    depth: usize, // Number of components in the directory it applies to
+    list: IgnoreList,
+}
+
+impl IgnoreFile {
+    pub fn new(source: &str, depth: usize, list: IgnoreList) -> Self {
+        Self {
+            source: source.to_string(),
+            depth,
+            list,
+        }
+    }
+
+    pub fn builtin() -> Self {
+        Self::new(
+            BUILTIN_SOURCE,
+            0,
+            IgnoreList::parse(&BUILTIN_IGNORE.join("\n")),
+        )
+    }
+
+    /// Load the file at `path` (`Ok(None)` if it doesn't exist).
+    pub fn load(path: &Path, source: &str, depth: usize) -> io::Result<Option<Self>> {
+        match fs::read_to_string(path) {
+            Ok(text) => Ok(Some(Self::new(source, depth, IgnoreList::parse(&text)))),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn list(&self) -> &IgnoreList {
+        &self.list
+    }
+
+    pub fn list_mut(&mut self) -> &mut IgnoreList {
+        &mut self.list
+    }
+
+    fn last_match(&self, comps: &[&str], is_dir: bool) -> Option<&Pattern> {
+        if comps.len() <= self.depth {
+            return None;
+        }
+        self.list.last_match(&comps[self.depth..], is_dir)
+    }
+}
+
+/// The pattern that decided whether a path is ignored (see `tub check-ignore`).
+#[derive(Debug, PartialEq, Clone)]
+pub struct IgnoreMatch {
+    pub source: String,
+    pub lineno: usize,
+    pub pattern: String,
+    pub ignored: bool,
+}
+
+impl IgnoreMatch {
+    fn new(file: &IgnoreFile, pattern: &Pattern) -> Self {
+        Self {
+            source: file.source.clone(),
+            lineno: pattern.lineno,
+            pattern: pattern.line.clone(),
+            ignored: !pattern.negate,
+        }
+    }
+}
+
+/// All the layers of patterns for a tree.
+///
+/// From lowest to highest precedence: the built-in patterns, the global file,
+/// the top `.tubignore`, then nested `.tubignore` files.  The nested ones are
+/// pushed and popped as the walk enters and leaves directories, so they're
+/// always the ancestors of the path being checked.
+#[derive(Debug, Clone)]
+pub struct IgnoreStack {
+    base: Vec<IgnoreFile>,
+    root: IgnoreFile,
+    nested: Vec<IgnoreFile>,
+}
+
+impl Default for IgnoreStack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IgnoreStack {
+    pub fn new() -> Self {
+        Self {
+            base: vec![IgnoreFile::builtin()],
+            root: IgnoreFile::new(DOTIGNORE, 0, IgnoreList::new()),
+            nested: Vec::new(),
+        }
+    }
+
+    pub fn set_global(&mut self, global: Option<IgnoreFile>) {
+        self.base.truncate(1);
+        self.base.extend(global);
+    }
+
+    pub fn set_root(&mut self, root: IgnoreFile) {
+        self.root = root;
+    }
+
+    /// The patterns in the top `.tubignore`.
+    pub fn root(&self) -> &IgnoreList {
+        self.root.list()
+    }
+
+    pub fn root_mut(&mut self) -> &mut IgnoreList {
+        self.root.list_mut()
+    }
+
+    pub fn push(&mut self, file: IgnoreFile) {
+        self.nested.push(file);
+    }
+
+    pub fn pop(&mut self) -> Option<IgnoreFile> {
+        self.nested.pop()
+    }
+
+    pub fn clear_nested(&mut self) {
+        self.nested.clear();
+    }
+
+    fn layers(&self) -> impl Iterator<Item = &IgnoreFile> {
+        self.nested
+            .iter()
+            .rev()
+            .chain(std::iter::once(&self.root))
+            .chain(self.base.iter().rev())
+    }
+
+    /// The deciding pattern for the path components `comps`, if any.
+    pub fn check(&self, comps: &[&str], is_dir: bool) -> Option<IgnoreMatch> {
+        self.layers().find_map(|file| {
+            file.last_match(comps, is_dir)
+                .map(|pattern| IgnoreMatch::new(file, pattern))
+        })
+    }
+
+    /// Like `check()` but only for the built-in patterns.
+    pub fn check_builtin(&self, comps: &[&str], is_dir: bool) -> Option<IgnoreMatch> {
+        let file = &self.base[0];
+        file.last_match(comps, is_dir)
+            .map(|pattern| IgnoreMatch::new(file, pattern))
+    }
+
+    /// Whether the relative path `relpath` is ignored.
+    pub fn is_ignored(&self, relpath: &str, is_dir: bool) -> bool {
+        let comps: Vec<&str> = relpath.split('/').filter(|c| !c.is_empty()).collect();
+        for file in self.layers() {
+            if let Some(pattern) = file.last_match(&comps, is_dir) {
+                return !pattern.negate;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(pattern: &str, cases: &[(&str, bool, bool)]) {
+        let list = IgnoreList::parse(pattern);
+        for (relpath, is_dir, expected) in cases {
+            assert_eq!(
+                list.is_ignored(relpath, *is_dir),
+                *expected,
+                "{:?} {:?} {}",
+                pattern,
+                relpath,
+                is_dir
+            );
+        }
+    }
+
+    #[test]
+    fn test_glob_match() {
+        let cases = [
+            ("*", "", true),
+            ("*", "foo", true),
+            ("*.o", "main.o", true),
+            ("*.o", ".o", true),
+            ("*.o", "main.c", false),
+            ("*.o", "main.o.c", false),
+            ("a*b*c", "abc", true),
+            ("a*b*c", "axxbyyc", true),
+            ("a*b*c", "axxbyy", false),
+            ("?", "é", true),
+            ("??", "é", false),
+            ("f?o", "foo", true),
+            ("[abc]", "b", true),
+            ("[abc]", "d", false),
+            ("[!abc]", "d", true),
+            ("[^abc]", "a", false),
+            ("[a-z]*", "hello", true),
+            ("[a-z]*", "Hello", false),
+            ("[]]", "]", true),
+            ("[a-]", "-", true),
+            ("[", "[", true),
+            ("\\*", "*", true),
+            ("\\*", "x", false),
+            ("foo", "foo", true),
+            ("foo", "foobar", false),
+        ];
+        for (pattern, text, expected) in cases {
+            assert_eq!(
+                glob_match(&parse_glob(pattern), text),
+                expected,
+                "{pattern:?} {text:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        for line in ["", "   ", "#comment", "# spaced", "/", "!", "//"] {
+            assert!(Pattern::parse(line).is_none(), "{line:?}");
+        }
+        let p = Pattern::parse("!target/ ").unwrap();
+        assert!(p.negate && p.dir_only && p.basename);
+        assert_eq!(p.as_str(), "!target/ ");
+        let p = Pattern::parse("\\!important").unwrap();
+        assert!(!p.negate);
+        assert_eq!(p.parts, vec![Part::Glob(parse_glob("!important"))]);
+        let p = Pattern::parse("\\#hash").unwrap();
+        assert_eq!(p.parts, vec![Part::Glob(parse_glob("#hash"))]);
+        let p = Pattern::parse("/a/**/**/b").unwrap();
+        assert!(!p.basename);
+        assert_eq!(p.parts.len(), 3);
+        let p = Pattern::parse("trailing\\ ").unwrap();
+        assert_eq!(p.parts, vec![Part::Glob(parse_glob("trailing\\ "))]);
+    }
+
+    #[test]
+    fn test_basename_patterns() {
+        check(
+            "*.o",
+            &[
+                ("main.o", false, true),
+                ("src/main.o", false, true),
+                ("a/b/c/d.o", false, true),
+                ("objs.o", true, true),
+                ("main.c", false, false),
+                ("main.o/x.c", false, false),
+            ],
+        );
+        check(
+            "node_modules",
+            &[
+                ("node_modules", true, true),
+                ("web/node_modules", true, true),
+                ("web/node_modules_x", true, false),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_dir_only() {
+        check(
+            "target/",
+            &[
+                ("target", true, true),
+                ("sub/target", true, true),
+                ("target", false, false),
+                ("sub/target", false, false),
+            ],
+        );
+        check(
+            "/build/",
+            &[
+                ("build", true, true),
+                ("build", false, false),
+                ("sub/build", true, false),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_anchored() {
+        check(
+            "/TODO",
+            &[("TODO", false, true), ("src/TODO", false, false)],
+        );
+        check(
+            "doc/*.txt",
+            &[
+                ("doc/notes.txt", false, true),
+                ("doc/sub/notes.txt", false, false),
+                ("x/doc/notes.txt", false, false),
+            ],
+        );
+        check(
+            "a/b",
+            &[
+                ("a/b", false, true),
+                ("x/a/b", false, false),
+                ("a", true, false),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_double_star() {
+        check(
+            "**/tmp",
+            &[
+                ("tmp", true, true),
+                ("a/tmp", true, true),
+                ("a/b/tmp", false, true),
+                ("a/tmpx", false, false),
+            ],
+        );
+        check(
+            "a/**/b",
+            &[
+                ("a/b", false, true),
+                ("a/x/b", false, true),
+                ("a/x/y/b", false, true),
+                ("a/x/y/c", false, false),
+                ("x/a/b", false, false),
+            ],
+        );
+        check(
+            "logs/**",
+            &[
+                ("logs", true, false),
+                ("logs/a", false, true),
+                ("logs/a/b", false, true),
+                ("x/logs/a", false, false),
+            ],
+        );
+        check(
+            "**/*.rs",
+            &[("main.rs", false, true), ("src/bin/main.rs", false, true)],
+        );
+    }
+
+    #[test]
+    fn test_negation() {
+        let text = "# Build output\n*.o\n!keep.o\n\nlogs/\n!logs/\n";
+        check(
+            text,
+            &[
+                ("main.o", false, true),
+                ("keep.o", false, false),
+                ("src/keep.o", false, false),
+                ("logs", true, false),
+            ],
+        );
+        // Order matters, the last match wins
+        check(
+            "!keep.o\n*.o",
+            &[("keep.o", false, true), ("main.o", false, true)],
+        );
+    }
+
+    #[test]
+    fn test_ignore_list() {
+        let mut list = IgnoreList::new();
+        assert!(list.is_empty());
+        assert!(list.add("*.o"));
+        assert!(!list.add("*.o"));
+        assert!(!list.add("# comment"));
+        assert!(!list.add(""));
+        assert!(list.add("!keep.o"));
+        assert_eq!(list.len(), 2);
+        assert_eq!(list.lines(), ["*.o", "!keep.o"]);
+        assert!(list.is_ignored("a.o", false));
+        assert!(!list.is_ignored("keep.o", false));
+        let comps = ["src", "keep.o"];
+        assert_eq!(list.last_match(&comps, false).unwrap().as_str(), "!keep.o");
+        assert!(list.last_match(&["a.c"], false).is_none());
+        assert!(list.remove("!keep.o"));
+        assert!(!list.remove("!keep.o"));
+        assert!(list.is_ignored("keep.o", false));
+        list.clear();
+        assert!(!list.is_ignored("keep.o", false));
+
+        // Duplicates stay, and the last one wins
+        let mut list = IgnoreList::parse("*.o\n!*.o\n*.o\n");
+        assert_eq!(list.lines(), ["*.o", "!*.o", "*.o"]);
+        assert!(list.is_ignored("a.o", false));
+        assert!(list.add("!*.o"));
+        assert!(!list.is_ignored("a.o", false));
+        assert_eq!(list.len(), 4);
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let text = "# Build output\n*.o\n\n# Logs\n*.log\ntmp/\n";
+        let mut list = IgnoreList::parse(text);
+        assert_eq!(list.rewrite(text), text);
+        assert!(list.remove("*.log"));
+        assert!(list.add("*.tmp"));
+        assert_eq!(
+            list.rewrite(text),
+            "# Build output\n*.o\n\n# Logs\ntmp/\n*.tmp\n"
+        );
+        assert_eq!(list.rewrite(""), "*.o\ntmp/\n*.tmp\n");
+        let text = "a\n!a\na\n";
+        let mut list = IgnoreList::parse(text);
+        assert!(list.remove("!a"));
+        assert_eq!(list.rewrite(text), "a\na\n");
+    }
+
+    #[test]
+    fn test_lineno() {
+        let mut list = IgnoreList::parse("# Comment\n\n*.o\n\n!keep.o\n");
+        let linenos: Vec<usize> = list.patterns.iter().map(|p| p.lineno()).collect();
+        assert_eq!(linenos, [3, 5]);
+        assert!(list.add("tmp/"));
+        assert_eq!(list.last_match(&["tmp"], true).unwrap().lineno(), 6);
+    }
+
+    #[test]
+    fn test_global_ignore_path() {
+        // Only check the shape, the environment belongs to whoever runs the tests
+        if let Some(path) = global_ignore_path() {
+            assert!(path.ends_with("tub/ignore"));
+        }
+    }
+
+    #[test]
+    fn test_ignore_file() {
+        let tmp = crate::helpers::TestTempDir::new();
+        assert!(
+            IgnoreFile::load(&tmp.build(&["nope"]), "nope", 0)
+                .unwrap()
+                .is_none()
+        );
+        tmp.write(&["ignore"], b"*.o\n");
+        let file = IgnoreFile::load(&tmp.build(&["ignore"]), "x/.tubignore", 1)
+            .unwrap()
+            .unwrap();
+        assert_eq!(file.source(), "x/.tubignore");
+        assert_eq!(file.list().lines(), ["*.o"]);
+        assert!(file.last_match(&["x", "a.o"], false).is_some());
+        assert!(file.last_match(&["a.o"], false).is_none());
+        tmp.mkdir(&["dir"]);
+        assert!(IgnoreFile::load(&tmp.build(&["dir"]), "dir", 0).is_err());
+    }
+
+    #[test]
+    fn test_ignore_stack() {
+        let mut stack = IgnoreStack::new();
+        assert!(stack.is_ignored(".git", true));
+        assert!(stack.is_ignored("sub/.git", false));
+        assert!(stack.is_ignored(".tub", true));
+        assert!(!stack.is_ignored("sub/.tub", true));
+        assert!(!stack.is_ignored("main.o", false));
+
+        stack.set_global(Some(IgnoreFile::new(
+            "global",
+            0,
+            IgnoreList::parse("*.o\n*.log\n"),
+        )));
+        stack.root_mut().add("!keep.o");
+        stack.root_mut().add("!.git");
+        assert_eq!(stack.root().lines(), ["!keep.o", "!.git"]);
+        assert!(stack.is_ignored("main.o", false));
+        assert!(!stack.is_ignored("keep.o", false));
+        assert!(!stack.is_ignored(".git", true));
+        assert_eq!(
+            stack.check(&["a", "b.log"], false),
+            Some(IgnoreMatch {
+                source: "global".to_string(),
+                lineno: 2,
+                pattern: "*.log".to_string(),
+                ignored: true,
+            })
+        );
+        assert_eq!(
+            stack.check_builtin(&[".git"], true).unwrap().source,
+            BUILTIN_SOURCE
+        );
+
+        // Nested layers beat the ones above them, relative to their directory
+        stack.push(IgnoreFile::new(
+            "a/.tubignore",
+            1,
+            IgnoreList::parse("!*.log\n/keep.o\n"),
+        ));
+        assert!(!stack.is_ignored("a/b.log", false));
+        assert!(stack.is_ignored("a/keep.o", false));
+        assert!(!stack.is_ignored("a/b/keep.o", false));
+        assert!(stack.is_ignored("b.log", false));
+        assert_eq!(
+            stack.check(&["a", "keep.o"], false).unwrap().source,
+            "a/.tubignore"
+        );
+        assert!(stack.pop().is_some());
+        assert!(stack.is_ignored("a/b.log", false));
+        stack.push(IgnoreFile::new("a/.tubignore", 1, IgnoreList::new()));
+        stack.clear_nested();
+        assert!(stack.pop().is_none());
+
+        stack.set_global(None);
+        assert!(!stack.is_ignored("main.o", false));
+        assert!(stack.check(&["main.c"], false).is_none());
+    }
+}
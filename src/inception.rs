@@ -5,13 +5,20 @@
 //! level operations are very deliberately kept out of `chaos`.
 
 use crate::base::*;
-use crate::chaos::{Name, Object, Store};
+use crate::chaos::{Codec, Name, Object, ObjectReader, Store};
+use crate::chunker::{ChunkReader, Chunker, Chunking};
+use crate::cipher::{Cipher, Keys, Secret, TAG_LEN};
+use crate::delta::{DeltaKind, MAX_DELTA_DEPTH, MIN_DELTA_SIZE, apply_kind, encode_kind, is_text};
+use crate::dictionary::{Dictionary, is_sample};
+use crate::merkle::MerkleBuilder;
 use crate::protocol::Hasher;
+use rayon::prelude::*;
 use std::collections::HashMap;
 use std::io::prelude::*;
 use std::marker::PhantomData;
+use std::os::unix::fs::FileExt;
 use std::slice::Iter;
-use std::{cmp, fs, io};
+use std::{cmp, fs, io, mem};
 use zstd;
 
 /*
@@ -27,8 +34,26 @@ We'll use three bytes to specify the encoding:
 
     | Delta Byte | Compress Byte | Encrypt Byte |
 
-A value of 0 in a field means do nothing (pass through).  A Delta byte of 1
-means general delta, 2 means document delta, and so on.
+A value of 0 in a field means do nothing (pass through).  A Compress byte of 1
+means zstd, 2 means zstd with a trained dictionary (see `dictionary`).  The
+Delta byte (`delta::DeltaKind`):
+
+    | Byte | Meaning                                         |
+    |------|-------------------------------------------------|
+    |    0 | Pass through (a stream of objects, `Encoder`)   |
+    |    1 | General delta                                   |
+    |    2 | Document delta                                  |
+    |    3 | Reserved for the next delta kind                |
+    |    4 | Whole (one object, not a delta)                 |
+    |   5+ | Reserved for more delta kinds                   |
+
+A whole container isn't 0 even though nothing is delta compressed, because 0
+was already taken by object stream containers, and nothing after the Encoding
+tells the two apart (a whole container has its Target there, a stream container
+goes right into compressed data).  `chaos::Codec.held()` only has the Delta
+byte to go on, so the two need different bytes.  New delta kinds take 3 and
+then 5 and up, never 4, and older code reads a byte it doesn't know as
+`DeltaKind::Unknown` (not a container it can open).
 
 We'll have at least two types of delta compression: "general" (basically what
 Git does) and "document" (a special high performance content aware delta format
@@ -61,31 +86,94 @@ can test quickly with Store.load()), then we need lookup in this tree.  We'll be
 aggressively iterating on these details for a while, so hold on, partner!
 */
 
-/*
-We should probably define a trait for a common object stream interface, one we
-use whether the steam is being decoded out of a container object, read out of
-a file, or recv'd over a socket.  And have the same for the write direction.
-*/
+pub const ENCODING_LEN: usize = 3;
 
-pub trait Stream<R: Read, H: Hasher, const N: usize> {
-    fn new(inner: R) -> Self;
-    fn send(&mut self, obj: &Object<H, N>) -> io::Result<()>;
-    fn recv(&mut self, obj: &mut Object<H, N>) -> io::Result<()>;
+/// The `| Delta Byte | Compress Byte | Encrypt Byte |` container header.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Encoding {
+    pub delta: u8,
+    pub compress: u8,
+    pub encrypt: u8,
 }
 
-#[derive(Debug)]
-pub struct Container<H: Hasher, const N: usize> {
-    inner: Object<H, N>,
+impl Encoding {
+    pub fn new(delta: u8, compress: u8, encrypt: u8) -> Self {
+        Self {
+            delta,
+            compress,
+            encrypt,
+        }
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        Self::new(buf[0], buf[1], buf[2])
+    }
+
+    pub fn to_bytes(&self) -> [u8; ENCODING_LEN] {
+        [self.delta, self.compress, self.encrypt]
+    }
+
+    pub fn cipher(&self) -> Cipher {
+        self.encrypt.into()
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compress.into()
+    }
 }
 
-impl<H: Hasher, const N: usize> Container<H, N> {
-    pub fn new(inner: Object<H, N>) -> Self {
-        Self { inner }
+/// The Compress byte.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Compression {
+    None,
+    Zstd,
+    ZstdDict,
+    Unknown,
+}
+
+impl From<u8> for Compression {
+    fn from(item: u8) -> Self {
+        match item {
+            0 => Self::None,
+            1 => Self::Zstd,
+            2 => Self::ZstdDict,
+            _ => Self::Unknown,
+        }
     }
+}
 
-    pub fn has_space(&self, obj: &Object<H, N>) -> bool {
-        obj.len() < self.inner.remaining()
+/// A common object stream interface.
+///
+/// We use the same interface whether the stream is read out of a file (see
+/// `FileStream`), decoded out of a container object (see `Container`), or
+/// recv'd over a socket (see `SocketStream`).  Same for the write direction.
+pub trait Stream<I, H: Hasher, const N: usize> {
+    fn new(inner: I) -> Self;
+
+    /// Write `obj` to the stream.
+    fn send(&mut self, obj: &Object<H, N>) -> io::Result<()>;
+
+    /// Read the next object into `obj`, returns `false` at the end of the stream.
+    fn recv(&mut self, obj: &mut Object<H, N>) -> io::Result<bool>;
+}
+
+/// Copy every object from `src` to `dst`, returns the number copied.
+pub fn pipe<I, J, S, D, H, const N: usize>(
+    src: &mut S,
+    dst: &mut D,
+    obj: &mut Object<H, N>,
+) -> io::Result<u64>
+where
+    S: Stream<I, H, N>,
+    D: Stream<J, H, N>,
+    H: Hasher,
+{
+    let mut count = 0;
+    while src.recv(obj)? {
+        dst.send(obj)?;
+        count += 1;
     }
+    Ok(count)
 }
 
 // Wrapper around Object, implements Read trait to read from Object data.
@@ -100,6 +188,12 @@ impl<H: Hasher, const N: usize> ReadFrom<H, N> {
         Self { obj, pos: 0 }
     }
 
+    // Start reading at `pos` bytes into the object data (skipping a header).
+    pub fn new_at(obj: Object<H, N>, pos: usize) -> Self {
+        assert!(pos <= obj.as_data().len());
+        Self { obj, pos }
+    }
+
     pub fn into_inner(self) -> Object<H, N> {
         self.obj
     }
@@ -127,11 +221,17 @@ impl<H: Hasher, const N: usize> io::Read for ReadFrom<H, N> {
 #[derive(Debug)]
 pub struct WriteTo<H: Hasher, const N: usize> {
     obj: Object<H, N>,
+    reserve: usize,
 }
 
 impl<H: Hasher, const N: usize> WriteTo<H, N> {
     pub fn new(obj: Object<H, N>) -> Self {
-        Self { obj }
+        Self { obj, reserve: 0 }
+    }
+
+    // Leave `reserve` bytes free at the end (eg, for an AEAD tag).
+    pub fn with_reserve(obj: Object<H, N>, reserve: usize) -> Self {
+        Self { obj, reserve }
     }
 
     pub fn into_inner(self) -> Object<H, N> {
@@ -141,7 +241,8 @@ impl<H: Hasher, const N: usize> WriteTo<H, N> {
 
 impl<H: Hasher, const N: usize> io::Write for WriteTo<H, N> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let remaining = cmp::min(buf.len(), self.obj.remaining());
+        let available = self.obj.remaining().saturating_sub(self.reserve);
+        let remaining = cmp::min(buf.len(), available);
         if remaining > 0 {
             self.obj.as_mut_vec().extend_from_slice(&buf[0..remaining]);
             Ok(remaining)
@@ -180,6 +281,14 @@ impl<const N: usize> LocationMap<N> {
         self.map.get(key)
     }
 
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
     pub fn deserialize(&mut self, buf: &[u8]) {
         assert!(!buf.is_empty());
         assert!(buf.len() % (N + N) == 0);
@@ -285,26 +394,97 @@ impl<H: Hasher, const N: usize> Fanout<H, N> {
 /// (objects will compress much better back to back in the same compression
 /// stream).  It also means we can write a commit with a single call to
 /// `Store.save()`.
+///
+/// When given `Keys`, the compressed stream is then encrypted in memory, so
+/// the plaintext never touches disk.  The container is still an ordinary
+/// object whose hash covers the ciphertext, so it can be verified (and synced)
+/// without the key.  The container data looks like this:
+///
+/// | Encoding | Dictionary | Nonce    | Compressed (maybe encrypted) stream | Tag   |
+/// |----------|------------|----------|-------------------------------------|-------|
+/// |        3 |       0, N | 0, 12, 24|                                     | 0, 16 |
+///
+/// When compressed with a trained `Dictionary`, its `Name` follows the
+/// Encoding (and like the Encoding, is authenticated when encrypted).
 pub struct Encoder<H: Hasher, const N: usize> {
     phantom: PhantomData<H>,
     inner: zstd::Encoder<'static, WriteTo<H, N>>,
+    keys: Option<Keys>,
+    header: usize,
 }
 
 impl<H: Hasher, const N: usize> Encoder<H, N> {
-    fn new(dst: Object<H, N>, level: i32) -> io::Result<Self> {
+    pub fn new(dst: Object<H, N>, level: i32) -> io::Result<Self> {
+        Self::build(dst, level, None, None)
+    }
+
+    pub fn new_encrypted(dst: Object<H, N>, level: i32, keys: Keys) -> io::Result<Self> {
+        Self::build(dst, level, None, Some(keys))
+    }
+
+    pub fn with_dictionary(
+        dst: Object<H, N>,
+        level: i32,
+        dict: &Dictionary<N>,
+        keys: Option<Keys>,
+    ) -> io::Result<Self> {
+        Self::build(dst, level, Some(dict), keys)
+    }
+
+    fn build(
+        mut dst: Object<H, N>,
+        level: i32,
+        dict: Option<&Dictionary<N>>,
+        keys: Option<Keys>,
+    ) -> io::Result<Self> {
+        let cipher = match &keys {
+            Some(keys) => keys.cipher(),
+            None => Cipher::None,
+        };
+        let compress = match dict {
+            Some(_) => Compression::ZstdDict,
+            None => Compression::Zstd,
+        };
+        dst.clear();
+        dst.extend(&Encoding::new(0, compress as u8, cipher as u8).to_bytes());
+        if let Some(dict) = dict {
+            dst.extend(dict.hash().as_buf());
+        }
+        let header = dst.as_data().len();
+        dst.extend(&vec![0; cipher.nonce_len()]);
+        let wto = WriteTo::with_reserve(dst, cipher.tag_len());
+        let inner = match dict {
+            Some(dict) => zstd::Encoder::with_dictionary(wto, level, dict.as_buf())?,
+            None => zstd::Encoder::new(wto, level)?,
+        };
         Ok(Self {
             phantom: PhantomData,
-            inner: zstd::Encoder::new(WriteTo::new(dst), level)?,
+            inner,
+            keys,
+            header,
         })
     }
 
-    fn write_next(&mut self, obj: &Object<H, N>) -> io::Result<bool> {
+    /// Bytes still free in the container (before compression is flushed).
+    pub fn remaining(&self) -> usize {
+        let wto = self.inner.get_ref();
+        wto.obj.remaining().saturating_sub(wto.reserve)
+    }
+
+    pub fn write_next(&mut self, obj: &Object<H, N>) -> io::Result<bool> {
         self.inner.write_all(obj.as_buf())?;
         Ok(true) // FIXME
     }
 
-    fn finish(self) -> io::Result<Object<H, N>> {
+    pub fn finish(self) -> io::Result<Object<H, N>> {
         let mut obj = self.inner.finish()?.into_inner();
+        if let Some(keys) = self.keys {
+            let nonce_len = keys.cipher().nonce_len();
+            let (header, rest) = obj.as_mut_data().split_at_mut(self.header);
+            let (nonce, body) = rest.split_at_mut(nonce_len);
+            let tag = keys.encrypt(header, nonce, body)?;
+            obj.extend(&tag);
+        }
         obj.finalize_with_kind(ObjKind::Stream as u8); // FIXME: How to handle kind?
         Ok(obj)
     }
@@ -317,9 +497,112 @@ pub struct Decoder<H: Hasher, const N: usize> {
 
 impl<H: Hasher, const N: usize> Decoder<H, N> {
     pub fn new(src: Object<H, N>) -> io::Result<Self> {
+        Self::build(src, None, None)
+    }
+
+    pub fn new_encrypted(src: Object<H, N>, keys: &Keys) -> io::Result<Self> {
+        Self::build(src, None, Some(keys))
+    }
+
+    /// Decode a container compressed with `dict` (see `Decoder::dictionary()`).
+    pub fn with_dictionary(
+        src: Object<H, N>,
+        dict: &Dictionary<N>,
+        keys: Option<&Keys>,
+    ) -> io::Result<Self> {
+        Self::build(src, Some(dict), keys)
+    }
+
+    /// The `Name` of the dictionary needed to decode the container `src`.
+    pub fn dictionary(src: &Object<H, N>) -> Option<Name<N>> {
+        let data = src.as_data();
+        if data.len() >= ENCODING_LEN + N
+            && Encoding::from_bytes(data).compression() == Compression::ZstdDict
+        {
+            Some(Name::from(&data[ENCODING_LEN..ENCODING_LEN + N]))
+        } else {
+            None
+        }
+    }
+
+    fn build(
+        mut src: Object<H, N>,
+        dict: Option<&Dictionary<N>>,
+        keys: Option<&Keys>,
+    ) -> io::Result<Self> {
+        if src.as_data().len() < ENCODING_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "container too short",
+            ));
+        }
+        let encoding = Encoding::from_bytes(src.as_data());
+        let header = match encoding.compression() {
+            Compression::Zstd if encoding.delta == 0 => ENCODING_LEN,
+            Compression::ZstdDict if encoding.delta == 0 => ENCODING_LEN + N,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "unsupported container encoding",
+                ));
+            }
+        };
+        if src.as_data().len() < header {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "container too short",
+            ));
+        }
+        let dict = match (Self::dictionary(&src), dict) {
+            (None, _) => None,
+            (Some(hash), Some(dict)) if &hash == dict.hash() => Some(dict),
+            (Some(hash), _) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("need dictionary {} to decode container", hash),
+                ));
+            }
+        };
+        let cipher = encoding.cipher();
+        let start = match (cipher, keys) {
+            (Cipher::None, _) => header,
+            (Cipher::Unknown, _) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "unsupported container cipher",
+                ));
+            }
+            (_, Some(keys)) if keys.cipher() == cipher => {
+                let size = src.as_data().len();
+                if size < header + cipher.overhead() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "container too short",
+                    ));
+                }
+                let (ad, rest) = src.as_mut_data().split_at_mut(header);
+                let (nonce, rest) = rest.split_at_mut(cipher.nonce_len());
+                let (body, tag) = rest.split_at_mut(rest.len() - TAG_LEN);
+                keys.decrypt(ad, nonce, body, tag)?;
+                let len = src.len() - TAG_LEN;
+                src.as_mut_vec().truncate(len);
+                header + cipher.nonce_len()
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "need key to decrypt container",
+                ));
+            }
+        };
+        let rfo = io::BufReader::new(ReadFrom::new_at(src, start));
+        let inner = match dict {
+            Some(dict) => zstd::Decoder::with_dictionary(rfo, dict.as_buf())?,
+            None => zstd::Decoder::with_buffer(rfo)?,
+        };
         Ok(Self {
             phantom: PhantomData,
-            inner: zstd::Decoder::new(ReadFrom::new(src))?,
+            inner,
         })
     }
 
@@ -338,244 +621,2609 @@ impl<H: Hasher, const N: usize> Decoder<H, N> {
     }
 }
 
-#[derive(Debug, Default)]
-pub struct LeafHashes<const N: usize> {
-    total: u64,
-    hashes: Vec<Name<N>>,
+/// An object stream in a file (like a `Store` file).
+///
+/// Objects are read through a buffer with `ObjectReader`, but written straight
+/// to the file, so open it for append when writing.
+pub struct FileStream<H: Hasher, const N: usize> {
+    phantom: PhantomData<H>,
+    inner: io::BufReader<fs::File>,
 }
 
-impl<const N: usize> LeafHashes<N> {
-    pub fn new() -> Self {
+impl<H: Hasher, const N: usize> FileStream<H, N> {
+    pub fn into_inner(self) -> fs::File {
+        self.inner.into_inner()
+    }
+}
+
+impl<H: Hasher, const N: usize> Stream<fs::File, H, N> for FileStream<H, N> {
+    fn new(inner: fs::File) -> Self {
         Self {
-            total: 0,
-            hashes: Vec::new(),
+            phantom: PhantomData,
+            inner: io::BufReader::new(inner),
         }
     }
 
-    pub fn iter(&self) -> Iter<Name<N>> {
-        self.hashes.iter()
+    fn send(&mut self, obj: &Object<H, N>) -> io::Result<()> {
+        self.inner.get_mut().write_all(obj.as_buf())
     }
 
-    pub fn append_leaf(&mut self, hash: Name<N>, size: usize) {
-        self.hashes.push(hash);
-        self.total += size as u64;
+    fn recv(&mut self, obj: &mut Object<H, N>) -> io::Result<bool> {
+        ObjectReader::new(&mut self.inner).read_next(obj)
     }
+}
 
-    pub fn serialize(&self, buf: &mut Vec<u8>) {
-        buf.extend_from_slice(&self.total.to_le_bytes());
-        for hash in self.hashes.iter() {
-            buf.extend_from_slice(hash.as_buf());
-        }
-    }
+/// An object stream over any `Read + Write` byte stream, eg a `UnixStream`.
+///
+/// Unlike `FileStream`, the other end isn't trusted, so `recv()` returns an
+/// error for an invalid object rather than panicking.  And a stream that ends
+/// in the middle of an object is an error too.
+pub struct SocketStream<S: Read + Write, H: Hasher, const N: usize> {
+    phantom: PhantomData<H>,
+    inner: S,
+}
 
-    pub fn deserialize(buf: &[u8]) -> Self {
-        let total = u64::from_le_bytes(buf[0..8].try_into().expect("oops"));
-        let mut hashes: Vec<Name<N>> = Vec::new();
-        let mut offset = 8;
-        while offset < buf.len() {
-            let hash = Name::from(&buf[offset..offset + N]);
-            hashes.push(hash);
-            offset += N;
-        }
-        assert_eq!(offset, buf.len());
-        Self { total, hashes }
+impl<S: Read + Write, H: Hasher, const N: usize> SocketStream<S, H, N> {
+    pub fn into_inner(self) -> S {
+        self.inner
     }
 }
 
-pub fn hash_file<H: Hasher, const N: usize>(
-    obj: &mut Object<H, N>,
-    mut file: fs::File,
-    size: u64,
-) -> io::Result<Name<N>> {
-    if size == 0 {
-        panic!("No good, yo, your size is ZERO!");
-    }
-    if size > OBJECT_MAX_SIZE as u64 {
-        let mut leaves = LeafHashes::<N>::new();
-        let mut remaining = size;
-        while remaining > 0 {
-            let s = cmp::min(remaining, OBJECT_MAX_SIZE as u64);
-            remaining -= s;
-            obj.reset(s as usize, ObjKind::Data as u8);
-            file.read_exact(obj.as_mut_data())?;
-            leaves.append_leaf(obj.finalize(), obj.info().size());
+impl<S: Read + Write, H: Hasher, const N: usize> Stream<S, H, N> for SocketStream<S, H, N> {
+    fn new(inner: S) -> Self {
+        Self {
+            phantom: PhantomData,
+            inner,
         }
-        obj.clear();
-        leaves.serialize(obj.as_mut_vec());
-        Ok(obj.finalize_with_kind(ObjKind::BigData as u8))
-    } else {
-        obj.reset(size as usize, ObjKind::Data as u8);
-        file.read_exact(obj.as_mut_data())?;
-        Ok(obj.finalize())
     }
-}
 
-pub fn import_file<H: Hasher, const N: usize>(
-    store: &mut Store<H, N>,
-    obj: &mut Object<H, N>,
-    mut file: fs::File,
-    size: u64,
-) -> io::Result<Name<N>> {
-    if size == 0 {
-        panic!("No good, yo, your size is ZERO!");
-    }
-    if size > OBJECT_MAX_SIZE as u64 {
-        let mut leaves = LeafHashes::<N>::new();
-        let mut remaining = size;
-        while remaining > 0 {
-            let s = cmp::min(remaining, OBJECT_MAX_SIZE as u64);
-            remaining -= s;
-            obj.reset(s as usize, ObjKind::Data as u8);
-            file.read_exact(obj.as_mut_data())?;
-            leaves.append_leaf(obj.finalize(), obj.info().size());
-            store.save(obj)?;
-        }
-        obj.clear();
-        leaves.serialize(obj.as_mut_vec());
-        let root = obj.finalize_with_kind(ObjKind::BigData as u8);
-        store.save(obj)?;
-        Ok(root)
-    } else {
-        obj.reset(size as usize, ObjKind::Data as u8);
-        file.read_exact(obj.as_mut_data())?;
-        let hash = obj.finalize();
-        store.save(obj)?;
-        Ok(hash)
+    fn send(&mut self, obj: &Object<H, N>) -> io::Result<()> {
+        self.inner.write_all(obj.as_buf())?;
+        self.inner.flush()
     }
-}
 
-pub fn restore_file<H: Hasher, const N: usize>(
-    store: &mut Store<H, N>,
-    obj: &mut Object<H, N>,
-    file: &mut fs::File,
-    root: &Name<N>,
-) -> io::Result<bool> {
-    if store.load(root, obj)? {
-        let kind = obj.kind();
-        //assert_eq!(kind as u8, obj.info().kind());
-        match obj.kind() {
-            ObjKind::Data => {
-                file.write_all(obj.as_data())?;
-            }
-            ObjKind::BigData => {
-                let hashes = LeafHashes::<N>::deserialize(obj.as_data());
-                for hash in hashes.iter() {
-                    if store.load(hash, obj)? {
-                        file.write_all(obj.as_data())?;
-                    } else {
-                        panic!("Cannot find {} leaf {}", root, hash);
-                    }
-                }
-            }
-            _ => {
-                panic!("No good, yo, no good at all! 😵‍💫 {:?}", kind);
+    fn recv(&mut self, obj: &mut Object<H, N>) -> io::Result<bool> {
+        obj.clear();
+        let header = obj.as_mut_header();
+        let mut filled = 0;
+        while filled < header.len() {
+            match self.inner.read(&mut header[filled..]) {
+                Ok(0) if filled == 0 => return Ok(false),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
             }
         }
+        obj.resize_to_info();
+        self.inner.read_exact(obj.as_mut_data())?;
+        if !obj.is_valid() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid object {}", obj.hash()),
+            ));
+        }
         Ok(true)
-    } else {
-        Ok(false)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::chaos::{DefaultName, DefaultObject, DefaultStore};
-    use crate::helpers::TestTempDir;
-    use crate::protocol::Blake3;
-    use getrandom;
+/// An object stream compressed (and maybe encrypted) into a container object.
+///
+/// Wrap an empty object and `send()` to build a new container (then call
+/// `finish()`), or wrap an existing container and `recv()` from it.
+pub struct Container<H: Hasher, const N: usize> {
+    inner: Option<Object<H, N>>,
+    enc: Option<Encoder<H, N>>,
+    dec: Option<Decoder<H, N>>,
+}
 
-    #[test]
-    fn test_fanout() {
-        let tmp = TestTempDir::new();
-        let file = tmp.create(&["some_file.store"]);
-        let store = DefaultStore::new(file);
-        let obj = DefaultObject::new();
-        let mut fanout = Fanout::new(store, obj);
-        let mut hash = DefaultName::new();
-        let mut cont = DefaultName::new();
-        for _ in 0..1024 {
-            getrandom::fill(hash.as_mut_buf()).unwrap();
-            assert!(fanout.get(&hash).unwrap().is_none());
-            getrandom::fill(cont.as_mut_buf()).unwrap();
-            fanout.insert(hash.clone(), cont.clone()).unwrap();
-            assert_eq!(fanout.get(&hash).unwrap().unwrap(), cont);
-        }
-        let (store, _obj) = fanout.into_inners();
-        assert_eq!(store.len(), 1024);
+impl<H: Hasher, const N: usize> Container<H, N> {
+    /// Whether `obj` can still be sent without overflowing the container.
+    ///
+    /// This assumes `obj` won't compress at all, so it's conservative.
+    pub fn has_space(&self, obj: &Object<H, N>) -> bool {
+        let remaining = match (&self.inner, &self.enc) {
+            (Some(inner), _) => inner.remaining(),
+            (_, Some(enc)) => enc.remaining(),
+            _ => 0,
+        };
+        obj.len() < remaining
     }
 
-    #[test]
-    fn test_rfo_empty() {
-        let obj = DefaultObject::new();
-        let mut rfo = ReadFrom::new(obj);
-        let mut buf = [0; 69];
-        assert_eq!(rfo.read(&mut buf).unwrap(), 0);
-        assert_eq!(buf, [0; 69]);
+    /// Finish writing the container and return it.
+    ///
+    /// A container that was never sent to is returned unchanged.
+    pub fn finish(self) -> io::Result<Object<H, N>> {
+        match (self.inner, self.enc) {
+            (Some(inner), _) => Ok(inner),
+            (_, Some(enc)) => enc.finish(),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "container was opened for reading",
+            )),
+        }
     }
+}
 
-    #[test]
-    fn test_rfo_42() {
-        let mut buf = [0; 69];
-        let mut obj = DefaultObject::new();
-        let mut data = [0; 42];
-        getrandom::fill(&mut data).unwrap();
-        obj.as_mut_vec().extend_from_slice(&data);
-        let mut rfo = ReadFrom::new(obj);
-        assert_eq!(rfo.read(&mut buf).unwrap(), 42);
-        assert_eq!(buf[0..42], data);
-        assert_eq!(buf[42..69], [0; 27]);
-        buf.fill(0);
-        assert_eq!(rfo.read(&mut buf).unwrap(), 0);
-        assert_eq!(buf, [0; 69]);
+impl<H: Hasher, const N: usize> Stream<Object<H, N>, H, N> for Container<H, N> {
+    fn new(inner: Object<H, N>) -> Self {
+        Self {
+            inner: Some(inner),
+            enc: None,
+            dec: None,
+        }
     }
 
-    #[test]
-    fn test_rfo_max() {
-        let mut obj = DefaultObject::new();
-        obj.reset(OBJECT_MAX_SIZE, 0);
-        getrandom::fill(obj.as_mut_data()).unwrap();
-        let mut rfo = ReadFrom::new(obj);
-        let mut buf = [0; 69];
-        let mut output = Vec::new();
-        while let Ok(s) = rfo.read(&mut buf) {
-            if s == 0 {
-                break;
+    fn send(&mut self, obj: &Object<H, N>) -> io::Result<()> {
+        if self.enc.is_none() {
+            match self.inner.take() {
+                Some(inner) => self.enc = Some(Encoder::new(inner, 0)?),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "container was opened for reading",
+                    ));
+                }
             }
-            output.extend_from_slice(&buf[0..s]);
         }
-        buf.fill(0);
-        assert_eq!(rfo.read(&mut buf).unwrap(), 0);
-        assert_eq!(buf, [0; 69]);
-        let obj = rfo.into_inner();
-        assert_eq!(&output, obj.as_data());
+        self.enc.as_mut().unwrap().write_next(obj)?;
+        Ok(())
     }
 
-    #[test]
-    fn test_wto_till_full() {
-        let obj = DefaultObject::new();
-        let mut wto = WriteTo::new(obj);
-        let mut buf = [0; 69];
-        getrandom::fill(&mut buf).unwrap();
-        let mut expected = Vec::new();
-        while let Ok(s) = wto.write(&buf) {
-            if s == 0 {
-                break;
+    fn recv(&mut self, obj: &mut Object<H, N>) -> io::Result<bool> {
+        if self.dec.is_none() {
+            match self.inner.take() {
+                Some(inner) => self.dec = Some(Decoder::new(inner)?),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "container was opened for writing",
+                    ));
+                }
             }
-            expected.extend_from_slice(&buf[0..s]);
-            getrandom::fill(&mut buf).unwrap();
         }
-        assert_eq!(expected.len(), OBJECT_MAX_SIZE);
-        let obj = wto.into_inner();
-        assert_eq!(obj.as_data(), &expected[..]);
+        self.dec.as_mut().unwrap().read_next(obj)
     }
+}
 
-    #[test]
-    fn test_zstd_roundtrip() {
-        let dst = DefaultObject::new();
-        let wto = WriteTo::new(dst);
-        let mut enc: zstd::Encoder<'static, WriteTo<Blake3, 30>> =
-            zstd::Encoder::new(wto, 0).unwrap();
+/*
+The BigData root object lists the leaves of a large file:
+
+    | Total | Chunking | Leaves |
+    |     7 |        1 |        |
+
+Total is the file size and Chunking is how the file was cut into leaves (see
+`chunker`).  With Fixed chunking every leaf but the last is OBJECT_MAX_SIZE, so
+each leaf is just its hash.  With FastCDC each leaf is a 4 byte size followed
+by its hash.
+
+The Total and Chunking fields together are read as a u64 (LITTLE ENDIAN), so a
+Fixed root is the same as before chunking was recorded at all.
+
+Verifying one leaf against a flat leaf list means fetching the whole list, so
+new BigData roots are instead a small header pointing at the leaf tree:
+
+    | Total | Layout | Merkle Root | Leaves |
+    |     7 |      1 |           N |      N |
+
+Layout is the Chunking byte with the MERKLE_LAYOUT bit set.  Merkle Root is
+the root of the binary Merkle tree over the leaves (see `merkle`).  Proving that
+a byte range belongs to the root `Name` then takes only the header plus a
+compact `merkle::RangeProof`.
+
+Leaves names the top node of the leaf tree.  A node holding leaves is a flat
+leaf list as above (at most BIGDATA_FANOUT leaves).  An interior node lists its
+children (at most BIGDATA_FANOUT) with the bytes below each:
+
+    | Total | Layout | Size | Child | ...
+    |     7 |      1 |    8 |     N |
+
+Here Layout is the Chunking byte with the INTERIOR_LAYOUT bit set.  Both kinds
+of node are BigData objects.  Small files have a single leaf list under the
+header.  The tree is built in one streaming pass (see `LeafTreeEncoder`), so we
+never hold all the leaf hashes at once, and reading only needs one node per
+level.
+*/
+
+pub const MERKLE_LAYOUT: u8 = 0x80;
+pub const INTERIOR_LAYOUT: u8 = 0x40;
+pub const BIGDATA_FANOUT: usize = 4096;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct BigDataHeader<const N: usize> {
+    pub total: u64,
+    pub chunking: Chunking,
+    pub merkle: Name<N>,
+    pub leaves: Name<N>,
+}
+
+impl<const N: usize> BigDataHeader<N> {
+    pub const LEN: usize = 8 + N + N;
+
+    pub fn is_header(buf: &[u8]) -> bool {
+        buf.len() == Self::LEN && buf[7] & MERKLE_LAYOUT != 0
+    }
+
+    pub fn serialize(&self, buf: &mut Vec<u8>) {
+        assert!(self.total >> 56 == 0);
+        let layout = self.chunking as u8 | MERKLE_LAYOUT;
+        let head = self.total | (layout as u64) << 56;
+        buf.extend_from_slice(&head.to_le_bytes());
+        buf.extend_from_slice(self.merkle.as_buf());
+        buf.extend_from_slice(self.leaves.as_buf());
+    }
+
+    pub fn deserialize(buf: &[u8]) -> io::Result<Self> {
+        if !Self::is_header(buf) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a BigData header",
+            ));
+        }
+        let head = u64::from_le_bytes(buf[0..8].try_into().expect("oops"));
+        let chunking: Chunking = (buf[7] & !MERKLE_LAYOUT).into();
+        if chunking == Chunking::Unknown {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unknown chunking",
+            ));
+        }
+        Ok(Self {
+            total: head & !(0xff << 56),
+            chunking,
+            merkle: Name::from(&buf[8..8 + N]),
+            leaves: Name::from(&buf[8 + N..8 + N + N]),
+        })
+    }
+}
+
+/// Interior node of a BigData leaf tree.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Interior<const N: usize> {
+    pub chunking: Chunking,
+    pub children: Vec<(u64, Name<N>)>,
+}
+
+impl<const N: usize> Interior<N> {
+    pub fn is_interior(buf: &[u8]) -> bool {
+        buf.len() >= 8 && buf[7] & (MERKLE_LAYOUT | INTERIOR_LAYOUT) == INTERIOR_LAYOUT
+    }
+
+    pub fn total(&self) -> u64 {
+        self.children.iter().map(|c| c.0).sum()
+    }
+
+    pub fn serialize(&self, buf: &mut Vec<u8>) {
+        let total = self.total();
+        assert!(total >> 56 == 0);
+        let layout = self.chunking as u8 | INTERIOR_LAYOUT;
+        let head = total | (layout as u64) << 56;
+        buf.extend_from_slice(&head.to_le_bytes());
+        for (size, hash) in self.children.iter() {
+            buf.extend_from_slice(&size.to_le_bytes());
+            buf.extend_from_slice(hash.as_buf());
+        }
+    }
+
+    pub fn deserialize(buf: &[u8]) -> io::Result<Self> {
+        let bad = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        if !Self::is_interior(buf) || (buf.len() - 8) % (8 + N) != 0 {
+            return Err(bad("not a BigData interior node"));
+        }
+        let chunking: Chunking = (buf[7] & !INTERIOR_LAYOUT).into();
+        if chunking == Chunking::Unknown {
+            return Err(bad("unknown chunking"));
+        }
+        let mut children = Vec::with_capacity((buf.len() - 8) / (8 + N));
+        let mut offset = 8;
+        while offset < buf.len() {
+            let size = u64::from_le_bytes(buf[offset..offset + 8].try_into().expect("oops"));
+            children.push((size, Name::from(&buf[offset + 8..offset + 8 + N])));
+            offset += 8 + N;
+        }
+        let node = Self { chunking, children };
+        let head = u64::from_le_bytes(buf[0..8].try_into().expect("oops"));
+        if node.total() != head & !(0xff << 56) {
+            return Err(bad("interior node total does not match"));
+        }
+        Ok(node)
+    }
+}
+
+/// Builds a BigData leaf tree (and its header) in one streaming pass.
+///
+/// Only one partial node per level is held, so memory stays small no matter
+/// how many leaves.  Nodes are saved as they fill up when we have a store.
+pub struct LeafTreeEncoder<H: Hasher, const N: usize> {
+    fanout: usize,
+    list: LeafHashes<N>,
+    levels: Vec<Vec<(u64, Name<N>)>>,
+    merkle: MerkleBuilder<H, N>,
+    obj: Object<H, N>,
+}
+
+impl<H: Hasher, const N: usize> LeafTreeEncoder<H, N> {
+    pub fn new(chunking: Chunking) -> Self {
+        Self::with_fanout(chunking, BIGDATA_FANOUT)
+    }
+
+    pub fn with_fanout(chunking: Chunking, fanout: usize) -> Self {
+        assert!(fanout > 1);
+        Self {
+            fanout,
+            list: LeafHashes::with_chunking(chunking),
+            levels: Vec::new(),
+            merkle: MerkleBuilder::new(),
+            obj: Object::new(),
+        }
+    }
+
+    pub fn chunking(&self) -> Chunking {
+        self.list.chunking()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.merkle.total()
+    }
+
+    pub fn append_leaf(
+        &mut self,
+        store: Option<&mut Store<H, N>>,
+        hash: Name<N>,
+        size: usize,
+    ) -> io::Result<()> {
+        self.merkle.append_leaf(size as u32, &hash);
+        self.list.append_leaf(hash, size);
+        if self.list.len() == self.fanout {
+            self.flush_list(store)?;
+        }
+        Ok(())
+    }
+
+    fn save_node(&mut self, store: Option<&mut Store<H, N>>) -> io::Result<Name<N>> {
+        let hash = self.obj.finalize_with_kind(ObjKind::BigData as u8);
+        if let Some(store) = store {
+            store.save(&self.obj)?;
+        }
+        Ok(hash)
+    }
+
+    fn flush_list(&mut self, mut store: Option<&mut Store<H, N>>) -> io::Result<()> {
+        let chunking = self.list.chunking();
+        let list = mem::replace(&mut self.list, LeafHashes::with_chunking(chunking));
+        self.obj.clear();
+        list.serialize(self.obj.as_mut_vec());
+        let hash = self.save_node(store.as_deref_mut())?;
+        self.push_child(store, 0, list.total(), hash)
+    }
+
+    fn push_child(
+        &mut self,
+        store: Option<&mut Store<H, N>>,
+        level: usize,
+        size: u64,
+        hash: Name<N>,
+    ) -> io::Result<()> {
+        if self.levels.len() == level {
+            self.levels.push(Vec::new());
+        }
+        self.levels[level].push((size, hash));
+        if self.levels[level].len() == self.fanout {
+            self.flush_level(store, level)?;
+        }
+        Ok(())
+    }
+
+    fn flush_level(&mut self, mut store: Option<&mut Store<H, N>>, level: usize) -> io::Result<()> {
+        let node = Interior {
+            chunking: self.list.chunking(),
+            children: mem::take(&mut self.levels[level]),
+        };
+        self.obj.clear();
+        node.serialize(self.obj.as_mut_vec());
+        let hash = self.save_node(store.as_deref_mut())?;
+        self.push_child(store, level + 1, node.total(), hash)
+    }
+
+    /// Flush the partial nodes and write the header, returning the root.
+    pub fn finish(mut self, mut store: Option<&mut Store<H, N>>) -> io::Result<Name<N>> {
+        assert!(self.merkle.count() > 0);
+        if !self.list.is_empty() {
+            self.flush_list(store.as_deref_mut())?;
+        }
+        let mut level = 0;
+        let top = loop {
+            if level + 1 == self.levels.len() && self.levels[level].len() == 1 {
+                break self.levels[level][0].1;
+            }
+            match self.levels[level].len() {
+                0 => {}
+                1 => {
+                    // No point in an interior node with a single child
+                    let (size, hash) = self.levels[level].pop().unwrap();
+                    self.push_child(store.as_deref_mut(), level + 1, size, hash)?;
+                }
+                _ => self.flush_level(store.as_deref_mut(), level)?,
+            }
+            level += 1;
+        };
+        let header = BigDataHeader {
+            total: self.merkle.total(),
+            chunking: self.list.chunking(),
+            merkle: self.merkle.finish(),
+            leaves: top,
+        };
+        self.obj.clear();
+        header.serialize(self.obj.as_mut_vec());
+        self.save_node(store)
+    }
+}
+
+fn bad_tree<const N: usize>(hash: &Name<N>, msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{} {}", hash, msg))
+}
+
+// Loads a BigData node, failing if we don't have it.
+fn load_node<H: Hasher, const N: usize>(
+    store: &mut Store<H, N>,
+    hash: &Name<N>,
+    obj: &mut Object<H, N>,
+) -> io::Result<()> {
+    if !store.load(hash, obj)? {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("cannot find BigData node {}", hash),
+        ));
+    }
+    if obj.kind() != ObjKind::BigData {
+        return Err(bad_tree(hash, "is not a BigData node"));
+    }
+    Ok(())
+}
+
+/// Walks the leaves of a BigData root in order, one node at a time.
+///
+/// The tree is checked against the header (total, chunking, and Merkle root)
+/// once the last leaf has been walked.
+pub struct LeafWalker<H: Hasher, const N: usize> {
+    root: Name<N>,
+    header: Option<BigDataHeader<N>>,
+    pending: Vec<Name<N>>, // Nodes still to visit, next one last
+    list: LeafHashes<N>,
+    index: usize,
+    merkle: MerkleBuilder<H, N>,
+    obj: Object<H, N>,
+}
+
+impl<H: Hasher, const N: usize> LeafWalker<H, N> {
+    /// Start walking `root` (`None` if we don't have it).
+    pub fn new(store: &mut Store<H, N>, root: &Name<N>) -> io::Result<Option<Self>> {
+        let mut obj = Object::new();
+        if !store.load(root, &mut obj)? {
+            return Ok(None);
+        }
+        if obj.kind() != ObjKind::BigData {
+            return Err(bad_tree(root, "is not a BigData root"));
+        }
+        let mut walker = Self {
+            root: *root,
+            header: None,
+            pending: Vec::new(),
+            list: LeafHashes::new(),
+            index: 0,
+            merkle: MerkleBuilder::new(),
+            obj,
+        };
+        if BigDataHeader::<N>::is_header(walker.obj.as_data()) {
+            let header = BigDataHeader::deserialize(walker.obj.as_data())?;
+            walker.list = LeafHashes::with_chunking(header.chunking);
+            walker.pending.push(header.leaves);
+            walker.header = Some(header);
+        } else {
+            // A flat leaf list from before there were headers
+            walker.list = LeafHashes::deserialize(walker.obj.as_data())?;
+        }
+        Ok(Some(walker))
+    }
+
+    pub fn chunking(&self) -> Chunking {
+        self.list.chunking()
+    }
+
+    /// Size of the whole file (as recorded in the root).
+    pub fn total(&self) -> u64 {
+        match &self.header {
+            Some(header) => header.total,
+            None => self.list.total(),
+        }
+    }
+
+    /// The next `(hash, size)` leaf, or `None` when done.
+    pub fn next_leaf(&mut self, store: &mut Store<H, N>) -> io::Result<Option<(Name<N>, usize)>> {
+        loop {
+            if self.index < self.list.len() {
+                let hash = self.list.hashes[self.index];
+                let size = self.list.leaf_size(self.index);
+                self.index += 1;
+                self.merkle.append_leaf(size as u32, &hash);
+                return Ok(Some((hash, size)));
+            }
+            let hash = match self.pending.pop() {
+                Some(hash) => hash,
+                None => return self.check().map(|_| None),
+            };
+            load_node(store, &hash, &mut self.obj)?;
+            let data = self.obj.as_data();
+            if Interior::<N>::is_interior(data) {
+                let node = Interior::<N>::deserialize(data)?;
+                if node.chunking != self.chunking() || node.children.is_empty() {
+                    return Err(bad_tree(&hash, "is a bad interior node"));
+                }
+                self.pending
+                    .extend(node.children.iter().rev().map(|child| child.1));
+            } else if BigDataHeader::<N>::is_header(data) {
+                return Err(bad_tree(&hash, "is a header inside a leaf tree"));
+            } else {
+                let list = LeafHashes::<N>::deserialize(data)?;
+                if list.chunking() != self.chunking() || list.is_empty() {
+                    return Err(bad_tree(&hash, "is a bad leaf list"));
+                }
+                self.list = list;
+                self.index = 0;
+            }
+        }
+    }
+
+    fn check(&self) -> io::Result<()> {
+        if let Some(header) = &self.header {
+            if self.merkle.count() == 0
+                || self.merkle.total() != header.total
+                || self.merkle.finish() != header.merkle
+            {
+                return Err(bad_tree(&self.root, "leaf tree does not match header"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// All the leaves of a BigData root (`None` if we don't have it).
+///
+/// This holds every leaf hash in memory, so use `LeafWalker` when you can.
+pub fn load_leaves<H: Hasher, const N: usize>(
+    store: &mut Store<H, N>,
+    root: &Name<N>,
+) -> io::Result<Option<LeafHashes<N>>> {
+    let mut walker = match LeafWalker::<H, N>::new(store, root)? {
+        Some(walker) => walker,
+        None => return Ok(None),
+    };
+    let mut leaves = LeafHashes::with_chunking(walker.chunking());
+    while let Some((hash, size)) = walker.next_leaf(store)? {
+        leaves.append_leaf(hash, size);
+    }
+    Ok(Some(leaves))
+}
+
+#[derive(Debug, Default)]
+pub struct LeafHashes<const N: usize> {
+    total: u64,
+    chunking: Chunking,
+    hashes: Vec<Name<N>>,
+    sizes: Vec<u32>,
+}
+
+impl<const N: usize> LeafHashes<N> {
+    pub fn new() -> Self {
+        Self::with_chunking(Chunking::Fixed)
+    }
+
+    pub fn with_chunking(chunking: Chunking) -> Self {
+        assert!(chunking != Chunking::Unknown);
+        Self {
+            total: 0,
+            chunking,
+            hashes: Vec::new(),
+            sizes: Vec::new(),
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_, Name<N>> {
+        self.hashes.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn chunking(&self) -> Chunking {
+        self.chunking
+    }
+
+    pub fn leaf_size(&self, index: usize) -> usize {
+        match self.chunking {
+            Chunking::Fixed => {
+                if index + 1 < self.hashes.len() {
+                    OBJECT_MAX_SIZE
+                } else {
+                    (self.total - (index * OBJECT_MAX_SIZE) as u64) as usize
+                }
+            }
+            _ => self.sizes[index] as usize,
+        }
+    }
+
+    pub fn append_leaf(&mut self, hash: Name<N>, size: usize) {
+        self.hashes.push(hash);
+        if self.chunking != Chunking::Fixed {
+            self.sizes.push(size as u32);
+        }
+        self.total += size as u64;
+    }
+
+    pub fn serialize(&self, buf: &mut Vec<u8>) {
+        assert!(self.total >> 56 == 0);
+        let head = self.total | (self.chunking as u64) << 56;
+        buf.extend_from_slice(&head.to_le_bytes());
+        for (i, hash) in self.hashes.iter().enumerate() {
+            if self.chunking != Chunking::Fixed {
+                buf.extend_from_slice(&self.sizes[i].to_le_bytes());
+            }
+            buf.extend_from_slice(hash.as_buf());
+        }
+    }
+
+    pub fn deserialize(buf: &[u8]) -> io::Result<Self> {
+        let bad = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        if buf.len() < 8 {
+            return Err(bad("leaf list is too short"));
+        }
+        let head = u64::from_le_bytes(buf[0..8].try_into().expect("oops"));
+        let chunking: Chunking = ((head >> 56) as u8).into();
+        if chunking == Chunking::Unknown {
+            return Err(bad("unknown chunking"));
+        }
+        let step = if chunking == Chunking::Fixed {
+            N
+        } else {
+            4 + N
+        };
+        if (buf.len() - 8) % step != 0 {
+            return Err(bad("leaf list is the wrong size"));
+        }
+        let mut leaves = Self::with_chunking(chunking);
+        let mut offset = 8;
+        while offset < buf.len() {
+            let mut size = 0;
+            if chunking != Chunking::Fixed {
+                size = u32::from_le_bytes(buf[offset..offset + 4].try_into().expect("oops"));
+                offset += 4;
+            }
+            leaves.append_leaf(Name::from(&buf[offset..offset + N]), size as usize);
+            offset += N;
+        }
+        let total = head & !(0xff << 56);
+        let count = leaves.len() as u64;
+        let max = OBJECT_MAX_SIZE as u64;
+        let good = match chunking {
+            Chunking::Fixed if count == 0 => total == 0,
+            Chunking::Fixed => total > (count - 1) * max && total <= count * max,
+            _ => total == leaves.total,
+        };
+        if !good {
+            return Err(bad("leaf list total does not match"));
+        }
+        leaves.total = total;
+        Ok(leaves)
+    }
+}
+
+// Reads leaves in order, from any `Read`.
+enum LeafReader<R: Read> {
+    Fixed(R),
+    FastCdc(ChunkReader<R>),
+}
+
+impl<R: Read> LeafReader<R> {
+    fn new(inner: R, chunking: Chunking) -> Self {
+        match chunking {
+            Chunking::Fixed => Self::Fixed(inner),
+            Chunking::FastCdc => Self::FastCdc(ChunkReader::new(inner, Chunker::default())),
+            Chunking::Unknown => panic!("Cannot chunk with {:?}", chunking),
+        }
+    }
+
+    // Reads the next leaf into `obj` (not yet finalized), false at EOF.
+    fn read_leaf<H: Hasher, const N: usize>(&mut self, obj: &mut Object<H, N>) -> io::Result<bool> {
+        match self {
+            Self::Fixed(inner) => {
+                obj.reset(OBJECT_MAX_SIZE, ObjKind::Data as u8);
+                let size = read_full(inner, obj.as_mut_data())?;
+                obj.as_mut_vec().truncate(N + INFO_LEN + size);
+                Ok(size > 0)
+            }
+            Self::FastCdc(reader) => match reader.next_chunk()? {
+                Some(chunk) => {
+                    obj.reset(chunk.len(), ObjKind::Data as u8);
+                    obj.as_mut_data().copy_from_slice(chunk);
+                    Ok(true)
+                }
+                None => Ok(false),
+            },
+        }
+    }
+}
+
+// Reads until `buf` is full or EOF, returning how much was read.
+fn read_full<R: Read>(inner: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut size = 0;
+    while size < buf.len() {
+        match inner.read(&mut buf[size..]) {
+            Ok(0) => break,
+            Ok(n) => size += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(size)
+}
+
+fn save_leaves<H: Hasher, const N: usize>(
+    store: &mut Option<&mut Store<H, N>>,
+    tree: &mut LeafTreeEncoder<H, N>,
+    batch: &[Object<H, N>],
+) -> io::Result<()> {
+    for obj in batch.iter() {
+        if let Some(store) = store.as_mut() {
+            store.save(obj)?;
+        }
+        tree.append_leaf(store.as_deref_mut(), obj.hash(), obj.info().size())?;
+    }
+    Ok(())
+}
+
+/*
+Leaves are hashed in parallel a batch at a time (one leaf per thread), then
+saved in order.  With Fixed chunking from a file the leaf boundaries are known
+up front, so the leaves are also read in parallel with positional reads.
+
+Only Fixed chunking from a file gets the full speedup.  FastCDC cut points
+depend on everything before them, so finding them stays sequential (as does
+reading from a pipe) and only the hashing of each batch runs in parallel.
+
+A leaf can be up to OBJECT_MAX_SIZE, so a batch is at most POOL_MAX_LEAVES
+leaves no matter how many threads there are (8 x 16 MiB = 128 MiB).
+*/
+
+const POOL_MAX_LEAVES: usize = 8;
+
+fn new_pool<H: Hasher, const N: usize>() -> Vec<Object<H, N>> {
+    let batch = rayon::current_num_threads().clamp(1, POOL_MAX_LEAVES);
+    (0..batch).map(|_| Object::new()).collect()
+}
+
+fn chunk_stream<H: Hasher, const N: usize, R: Read>(
+    store: &mut Option<&mut Store<H, N>>,
+    tree: &mut LeafTreeEncoder<H, N>,
+    inner: R,
+) -> io::Result<()> {
+    let mut reader = LeafReader::new(inner, tree.chunking());
+    let mut pool = new_pool();
+    loop {
+        let mut n = 0;
+        while n < pool.len() && reader.read_leaf(&mut pool[n])? {
+            n += 1;
+        }
+        if n == 0 {
+            return Ok(());
+        }
+        pool[..n].par_iter_mut().for_each(|obj| {
+            obj.finalize_with_kind(ObjKind::Data as u8);
+        });
+        save_leaves(store, tree, &pool[..n])?;
+    }
+}
+
+fn chunk_positional<H: Hasher, const N: usize>(
+    store: &mut Option<&mut Store<H, N>>,
+    tree: &mut LeafTreeEncoder<H, N>,
+    file: &fs::File,
+    size: u64,
+) -> io::Result<()> {
+    let mut pool = new_pool();
+    let count = size.div_ceil(OBJECT_MAX_SIZE as u64);
+    let mut first = 0;
+    while first < count {
+        let n = cmp::min(count - first, pool.len() as u64) as usize;
+        pool[..n]
+            .par_iter_mut()
+            .enumerate()
+            .map(|(i, obj)| {
+                let offset = (first + i as u64) * OBJECT_MAX_SIZE as u64;
+                let s = cmp::min(size - offset, OBJECT_MAX_SIZE as u64);
+                obj.reset(s as usize, ObjKind::Data as u8);
+                file.read_exact_at(obj.as_mut_data(), offset)?;
+                obj.finalize();
+                Ok(())
+            })
+            .collect::<io::Result<()>>()?;
+        save_leaves(store, tree, &pool[..n])?;
+        first += n as u64;
+    }
+    Ok(())
+}
+
+// Cuts the file into leaves, saving each leaf (and the tree) if we have a store.
+fn chunk_file<H: Hasher, const N: usize>(
+    mut store: Option<&mut Store<H, N>>,
+    file: fs::File,
+    size: u64,
+    chunking: Chunking,
+) -> io::Result<Name<N>> {
+    let mut tree = LeafTreeEncoder::new(chunking);
+    match chunking {
+        Chunking::Fixed => chunk_positional(&mut store, &mut tree, &file, size)?,
+        _ => chunk_stream(&mut store, &mut tree, file.take(size))?,
+    }
+    if tree.total() != size {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    tree.finish(store)
+}
+
+// Buffers up to one leaf to decide between a Data object and a BigData root.
+fn chunk_reader<H: Hasher, const N: usize, R: Read>(
+    mut store: Option<&mut Store<H, N>>,
+    obj: &mut Object<H, N>,
+    mut inner: R,
+    chunking: Chunking,
+) -> io::Result<Option<Name<N>>> {
+    obj.reset(OBJECT_MAX_SIZE, ObjKind::Data as u8);
+    let size = read_full(&mut inner, obj.as_mut_data())?;
+    if size == 0 {
+        obj.clear();
+        return Ok(None);
+    }
+    let mut extra = [0_u8; 1];
+    if size < OBJECT_MAX_SIZE || read_full(&mut inner, &mut extra)? == 0 {
+        obj.as_mut_vec().truncate(N + INFO_LEN + size);
+        let hash = obj.finalize_with_kind(ObjKind::Data as u8);
+        if let Some(store) = store {
+            store.save(obj)?;
+        }
+        return Ok(Some(hash));
+    }
+    let mut head = io::Cursor::new(mem::take(obj.as_mut_vec()));
+    head.set_position((N + INFO_LEN) as u64);
+    let mut tree = LeafTreeEncoder::new(chunking);
+    chunk_stream(&mut store, &mut tree, head.chain(&extra[..]).chain(inner))?;
+    obj.clear();
+    Ok(Some(tree.finish(store)?))
+}
+
+/// Hash everything from `inner`, which can be of unknown length (like stdin or
+/// a pipe).  Returns `None` for empty input, which has no object.
+pub fn hash_reader<H: Hasher, const N: usize, R: Read>(
+    obj: &mut Object<H, N>,
+    inner: R,
+    chunking: Chunking,
+) -> io::Result<Option<Name<N>>> {
+    chunk_reader(None, obj, inner, chunking)
+}
+
+/// Import everything from `inner`, which can be of unknown length (like stdin
+/// or a pipe).  Returns `None` for empty input, which has no object.
+pub fn import_reader<H: Hasher, const N: usize, R: Read>(
+    store: &mut Store<H, N>,
+    obj: &mut Object<H, N>,
+    inner: R,
+    chunking: Chunking,
+) -> io::Result<Option<Name<N>>> {
+    chunk_reader(Some(store), obj, inner, chunking)
+}
+
+pub fn hash_file<H: Hasher, const N: usize>(
+    obj: &mut Object<H, N>,
+    file: fs::File,
+    size: u64,
+) -> io::Result<Name<N>> {
+    hash_file_with(obj, file, size, Chunking::default())
+}
+
+pub fn hash_file_with<H: Hasher, const N: usize>(
+    obj: &mut Object<H, N>,
+    mut file: fs::File,
+    size: u64,
+    chunking: Chunking,
+) -> io::Result<Name<N>> {
+    if size == 0 {
+        panic!("No good, yo, your size is ZERO!");
+    }
+    if size > OBJECT_MAX_SIZE as u64 {
+        chunk_file::<H, N>(None, file, size, chunking)
+    } else {
+        obj.reset(size as usize, ObjKind::Data as u8);
+        file.read_exact(obj.as_mut_data())?;
+        Ok(obj.finalize())
+    }
+}
+
+pub fn import_file<H: Hasher, const N: usize>(
+    store: &mut Store<H, N>,
+    obj: &mut Object<H, N>,
+    file: fs::File,
+    size: u64,
+) -> io::Result<Name<N>> {
+    import_file_with(store, obj, file, size, Chunking::default())
+}
+
+pub fn import_file_with<H: Hasher, const N: usize>(
+    store: &mut Store<H, N>,
+    obj: &mut Object<H, N>,
+    mut file: fs::File,
+    size: u64,
+    chunking: Chunking,
+) -> io::Result<Name<N>> {
+    if size == 0 {
+        panic!("No good, yo, your size is ZERO!");
+    }
+    if size > OBJECT_MAX_SIZE as u64 {
+        chunk_file(Some(store), file, size, chunking)
+    } else {
+        obj.reset(size as usize, ObjKind::Data as u8);
+        file.read_exact(obj.as_mut_data())?;
+        let hash = obj.finalize();
+        store.save(obj)?;
+        Ok(hash)
+    }
+}
+
+/// Options for `restore_file_with()`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RestoreOptions {
+    /// Seek over zero filled blocks rather than writing them, leaving holes
+    /// where the filesystem supports sparse files.
+    pub sparse: bool,
+    /// fsync the file once it's fully written.
+    pub sync: bool,
+}
+
+/// Sparse writes are done in blocks of this size.
+pub const SPARSE_BLOCK_SIZE: usize = 4096;
+
+// Writes at the end of a file, optionally skipping zero filled blocks.  Holes
+// are only possible with positional writes, which O_APPEND doesn't allow.
+struct RestoreWriter<'a> {
+    file: &'a mut fs::File,
+    offset: u64,
+    sparse: bool,
+}
+
+impl<'a> RestoreWriter<'a> {
+    fn new(file: &'a mut fs::File, sparse: bool) -> io::Result<Self> {
+        let offset = file.metadata()?.len();
+        let sparse = sparse && !is_append(file)?;
+        Ok(Self {
+            file,
+            offset,
+            sparse,
+        })
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        if !self.sparse {
+            self.file.write_all(buf)?;
+            self.offset += buf.len() as u64;
+            return Ok(());
+        }
+        let mut start = 0; // Start of pending non-zero blocks
+        for (i, block) in buf.chunks(SPARSE_BLOCK_SIZE).enumerate() {
+            let pos = i * SPARSE_BLOCK_SIZE;
+            if block.iter().all(|b| *b == 0) {
+                if start < pos {
+                    self.file
+                        .write_all_at(&buf[start..pos], self.offset + start as u64)?;
+                }
+                start = pos + block.len();
+            }
+        }
+        if start < buf.len() {
+            self.file
+                .write_all_at(&buf[start..], self.offset + start as u64)?;
+        }
+        self.offset += buf.len() as u64;
+        Ok(())
+    }
+
+    // Trailing holes don't extend the file, so set the final length.
+    fn finish(self) -> io::Result<u64> {
+        if self.sparse && self.file.metadata()?.len() < self.offset {
+            self.file.set_len(self.offset)?;
+        }
+        Ok(self.offset)
+    }
+}
+
+fn is_append(file: &fs::File) -> io::Result<bool> {
+    use std::os::fd::AsRawFd;
+    // SAFETY: F_GETFL takes no argument and only reads the flags of the open
+    // fd, which `file` keeps alive for the duration of the call.
+    let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(flags & libc::O_APPEND != 0)
+}
+
+pub fn restore_file<H: Hasher, const N: usize>(
+    store: &mut Store<H, N>,
+    obj: &mut Object<H, N>,
+    file: &mut fs::File,
+    root: &Name<N>,
+) -> io::Result<bool> {
+    restore_file_with(store, obj, file, root, RestoreOptions::default())
+}
+
+/// Write the file with `root` to the end of `file` (`false` if we don't have it).
+///
+/// Every leaf is verified as it's loaded, and the length of the restored file
+/// is checked against the total recorded in the root.
+pub fn restore_file_with<H: Hasher, const N: usize>(
+    store: &mut Store<H, N>,
+    obj: &mut Object<H, N>,
+    file: &mut fs::File,
+    root: &Name<N>,
+    opts: RestoreOptions,
+) -> io::Result<bool> {
+    if !store.load(root, obj)? {
+        return Ok(false);
+    }
+    let mut out = RestoreWriter::new(file, opts.sparse)?;
+    let start = out.offset;
+    let total = match obj.kind() {
+        ObjKind::Data => {
+            out.write(obj.as_data())?;
+            obj.as_data().len() as u64
+        }
+        ObjKind::BigData => {
+            let mut walker = LeafWalker::new(store, root)?.unwrap();
+            while let Some((hash, size)) = walker.next_leaf(store)? {
+                if !store.load(&hash, obj)? {
+                    panic!("Cannot find {} leaf {}", root, hash);
+                }
+                if obj.as_data().len() != size {
+                    return Err(bad_tree(&hash, "leaf has the wrong size"));
+                }
+                out.write(obj.as_data())?;
+            }
+            walker.total()
+        }
+        kind => {
+            panic!("No good, yo, no good at all! 😵‍💫 {:?}", kind);
+        }
+    };
+    let end = out.finish()?;
+    let len = file.metadata()?.len();
+    if end - start != total || len != end {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} restored to {} bytes, expected {}",
+                root,
+                len - start,
+                total
+            ),
+        ));
+    }
+    if opts.sync {
+        file.sync_all()?;
+    }
+    Ok(true)
+}
+
+/// Clone the contents of `src` into `dst` without copying any data.
+///
+/// This is `FICLONE` (Btrfs, XFS, and friends).  Returns `false` when the
+/// filesystem doesn't support it, in which case nothing was done.
+pub fn reflink(src: &fs::File, dst: &fs::File) -> io::Result<bool> {
+    use std::os::fd::AsRawFd;
+    // SAFETY: FICLONE takes the source fd as its argument (not a pointer), and
+    // both fds are kept open by the borrowed `src` and `dst` during the call.
+    let ret = unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) };
+    if ret == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EOPNOTSUPP | libc::EXDEV | libc::EINVAL | libc::ENOTTY) => Ok(false),
+        _ => Err(err),
+    }
+}
+
+/// Random access `Read + Seek` over a stored file (a BigData or Data root).
+///
+/// Only the leaf covering the current position is loaded (and verified), plus
+/// the nodes on the path down to it when moving to another leaf list.
+pub struct BigDataReader<'a, H: Hasher, const N: usize> {
+    store: &'a mut Store<H, N>,
+    top: Option<Name<N>>, // Top node of the leaf tree (None for a Data root)
+    total: u64,
+    hashes: Vec<Name<N>>, // Leaves in the current leaf list
+    offsets: Vec<u64>,    // Start of each of those leaves, then their end
+    obj: Object<H, N>,
+    current: Option<usize>,
+    pos: u64,
+}
+
+impl<'a, H: Hasher, const N: usize> BigDataReader<'a, H, N> {
+    /// Open the file with `root` (`None` if we don't have it).
+    pub fn open(store: &'a mut Store<H, N>, root: &Name<N>) -> io::Result<Option<Self>> {
+        let mut obj = Object::new();
+        if !store.load(root, &mut obj)? {
+            return Ok(None);
+        }
+        let mut reader = Self {
+            store,
+            top: None,
+            total: 0,
+            hashes: Vec::new(),
+            offsets: Vec::new(),
+            obj,
+            current: None,
+            pos: 0,
+        };
+        match reader.obj.kind() {
+            ObjKind::Data => {
+                reader.total = reader.obj.info().size() as u64;
+                reader.hashes.push(*root);
+                reader.offsets.extend([0, reader.total]);
+                reader.current = Some(0);
+            }
+            ObjKind::BigData => {
+                let data = reader.obj.as_data();
+                if BigDataHeader::<N>::is_header(data) {
+                    let header = BigDataHeader::<N>::deserialize(data)?;
+                    reader.top = Some(header.leaves);
+                    reader.total = header.total;
+                } else {
+                    reader.top = Some(*root);
+                    reader.total = LeafHashes::<N>::deserialize(data)?.total();
+                }
+            }
+            kind => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is {:?}, not a file", root, kind),
+                ));
+            }
+        }
+        Ok(Some(reader))
+    }
+
+    pub fn len(&self) -> u64 {
+        self.total
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Walks down from the top node to the leaf list covering `pos`.
+    fn descend(&mut self, pos: u64) -> io::Result<()> {
+        let mut hash = self.top.unwrap();
+        let mut base = 0;
+        let mut size = self.total;
+        self.current = None;
+        loop {
+            load_node(self.store, &hash, &mut self.obj)?;
+            let data = self.obj.as_data();
+            if Interior::<N>::is_interior(data) {
+                let node = Interior::<N>::deserialize(data)?;
+                if node.total() != size {
+                    return Err(bad_tree(&hash, "does not match its parent"));
+                }
+                let mut child = None;
+                for (s, h) in node.children.iter() {
+                    if pos < base + s {
+                        child = Some((*s, *h));
+                        break;
+                    }
+                    base += s;
+                }
+                match child {
+                    Some((s, h)) => {
+                        size = s;
+                        hash = h;
+                    }
+                    None => return Err(bad_tree(&hash, "does not cover the offset")),
+                }
+            } else if BigDataHeader::<N>::is_header(data) {
+                return Err(bad_tree(&hash, "is a header inside a leaf tree"));
+            } else {
+                let list = LeafHashes::<N>::deserialize(data)?;
+                if list.total() != size || list.is_empty() {
+                    return Err(bad_tree(&hash, "does not match its parent"));
+                }
+                self.hashes = list.iter().cloned().collect();
+                self.offsets.clear();
+                let mut offset = base;
+                for i in 0..list.len() {
+                    self.offsets.push(offset);
+                    offset += list.leaf_size(i) as u64;
+                }
+                self.offsets.push(offset);
+                return Ok(());
+            }
+        }
+    }
+
+    fn load_leaf(&mut self, index: usize) -> io::Result<()> {
+        if self.current == Some(index) {
+            return Ok(());
+        }
+        self.current = None;
+        let hash = &self.hashes[index];
+        let size = (self.offsets[index + 1] - self.offsets[index]) as usize;
+        if !self.store.load_unchecked(hash, &mut self.obj)? {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("cannot find leaf {}", hash),
+            ));
+        }
+        if !self.obj.validate_against(hash)
+            || self.obj.kind() != ObjKind::Data
+            || self.obj.info().size() != size
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("leaf {} is corrupt", hash),
+            ));
+        }
+        self.current = Some(index);
+        Ok(())
+    }
+}
+
+impl<H: Hasher, const N: usize> io::Read for BigDataReader<'_, H, N> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len() || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.offsets.is_empty()
+            || self.pos < self.offsets[0]
+            || self.pos >= self.offsets[self.offsets.len() - 1]
+        {
+            self.descend(self.pos)?;
+        }
+        let index = self.offsets.partition_point(|&o| o <= self.pos) - 1;
+        self.load_leaf(index)?;
+        let data = self.obj.as_data();
+        let start = (self.pos - self.offsets[index]) as usize;
+        let amount = cmp::min(data.len() - start, buf.len());
+        buf[0..amount].copy_from_slice(&data[start..start + amount]);
+        self.pos += amount as u64;
+        Ok(amount)
+    }
+}
+
+impl<H: Hasher, const N: usize> io::Seek for BigDataReader<'_, H, N> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            io::SeekFrom::Start(offset) => Some(offset),
+            io::SeekFrom::End(offset) => self.len().checked_add_signed(offset),
+            io::SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+/*
+A delta container stores one object as a delta against a base object:
+
+    | Encoding | Target | Base | Depth | Nonce     | Compressed Delta | Tag   |
+    |        3 |      N |    N |     1 | 0, 12, 24 |                  | 0, 16 |
+
+A whole container (Delta byte 4) stores one object as is, which is how objects
+are encrypted at rest (and how small objects are compressed with the trained
+dictionary, in which case Compress is 2 and the Dictionary is named):
+
+    | Encoding | Target | Dictionary | Nonce     | Compressed Info + Data | Tag   |
+    |        3 |      N |       0, N | 0, 12, 24 |                        | 0, 16 |
+
+Either container is itself an ordinary (Stream kind) object, so it's verifiable
+(and syncable) like any other, even without the key.  The reconstructed target
+is verified against Target.  Because the container names its Target right after
+the Encoding, the `Store` indexes it under Target (see `chaos::Codec` and
+`ContainerCodec`), so these objects are first class store entries that
+`Store.load()` just works on, and that survive a `Store.reindex()`.
+
+When encrypted, everything before the Nonce is the AAD, so the Target can be
+read without the key but can't be changed without it.
+
+Depth is how many deltas must be applied to reconstruct Target (a delta against
+a full object has depth 1).  We won't go past MAX_DELTA_DEPTH, at which point
+we store the full object again.
+
+When both base and target look like text we use the document delta, otherwise
+the general delta.  Because the container names both its Target and its Base,
+a depth 1 container also works as a self contained patch you can send to
+someone who has Base (see `make_patch()` and `apply_patch()`).
+*/
+
+fn delta_header_len<const N: usize>() -> usize {
+    ENCODING_LEN + N + N + 1
+}
+
+fn whole_header_len<const N: usize>(data: &[u8]) -> usize {
+    match Encoding::from_bytes(data).compression() {
+        Compression::ZstdDict => ENCODING_LEN + N + N,
+        _ => ENCODING_LEN + N,
+    }
+}
+
+// Returns (kind, target, base, depth) from a delta container.
+fn parse_delta<H: Hasher, const N: usize>(
+    obj: &Object<H, N>,
+) -> io::Result<(DeltaKind, Name<N>, Name<N>, u8)> {
+    let data = obj.as_data();
+    if obj.kind() != ObjKind::Stream || data.len() < delta_header_len::<N>() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a delta container",
+        ));
+    }
+    let encoding = Encoding::from_bytes(data);
+    let kind: DeltaKind = encoding.delta.into();
+    if !matches!(kind, DeltaKind::General | DeltaKind::Document) || encoding.compress != 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "unsupported delta encoding",
+        ));
+    }
+    let mut offset = ENCODING_LEN;
+    let target = Name::from(&data[offset..offset + N]);
+    offset += N;
+    let base = Name::from(&data[offset..offset + N]);
+    offset += N;
+    Ok((kind, target, base, data[offset]))
+}
+
+// Appends the compressed (and maybe encrypted) `payload` to the container
+// header already in `tmp`, then finalizes it.
+fn seal<H: Hasher, const N: usize>(
+    tmp: &mut Object<H, N>,
+    payload: &[u8],
+    dict: Option<&Dictionary<N>>,
+    keys: Option<&Keys>,
+) -> io::Result<Name<N>> {
+    let header = tmp.as_data().len();
+    let cipher = keys.map_or(Cipher::None, |keys| keys.cipher());
+    tmp.as_mut_data()[2] = cipher as u8;
+    tmp.extend(&[0; 24][..cipher.nonce_len()]);
+    match dict {
+        Some(dict) => {
+            let mut zc = zstd::bulk::Compressor::with_dictionary(0, dict.as_buf())?;
+            tmp.extend(&zc.compress(payload)?);
+        }
+        None => tmp.extend(&zstd::bulk::compress(payload, 0)?),
+    }
+    if let Some(keys) = keys {
+        let (ad, rest) = tmp.as_mut_data().split_at_mut(header);
+        let (nonce, body) = rest.split_at_mut(cipher.nonce_len());
+        let tag = keys.encrypt(ad, nonce, body)?;
+        tmp.extend(&tag);
+    }
+    Ok(tmp.finalize_with_kind(ObjKind::Stream as u8))
+}
+
+// The payload of the container in `obj` (after `header` bytes), decrypted and
+// decompressed.  Decrypts in place, so `obj` is garbage afterward.
+fn unseal<H: Hasher, const N: usize>(
+    obj: &mut Object<H, N>,
+    header: usize,
+    dict: Option<&Dictionary<N>>,
+    secret: Option<&Secret>,
+) -> io::Result<Vec<u8>> {
+    let cipher = Encoding::from_bytes(obj.as_data()).cipher();
+    if obj.as_data().len() < header + cipher.overhead() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "container too short",
+        ));
+    }
+    let start = match (cipher, secret) {
+        (Cipher::None, _) => header,
+        (Cipher::Unknown, _) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unsupported container cipher",
+            ));
+        }
+        (_, Some(secret)) => {
+            let (ad, rest) = obj.as_mut_data().split_at_mut(header);
+            let (nonce, rest) = rest.split_at_mut(cipher.nonce_len());
+            let (body, tag) = rest.split_at_mut(rest.len() - TAG_LEN);
+            secret.derive(cipher).decrypt(ad, nonce, body, tag)?;
+            header + cipher.nonce_len()
+        }
+        (_, None) => {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "need key to decrypt container",
+            ));
+        }
+    };
+    let end = obj.as_data().len() - cipher.tag_len();
+    match dict {
+        Some(dict) => {
+            let body = &obj.as_data()[start..end];
+            let mut payload = Vec::new();
+            zstd::Decoder::with_dictionary(body, dict.as_buf())?.read_to_end(&mut payload)?;
+            Ok(payload)
+        }
+        None => zstd::stream::decode_all(&obj.as_data()[start..end]),
+    }
+}
+
+// Builds a delta container in `tmp` (which holds the base data on entry).
+fn build_delta<H: Hasher, const N: usize>(
+    tmp: &mut Object<H, N>,
+    obj: &Object<H, N>,
+    base: &Name<N>,
+    depth: u8,
+    keys: Option<&Keys>,
+) -> io::Result<Name<N>> {
+    let kind = if is_text(tmp.as_data()) && is_text(obj.as_data()) {
+        DeltaKind::Document
+    } else {
+        DeltaKind::General
+    };
+    let mut delta = Vec::new();
+    encode_kind(kind, tmp.as_data(), obj.as_data(), &mut delta);
+    tmp.clear();
+    tmp.extend(&Encoding::new(kind as u8, 1, 0).to_bytes());
+    tmp.extend(obj.hash().as_buf());
+    tmp.extend(base.as_buf());
+    tmp.extend(&[depth]);
+    seal(tmp, &delta, None, keys)
+}
+
+// Builds a whole container holding `obj` in `tmp`.
+fn build_whole<H: Hasher, const N: usize>(
+    tmp: &mut Object<H, N>,
+    obj: &Object<H, N>,
+    dict: Option<&Dictionary<N>>,
+    keys: Option<&Keys>,
+) -> io::Result<Name<N>> {
+    let compress = match dict {
+        Some(_) => Compression::ZstdDict,
+        None => Compression::Zstd,
+    };
+    tmp.clear();
+    tmp.extend(&Encoding::new(DeltaKind::Whole as u8, compress as u8, 0).to_bytes());
+    tmp.extend(obj.hash().as_buf());
+    if let Some(dict) = dict {
+        tmp.extend(dict.hash().as_buf());
+    }
+    seal(tmp, &obj.as_buf()[N..], dict, keys)
+}
+
+// Reconstructs the target into `obj` (which holds the base data on entry).
+fn apply_delta<H: Hasher, const N: usize>(
+    kind: DeltaKind,
+    delta: &[u8],
+    obj: &mut Object<H, N>,
+) -> io::Result<Name<N>> {
+    let mut data = Vec::new();
+    apply_kind(kind, obj.as_data(), delta, &mut data)?;
+    if data.is_empty() || data.len() > OBJECT_MAX_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "bad delta target size",
+        ));
+    }
+    obj.clear();
+    obj.extend(&data);
+    Ok(obj.finalize_with_kind(ObjKind::Data as u8))
+}
+
+/// Number of deltas needed to load `hash` (`None` if we don't have it).
+pub fn delta_depth<H: Hasher, const N: usize>(
+    store: &mut Store<H, N>,
+    hash: &Name<N>,
+    obj: &mut Object<H, N>,
+) -> io::Result<Option<u8>> {
+    match store.container_of(hash) {
+        None if store.contains(hash) => Ok(Some(0)),
+        None => Ok(None),
+        Some(container) => {
+            if !store.load(&container, obj)? {
+                Ok(None)
+            } else if DeltaKind::from(obj.as_data()[0]) == DeltaKind::Whole {
+                Ok(Some(0))
+            } else {
+                Ok(Some(parse_delta(obj)?.3))
+            }
+        }
+    }
+}
+
+/// The `chaos::Codec` a `Tub` store uses.
+///
+/// A Data object saved with a delta base (see `Store.save_with_base()`) is
+/// stored as a delta container when the base is available, the chain stays
+/// within `MAX_DELTA_DEPTH`, and the container is less than half the full
+/// size.
+///
+/// Given a dictionary (see `dictionary`), other small objects are compressed
+/// with it when that makes them smaller.  Containers name the dictionary they
+/// need, so older dictionaries are loaded from the store as needed.
+///
+/// Given a `Secret` and a cipher, every object is encrypted (in a delta or a
+/// whole container).  Encrypted containers can be read with the secret whatever
+/// the cipher is, so with `Cipher::None` new objects are stored as is but old
+/// ones can still be read.
+#[derive(Clone, Default)]
+pub struct ContainerCodec<const N: usize> {
+    secret: Option<Secret>,
+    cipher: Cipher,
+    dict: Option<Dictionary<N>>,
+}
+
+impl<const N: usize> ContainerCodec<N> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_secret(secret: Secret, cipher: Cipher) -> Self {
+        Self {
+            secret: Some(secret),
+            cipher,
+            dict: None,
+        }
+    }
+
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    pub fn dictionary(&self) -> Option<&Dictionary<N>> {
+        self.dict.as_ref()
+    }
+
+    /// Compress small objects with `dict` from now on.
+    pub fn set_dictionary(&mut self, dict: Option<Dictionary<N>>) {
+        self.dict = dict;
+    }
+
+    // Keys for encrypting new objects (if we do).
+    fn keys(&self) -> Option<Keys> {
+        match (&self.secret, self.cipher) {
+            (Some(secret), Cipher::XChaCha20Poly1305 | Cipher::Aes256Gcm) => {
+                Some(secret.derive(self.cipher))
+            }
+            _ => None,
+        }
+    }
+
+    // The dictionary named by the whole container `obj` (if it names one).
+    fn load_dictionary<H: Hasher>(
+        &self,
+        store: &mut Store<H, N>,
+        obj: &Object<H, N>,
+    ) -> io::Result<Option<Dictionary<N>>> {
+        if Encoding::from_bytes(obj.as_data()).compression() != Compression::ZstdDict {
+            return Ok(None);
+        }
+        let start = ENCODING_LEN + N;
+        let name = Name::from(&obj.as_data()[start..start + N]);
+        if let Some(dict) = &self.dict {
+            if dict.hash() == &name {
+                return Ok(Some(dict.clone()));
+            }
+        }
+        match Dictionary::load(store, &name, &mut Object::new())? {
+            Some(dict) => Ok(Some(dict)),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("need dictionary {} to decode container", name),
+            )),
+        }
+    }
+}
+
+impl<H: Hasher, const N: usize> Codec<H, N> for ContainerCodec<N> {
+    fn held_len(&self) -> usize {
+        ENCODING_LEN + N
+    }
+
+    fn held(&self, data: &[u8]) -> Option<Name<N>> {
+        match Encoding::from_bytes(data).delta.into() {
+            DeltaKind::General | DeltaKind::Document | DeltaKind::Whole => {
+                Some(Name::from(&data[ENCODING_LEN..ENCODING_LEN + N]))
+            }
+            _ => None,
+        }
+    }
+
+    fn encode(
+        &self,
+        store: &mut Store<H, N>,
+        obj: &Object<H, N>,
+        base: Option<&Name<N>>,
+        tmp: &mut Object<H, N>,
+    ) -> io::Result<bool> {
+        let keys = self.keys();
+        let size = obj.info().size();
+        if let Some(base) = base {
+            if obj.kind() == ObjKind::Data && size >= MIN_DELTA_SIZE && base != &obj.hash() {
+                if let Some(depth) = delta_depth(store, base, tmp)? {
+                    if depth < MAX_DELTA_DEPTH && store.load(base, tmp)? {
+                        build_delta(tmp, obj, base, depth + 1, keys.as_ref())?;
+                        if tmp.info().size() < size / 2 {
+                            return Ok(true);
+                        }
+                    }
+                }
+            }
+        }
+        let dict = self.dict.as_ref().filter(|_| is_sample(obj));
+        if let Some(dict) = dict {
+            build_whole(tmp, obj, Some(dict), keys.as_ref())?;
+            if keys.is_some() || tmp.len() < obj.len() {
+                return Ok(true);
+            }
+        }
+        match keys {
+            Some(keys) => {
+                build_whole(tmp, obj, None, Some(&keys))?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn decode(
+        &self,
+        store: &mut Store<H, N>,
+        hash: &Name<N>,
+        obj: &mut Object<H, N>,
+        depth: u8,
+    ) -> io::Result<()> {
+        let container = obj.hash();
+        let target = Name::from(&obj.as_data()[ENCODING_LEN..ENCODING_LEN + N]);
+        if &target != hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Container {} does not contain {}", container, hash),
+            ));
+        }
+        if DeltaKind::from(obj.as_data()[0]) == DeltaKind::Whole {
+            let header = whole_header_len::<N>(obj.as_data());
+            if obj.as_data().len() < header {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Container {} is too short", container),
+                ));
+            }
+            if !matches!(
+                Encoding::from_bytes(obj.as_data()).compression(),
+                Compression::Zstd | Compression::ZstdDict
+            ) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "unsupported container compression",
+                ));
+            }
+            let dict = self.load_dictionary(store, obj)?;
+            let payload = unseal(obj, header, dict.as_ref(), self.secret.as_ref())?;
+            if payload.len() <= INFO_LEN {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Container {} is too short", container),
+                ));
+            }
+            let buf = obj.as_mut_vec();
+            buf.clear();
+            buf.extend_from_slice(hash.as_buf());
+            buf.extend_from_slice(&payload);
+            return Ok(());
+        }
+        let (kind, _, base, _) = parse_delta(obj)?;
+        if depth >= MAX_DELTA_DEPTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Delta chain for {} is deeper than {}",
+                    hash, MAX_DELTA_DEPTH
+                ),
+            ));
+        }
+        let delta = unseal(obj, delta_header_len::<N>(), None, self.secret.as_ref())?;
+        if !store.load_at(&base, obj, depth + 1)? {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Cannot find delta base {} for {}", base, hash),
+            ));
+        }
+        apply_delta(kind, &delta, obj)?;
+        Ok(())
+    }
+}
+
+/// Make a patch container in `patch` that turns the (finalized) `base` object
+/// into the (finalized) `target` object.
+pub fn make_patch<H: Hasher, const N: usize>(
+    base: &Object<H, N>,
+    target: &Object<H, N>,
+    patch: &mut Object<H, N>,
+) -> io::Result<Name<N>> {
+    patch.clear();
+    patch.extend(base.as_data());
+    build_delta(patch, target, &base.hash(), 1, None)
+}
+
+/// Apply the `patch` container to the (finalized) `base` object, putting the
+/// verified target in `obj`.
+pub fn apply_patch<H: Hasher, const N: usize>(
+    patch: &Object<H, N>,
+    base: &Object<H, N>,
+    obj: &mut Object<H, N>,
+) -> io::Result<Name<N>> {
+    let (kind, target, base_hash, _) = parse_delta(patch)?;
+    if base_hash != base.hash() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("patch is against {}, not {}", base_hash, base.hash()),
+        ));
+    }
+    if Encoding::from_bytes(patch.as_data()).cipher() != Cipher::None {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "patch is encrypted",
+        ));
+    }
+    let delta = zstd::stream::decode_all(&patch.as_data()[delta_header_len::<N>()..])?;
+    obj.clear();
+    obj.extend(base.as_data());
+    if apply_delta(kind, &delta, obj)? != target {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "patch result does not match target",
+        ));
+    }
+    Ok(target)
+}
+
+/// Like `import_file()` (for files that fit in a single object), but tries to
+/// store the file as a delta against `base`.
+pub fn import_file_delta<H: Hasher, const N: usize>(
+    store: &mut Store<H, N>,
+    obj: &mut Object<H, N>,
+    mut file: fs::File,
+    size: u64,
+    base: &Name<N>,
+) -> io::Result<Name<N>> {
+    assert!(size > 0 && size <= OBJECT_MAX_SIZE as u64);
+    obj.reset(size as usize, ObjKind::Data as u8);
+    file.read_exact(obj.as_mut_data())?;
+    let hash = obj.finalize();
+    store.save_with_base(obj, Some(base))?;
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chaos::{DefaultName, DefaultObject, DefaultStore};
+    use crate::helpers::TestTempDir;
+    use crate::protocol::Blake3;
+    use getrandom;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_fanout() {
+        let tmp = TestTempDir::new();
+        let file = tmp.create(&["some_file.store"]);
+        let store = DefaultStore::new(file);
+        let obj = DefaultObject::new();
+        let mut fanout = Fanout::new(store, obj);
+        let mut hash = DefaultName::new();
+        let mut cont = DefaultName::new();
+        for _ in 0..1024 {
+            getrandom::fill(hash.as_mut_buf()).unwrap();
+            assert!(fanout.get(&hash).unwrap().is_none());
+            getrandom::fill(cont.as_mut_buf()).unwrap();
+            fanout.insert(hash, cont).unwrap();
+            assert_eq!(fanout.get(&hash).unwrap().unwrap(), cont);
+        }
+        let (store, _obj) = fanout.into_inners();
+        assert_eq!(store.len(), 1024);
+    }
+
+    #[test]
+    fn test_leaf_hashes_chunking() {
+        let mut fixed: LeafHashes<30> = LeafHashes::new();
+        let mut cdc: LeafHashes<30> = LeafHashes::with_chunking(Chunking::FastCdc);
+        let mut hash = DefaultName::new();
+        for size in [OBJECT_MAX_SIZE, OBJECT_MAX_SIZE, 69] {
+            hash.randomize();
+            fixed.append_leaf(hash, size);
+            cdc.append_leaf(hash, size);
+        }
+        let mut buf = Vec::new();
+        fixed.serialize(&mut buf);
+        assert_eq!(buf.len(), 8 + 3 * 30);
+        assert_eq!(buf[7], 0);
+        let fixed2 = LeafHashes::<30>::deserialize(&buf).unwrap();
+        assert_eq!(fixed2.chunking(), Chunking::Fixed);
+        assert_eq!(fixed2.total(), fixed.total());
+        assert_eq!(fixed2.leaf_size(1), OBJECT_MAX_SIZE);
+        assert_eq!(fixed2.leaf_size(2), 69);
+
+        buf.clear();
+        cdc.serialize(&mut buf);
+        assert_eq!(buf.len(), 8 + 3 * 34);
+        assert_eq!(buf[7], 1);
+        let cdc2 = LeafHashes::<30>::deserialize(&buf).unwrap();
+        assert_eq!(cdc2.chunking(), Chunking::FastCdc);
+        assert_eq!(cdc2.total(), 2 * OBJECT_MAX_SIZE as u64 + 69);
+        assert_eq!(cdc2.leaf_size(2), 69);
+        assert!(cdc2.iter().eq(cdc.iter()));
+
+        // Corrupt lists are errors, never panics
+        let bad = |buf: &[u8]| {
+            let err = LeafHashes::<30>::deserialize(buf).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        };
+        bad(&[]);
+        bad(&buf[..7]);
+        bad(&buf[..buf.len() - 1]);
+        let mut copy = buf.clone();
+        copy[7] = 0x7f;
+        bad(&copy);
+        let mut copy = buf.clone();
+        copy[0] ^= 1;
+        bad(&copy);
+        let mut short = Vec::new();
+        fixed.serialize(&mut short);
+        bad(&short[..8 + 2 * 30]);
+        let mut copy = buf.clone();
+        copy[7] = 0;
+        bad(&copy);
+    }
+
+    #[test]
+    fn test_new_pool() {
+        for (threads, expected) in [(1, 1), (2, 2), (8, 8), (32, POOL_MAX_LEAVES)] {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            assert_eq!(pool.install(new_pool::<Blake3, 30>).len(), expected);
+        }
+    }
+
+    #[test]
+    fn test_import_file_cdc() {
+        let tmp = TestTempDir::new();
+        let mut store = DefaultStore::new(tmp.create(&["some_file.store"]));
+        let mut obj = DefaultObject::new();
+        let mut data = vec![0_u8; 2 * OBJECT_MAX_SIZE + 12345];
+        getrandom::fill(&mut data).unwrap();
+        tmp.write(&["a"], &data);
+        data.insert(1000, 69);
+        tmp.write(&["b"], &data);
+
+        let size = data.len() as u64 - 1;
+        let a = import_file(&mut store, &mut obj, tmp.open(&["a"]), size).unwrap();
+        assert_eq!(hash_file(&mut obj, tmp.open(&["a"]), size).unwrap(), a);
+        let before = store.len();
+        let b = import_file(&mut store, &mut obj, tmp.open(&["b"]), size + 1).unwrap();
+        // Only the first leaf (plus the leaf list and root) changed
+        assert_eq!(store.len(), before + 3);
+
+        store.load(&b, &mut obj).unwrap();
+        assert_eq!(obj.info().size(), BigDataHeader::<30>::LEN);
+        let leaves = load_leaves(&mut store, &b).unwrap().unwrap();
+        assert_eq!(leaves.chunking(), Chunking::FastCdc);
+        assert_eq!(leaves.total(), size + 1);
+        let mut file = tmp.create(&["c"]);
+        assert!(restore_file(&mut store, &mut obj, &mut file, &b).unwrap());
+        assert_eq!(tmp.read(&["c"]), data);
+
+        // Fixed chunking changes every leaf
+        let before = store.len();
+        let file = tmp.open(&["b"]);
+        let c = import_file_with(&mut store, &mut obj, file, size + 1, Chunking::Fixed).unwrap();
+        assert_eq!(store.len(), before + 5);
+        let file = tmp.open(&["b"]);
+        assert_eq!(
+            hash_file_with(&mut obj, file, size + 1, Chunking::Fixed).unwrap(),
+            c
+        );
+        let mut file = tmp.create(&["d"]);
+        assert!(restore_file(&mut store, &mut obj, &mut file, &c).unwrap());
+        assert_eq!(tmp.read(&["d"]), data);
+
+        // A file shorter than its size is an error
+        let file = tmp.open(&["a"]);
+        assert!(import_file(&mut store, &mut obj, file, size + 1).is_err());
+        let file = tmp.open(&["a"]);
+        assert!(hash_file_with(&mut obj, file, size + 1, Chunking::Fixed).is_err());
+    }
+
+    #[test]
+    fn test_import_reader() {
+        let tmp = TestTempDir::new();
+        let mut store = DefaultStore::new(tmp.create(&["some_file.store"]));
+        let mut obj = DefaultObject::new();
+        let empty: &[u8] = b"";
+        for chunking in [Chunking::Fixed, Chunking::FastCdc] {
+            assert!(hash_reader(&mut obj, empty, chunking).unwrap().is_none());
+            assert!(
+                import_reader(&mut store, &mut obj, empty, chunking)
+                    .unwrap()
+                    .is_none()
+            );
+        }
+        assert_eq!(store.len(), 0);
+
+        let mut data = vec![0_u8; OBJECT_MAX_SIZE + 1];
+        getrandom::fill(&mut data).unwrap();
+        tmp.write(&["f"], &data);
+        for size in [1, 69, OBJECT_MAX_SIZE, OBJECT_MAX_SIZE + 1] {
+            for chunking in [Chunking::Fixed, Chunking::FastCdc] {
+                let file = tmp.open(&["f"]);
+                let expected = hash_file_with(&mut obj, file, size as u64, chunking).unwrap();
+                let hash = hash_reader(&mut obj, &data[..size], chunking).unwrap();
+                assert_eq!(hash, Some(expected));
+                let hash = import_reader(&mut store, &mut obj, &data[..size], chunking).unwrap();
+                assert_eq!(hash, Some(expected));
+                let mut reader = BigDataReader::open(&mut store, &expected).unwrap().unwrap();
+                let mut buf = Vec::new();
+                reader.read_to_end(&mut buf).unwrap();
+                assert_eq!(buf, &data[..size]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bigdata_header() {
+        let mut header = BigDataHeader::<30> {
+            total: 69 * OBJECT_MAX_SIZE as u64,
+            chunking: Chunking::Fixed,
+            merkle: DefaultName::new(),
+            leaves: DefaultName::new(),
+        };
+        header.merkle.randomize();
+        header.leaves.randomize();
+        for chunking in [Chunking::Fixed, Chunking::FastCdc] {
+            header.chunking = chunking;
+            let mut buf = Vec::new();
+            header.serialize(&mut buf);
+            assert_eq!(buf.len(), BigDataHeader::<30>::LEN);
+            assert_eq!(buf[7], chunking as u8 | MERKLE_LAYOUT);
+            assert!(BigDataHeader::<30>::is_header(&buf));
+            assert_eq!(BigDataHeader::<30>::deserialize(&buf).unwrap(), header);
+            assert!(!BigDataHeader::<30>::is_header(&buf[1..]));
+            buf[7] = 0x82;
+            assert!(BigDataHeader::<30>::deserialize(&buf).is_err());
+        }
+
+        // A flat leaf list is never a header
+        let mut leaves: LeafHashes<30> = LeafHashes::with_chunking(Chunking::FastCdc);
+        leaves.append_leaf(header.leaves, 69);
+        let mut buf = Vec::new();
+        leaves.serialize(&mut buf);
+        buf.extend_from_slice(&[0; 26]);
+        assert_eq!(buf.len(), BigDataHeader::<30>::LEN);
+        assert!(!BigDataHeader::<30>::is_header(&buf));
+    }
+
+    #[test]
+    fn test_interior() {
+        let mut node = Interior::<30> {
+            chunking: Chunking::FastCdc,
+            children: Vec::new(),
+        };
+        for size in [OBJECT_MAX_SIZE as u64 * 4096, 69] {
+            let mut hash = DefaultName::new();
+            hash.randomize();
+            node.children.push((size, hash));
+        }
+        let mut buf = Vec::new();
+        node.serialize(&mut buf);
+        assert_eq!(buf.len(), 8 + 2 * 38);
+        assert_eq!(buf[7], 1 | INTERIOR_LAYOUT);
+        assert!(Interior::<30>::is_interior(&buf));
+        assert!(!BigDataHeader::<30>::is_header(&buf));
+        assert_eq!(Interior::<30>::deserialize(&buf).unwrap(), node);
+        assert!(Interior::<30>::deserialize(&buf[..buf.len() - 1]).is_err());
+        buf[0] ^= 1;
+        assert!(Interior::<30>::deserialize(&buf).is_err());
+
+        // Neither leaf lists nor headers are interior nodes
+        let mut buf = Vec::new();
+        LeafHashes::<30>::new().serialize(&mut buf);
+        assert!(!Interior::<30>::is_interior(&buf));
+        buf[7] = MERKLE_LAYOUT | INTERIOR_LAYOUT;
+        assert!(!Interior::<30>::is_interior(&buf));
+    }
+
+    #[test]
+    fn test_leaf_tree() {
+        let tmp = TestTempDir::new();
+        let mut store = DefaultStore::new(tmp.create(&["some_file.store"]));
+        let mut obj = DefaultObject::new();
+        for fanout in [2, 3, 5] {
+            for count in [1, 2, 3, 4, 5, 9, 10, 26, 27, 28] {
+                let mut data = Vec::new();
+                let mut tree: LeafTreeEncoder<Blake3, 30> =
+                    LeafTreeEncoder::with_fanout(Chunking::FastCdc, fanout);
+                let mut hasher: LeafTreeEncoder<Blake3, 30> =
+                    LeafTreeEncoder::with_fanout(Chunking::FastCdc, fanout);
+                let mut expected: LeafHashes<30> = LeafHashes::with_chunking(Chunking::FastCdc);
+                for _ in 0..count {
+                    let hash = obj.randomize(true);
+                    let size = obj.info().size();
+                    obj.set_kind(ObjKind::Data);
+                    let hash = if store.contains(&hash) {
+                        hash
+                    } else {
+                        obj.finalize()
+                    };
+                    store.save(&obj).unwrap();
+                    data.extend_from_slice(obj.as_data());
+                    tree.append_leaf(Some(&mut store), hash, size).unwrap();
+                    hasher.append_leaf(None, hash, size).unwrap();
+                    expected.append_leaf(hash, size);
+                }
+                let root = tree.finish(Some(&mut store)).unwrap();
+                assert_eq!(hasher.finish(None).unwrap(), root);
+
+                let leaves = load_leaves(&mut store, &root).unwrap().unwrap();
+                assert!(leaves.iter().eq(expected.iter()));
+                assert_eq!(leaves.total(), data.len() as u64);
+
+                let name = format!("{fanout}-{count}");
+                let mut file = tmp.create(&[&name]);
+                assert!(restore_file(&mut store, &mut obj, &mut file, &root).unwrap());
+                assert_eq!(tmp.read(&[&name]), data);
+
+                let mut reader = BigDataReader::open(&mut store, &root).unwrap().unwrap();
+                assert_eq!(reader.len(), data.len() as u64);
+                for start in [data.len() - 1, 0, data.len() / 2, 7] {
+                    reader.seek(io::SeekFrom::Start(start as u64)).unwrap();
+                    let mut buf = Vec::new();
+                    reader.read_to_end(&mut buf).unwrap();
+                    assert_eq!(buf, &data[start..]);
+                }
+
+                let end = data.len() as u64;
+                let proof = crate::merkle::BigDataProof::new(&mut store, &root, end - 1, end)
+                    .unwrap()
+                    .unwrap();
+                assert!(proof.verify::<Blake3>(&root).is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn test_leaf_tree_depth() {
+        let tmp = TestTempDir::new();
+        let mut store = DefaultStore::new(tmp.create(&["some_file.store"]));
+        let mut tree: LeafTreeEncoder<Blake3, 30> =
+            LeafTreeEncoder::with_fanout(Chunking::Fixed, 2);
+        let mut hash = DefaultName::new();
+        for _ in 0..8 {
+            hash.randomize();
+            tree.append_leaf(Some(&mut store), hash, OBJECT_MAX_SIZE)
+                .unwrap();
+        }
+        let root = tree.finish(Some(&mut store)).unwrap();
+        // 4 leaf lists, 2 + 1 interior nodes, and the header
+        assert_eq!(store.len(), 8);
+
+        // Walking checks the tree against the header
+        let mut obj = DefaultObject::new();
+        store.load(&root, &mut obj).unwrap();
+        let header = BigDataHeader::<30>::deserialize(obj.as_data()).unwrap();
+        assert_eq!(header.total, 8 * OBJECT_MAX_SIZE as u64);
+        let mut walker = LeafWalker::new(&mut store, &root).unwrap().unwrap();
+        let mut count = 0;
+        while walker.next_leaf(&mut store).unwrap().is_some() {
+            count += 1;
+        }
+        assert_eq!(count, 8);
+
+        let mut bad = header.clone();
+        bad.total += 1;
+        obj.clear();
+        bad.serialize(obj.as_mut_vec());
+        let bad_root = obj.finalize_with_kind(ObjKind::BigData as u8);
+        store.save(&obj).unwrap();
+        assert!(load_leaves(&mut store, &bad_root).is_err());
+
+        // A corrupt leaf list is an error from the walker
+        obj.clear();
+        obj.as_mut_vec()
+            .extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0x7f]);
+        let corrupt = obj.finalize_with_kind(ObjKind::BigData as u8);
+        store.save(&obj).unwrap();
+        let mut bad = header.clone();
+        bad.leaves = corrupt;
+        obj.clear();
+        bad.serialize(obj.as_mut_vec());
+        let bad_root = obj.finalize_with_kind(ObjKind::BigData as u8);
+        store.save(&obj).unwrap();
+        let err = load_leaves(&mut store, &bad_root).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut missing = header.clone();
+        missing.leaves.randomize();
+        obj.clear();
+        missing.serialize(obj.as_mut_vec());
+        let missing_root = obj.finalize_with_kind(ObjKind::BigData as u8);
+        store.save(&obj).unwrap();
+        let err = load_leaves(&mut store, &missing_root).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(
+            load_leaves(&mut store, &DefaultName::new())
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    // Mostly zeros, with a few runs of random data
+    fn sparse_data(size: usize) -> Vec<u8> {
+        let mut data = vec![0_u8; size];
+        for start in [0, 5000, size / 2, size - 100] {
+            getrandom::fill(&mut data[start..start + 100]).unwrap();
+        }
+        data
+    }
+
+    #[test]
+    fn test_restore_sparse() {
+        use std::os::unix::fs::MetadataExt;
+        let tmp = TestTempDir::new();
+        let mut store = DefaultStore::new(tmp.create(&["some_file.store"]));
+        let mut obj = DefaultObject::new();
+        let mut data = sparse_data(OBJECT_MAX_SIZE + (1 << 21));
+        data.extend(vec![0; 12345]); // Trailing hole
+        tmp.write(&["src"], &data);
+        let file = fs::File::open(tmp.build(&["src"])).unwrap();
+        let root = import_file_with(
+            &mut store,
+            &mut obj,
+            file,
+            data.len() as u64,
+            Chunking::FastCdc,
+        )
+        .unwrap();
+
+        for (sparse, sync) in [(false, false), (true, false), (true, true)] {
+            let name = format!("{sparse}-{sync}");
+            let pb = tmp.build(&[&name]);
+            let mut file = fs::File::create(&pb).unwrap();
+            let opts = RestoreOptions { sparse, sync };
+            assert!(restore_file_with(&mut store, &mut obj, &mut file, &root, opts).unwrap());
+            assert_eq!(tmp.read(&[&name]), data);
+            let meta = fs::metadata(&pb).unwrap();
+            assert_eq!(meta.len(), data.len() as u64);
+            if sparse {
+                assert!(meta.blocks() * 512 < data.len() as u64 / 2);
+            }
+        }
+
+        // Can't seek with O_APPEND, so this falls back to writing every byte
+        let mut file = tmp.create(&["append"]);
+        let opts = RestoreOptions {
+            sparse: true,
+            sync: false,
+        };
+        assert!(restore_file_with(&mut store, &mut obj, &mut file, &root, opts).unwrap());
+        assert_eq!(tmp.read(&["append"]), data);
+
+        // Small Data objects too
+        let small = sparse_data(OBJECT_MAX_SIZE);
+        obj.clear();
+        obj.extend(&small);
+        let hash = obj.finalize_with_kind(ObjKind::Data as u8);
+        store.save(&obj).unwrap();
+        let mut file = fs::File::create(tmp.build(&["small"])).unwrap();
+        assert!(restore_file_with(&mut store, &mut obj, &mut file, &hash, opts).unwrap());
+        assert_eq!(tmp.read(&["small"]), small);
+    }
+
+    #[test]
+    fn test_restore_length() {
+        let tmp = TestTempDir::new();
+        let mut store = DefaultStore::new(tmp.create(&["some_file.store"]));
+        let mut obj = DefaultObject::new();
+        let leaf = obj.randomize(true);
+        let size = obj.as_data().len();
+        store.save(&obj).unwrap();
+
+        // A flat root that lies about the size of its leaf
+        let mut leaves: LeafHashes<30> = LeafHashes::with_chunking(Chunking::FastCdc);
+        leaves.append_leaf(leaf, size + 1);
+        obj.clear();
+        leaves.serialize(obj.as_mut_vec());
+        let root = obj.finalize_with_kind(ObjKind::BigData as u8);
+        store.save(&obj).unwrap();
+        let mut file = fs::File::create(tmp.build(&["out"])).unwrap();
+        let err = restore_file(&mut store, &mut obj, &mut file, &root).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut missing = DefaultName::new();
+        missing.randomize();
+        assert!(!restore_file(&mut store, &mut obj, &mut file, &missing).unwrap());
+    }
+
+    // The filesystem type (`statfs().f_type`) of `path`.
+    fn fs_type(path: &std::path::Path) -> i64 {
+        use std::os::unix::ffi::OsStrExt;
+        let cpath = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+        // SAFETY: `cpath` is a valid NUL terminated string and `buf` is a
+        // properly sized statfs struct, both outliving the call.
+        let mut buf: libc::statfs = unsafe { std::mem::zeroed() };
+        assert_eq!(unsafe { libc::statfs(cpath.as_ptr(), &mut buf) }, 0);
+        buf.f_type as i64
+    }
+
+    #[test]
+    fn test_reflink() {
+        let tmp = TestTempDir::new();
+        tmp.write(&["a"], b"Hello reflinks");
+        let src = fs::File::open(tmp.build(&["a"])).unwrap();
+        let dst = fs::File::create(tmp.build(&["b"])).unwrap();
+        let cloned = reflink(&src, &dst).unwrap();
+        match fs_type(tmp.path()) {
+            libc::BTRFS_SUPER_MAGIC => assert!(cloned),
+            libc::TMPFS_MAGIC | libc::EXT4_SUPER_MAGIC | libc::OVERLAYFS_SUPER_MAGIC => {
+                assert!(!cloned)
+            }
+            _ => {} // XFS depends on how it was made, others we don't know
+        }
+        if cloned {
+            assert_eq!(tmp.read(&["b"]), b"Hello reflinks");
+        } else {
+            assert!(tmp.read(&["b"]).is_empty());
+        }
+
+        // A destination we can't write to is an error, not "unsupported"
+        let dst = fs::File::open(tmp.build(&["b"])).unwrap();
+        assert!(reflink(&src, &dst).is_err());
+    }
+
+    #[test]
+    fn test_bigdata_reader() {
+        let tmp = TestTempDir::new();
+        let mut store = DefaultStore::new(tmp.create(&["some_file.store"]));
+        let mut obj = DefaultObject::new();
+        let mut data = vec![0_u8; 10000];
+        getrandom::fill(&mut data).unwrap();
+        let mut leaves: LeafHashes<30> = LeafHashes::with_chunking(Chunking::FastCdc);
+        let mut start = 0;
+        for size in [1000, 4000, 1, 2999, 2000] {
+            obj.clear();
+            obj.extend(&data[start..start + size]);
+            leaves.append_leaf(obj.finalize_with_kind(ObjKind::Data as u8), size);
+            store.save(&obj).unwrap();
+            start += size;
+        }
+        obj.clear();
+        leaves.serialize(obj.as_mut_vec());
+        let root = obj.finalize_with_kind(ObjKind::BigData as u8);
+        store.save(&obj).unwrap();
+
+        let mut reader = BigDataReader::open(&mut store, &root).unwrap().unwrap();
+        assert_eq!(reader.len(), 10000);
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, data);
+        for (start, size) in [(0, 10), (995, 10), (4999, 3), (5000, 5000), (9999, 1)] {
+            let mut buf = vec![0; size];
+            reader.seek(io::SeekFrom::Start(start as u64)).unwrap();
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, &data[start..start + size]);
+        }
+        assert_eq!(reader.seek(io::SeekFrom::End(-3)).unwrap(), 9997);
+        assert_eq!(reader.seek(io::SeekFrom::Current(-7)).unwrap(), 9990);
+        let mut buf = [0; 16];
+        assert_eq!(reader.read(&mut buf).unwrap(), 10);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        assert!(reader.seek(io::SeekFrom::Current(-10001)).is_err());
+        reader.seek(io::SeekFrom::Start(20000)).unwrap();
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+
+        // A small file is a single Data object
+        let leaf = *leaves.iter().next().unwrap();
+        let mut reader = BigDataReader::open(&mut store, &leaf).unwrap().unwrap();
+        assert_eq!(reader.len(), 1000);
+        reader.seek(io::SeekFrom::Start(990)).unwrap();
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, &data[990..1000]);
+
+        assert!(
+            BigDataReader::open(&mut store, &DefaultName::new())
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    fn delta_store(tmp: &TestTempDir) -> DefaultStore {
+        let mut store = DefaultStore::new(tmp.create(&["some_file.store"]));
+        store.set_codec(Arc::new(ContainerCodec::new()));
+        store
+    }
+
+    #[test]
+    fn test_delta_storage() {
+        let tmp = TestTempDir::new();
+        let mut store = delta_store(&tmp);
+        let mut obj = DefaultObject::new();
+        let mut data = vec![0_u8; 65536];
+        getrandom::fill(&mut data).unwrap();
+
+        let mut versions = Vec::new();
+        obj.clear();
+        obj.extend(&data);
+        let mut base = obj.finalize_with_kind(ObjKind::Data as u8);
+        store.save(&obj).unwrap();
+        versions.push((base, data.clone()));
+        for i in 0..(MAX_DELTA_DEPTH as usize + 3) {
+            data[i * 1000] ^= 1;
+            data.extend_from_slice(b"another line\n");
+            obj.clear();
+            obj.extend(&data);
+            let hash = obj.finalize_with_kind(ObjKind::Data as u8);
+            assert!(store.save_with_base(&obj, Some(&base)).unwrap());
+            assert!(store.contains(&hash));
+            versions.push((hash, data.clone()));
+            base = hash;
+        }
+        // The chain hits MAX_DELTA_DEPTH then starts over with a full object
+        let deltas = versions
+            .iter()
+            .filter(|(hash, _)| store.container_of(hash).is_some())
+            .count();
+        assert_eq!(deltas, versions.len() - 2);
+        assert_eq!(store.len(), versions.len());
+        let check = |store: &mut DefaultStore, obj: &mut DefaultObject| {
+            let mut tmp = DefaultObject::new();
+            for (i, (hash, data)) in versions.iter().enumerate() {
+                let depth = delta_depth(store, hash, &mut tmp).unwrap();
+                let expected = i % (MAX_DELTA_DEPTH as usize + 1);
+                assert_eq!(depth, Some(expected as u8));
+                assert!(store.load(hash, obj).unwrap());
+                assert_eq!(obj.as_data(), &data[..]);
+                assert_eq!(obj.kind(), ObjKind::Data);
+            }
+        };
+        check(&mut store, &mut obj);
+
+        // The store finds the delta containers again on its own
+        store.reindex(&mut obj).unwrap();
+        check(&mut store, &mut obj);
+        let idx = tmp.create(&["some_file.idx"]);
+        store.reindex_from(&mut obj, idx).unwrap();
+        let idx = tmp.open(&["some_file.idx"]);
+        store.reindex_from(&mut obj, idx).unwrap();
+        check(&mut store, &mut obj);
+
+        // Saving again is a no-op
+        let before = store.len();
+        store.load(&versions[3].0, &mut obj).unwrap();
+        assert!(!store.save_with_base(&obj, Some(&versions[0].0)).unwrap());
+        assert!(!store.save(&obj).unwrap());
+        assert_eq!(store.len(), before);
+
+        // Unrelated content is stored in full
+        getrandom::fill(obj.as_mut_data()).unwrap();
+        let hash = obj.finalize();
+        store.save_with_base(&obj, Some(&versions[0].0)).unwrap();
+        assert!(store.contains(&hash));
+        assert_eq!(store.container_of(&hash), None);
+
+        // So is anything without a base we have
+        data.extend_from_slice(b"one more line\n");
+        obj.clear();
+        obj.extend(&data);
+        let hash = obj.finalize_with_kind(ObjKind::Data as u8);
+        store
+            .save_with_base(&obj, Some(&DefaultName::new()))
+            .unwrap();
+        assert_eq!(store.container_of(&hash), None);
+
+        let missing = DefaultName::new();
+        assert!(!store.load(&missing, &mut obj).unwrap());
+        assert_eq!(delta_depth(&mut store, &missing, &mut obj).unwrap(), None);
+    }
+
+    #[test]
+    fn test_delta_storage_errors() {
+        let tmp = TestTempDir::new();
+        let mut store = delta_store(&tmp);
+        let mut base = DefaultObject::new();
+        let mut obj = DefaultObject::new();
+        let text = "Some line that is in the document\n".repeat(100);
+        base.extend(text.as_bytes());
+        base.finalize_with_kind(ObjKind::Data as u8);
+        obj.extend(text.replace("Some", "Any").as_bytes());
+        let hash = obj.finalize_with_kind(ObjKind::Data as u8);
+
+        // A container whose base is missing is an error, not a panic
+        let mut patch = DefaultObject::new();
+        make_patch(&base, &obj, &mut patch).unwrap();
+        store.save(&patch).unwrap();
+        assert_eq!(store.container_of(&hash), Some(patch.hash()));
+        let err = store.load(&hash, &mut obj).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        // A delta that does not reconstruct its Target is an error too
+        let mut other = DefaultObject::new();
+        other.extend(text.replace("line", "LINE").as_bytes());
+        other.finalize_with_kind(ObjKind::Data as u8);
+        store.save(&other).unwrap();
+        let mut bogus = DefaultObject::new();
+        make_patch(&other, &base, &mut bogus).unwrap();
+        let data = bogus.as_mut_data();
+        data[ENCODING_LEN..ENCODING_LEN + 30].copy_from_slice(hash.as_buf());
+        bogus.finalize_with_kind(ObjKind::Stream as u8);
+        let tmp2 = TestTempDir::new();
+        let mut store2 = delta_store(&tmp2);
+        store2.save(&other).unwrap();
+        store2.save(&bogus).unwrap();
+        let err = store2.load(&hash, &mut obj).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_document_delta_storage() {
+        let tmp = TestTempDir::new();
+        let mut store = delta_store(&tmp);
+        let mut obj = DefaultObject::new();
+        let mut text = String::new();
+        for i in 0..2000 {
+            text.push_str(&format!("This is line {} of the document\n", i));
+        }
+        obj.clear();
+        obj.extend(text.as_bytes());
+        let base = obj.finalize_with_kind(ObjKind::Data as u8);
+        store.save(&obj).unwrap();
+
+        let text2 = text.replace("line 1000 ", "LINE 1000 ");
+        obj.clear();
+        obj.extend(text2.as_bytes());
+        let hash = obj.finalize_with_kind(ObjKind::Data as u8);
+        store.save_with_base(&obj, Some(&base)).unwrap();
+        let container = store.container_of(&hash).unwrap();
+        store.load(&container, &mut obj).unwrap();
+        assert_eq!(parse_delta(&obj).unwrap().0, DeltaKind::Document);
+        assert!(store.load(&hash, &mut obj).unwrap());
+        assert_eq!(obj.as_data(), text2.as_bytes());
+    }
+
+    #[test]
+    fn test_patch() {
+        let mut base = DefaultObject::new();
+        let mut target = DefaultObject::new();
+        let mut patch = DefaultObject::new();
+        let mut obj = DefaultObject::new();
+        for (a, b) in [
+            (&b"foo\nbar\nbaz\n"[..], &b"foo\nbaz\nbar\n"[..]),
+            (&b"\x00binary\x01"[..], &b"\x00binary\x02"[..]),
+        ] {
+            base.clear();
+            base.extend(a);
+            base.finalize_with_kind(ObjKind::Data as u8);
+            target.clear();
+            target.extend(b);
+            let hash = target.finalize_with_kind(ObjKind::Data as u8);
+            make_patch(&base, &target, &mut patch).unwrap();
+            assert_eq!(patch.kind(), ObjKind::Stream);
+            assert_eq!(apply_patch(&patch, &base, &mut obj).unwrap(), hash);
+            assert_eq!(obj.as_data(), b);
+            assert_eq!(obj.kind(), ObjKind::Data);
+
+            // Wrong base
+            assert!(apply_patch(&patch, &target, &mut obj).is_err());
+        }
+        assert!(apply_patch(&base, &base, &mut obj).is_err());
+    }
+
+    #[test]
+    fn test_rfo_empty() {
+        let obj = DefaultObject::new();
+        let mut rfo = ReadFrom::new(obj);
+        let mut buf = [0; 69];
+        assert_eq!(rfo.read(&mut buf).unwrap(), 0);
+        assert_eq!(buf, [0; 69]);
+    }
+
+    #[test]
+    fn test_rfo_42() {
+        let mut buf = [0; 69];
+        let mut obj = DefaultObject::new();
+        let mut data = [0; 42];
+        getrandom::fill(&mut data).unwrap();
+        obj.as_mut_vec().extend_from_slice(&data);
+        let mut rfo = ReadFrom::new(obj);
+        assert_eq!(rfo.read(&mut buf).unwrap(), 42);
+        assert_eq!(buf[0..42], data);
+        assert_eq!(buf[42..69], [0; 27]);
+        buf.fill(0);
+        assert_eq!(rfo.read(&mut buf).unwrap(), 0);
+        assert_eq!(buf, [0; 69]);
+    }
+
+    #[test]
+    fn test_rfo_max() {
+        let mut obj = DefaultObject::new();
+        obj.reset(OBJECT_MAX_SIZE, 0);
+        getrandom::fill(obj.as_mut_data()).unwrap();
+        let mut rfo = ReadFrom::new(obj);
+        let mut buf = [0; 69];
+        let mut output = Vec::new();
+        while let Ok(s) = rfo.read(&mut buf) {
+            if s == 0 {
+                break;
+            }
+            output.extend_from_slice(&buf[0..s]);
+        }
+        buf.fill(0);
+        assert_eq!(rfo.read(&mut buf).unwrap(), 0);
+        assert_eq!(buf, [0; 69]);
+        let obj = rfo.into_inner();
+        assert_eq!(&output, obj.as_data());
+    }
+
+    #[test]
+    fn test_wto_till_full() {
+        let obj = DefaultObject::new();
+        let mut wto = WriteTo::new(obj);
+        let mut buf = [0; 69];
+        getrandom::fill(&mut buf).unwrap();
+        let mut expected = Vec::new();
+        while let Ok(s) = wto.write(&buf) {
+            if s == 0 {
+                break;
+            }
+            expected.extend_from_slice(&buf[0..s]);
+            getrandom::fill(&mut buf).unwrap();
+        }
+        assert_eq!(expected.len(), OBJECT_MAX_SIZE);
+        let obj = wto.into_inner();
+        assert_eq!(obj.as_data(), &expected[..]);
+    }
+
+    #[test]
+    fn test_zstd_roundtrip() {
+        let dst = DefaultObject::new();
+        let wto = WriteTo::new(dst);
+        let mut enc: zstd::Encoder<'static, WriteTo<Blake3, 30>> =
+            zstd::Encoder::new(wto, 0).unwrap();
         let mut obj = DefaultObject::new();
         let mut expected = Vec::new();
         for _ in 0..100 {
@@ -608,12 +3256,512 @@ mod tests {
         assert!(inner.is_valid());
 
         let mut dec = Decoder::new(inner).unwrap();
-        for i in 0..100 {
+        for buf in expected.iter() {
             dec.read_next(&mut obj).unwrap();
             assert!(obj.is_valid());
-            assert_eq!(obj.as_buf(), &expected[i]);
+            assert_eq!(obj.as_buf(), buf);
         }
         assert!(!dec.read_next(&mut obj).unwrap());
         assert_eq!(obj.as_buf(), &[0; 34]);
     }
+
+    #[test]
+    fn test_container_encrypted() {
+        let secret = Secret::generate();
+        for cipher in [Cipher::XChaCha20Poly1305, Cipher::Aes256Gcm] {
+            let mut enc =
+                Encoder::new_encrypted(DefaultObject::new(), 0, secret.derive(cipher)).unwrap();
+            let mut obj = DefaultObject::new();
+            let mut expected: Vec<Vec<u8>> = Vec::new();
+            for _ in 0..100 {
+                obj.randomize(true);
+                expected.push(Vec::from(obj.as_buf()));
+                enc.write_next(&obj).unwrap();
+            }
+            let inner: DefaultObject = enc.finish().unwrap();
+            assert!(inner.is_valid());
+            assert_eq!(&inner.as_data()[0..ENCODING_LEN], &[0, 1, cipher as u8]);
+            let copy = Vec::from(inner.as_buf());
+
+            // Needs the right key
+            assert!(Decoder::new(inner).is_err());
+            let mut inner = DefaultObject::new();
+            inner.as_mut_vec().clone_from(&copy);
+            let other = Secret::generate().derive(cipher);
+            assert!(Decoder::new_encrypted(inner, &other).is_err());
+
+            // Flipping any bit in the nonce, ciphertext, or tag is detected
+            let keys = secret.derive(cipher);
+            let size = copy.len();
+            let start = 30 + INFO_LEN + ENCODING_LEN;
+            for i in [start, start + cipher.nonce_len(), size / 2, size - 1] {
+                let mut inner = DefaultObject::new();
+                inner.as_mut_vec().clone_from(&copy);
+                inner.as_mut_buf()[i] ^= 1;
+                let err = Decoder::new_encrypted(inner, &keys).err().unwrap();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+                assert_eq!(err.to_string(), "container failed authentication");
+            }
+
+            let mut inner = DefaultObject::new();
+            inner.as_mut_vec().clone_from(&copy);
+            let mut dec = Decoder::new_encrypted(inner, &keys).unwrap();
+            for buf in expected.iter() {
+                assert!(dec.read_next(&mut obj).unwrap());
+                assert_eq!(obj.as_buf(), buf);
+            }
+            assert!(!dec.read_next(&mut obj).unwrap());
+        }
+    }
+
+    fn sealed_store(tmp: &TestTempDir, secret: Secret, cipher: Cipher) -> DefaultStore {
+        let mut store = DefaultStore::new(tmp.create(&["some_file.store"]));
+        store.set_codec(Arc::new(ContainerCodec::with_secret(secret, cipher)));
+        store
+    }
+
+    #[test]
+    fn test_sealed_containers() {
+        let secret = Secret::generate();
+        let mut base = DefaultObject::new();
+        let mut obj = DefaultObject::new();
+        let mut tmp = DefaultObject::new();
+        let text = "Some line that is in the document\n".repeat(100);
+        base.extend(text.as_bytes());
+        let base_hash = base.finalize_with_kind(ObjKind::Data as u8);
+        obj.extend(text.replace("Some", "Any").as_bytes());
+        obj.finalize_with_kind(ObjKind::Data as u8);
+        let expected = Vec::from(obj.as_buf());
+        for cipher in [Cipher::XChaCha20Poly1305, Cipher::Aes256Gcm] {
+            let keys = secret.derive(cipher);
+            for delta in [false, true] {
+                let header = if delta {
+                    tmp.clear();
+                    tmp.extend(base.as_data());
+                    build_delta(&mut tmp, &obj, &base_hash, 1, Some(&keys)).unwrap();
+                    delta_header_len::<30>()
+                } else {
+                    build_whole(&mut tmp, &obj, None, Some(&keys)).unwrap();
+                    whole_header_len::<30>(tmp.as_data())
+                };
+                assert!(tmp.is_valid());
+                assert_eq!(tmp.as_data()[1..ENCODING_LEN], [1, cipher as u8]);
+                let copy = Vec::from(tmp.as_buf());
+                let mut unsealed = DefaultObject::new();
+                let reset = |o: &mut DefaultObject| o.as_mut_vec().clone_from(&copy);
+
+                reset(&mut unsealed);
+                assert!(unseal(&mut unsealed, header, None, Some(&secret)).is_ok());
+
+                // Needs the key
+                reset(&mut unsealed);
+                let err = unseal(&mut unsealed, header, None, None).unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+                reset(&mut unsealed);
+                assert!(unseal(&mut unsealed, header, None, Some(&Secret::generate())).is_err());
+
+                // The header is the AAD: Target (and Base, Depth) still parse
+                // after a bit flip, but fail authentication
+                let mut spots = vec![ENCODING_LEN, ENCODING_LEN + 29];
+                if delta {
+                    spots.extend([ENCODING_LEN + 30, header - 1]);
+                }
+                for i in spots {
+                    reset(&mut unsealed);
+                    unsealed.as_mut_data()[i] ^= 1;
+                    if delta {
+                        assert!(parse_delta(&unsealed).is_ok());
+                    }
+                    let err = unseal(&mut unsealed, header, None, Some(&secret)).unwrap_err();
+                    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+                    assert_eq!(err.to_string(), "container failed authentication");
+                }
+            }
+        }
+
+        // Only plain patches can be applied
+        let keys = secret.derive(Cipher::Aes256Gcm);
+        tmp.clear();
+        tmp.extend(base.as_data());
+        build_delta(&mut tmp, &obj, &base_hash, 1, Some(&keys)).unwrap();
+        let err = apply_patch(&tmp, &base, &mut obj).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert_eq!(obj.as_buf(), &expected[..]);
+    }
+
+    #[test]
+    fn test_sealed_storage() {
+        for cipher in [Cipher::XChaCha20Poly1305, Cipher::Aes256Gcm] {
+            let tmp = TestTempDir::new();
+            let secret = Secret::generate();
+            let copy = Secret::from(secret.as_buf()).unwrap();
+            let mut store = sealed_store(&tmp, secret, cipher);
+            let mut obj = DefaultObject::new();
+            let mut text = String::new();
+            for i in 0..2000 {
+                text.push_str(&format!("This is line {} of the secret document\n", i));
+            }
+            obj.extend(text.as_bytes());
+            let base = obj.finalize_with_kind(ObjKind::Data as u8);
+            assert!(store.save(&obj).unwrap());
+            let text2 = text.replace("line 1000 ", "LINE 1000 ");
+            obj.clear();
+            obj.extend(text2.as_bytes());
+            let hash = obj.finalize_with_kind(ObjKind::Data as u8);
+            assert!(store.save_with_base(&obj, Some(&base)).unwrap());
+
+            // Every object is in a container, and none of the text is visible
+            let whole = store.container_of(&base).unwrap();
+            let delta = store.container_of(&hash).unwrap();
+            assert_ne!(whole, delta);
+            store.load(&delta, &mut obj).unwrap();
+            assert_eq!(parse_delta(&obj).unwrap().3, 1);
+            let raw = tmp.read(&["some_file.store"]);
+            assert!(!raw.windows(12).any(|w| w == b"This is line"));
+
+            let check = |store: &mut DefaultStore, obj: &mut DefaultObject| {
+                assert!(store.load(&base, obj).unwrap());
+                assert_eq!(obj.as_data(), text.as_bytes());
+                assert!(store.load(&hash, obj).unwrap());
+                assert_eq!(obj.as_data(), text2.as_bytes());
+            };
+            check(&mut store, &mut obj);
+            store.reindex(&mut obj).unwrap();
+            check(&mut store, &mut obj);
+
+            // Reading needs the secret, but not the same cipher
+            let file = tmp.open(&["some_file.store"]);
+            let mut store = DefaultStore::new(file);
+            store.set_codec(Arc::new(ContainerCodec::with_secret(copy, Cipher::None)));
+            store.reindex(&mut obj).unwrap();
+            check(&mut store, &mut obj);
+            let file = tmp.open(&["some_file.store"]);
+            let mut store = DefaultStore::new(file);
+            store.set_codec(Arc::new(ContainerCodec::new()));
+            store.reindex(&mut obj).unwrap();
+            let err = store.load(&base, &mut obj).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        }
+    }
+
+    #[test]
+    fn test_dictionary_storage() {
+        let files: Vec<Vec<u8>> = (0..500).map(tiny_file).collect();
+        let samples: Vec<&[u8]> = files.iter().map(|f| f.as_slice()).collect();
+        let mut obj = DefaultObject::new();
+        let dict = Dictionary::train(&samples, 4096, &mut obj)
+            .unwrap()
+            .unwrap();
+        for secret in [None, Some(Secret::generate())] {
+            let tmp = TestTempDir::new();
+            let mut codec = match &secret {
+                Some(secret) => ContainerCodec::with_secret(secret.clone(), Cipher::Aes256Gcm),
+                None => ContainerCodec::new(),
+            };
+            codec.set_dictionary(Some(dict.clone()));
+            let mut store = DefaultStore::new(tmp.create(&["some_file.store"]));
+            store.set_codec(Arc::new(codec));
+            dict.save(&mut store, &mut obj).unwrap();
+            assert!(store.contains(dict.hash()));
+
+            let mut hashes = Vec::new();
+            for i in 1000..1100 {
+                obj.clear();
+                obj.extend(&small_file(i));
+                hashes.push(obj.finalize_with_kind(ObjKind::Data as u8));
+                assert!(store.save(&obj).unwrap());
+            }
+            let check = |store: &mut DefaultStore, obj: &mut DefaultObject| {
+                for (i, hash) in (1000..1100).zip(hashes.iter()) {
+                    let container = store.container_of(hash).unwrap();
+                    assert!(store.load(&container, obj).unwrap());
+                    assert_eq!(obj.as_data()[1], Compression::ZstdDict as u8);
+                    assert!(store.load(hash, obj).unwrap());
+                    assert_eq!(obj.as_data(), &small_file(i)[..]);
+                }
+            };
+            check(&mut store, &mut obj);
+
+            // The dictionary is loaded from the store when the codec doesn't
+            // have it (say, after training a new one)
+            let file = tmp.open(&["some_file.store"]);
+            let mut store = DefaultStore::new(file);
+            store.set_codec(Arc::new(match &secret {
+                Some(secret) => ContainerCodec::with_secret(secret.clone(), Cipher::None),
+                None => ContainerCodec::new(),
+            }));
+            store.reindex(&mut obj).unwrap();
+            check(&mut store, &mut obj);
+
+            // Big objects (and the dictionary itself) don't use it
+            let mut big = DefaultObject::new();
+            big.extend(&tiny_file(0).repeat(5000));
+            let hash = big.finalize_with_kind(ObjKind::Data as u8);
+            let mut codec = ContainerCodec::new();
+            codec.set_dictionary(Some(dict.clone()));
+            assert!(!Codec::encode(&codec, &mut store, &big, None, &mut obj).unwrap());
+            store.save(&big).unwrap();
+            assert_eq!(store.container_of(&hash), None);
+        }
+
+        // A missing dictionary is an error, not a panic
+        let tmp = TestTempDir::new();
+        let mut codec = ContainerCodec::new();
+        codec.set_dictionary(Some(dict.clone()));
+        let mut store = DefaultStore::new(tmp.create(&["some_file.store"]));
+        store.set_codec(Arc::new(codec));
+        obj.clear();
+        obj.extend(&small_file(7));
+        let hash = obj.finalize_with_kind(ObjKind::Data as u8);
+        store.save(&obj).unwrap();
+        store.set_codec(Arc::new(ContainerCodec::new()));
+        let err = store.load(&hash, &mut obj).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    // Tiny files are smaller than a container header, so stick a few together
+    fn small_file(i: usize) -> Vec<u8> {
+        (i..i + 4).flat_map(tiny_file).collect()
+    }
+
+    fn tiny_file(i: usize) -> Vec<u8> {
+        format!("[package]\nname = \"crate-{i}\"\nversion = \"0.{i}.0\"\nedition = \"2024\"\n")
+            .into_bytes()
+    }
+
+    #[test]
+    fn test_container_dictionary() {
+        const N: usize = 30;
+        let mut obj = DefaultObject::new();
+        let files: Vec<Vec<u8>> = (0..500).map(tiny_file).collect();
+        let samples: Vec<&[u8]> = files.iter().map(|f| f.as_slice()).collect();
+        let dict = Dictionary::train(&samples, 4096, &mut obj)
+            .unwrap()
+            .unwrap();
+        let other = Dictionary::train(&samples[..100], 1024, &mut obj)
+            .unwrap()
+            .unwrap();
+        assert_ne!(dict.hash(), other.hash());
+
+        let mut plain_total = 0;
+        let mut dict_total = 0;
+        for i in 1000..1100 {
+            obj.clear();
+            obj.extend(&tiny_file(i));
+            obj.finalize_with_kind(ObjKind::Data as u8);
+            let expected = Vec::from(obj.as_buf());
+
+            let mut enc = Encoder::new(DefaultObject::new(), 3).unwrap();
+            enc.write_next(&obj).unwrap();
+            let inner = enc.finish().unwrap();
+            assert_eq!(Decoder::dictionary(&inner), None);
+            plain_total += inner.len() - (N + INFO_LEN + ENCODING_LEN);
+
+            let mut enc = Encoder::with_dictionary(DefaultObject::new(), 3, &dict, None).unwrap();
+            enc.write_next(&obj).unwrap();
+            let inner = enc.finish().unwrap();
+            assert!(inner.is_valid());
+            assert_eq!(&inner.as_data()[0..ENCODING_LEN], &[0, 2, 0]);
+            assert_eq!(Decoder::dictionary(&inner), Some(*dict.hash()));
+            dict_total += inner.len() - (N + INFO_LEN + ENCODING_LEN + N);
+            let copy = Vec::from(inner.as_buf());
+
+            // Needs the right dictionary
+            let err = Decoder::new(inner).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::NotFound);
+            let mut inner = DefaultObject::new();
+            inner.as_mut_vec().clone_from(&copy);
+            assert!(Decoder::with_dictionary(inner, &other, None).is_err());
+
+            let mut inner = DefaultObject::new();
+            inner.as_mut_vec().clone_from(&copy);
+            let mut dec = Decoder::with_dictionary(inner, &dict, None).unwrap();
+            assert!(dec.read_next(&mut obj).unwrap());
+            assert_eq!(obj.as_buf(), expected);
+            assert!(!dec.read_next(&mut obj).unwrap());
+        }
+        // Compare just the compressed streams (the object inside starts with
+        // its random hash, so a tiny object can only shrink so much)
+        assert!(dict_total * 3 < plain_total * 2);
+    }
+
+    #[test]
+    fn test_container_dictionary_encrypted() {
+        let mut obj = DefaultObject::new();
+        let files: Vec<Vec<u8>> = (0..500).map(tiny_file).collect();
+        let samples: Vec<&[u8]> = files.iter().map(|f| f.as_slice()).collect();
+        let dict = Dictionary::train(&samples, 4096, &mut obj)
+            .unwrap()
+            .unwrap();
+        let secret = Secret::generate();
+        let keys = secret.derive(Cipher::XChaCha20Poly1305);
+
+        let mut enc = Encoder::with_dictionary(
+            DefaultObject::new(),
+            3,
+            &dict,
+            Some(secret.derive(Cipher::XChaCha20Poly1305)),
+        )
+        .unwrap();
+        let mut expected: Vec<Vec<u8>> = Vec::new();
+        for i in 0..10 {
+            obj.clear();
+            obj.extend(&tiny_file(i));
+            obj.finalize_with_kind(ObjKind::Data as u8);
+            expected.push(Vec::from(obj.as_buf()));
+            enc.write_next(&obj).unwrap();
+        }
+        let inner = enc.finish().unwrap();
+        assert_eq!(&inner.as_data()[0..ENCODING_LEN], &[0, 2, 1]);
+        assert_eq!(Decoder::dictionary(&inner), Some(*dict.hash()));
+        let copy = Vec::from(inner.as_buf());
+
+        // Needs both the key and the dictionary
+        assert!(Decoder::with_dictionary(inner, &dict, None).is_err());
+        let mut inner = DefaultObject::new();
+        inner.as_mut_vec().clone_from(&copy);
+        assert!(Decoder::new_encrypted(inner, &keys).is_err());
+
+        let mut inner = DefaultObject::new();
+        inner.as_mut_vec().clone_from(&copy);
+        let mut dec = Decoder::with_dictionary(inner, &dict, Some(&keys)).unwrap();
+        for buf in expected.iter() {
+            assert!(dec.read_next(&mut obj).unwrap());
+            assert_eq!(obj.as_buf(), buf);
+        }
+        assert!(!dec.read_next(&mut obj).unwrap());
+    }
+
+    fn random_objects(obj: &mut DefaultObject, count: usize) -> Vec<Vec<u8>> {
+        let mut expected = Vec::new();
+        for _ in 0..count {
+            obj.randomize(true);
+            expected.push(Vec::from(obj.as_buf()));
+        }
+        expected
+    }
+
+    #[test]
+    fn test_file_stream() {
+        let tmp = TestTempDir::new();
+        let mut obj = DefaultObject::new();
+        let expected = random_objects(&mut obj, 50);
+
+        let mut stream: FileStream<Blake3, 30> = FileStream::new(tmp.create(&["a.tub"]));
+        for buf in expected.iter() {
+            obj.as_mut_vec().clone_from(buf);
+            stream.send(&obj).unwrap();
+        }
+        assert_eq!(tmp.read(&["a.tub"]), expected.concat());
+
+        let mut stream: FileStream<Blake3, 30> = FileStream::new(tmp.open(&["a.tub"]));
+        for buf in expected.iter() {
+            assert!(stream.recv(&mut obj).unwrap());
+            assert_eq!(obj.as_buf(), buf);
+        }
+        assert!(!stream.recv(&mut obj).unwrap());
+
+        // Store files are object streams too
+        let mut store = DefaultStore::new(tmp.open(&["a.tub"]));
+        store.reindex(&mut obj).unwrap();
+        assert_eq!(store.len(), expected.len());
+    }
+
+    #[test]
+    fn test_socket_stream() {
+        use std::os::unix::net::UnixStream;
+        let mut obj = DefaultObject::new();
+        let expected = random_objects(&mut obj, 50);
+
+        let (a, b) = UnixStream::pair().unwrap();
+        let sent = expected.clone();
+        let handle = std::thread::spawn(move || {
+            let mut stream: SocketStream<UnixStream, Blake3, 30> = SocketStream::new(a);
+            let mut obj = DefaultObject::new();
+            for buf in sent.iter() {
+                obj.as_mut_vec().clone_from(buf);
+                stream.send(&obj).unwrap();
+            }
+        });
+        let mut stream: SocketStream<UnixStream, Blake3, 30> = SocketStream::new(b);
+        for buf in expected.iter() {
+            assert!(stream.recv(&mut obj).unwrap());
+            assert_eq!(obj.as_buf(), buf);
+        }
+        handle.join().unwrap();
+        assert!(!stream.recv(&mut obj).unwrap());
+
+        // Corrupt objects and truncated streams are errors, not panics
+        let mut bad = expected[0].clone();
+        let last = bad.len() - 1;
+        bad[last] ^= 1;
+        let mut stream: SocketStream<io::Cursor<Vec<u8>>, Blake3, 30> =
+            SocketStream::new(io::Cursor::new(bad));
+        let err = stream.recv(&mut obj).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        for size in [1, 33, 34, 35, expected[0].len() - 1] {
+            let mut stream: SocketStream<io::Cursor<Vec<u8>>, Blake3, 30> =
+                SocketStream::new(io::Cursor::new(expected[0][..size].to_vec()));
+            let err = stream.recv(&mut obj).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn test_container_stream() {
+        let mut obj = DefaultObject::new();
+        let expected = random_objects(&mut obj, 50);
+
+        // Never sent to, so the object comes back unchanged
+        let container = Container::new(DefaultObject::new());
+        assert!(container.has_space(&obj));
+        assert!(container.finish().unwrap().as_data().is_empty());
+
+        let mut container = Container::new(DefaultObject::new());
+        for buf in expected.iter() {
+            obj.as_mut_vec().clone_from(buf);
+            assert!(container.has_space(&obj));
+            container.send(&obj).unwrap();
+        }
+        assert!(container.recv(&mut obj).is_err());
+        let inner = container.finish().unwrap();
+        assert!(inner.is_valid());
+        assert_eq!(inner.kind(), ObjKind::Stream);
+
+        let mut container = Container::new(inner);
+        for buf in expected.iter() {
+            assert!(container.recv(&mut obj).unwrap());
+            assert_eq!(obj.as_buf(), buf);
+        }
+        assert!(!container.recv(&mut obj).unwrap());
+        assert!(container.send(&obj).is_err());
+        assert!(container.finish().is_err());
+    }
+
+    #[test]
+    fn test_pipe() {
+        let tmp = TestTempDir::new();
+        let mut obj = DefaultObject::new();
+        let expected = random_objects(&mut obj, 20);
+        let mut stream: FileStream<Blake3, 30> = FileStream::new(tmp.create(&["a.tub"]));
+        for buf in expected.iter() {
+            obj.as_mut_vec().clone_from(buf);
+            stream.send(&obj).unwrap();
+        }
+
+        // File --> Container --> Socket --> File
+        let mut src: FileStream<Blake3, 30> = FileStream::new(tmp.open(&["a.tub"]));
+        let mut container = Container::new(DefaultObject::new());
+        assert_eq!(pipe(&mut src, &mut container, &mut obj).unwrap(), 20);
+        let mut container = Container::new(container.finish().unwrap());
+        let mut socket: SocketStream<io::Cursor<Vec<u8>>, Blake3, 30> =
+            SocketStream::new(io::Cursor::new(Vec::new()));
+        assert_eq!(pipe(&mut container, &mut socket, &mut obj).unwrap(), 20);
+        let mut cursor = socket.into_inner();
+        cursor.set_position(0);
+        let mut socket: SocketStream<io::Cursor<Vec<u8>>, Blake3, 30> = SocketStream::new(cursor);
+        let mut dst: FileStream<Blake3, 30> = FileStream::new(tmp.create(&["b.tub"]));
+        assert_eq!(pipe(&mut socket, &mut dst, &mut obj).unwrap(), 20);
+        assert_eq!(tmp.read(&["b.tub"]), tmp.read(&["a.tub"]));
+    }
 }
@@ -2,13 +2,23 @@
 
 pub mod base;
 pub mod blockchain;
+pub mod cache;
 pub mod chaos;
+pub mod chunker;
+pub mod cipher;
 pub mod commands;
 pub mod dbase32;
+pub mod delta;
+pub mod dictionary;
+pub mod diff;
 pub mod dvcs;
 pub mod helpers;
+pub mod ignore;
 pub mod inception;
 pub mod mapreduce;
+pub mod merge;
+pub mod merkle;
+pub mod meta;
 pub mod protocol;
 pub mod tub;
 pub mod unchained;
@@ -1,12 +1,10 @@
-/// All like map reduce, yo!
+//! All like map reduce, yo!
 
 //use anyhow::Result;
 //use wasmtime::*;
 
 #[cfg(test)]
 mod tests {
-    use super::*;
-
     #[test]
     fn test_stuff() {}
 }
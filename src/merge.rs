@@ -0,0 +1,830 @@
+//! Three-way merges. 🔀
+//!
+//! Merging starts from the merge base, the closest commit both heads descend
+//! from (see `merge_base()`).  Each path is then compared across the flattened
+//! base, ours, and theirs trees (see `merge_flat()`):
+//!
+//! | Base | Ours | Theirs | Result                                   |
+//! |------|------|--------|------------------------------------------|
+//! | any  | X    | X      | X (both made the same change)            |
+//! | X    | X    | Y      | Y (only they changed it)                 |
+//! | X    | Y    | X      | Y (only we changed it)                   |
+//! | any  | Y    | Z      | Line-level merge of text files, or a conflict |
+//!
+//! A line-level merge (see `merge_lines()`) only conflicts when both sides
+//! changed the same (or adjacent) lines, in which case both versions are kept
+//! between git style conflict markers.
+
+use crate::chaos::Name;
+use crate::diff::diff_hunks;
+use crate::dvcs::{Item, ItemMap, MetaMap, Tree, leaves};
+use crate::protocol::Hasher;
+use imara_diff::sources::lines_with_terminator;
+use std::collections::{BTreeSet, HashSet, VecDeque};
+use std::io;
+use std::ops::Range;
+
+pub const MARKER_OURS: &str = "<<<<<<<";
+pub const MARKER_SEP: &str = "=======";
+pub const MARKER_THEIRS: &str = ">>>>>>>";
+
+/// Result of a line-level merge.
+#[derive(Debug, PartialEq, Clone)]
+pub struct LineMerge {
+    pub text: String,
+    pub conflicts: usize,
+}
+
+impl LineMerge {
+    pub fn is_clean(&self) -> bool {
+        self.conflicts == 0
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+struct Hunk {
+    base: Range<usize>,
+    side: Range<usize>,
+}
+
+fn hunks(base: &str, side: &str) -> Vec<Hunk> {
+    diff_hunks(base, side, 0)
+        .iter()
+        .map(|hunk| Hunk {
+            base: hunk.old_range(),
+            side: hunk.new_range(),
+        })
+        .collect()
+}
+
+// The lines of `side` for the base lines `lo..hi`, where `hunks` are the side's
+// hunks within that range (and `lo..hi` doesn't split any of them).
+fn side_range(hunks: &[Hunk], lo: usize, hi: usize) -> Range<usize> {
+    match (hunks.first(), hunks.last()) {
+        (Some(first), Some(last)) => {
+            first.side.start - (first.base.start - lo)..last.side.end + (hi - last.base.end)
+        }
+        _ => lo..hi,
+    }
+}
+
+fn push_lines(text: &mut String, lines: &[&str]) {
+    for line in lines {
+        text.push_str(line);
+    }
+}
+
+fn push_section(text: &mut String, lines: &[&str]) {
+    push_lines(text, lines);
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+}
+
+/// Three-way merge `ours` and `theirs` line by line.
+///
+/// Changes that overlap (or touch) conflict unless they're identical, and
+/// conflicts are written with markers labeled `ours_label` and `theirs_label`.
+pub fn merge_lines(
+    base: &str,
+    ours: &str,
+    theirs: &str,
+    ours_label: &str,
+    theirs_label: &str,
+) -> LineMerge {
+    let base_lines: Vec<&str> = lines_with_terminator(base).collect();
+    let ours_lines: Vec<&str> = lines_with_terminator(ours).collect();
+    let theirs_lines: Vec<&str> = lines_with_terminator(theirs).collect();
+    let a = hunks(base, ours);
+    let b = hunks(base, theirs);
+
+    let mut text = String::new();
+    let mut conflicts = 0;
+    let (mut i, mut j) = (0, 0);
+    let mut pos = 0;
+    loop {
+        // Start a chunk at the next hunk from either side
+        let (lo, mut hi) = match (a.get(i), b.get(j)) {
+            (Some(x), Some(y)) if x.base.start <= y.base.start => (x.base.start, x.base.end),
+            (_, Some(y)) => (y.base.start, y.base.end),
+            (Some(x), None) => (x.base.start, x.base.end),
+            (None, None) => break,
+        };
+        let (i0, j0) = (i, j);
+        // Then grow it until no hunk on either side overlaps it
+        loop {
+            if let Some(x) = a.get(i).filter(|x| x.base.start <= hi) {
+                hi = hi.max(x.base.end);
+                i += 1;
+            } else if let Some(y) = b.get(j).filter(|y| y.base.start <= hi) {
+                hi = hi.max(y.base.end);
+                j += 1;
+            } else {
+                break;
+            }
+        }
+        push_lines(&mut text, &base_lines[pos..lo]);
+        let ours_chunk = &ours_lines[side_range(&a[i0..i], lo, hi)];
+        let theirs_chunk = &theirs_lines[side_range(&b[j0..j], lo, hi)];
+        if j0 == j || ours_chunk == theirs_chunk {
+            push_lines(&mut text, ours_chunk);
+        } else if i0 == i {
+            push_lines(&mut text, theirs_chunk);
+        } else {
+            conflicts += 1;
+            push_section(&mut text, &[]);
+            text.push_str(&format!("{} {}\n", MARKER_OURS, ours_label));
+            push_section(&mut text, ours_chunk);
+            text.push_str(MARKER_SEP);
+            text.push('\n');
+            push_section(&mut text, theirs_chunk);
+            text.push_str(&format!("{} {}\n", MARKER_THEIRS, theirs_label));
+        }
+        pos = hi;
+    }
+    push_lines(&mut text, &base_lines[pos..]);
+    LineMerge { text, conflicts }
+}
+
+/// Find the merge base of the commits `ours` and `theirs`.
+///
+/// This is the nearest (breadth first from `theirs`) of the commits that
+/// `ours` descends from.  Returns `None` if they share no history.
+pub fn merge_base<H: Hasher, const N: usize>(
+    tree: &mut Tree<H, N>,
+    ours: &Name<N>,
+    theirs: &Name<N>,
+) -> io::Result<Option<Name<N>>> {
+    let mut ancestors = HashSet::new();
+    let mut queue = VecDeque::from([*ours]);
+    while let Some(hash) = queue.pop_front() {
+        if ancestors.insert(hash) {
+            if let Some(commit) = tree.load_commit(&hash)? {
+                queue.extend(commit.parents);
+            }
+        }
+    }
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::from([*theirs]);
+    while let Some(hash) = queue.pop_front() {
+        if ancestors.contains(&hash) {
+            return Ok(Some(hash));
+        }
+        if seen.insert(hash) {
+            if let Some(commit) = tree.load_commit(&hash)? {
+                queue.extend(commit.parents);
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Why a path couldn't be merged.
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[repr(u8)]
+pub enum ConflictKind {
+    /// Both changed the same lines (markers are in the working tree)
+    Content = 0,
+    /// Both changed it and at least one version isn't text
+    Binary,
+    /// One side changed it and the other removed it
+    ModifyDelete,
+    /// Both added it (or changed its type) differently
+    Type,
+}
+
+impl ConflictKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Content => "content",
+            Self::Binary => "binary",
+            Self::ModifyDelete => "modify/delete",
+            Self::Type => "type",
+        }
+    }
+
+    pub fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            0 => Some(Self::Content),
+            1 => Some(Self::Binary),
+            2 => Some(Self::ModifyDelete),
+            3 => Some(Self::Type),
+            _ => None,
+        }
+    }
+
+    pub fn parse(txt: &str) -> Option<Self> {
+        match txt {
+            "content" => Some(Self::Content),
+            "binary" => Some(Self::Binary),
+            "modify/delete" => Some(Self::ModifyDelete),
+            "type" => Some(Self::Type),
+            _ => None,
+        }
+    }
+}
+
+/// A path that needs a human.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Conflict {
+    pub path: String,
+    pub kind: ConflictKind,
+}
+
+/// An unfinished merge, saved in `.tub` until the conflicts are resolved and
+/// the merge is committed.
+#[derive(Debug, PartialEq, Clone)]
+pub struct MergeState<const N: usize> {
+    /// The commit being merged in (the second parent of the merge commit)
+    pub theirs: Name<N>,
+    pub conflicts: Vec<Conflict>,
+}
+
+impl<const N: usize> MergeState<N> {
+    pub fn new(theirs: Name<N>, conflicts: Vec<Conflict>) -> Self {
+        Self { theirs, conflicts }
+    }
+
+    /// Little endian, the `theirs` hash and then per conflict a kind byte plus
+    /// a u16 length prefixed path.  Paths can contain anything but `/` and
+    /// NUL, so no delimiters.  `InvalidInput` error for a path longer than
+    /// `u16::MAX` bytes.
+    pub fn serialize(&self, buf: &mut Vec<u8>) -> io::Result<()> {
+        buf.extend_from_slice(self.theirs.as_buf());
+        for c in self.conflicts.iter() {
+            let size: u16 = c.path.len().try_into().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Path of {} bytes is too long to merge", c.path.len()),
+                )
+            })?;
+            buf.push(c.kind as u8);
+            buf.extend_from_slice(&size.to_le_bytes());
+            buf.extend_from_slice(c.path.as_bytes());
+        }
+        Ok(())
+    }
+
+    pub fn deserialize(buf: &[u8]) -> Option<Self> {
+        let theirs = Name::from(buf.get(0..N)?);
+        let mut conflicts = Vec::new();
+        let mut offset = N;
+        while offset < buf.len() {
+            let kind = ConflictKind::from_u8(buf[offset])?;
+            let size = u16::from_le_bytes(buf.get(offset + 1..offset + 3)?.try_into().ok()?);
+            offset += 3;
+            let path = buf.get(offset..offset + size as usize)?;
+            offset += size as usize;
+            conflicts.push(Conflict {
+                path: String::from_utf8(path.to_vec()).ok()?,
+                kind,
+            });
+        }
+        Some(Self::new(theirs, conflicts))
+    }
+}
+
+/// Result of `merge_flat()`.
+#[derive(Debug, Default)]
+pub struct MergedTree<const N: usize> {
+    /// Merged items (conflicted paths keep our version, or theirs if we removed it)
+    pub flat: ItemMap<N>,
+    /// Text with conflict markers for `ConflictKind::Content` conflicts
+    pub marked: Vec<(String, String)>,
+    pub conflicts: Vec<Conflict>,
+}
+
+impl<const N: usize> MergedTree<N> {
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+}
+
+fn file_hash<const N: usize>(item: Option<&Item<N>>) -> Option<Option<Name<N>>> {
+    match item {
+        Some(Item::File(hash)) | Some(Item::ExeFile(hash)) => Some(Some(*hash)),
+        Some(Item::EmptyFile) => Some(None),
+        _ => None,
+    }
+}
+
+/// Three-way merge the flattened `base`, `ours`, and `theirs` trees.
+///
+/// Files changed on both sides get a line-level merge, with merged files
+/// saved in the store.  Only leaves are considered, so directories come and go
+/// with their contents.
+pub fn merge_flat<H: Hasher, const N: usize>(
+    tree: &mut Tree<H, N>,
+    base: &ItemMap<N>,
+    ours: &ItemMap<N>,
+    theirs: &ItemMap<N>,
+    labels: (&str, &str),
+) -> io::Result<MergedTree<N>> {
+    let (base, ours, theirs) = (leaves(base), leaves(ours), leaves(theirs));
+    let mut paths = BTreeSet::new();
+    paths.extend(base.keys());
+    paths.extend(ours.keys());
+    paths.extend(theirs.keys());
+
+    let mut merged = MergedTree {
+        flat: ItemMap::new(),
+        marked: Vec::new(),
+        conflicts: Vec::new(),
+    };
+    for path in paths {
+        let (b, o, t) = (base.get(path), ours.get(path), theirs.get(path));
+        let item = if o == t || t == b {
+            o.cloned()
+        } else if o == b {
+            t.cloned()
+        } else {
+            let kind = match (o, t) {
+                (Some(_), None) | (None, Some(_)) => ConflictKind::ModifyDelete,
+                _ => match (file_hash(b), file_hash(o), file_hash(t)) {
+                    (b, Some(o), Some(t)) if b.is_some() || !base.contains_key(path) => {
+                        let b = b.flatten();
+                        match merge_file(tree, [b, o, t], labels)? {
+                            Ok(data) => {
+                                let exe = matches!(ours.get(path), Some(Item::ExeFile(_)))
+                                    || matches!(theirs.get(path), Some(Item::ExeFile(_)));
+                                merged
+                                    .flat
+                                    .insert(path.to_owned(), tree.save_data(&data, exe)?);
+                                continue;
+                            }
+                            Err(Some(text)) => {
+                                merged.marked.push((path.to_owned(), text));
+                                ConflictKind::Content
+                            }
+                            Err(None) => ConflictKind::Binary,
+                        }
+                    }
+                    _ => ConflictKind::Type,
+                },
+            };
+            merged.conflicts.push(Conflict {
+                path: path.to_owned(),
+                kind,
+            });
+            o.or(t).cloned()
+        };
+        if let Some(item) = item {
+            merged.flat.insert(path.to_owned(), item);
+        }
+    }
+
+    // A file where the other side has a directory
+    let mut files: BTreeSet<String> = BTreeSet::new();
+    for path in merged.flat.keys() {
+        for (i, _) in path.match_indices('/') {
+            if merged.flat.contains_key(&path[..i]) {
+                files.insert(path[..i].to_owned());
+            }
+        }
+    }
+    for path in files {
+        merged.flat.remove(&path);
+        merged.marked.retain(|(p, _)| p != &path);
+        merged.conflicts.retain(|c| c.path != path);
+        merged.conflicts.push(Conflict {
+            path,
+            kind: ConflictKind::Type,
+        });
+    }
+    Ok(merged)
+}
+
+/// Three-way merge the metadata of the `base`, `ours`, and `theirs` trees,
+/// keeping only what's for a path in the merged `flat` tree.
+///
+/// Same as for the items, their change wins only when we didn't change it
+/// (so ours wins when both did).
+pub fn merge_metas<const N: usize>(
+    base: &MetaMap,
+    ours: &MetaMap,
+    theirs: &MetaMap,
+    flat: &ItemMap<N>,
+) -> MetaMap {
+    let mut paths: HashSet<&str> = HashSet::new();
+    for path in flat.keys() {
+        paths.insert(path);
+        for (i, _) in path.match_indices('/') {
+            paths.insert(&path[..i]);
+        }
+    }
+    let mut keys: HashSet<&String> = HashSet::from_iter(ours.keys());
+    keys.extend(theirs.keys());
+    let mut merged = MetaMap::new();
+    for key in keys {
+        if !paths.contains(key.as_str()) {
+            continue;
+        }
+        let (b, o) = (base.get(key), ours.get(key));
+        let meta = if o == b { theirs.get(key) } else { o };
+        if let Some(meta) = meta {
+            merged.insert(key.to_owned(), meta.clone());
+        }
+    }
+    merged
+}
+
+// Merge the file contents, `Ok` when merged cleanly, otherwise the text with
+// conflict markers (or `None` when it's not text).
+fn merge_file<H: Hasher, const N: usize>(
+    tree: &mut Tree<H, N>,
+    hashes: [Option<Name<N>>; 3],
+    labels: (&str, &str),
+) -> io::Result<Result<Vec<u8>, Option<String>>> {
+    let mut texts = Vec::new();
+    for hash in hashes {
+        let data = match hash {
+            Some(hash) => match tree.load_data(&hash)? {
+                Some(data) => data,
+                None => return Ok(Err(None)),
+            },
+            None => Vec::new(),
+        };
+        match String::from_utf8(data) {
+            Ok(text) if !text.contains('\0') => texts.push(text),
+            _ => return Ok(Err(None)),
+        }
+    }
+    let result = merge_lines(&texts[0], &texts[1], &texts[2], labels.0, labels.1);
+    if result.is_clean() {
+        Ok(Ok(result.text.into_bytes()))
+    } else {
+        Ok(Err(Some(result.text)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chaos::{DefaultName, DefaultStore};
+    use crate::dvcs::{DefaultCommit, DefaultTree};
+    use crate::helpers::TestTempDir;
+
+    fn merge(base: &str, ours: &str, theirs: &str) -> LineMerge {
+        merge_lines(base, ours, theirs, "ours", "theirs")
+    }
+
+    #[test]
+    fn test_merge_lines() {
+        let base = "a\nb\nc\nd\ne\n";
+
+        // Nothing or one side changed
+        assert_eq!(merge(base, base, base).text, base);
+        assert_eq!(merge(base, "a\nB\nc\nd\ne\n", base).text, "a\nB\nc\nd\ne\n");
+        assert_eq!(merge(base, base, "a\nb\nc\nd\n").text, "a\nb\nc\nd\n");
+
+        // Both changed different lines
+        let m = merge(base, "A\nb\nc\nd\ne\n", "a\nb\nc\nd\nE\nf\n");
+        assert!(m.is_clean());
+        assert_eq!(m.text, "A\nb\nc\nd\nE\nf\n");
+        let m = merge(base, "a\nc\nd\ne\n", "a\nb\nc\nD\ne\n");
+        assert_eq!(m.text, "a\nc\nD\ne\n");
+
+        // Both made the same change
+        let m = merge(base, "a\nX\nc\nd\ne\n", "a\nX\nc\nd\ne\n");
+        assert!(m.is_clean());
+        assert_eq!(m.text, "a\nX\nc\nd\ne\n");
+
+        // Both changed the same line
+        let m = merge(base, "a\nb\nOURS\nd\ne\n", "a\nb\nTHEIRS\nd\ne\n");
+        assert_eq!(m.conflicts, 1);
+        assert_eq!(
+            m.text,
+            "a\nb\n<<<<<<< ours\nOURS\n=======\nTHEIRS\n>>>>>>> theirs\nd\ne\n"
+        );
+
+        // Adjacent changes conflict too
+        let m = merge(base, "a\nB\nc\nd\ne\n", "a\nb\nC\nd\ne\n");
+        assert_eq!(m.conflicts, 1);
+        assert_eq!(
+            m.text,
+            "a\n<<<<<<< ours\nB\nc\n=======\nb\nC\n>>>>>>> theirs\nd\ne\n"
+        );
+
+        // Two separate conflicts
+        let m = merge(base, "1\nb\nc\nd\n5\n", "one\nb\nc\nd\nfive\n");
+        assert_eq!(m.conflicts, 2);
+        assert!(
+            m.text
+                .starts_with("<<<<<<< ours\n1\n=======\none\n>>>>>>> theirs\nb\n")
+        );
+    }
+
+    #[test]
+    fn test_merge_lines_edges() {
+        // Empty base (both added)
+        let m = merge("", "a\n", "a\n");
+        assert_eq!(m.text, "a\n");
+        let m = merge("", "a\n", "b\n");
+        assert_eq!(m.text, "<<<<<<< ours\na\n=======\nb\n>>>>>>> theirs\n");
+
+        // Missing final newline doesn't get the markers mixed in
+        let m = merge("x", "a", "b");
+        assert_eq!(m.text, "<<<<<<< ours\na\n=======\nb\n>>>>>>> theirs\n");
+        let m = merge("x\ny", "x\na", "x\ny\nz");
+        assert_eq!(m.conflicts, 1);
+        assert!(m.text.starts_with("x\n<<<<<<< ours\na\n=======\ny\nz\n"));
+
+        // Both removed everything
+        assert_eq!(
+            merge("a\nb\n", "", ""),
+            LineMerge {
+                text: String::new(),
+                conflicts: 0
+            }
+        );
+
+        // Insertions at the same spot
+        let m = merge("a\nb\n", "a\nX\nb\n", "a\nY\nb\n");
+        assert_eq!(
+            m.text,
+            "a\n<<<<<<< ours\nX\n=======\nY\n>>>>>>> theirs\nb\n"
+        );
+    }
+
+    fn save_commit(tree: &mut DefaultTree, parents: &[DefaultName], id: u8) -> DefaultName {
+        let mut commit = DefaultCommit::new(DefaultName::from(&[id; 30][..]), format!("{id}"));
+        commit.parents.extend_from_slice(parents);
+        tree.save_commit(&commit).unwrap()
+    }
+
+    #[test]
+    fn test_merge_base() {
+        let tmp = TestTempDir::new();
+        let mut store = DefaultStore::new(tmp.create(&["some_file.store"]));
+        let mut tree: DefaultTree = Tree::new(&mut store, tmp.path());
+
+        //   r - a1 - a2 - m
+        //    \          /
+        //     b1 ----- b2 - b3
+        let r = save_commit(&mut tree, &[], 0);
+        let a1 = save_commit(&mut tree, &[r], 1);
+        let a2 = save_commit(&mut tree, &[a1], 2);
+        let b1 = save_commit(&mut tree, &[r], 3);
+        let b2 = save_commit(&mut tree, &[b1], 4);
+        let b3 = save_commit(&mut tree, &[b2], 5);
+        let m = save_commit(&mut tree, &[a2, b2], 6);
+        let other = save_commit(&mut tree, &[], 7);
+
+        assert_eq!(merge_base(&mut tree, &a2, &b3).unwrap(), Some(r));
+        assert_eq!(merge_base(&mut tree, &b3, &a2).unwrap(), Some(r));
+        assert_eq!(merge_base(&mut tree, &a2, &a1).unwrap(), Some(a1));
+        assert_eq!(merge_base(&mut tree, &a1, &a2).unwrap(), Some(a1));
+        assert_eq!(merge_base(&mut tree, &a2, &a2).unwrap(), Some(a2));
+        assert_eq!(merge_base(&mut tree, &m, &b3).unwrap(), Some(b2));
+        assert_eq!(merge_base(&mut tree, &b3, &m).unwrap(), Some(b2));
+        assert_eq!(merge_base(&mut tree, &m, &other).unwrap(), None);
+    }
+
+    #[test]
+    fn test_merge_flat() {
+        let tmp = TestTempDir::new();
+        let mut store = DefaultStore::new(tmp.create(&["some_file.store"]));
+        let mut tree: DefaultTree = Tree::new(&mut store, tmp.path());
+
+        let mut file = |data: &str| tree.save_data(data.as_bytes(), false).unwrap();
+        let text = file("a\nb\nc\nd\n");
+        let ours_text = file("A\nb\nc\nd\n");
+        let theirs_text = file("a\nb\nc\nD\n");
+        let clash_ours = file("a\nOURS\nc\nd\n");
+        let clash_theirs = file("a\nTHEIRS\nc\nd\n");
+        let binary = file("\0\0\0");
+        let (one, two) = (file("one\n"), file("two\n"));
+
+        let map = |items: &[(&str, &Item<30>)]| -> ItemMap<30> {
+            items
+                .iter()
+                .map(|(p, i)| (p.to_string(), (*i).clone()))
+                .collect()
+        };
+        let base = map(&[
+            ("same", &one),
+            ("merged", &text),
+            ("clash", &text),
+            ("binary", &text),
+            ("gone", &one),
+            ("modified", &one),
+            ("dir", &Item::Dir(DefaultName::new())),
+            ("dir/x", &one),
+        ]);
+        let ours = map(&[
+            ("same", &one),
+            ("merged", &ours_text),
+            ("clash", &clash_ours),
+            ("binary", &binary),
+            ("modified", &two),
+            ("dir", &Item::Dir(DefaultName::new())),
+            ("dir/x", &one),
+            ("ours_new", &one),
+            ("both_new", &one),
+            ("both_clash", &one),
+            ("file_dir", &one),
+        ]);
+        let theirs = map(&[
+            ("same", &one),
+            ("merged", &theirs_text),
+            ("clash", &clash_theirs),
+            ("binary", &two),
+            ("gone", &one),
+            ("theirs_new", &two),
+            ("both_new", &one),
+            ("both_clash", &two),
+            ("file_dir/y", &one),
+            ("empty", &Item::EmptyDir),
+        ]);
+        let merged = merge_flat(&mut tree, &base, &ours, &theirs, ("HEAD", "theirs")).unwrap();
+        assert!(!merged.is_clean());
+        let mut conflicts: Vec<(&str, ConflictKind)> = merged
+            .conflicts
+            .iter()
+            .map(|c| (c.path.as_str(), c.kind))
+            .collect();
+        conflicts.sort_by_key(|c| c.0);
+        assert_eq!(
+            conflicts,
+            [
+                ("binary", ConflictKind::Binary),
+                ("both_clash", ConflictKind::Content),
+                ("clash", ConflictKind::Content),
+                ("file_dir", ConflictKind::Type),
+                ("modified", ConflictKind::ModifyDelete),
+            ]
+        );
+        let mut paths: Vec<&String> = merged.flat.keys().collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            [
+                "binary",
+                "both_clash",
+                "both_new",
+                "clash",
+                "empty",
+                "file_dir/y",
+                "merged",
+                "modified",
+                "ours_new",
+                "same",
+                "theirs_new",
+            ]
+        );
+        assert_eq!(merged.flat["clash"], clash_ours);
+        assert_eq!(merged.flat["modified"], two);
+        assert_eq!(merged.flat["theirs_new"], two);
+        let Item::File(hash) = merged.flat["merged"] else {
+            panic!("not a file");
+        };
+        assert_eq!(tree.load_data(&hash).unwrap().unwrap(), b"A\nb\nc\nD\n");
+        merged.marked.iter().find(|(p, _)| p == "clash").unwrap();
+        assert_eq!(
+            merged
+                .marked
+                .iter()
+                .find(|(p, _)| p == "both_clash")
+                .unwrap()
+                .1,
+            "<<<<<<< HEAD\none\n=======\ntwo\n>>>>>>> theirs\n"
+        );
+
+        // Nothing to do
+        let merged = merge_flat(&mut tree, &base, &ours, &ours, ("HEAD", "theirs")).unwrap();
+        assert!(merged.is_clean());
+        assert_eq!(merged.flat, leaves(&ours));
+    }
+
+    #[test]
+    fn test_merge_metas() {
+        use crate::meta::Meta;
+        let mode = |mode| Meta {
+            mode: Some(mode),
+            ..Meta::default()
+        };
+        let metas = |items: &[(&str, u32)]| -> MetaMap {
+            items
+                .iter()
+                .map(|(path, m)| (path.to_string(), mode(*m)))
+                .collect()
+        };
+        let mut flat: ItemMap<30> = ItemMap::new();
+        for path in ["same", "ours", "theirs", "both", "added", "d/file"] {
+            flat.insert(path.to_string(), Item::EmptyFile);
+        }
+        let base = metas(&[
+            ("same", 0o644),
+            ("ours", 0o644),
+            ("theirs", 0o644),
+            ("both", 0o644),
+            ("d", 0o755),
+        ]);
+        let ours = metas(&[
+            ("same", 0o644),
+            ("ours", 0o600),
+            ("theirs", 0o644),
+            ("both", 0o600),
+            ("d", 0o755),
+            ("gone", 0o644),
+        ]);
+        let theirs = metas(&[
+            ("same", 0o644),
+            ("ours", 0o644),
+            ("theirs", 0o600),
+            ("both", 0o640),
+            ("added", 0o755),
+            ("d", 0o700),
+        ]);
+        let merged = merge_metas(&base, &ours, &theirs, &flat);
+        assert_eq!(
+            merged,
+            metas(&[
+                ("same", 0o644),
+                ("ours", 0o600),
+                ("theirs", 0o600),
+                ("both", 0o600),
+                ("added", 0o755),
+                ("d", 0o700),
+            ])
+        );
+        assert!(merge_metas(&base, &base, &base, &ItemMap::<30>::new()).is_empty());
+    }
+
+    #[test]
+    fn test_conflict_kind() {
+        for kind in [
+            ConflictKind::Content,
+            ConflictKind::Binary,
+            ConflictKind::ModifyDelete,
+            ConflictKind::Type,
+        ] {
+            assert_eq!(ConflictKind::parse(kind.as_str()), Some(kind));
+            assert_eq!(ConflictKind::from_u8(kind as u8), Some(kind));
+        }
+        assert_eq!(ConflictKind::parse("nope"), None);
+        assert_eq!(ConflictKind::from_u8(4), None);
+    }
+
+    #[test]
+    fn test_merge_state() {
+        let theirs = DefaultName::from_dbase32("BDGJMPSVY3699CFILORUX47AADGJMPSVY3699CFILORUX47A");
+        let state = MergeState::new(theirs, vec![]);
+        let mut buf = Vec::new();
+        state.serialize(&mut buf).unwrap();
+        assert_eq!(buf, theirs.as_buf());
+        assert_eq!(MergeState::deserialize(&buf), Some(state));
+
+        let state = MergeState::new(
+            theirs,
+            vec![
+                Conflict {
+                    path: "src/main.rs".to_owned(),
+                    kind: ConflictKind::Content,
+                },
+                Conflict {
+                    path: "a b/c".to_owned(),
+                    kind: ConflictKind::ModifyDelete,
+                },
+                Conflict {
+                    path: "new\nline\tand tab".to_owned(),
+                    kind: ConflictKind::Binary,
+                },
+            ],
+        );
+        let mut buf = Vec::new();
+        state.serialize(&mut buf).unwrap();
+        let mut expected = theirs.as_buf().to_vec();
+        expected.extend_from_slice(b"\x00\x0b\x00src/main.rs");
+        expected.extend_from_slice(b"\x02\x05\x00a b/c");
+        expected.extend_from_slice(b"\x01\x10\x00new\nline\tand tab");
+        assert_eq!(buf, expected);
+        assert_eq!(MergeState::deserialize(&buf), Some(state));
+
+        // Truncated anywhere is an error, never a panic
+        for size in 1..buf.len() {
+            if ![30, 30 + 14, 30 + 14 + 8].contains(&size) {
+                assert_eq!(MergeState::<30>::deserialize(&buf[..size]), None);
+            }
+        }
+        assert_eq!(MergeState::<30>::deserialize(b""), None);
+        let mut bad = theirs.as_buf().to_vec();
+        bad.extend_from_slice(b"\x04\x03\x00foo");
+        assert_eq!(MergeState::<30>::deserialize(&bad), None);
+        let mut bad = theirs.as_buf().to_vec();
+        bad.extend_from_slice(b"\x00\x02\x00\xff\xfe");
+        assert_eq!(MergeState::<30>::deserialize(&bad), None);
+
+        let long = MergeState::new(
+            theirs,
+            vec![Conflict {
+                path: "a".repeat(u16::MAX as usize + 1),
+                kind: ConflictKind::Content,
+            }],
+        );
+        assert_eq!(
+            long.serialize(&mut Vec::new()).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+}
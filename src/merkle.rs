@@ -0,0 +1,599 @@
+//! Binary Merkle trees over BigData leaves, for verified partial reads. 🌳
+//!
+//! A BigData root records the Merkle root over its leaves, so a compact proof
+//! shows that a run of leaves (and so a byte range) belongs to a root `Name`.
+//! The leaf objects themselves are then verified against their own hashes as
+//! they come in, chunk by chunk, from anywhere (say an untrusted mirror).
+//!
+//! Like Bao, the tree is left balanced: the left subtree of a node with COUNT
+//! leaves gets the largest power of two less than COUNT.  Every node commits
+//! to the number of bytes below it, so leaf offsets are verified too:
+//!
+//! ```text
+//! Leaf   = H(0x00 | Size 4 | Leaf Hash N)
+//! Parent = H(0x01 | Left Size 8 | Left Hash N | Right Size 8 | Right Hash N)
+//! Root   = H(0x02 | Count 4 | Size 8 | Top Hash N)
+//! ```
+//!
+//! The Root binds the leaf count (and so the shape of the tree), otherwise the
+//! same proof could be read against more than one shape.
+//!
+//! A `RangeProof` is the proven leaves plus the subtrees next to them, in
+//! order, enough to recompute the Merkle root:
+//!
+//! | Count | First | Leaf Count | Leaves   | Nodes    |
+//! |-------|-------|------------|----------|----------|
+//! |     4 |     4 |          4 | (4 + N)* | (8 + N)* |
+//!
+//! A `BigDataProof` is the (small) BigData root header followed by a
+//! `RangeProof`, and is checked against the root `Name` itself.
+//!
+//! As always, LITTLE ENDIAN.
+
+use crate::base::ObjKind;
+use crate::chaos::{Name, Object, Store};
+use crate::inception::{BigDataHeader, LeafHashes, load_leaves};
+use crate::protocol::Hasher;
+use std::io;
+use std::marker::PhantomData;
+use std::slice::Iter;
+
+const LEAF_PREFIX: u8 = 0;
+const PARENT_PREFIX: u8 = 1;
+const ROOT_PREFIX: u8 = 2;
+
+/// A Merkle tree node: its hash and the number of bytes below it.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Node<const N: usize> {
+    pub size: u64,
+    pub hash: Name<N>,
+}
+
+fn leaf_node<H: Hasher, const N: usize>(size: u32, hash: &Name<N>) -> Node<N> {
+    let mut buf = Vec::with_capacity(1 + 4 + N);
+    buf.push(LEAF_PREFIX);
+    buf.extend_from_slice(&size.to_le_bytes());
+    buf.extend_from_slice(hash.as_buf());
+    let mut node = Node {
+        size: size as u64,
+        hash: Name::new(),
+    };
+    H::new().hash_into(&buf, node.hash.as_mut_buf());
+    node
+}
+
+fn parent_node<H: Hasher, const N: usize>(left: &Node<N>, right: &Node<N>) -> Node<N> {
+    let mut buf = Vec::with_capacity(1 + 2 * (8 + N));
+    buf.push(PARENT_PREFIX);
+    buf.extend_from_slice(&left.size.to_le_bytes());
+    buf.extend_from_slice(left.hash.as_buf());
+    buf.extend_from_slice(&right.size.to_le_bytes());
+    buf.extend_from_slice(right.hash.as_buf());
+    let mut node = Node {
+        size: left.size.wrapping_add(right.size),
+        hash: Name::new(),
+    };
+    H::new().hash_into(&buf, node.hash.as_mut_buf());
+    node
+}
+
+fn root_hash<H: Hasher, const N: usize>(count: u32, top: &Node<N>) -> Name<N> {
+    let mut buf = Vec::with_capacity(1 + 4 + 8 + N);
+    buf.push(ROOT_PREFIX);
+    buf.extend_from_slice(&count.to_le_bytes());
+    buf.extend_from_slice(&top.size.to_le_bytes());
+    buf.extend_from_slice(top.hash.as_buf());
+    let mut hash = Name::new();
+    H::new().hash_into(&buf, hash.as_mut_buf());
+    hash
+}
+
+// Number of leaves in the left subtree (count must be at least 2).
+fn split(count: usize) -> usize {
+    assert!(count > 1);
+    1 << (usize::BITS - (count - 1).leading_zeros() - 1)
+}
+
+fn subtree<H: Hasher, const N: usize>(leaves: &[(u32, Name<N>)]) -> Node<N> {
+    if leaves.len() == 1 {
+        leaf_node::<H, N>(leaves[0].0, &leaves[0].1)
+    } else {
+        let mid = split(leaves.len());
+        parent_node::<H, N>(
+            &subtree::<H, N>(&leaves[..mid]),
+            &subtree::<H, N>(&leaves[mid..]),
+        )
+    }
+}
+
+fn collect<const N: usize>(leaves: &LeafHashes<N>) -> Vec<(u32, Name<N>)> {
+    leaves
+        .iter()
+        .enumerate()
+        .map(|(i, hash)| (leaves.leaf_size(i) as u32, *hash))
+        .collect()
+}
+
+/// Builds a Merkle root one leaf at a time, holding only O(log n) nodes.
+///
+/// Completed left subtrees are merged as soon as they're full (a power of two
+/// leaves), then `finish()` merges what's left from the right, which gives the
+/// same left balanced tree as `split()`.
+#[derive(Debug)]
+pub struct MerkleBuilder<H: Hasher, const N: usize> {
+    stack: Vec<Node<N>>,
+    count: u64,
+    total: u64,
+    phantom: PhantomData<H>,
+}
+
+impl<H: Hasher, const N: usize> Default for MerkleBuilder<H, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H: Hasher, const N: usize> MerkleBuilder<H, N> {
+    pub fn new() -> Self {
+        Self {
+            stack: Vec::new(),
+            count: 0,
+            total: 0,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn append_leaf(&mut self, size: u32, hash: &Name<N>) {
+        self.stack.push(leaf_node::<H, N>(size, hash));
+        self.count += 1;
+        self.total += size as u64;
+        let mut merges = self.count.trailing_zeros();
+        while merges > 0 {
+            let right = self.stack.pop().unwrap();
+            let left = self.stack.pop().unwrap();
+            self.stack.push(parent_node::<H, N>(&left, &right));
+            merges -= 1;
+        }
+    }
+
+    /// The Merkle root (there must be at least one leaf).
+    pub fn finish(&self) -> Name<N> {
+        assert!(self.count > 0 && self.count <= u32::MAX as u64);
+        let mut iter = self.stack.iter().rev();
+        let mut node = *iter.next().unwrap();
+        for left in iter {
+            node = parent_node::<H, N>(left, &node);
+        }
+        root_hash::<H, N>(self.count as u32, &node)
+    }
+}
+
+/// Merkle root over the leaves (which must not be empty).
+pub fn merkle_root<H: Hasher, const N: usize>(leaves: &LeafHashes<N>) -> Name<N> {
+    let mut builder: MerkleBuilder<H, N> = MerkleBuilder::new();
+    for (size, hash) in collect(leaves) {
+        builder.append_leaf(size, &hash);
+    }
+    builder.finish()
+}
+
+/// Proof that a run of leaves belongs to a Merkle root.
+#[derive(Debug, PartialEq, Clone)]
+pub struct RangeProof<const N: usize> {
+    count: u32,
+    first: u32,
+    leaves: Vec<(u32, Name<N>)>,
+    nodes: Vec<Node<N>>,
+}
+
+fn prove_inner<H: Hasher, const N: usize>(
+    leaves: &[(u32, Name<N>)],
+    lo: usize,
+    first: usize,
+    end: usize,
+    nodes: &mut Vec<Node<N>>,
+) {
+    if lo + leaves.len() <= first || lo >= end {
+        nodes.push(subtree::<H, N>(leaves));
+    } else if leaves.len() > 1 {
+        let mid = split(leaves.len());
+        prove_inner::<H, N>(&leaves[..mid], lo, first, end, nodes);
+        prove_inner::<H, N>(&leaves[mid..], lo + mid, first, end, nodes);
+    }
+}
+
+struct Verifier<'a, const N: usize> {
+    first: usize,
+    end: usize,
+    leaves: Iter<'a, (u32, Name<N>)>,
+    nodes: Iter<'a, Node<N>>,
+    offset: u64, // Bytes before the first proven leaf
+}
+
+impl<const N: usize> Verifier<'_, N> {
+    fn verify<H: Hasher>(&mut self, count: usize, lo: usize) -> Option<Node<N>> {
+        if lo + count <= self.first {
+            let node = *self.nodes.next()?;
+            self.offset = self.offset.wrapping_add(node.size);
+            Some(node)
+        } else if lo >= self.end {
+            self.nodes.next().cloned()
+        } else if count == 1 {
+            let (size, hash) = self.leaves.next()?;
+            Some(leaf_node::<H, N>(*size, hash))
+        } else {
+            let mid = split(count);
+            let left = self.verify::<H>(mid, lo)?;
+            let right = self.verify::<H>(count - mid, lo + mid)?;
+            Some(parent_node::<H, N>(&left, &right))
+        }
+    }
+}
+
+impl<const N: usize> RangeProof<N> {
+    /// Prove the leaves covering bytes `start..end` (which must not be empty).
+    pub fn new<H: Hasher>(leaves: &LeafHashes<N>, start: u64, end: u64) -> Self {
+        assert!(start < end && end <= leaves.total());
+        let all = collect(leaves);
+        let mut first = 0;
+        let mut offset = 0;
+        while offset + all[first].0 as u64 <= start {
+            offset += all[first].0 as u64;
+            first += 1;
+        }
+        let mut last = first;
+        offset += all[last].0 as u64;
+        while offset < end {
+            last += 1;
+            offset += all[last].0 as u64;
+        }
+        let mut nodes = Vec::new();
+        prove_inner::<H, N>(&all, 0, first, last + 1, &mut nodes);
+        Self {
+            count: all.len() as u32,
+            first: first as u32,
+            leaves: all[first..=last].to_vec(),
+            nodes,
+        }
+    }
+
+    /// Index of the first proven leaf.
+    pub fn first(&self) -> usize {
+        self.first as usize
+    }
+
+    /// The proven leaves as `(size, hash)`.
+    pub fn leaves(&self) -> &[(u32, Name<N>)] {
+        &self.leaves
+    }
+
+    /// Check the proof against the Merkle root of a file of `total` bytes,
+    /// returning the byte offset of the first proven leaf.
+    pub fn verify<H: Hasher>(&self, root: &Name<N>, total: u64) -> Option<u64> {
+        let first = self.first as usize;
+        let end = first + self.leaves.len();
+        if self.leaves.is_empty() || end > self.count as usize {
+            return None;
+        }
+        let mut v = Verifier {
+            first,
+            end,
+            leaves: self.leaves.iter(),
+            nodes: self.nodes.iter(),
+            offset: 0,
+        };
+        let node = v.verify::<H>(self.count as usize, 0)?;
+        if v.leaves.next().is_none()
+            && v.nodes.next().is_none()
+            && &root_hash::<H, N>(self.count, &node) == root
+            && node.size == total
+        {
+            Some(v.offset)
+        } else {
+            None
+        }
+    }
+
+    pub fn serialize(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.count.to_le_bytes());
+        buf.extend_from_slice(&self.first.to_le_bytes());
+        buf.extend_from_slice(&(self.leaves.len() as u32).to_le_bytes());
+        for (size, hash) in self.leaves.iter() {
+            buf.extend_from_slice(&size.to_le_bytes());
+            buf.extend_from_slice(hash.as_buf());
+        }
+        for node in self.nodes.iter() {
+            buf.extend_from_slice(&node.size.to_le_bytes());
+            buf.extend_from_slice(node.hash.as_buf());
+        }
+    }
+
+    pub fn deserialize(buf: &[u8]) -> io::Result<Self> {
+        let bad = || io::Error::new(io::ErrorKind::InvalidData, "bad range proof");
+        let read_u32 = |offset: usize| match buf.get(offset..offset + 4) {
+            Some(b) => Ok(u32::from_le_bytes(b.try_into().unwrap())),
+            None => Err(bad()),
+        };
+        let count = read_u32(0)?;
+        let first = read_u32(4)?;
+        let n = read_u32(8)? as usize;
+        let mut offset = 12;
+        if buf.len() < offset + n * (4 + N) || (buf.len() - offset - n * (4 + N)) % (8 + N) != 0 {
+            return Err(bad());
+        }
+        let mut leaves = Vec::with_capacity(n);
+        for _ in 0..n {
+            let size = read_u32(offset)?;
+            leaves.push((size, Name::from(&buf[offset + 4..offset + 4 + N])));
+            offset += 4 + N;
+        }
+        let mut nodes = Vec::new();
+        while offset < buf.len() {
+            let size = u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap());
+            let hash = Name::from(&buf[offset + 8..offset + 8 + N]);
+            nodes.push(Node { size, hash });
+            offset += 8 + N;
+        }
+        Ok(Self {
+            count,
+            first,
+            leaves,
+            nodes,
+        })
+    }
+}
+
+/// Proof that a byte range of a stored file belongs to its root `Name`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct BigDataProof<const N: usize> {
+    header: BigDataHeader<N>,
+    range: RangeProof<N>,
+}
+
+impl<const N: usize> BigDataProof<N> {
+    /// Prove bytes `start..end` of the file with `root` (`None` if we don't
+    /// have it, or it's not a BigData root with a Merkle tree).
+    pub fn new<H: Hasher>(
+        store: &mut Store<H, N>,
+        root: &Name<N>,
+        start: u64,
+        end: u64,
+    ) -> io::Result<Option<Self>> {
+        let mut obj: Object<H, N> = Object::new();
+        if !store.load(root, &mut obj)?
+            || obj.kind() != ObjKind::BigData
+            || !BigDataHeader::<N>::is_header(obj.as_data())
+        {
+            return Ok(None);
+        }
+        let header = BigDataHeader::deserialize(obj.as_data())?;
+        if start >= end || end > header.total {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("bad range {}..{} of {} bytes", start, end, header.total),
+            ));
+        }
+        let leaves = load_leaves(store, root)?.unwrap();
+        let range = RangeProof::new::<H>(&leaves, start, end);
+        Ok(Some(Self { header, range }))
+    }
+
+    /// The proven leaves as `(size, hash)`.  Don't trust them till `verify()`!
+    pub fn leaves(&self) -> &[(u32, Name<N>)] {
+        self.range.leaves()
+    }
+
+    /// Check the proof against `root`, returning the byte offset of the first
+    /// proven leaf.  Each leaf object must still be verified against its hash.
+    pub fn verify<H: Hasher>(&self, root: &Name<N>) -> Option<u64> {
+        let mut obj: Object<H, N> = Object::new();
+        obj.clear();
+        self.header.serialize(obj.as_mut_vec());
+        if &obj.finalize_with_kind(ObjKind::BigData as u8) != root {
+            return None;
+        }
+        self.range
+            .verify::<H>(&self.header.merkle, self.header.total)
+    }
+
+    pub fn serialize(&self, buf: &mut Vec<u8>) {
+        self.header.serialize(buf);
+        self.range.serialize(buf);
+    }
+
+    pub fn deserialize(buf: &[u8]) -> io::Result<Self> {
+        let len = BigDataHeader::<N>::LEN;
+        if buf.len() < len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "bad BigData proof",
+            ));
+        }
+        Ok(Self {
+            header: BigDataHeader::deserialize(&buf[..len])?,
+            range: RangeProof::deserialize(&buf[len..])?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunker::Chunking;
+    use crate::helpers::flip_bit_in;
+    use crate::protocol::Blake3;
+
+    fn make_leaves(count: usize) -> LeafHashes<30> {
+        let mut leaves = LeafHashes::with_chunking(Chunking::FastCdc);
+        for i in 0..count {
+            let mut hash = Name::new();
+            hash.randomize();
+            leaves.append_leaf(hash, 1000 + i);
+        }
+        leaves
+    }
+
+    #[test]
+    fn test_split() {
+        assert_eq!(split(2), 1);
+        assert_eq!(split(3), 2);
+        assert_eq!(split(4), 2);
+        assert_eq!(split(5), 4);
+        assert_eq!(split(8), 4);
+        assert_eq!(split(9), 8);
+    }
+
+    #[test]
+    fn test_merkle_root() {
+        let leaves = make_leaves(1);
+        let (size, hash) = (1000, *leaves.iter().next().unwrap());
+        let leaf = leaf_node::<Blake3, 30>(size, &hash);
+        assert_eq!(
+            merkle_root::<Blake3, 30>(&leaves),
+            root_hash::<Blake3, 30>(1, &leaf)
+        );
+        assert_ne!(
+            root_hash::<Blake3, 30>(1, &leaf),
+            root_hash::<Blake3, 30>(2, &leaf)
+        );
+        let a = make_leaves(5);
+        let b = make_leaves(5);
+        assert_ne!(merkle_root::<Blake3, 30>(&a), merkle_root::<Blake3, 30>(&b));
+        // Leaf and parent nodes are domain separated
+        let node = leaf_node::<Blake3, 30>(1, &Name::new());
+        assert_ne!(parent_node::<Blake3, 30>(&node, &node).hash, node.hash);
+    }
+
+    #[test]
+    fn test_merkle_builder() {
+        for count in 1..40 {
+            let leaves = make_leaves(count);
+            let top = subtree::<Blake3, 30>(&collect(&leaves));
+            let expected = root_hash::<Blake3, 30>(count as u32, &top);
+            assert_eq!(merkle_root::<Blake3, 30>(&leaves), expected);
+        }
+        let mut builder: MerkleBuilder<Blake3, 30> = MerkleBuilder::new();
+        builder.append_leaf(5, &Name::new());
+        builder.append_leaf(7, &Name::new());
+        assert_eq!(builder.count(), 2);
+        assert_eq!(builder.total(), 12);
+        assert_eq!(builder.stack.len(), 1);
+    }
+
+    #[test]
+    fn test_range_proof() {
+        for count in [1, 2, 3, 5, 8, 13] {
+            let leaves = make_leaves(count);
+            let root = merkle_root::<Blake3, 30>(&leaves);
+            let total = leaves.total();
+            let mut offsets = vec![0];
+            for i in 0..count {
+                offsets.push(offsets[i] + leaves.leaf_size(i) as u64);
+            }
+            for first in 0..count {
+                for last in first..count {
+                    let start = offsets[first] + 1;
+                    let end = offsets[last + 1];
+                    let proof = RangeProof::new::<Blake3>(&leaves, start, end);
+                    assert_eq!(proof.first(), first);
+                    assert_eq!(proof.leaves().len(), last - first + 1);
+                    assert_eq!(proof.verify::<Blake3>(&root, total), Some(offsets[first]));
+                    assert_eq!(proof.verify::<Blake3>(&root, total + 1), None);
+
+                    let mut buf = Vec::new();
+                    proof.serialize(&mut buf);
+                    assert_eq!(RangeProof::deserialize(&buf).unwrap(), proof);
+                    if count != 5 {
+                        continue;
+                    }
+                    for bit in 0..buf.len() * 8 {
+                        let mut copy = buf.clone();
+                        flip_bit_in(&mut copy, bit);
+                        if let Ok(bad) = RangeProof::<30>::deserialize(&copy) {
+                            assert_eq!(bad.verify::<Blake3>(&root, total), None);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_bigdata_proof() {
+        use crate::chaos::{DefaultObject, DefaultStore};
+        use crate::helpers::TestTempDir;
+        use crate::inception::import_reader;
+
+        let tmp = TestTempDir::new();
+        let mut store = DefaultStore::new(tmp.create(&["some_file.store"]));
+        let mut obj = DefaultObject::new();
+        let mut data = vec![0_u8; 3 * crate::base::OBJECT_MAX_SIZE + 69];
+        getrandom::fill(&mut data).unwrap();
+        let root = import_reader(&mut store, &mut obj, &data[..], Chunking::Fixed)
+            .unwrap()
+            .unwrap();
+
+        let start = crate::base::OBJECT_MAX_SIZE as u64 + 7;
+        let proof = BigDataProof::new(&mut store, &root, start, start + 10)
+            .unwrap()
+            .unwrap();
+        assert_eq!(proof.leaves().len(), 1);
+        let offset = proof.verify::<Blake3>(&root).unwrap();
+        assert_eq!(offset, crate::base::OBJECT_MAX_SIZE as u64);
+
+        // Now "download" the proven leaf and check it
+        let (size, hash) = proof.leaves()[0];
+        assert!(store.load(&hash, &mut obj).unwrap());
+        assert_eq!(obj.info().size(), size as usize);
+        let s = (start - offset) as usize;
+        assert_eq!(
+            &obj.as_data()[s..s + 10],
+            &data[start as usize..start as usize + 10]
+        );
+
+        let mut buf = Vec::new();
+        proof.serialize(&mut buf);
+        let proof2 = BigDataProof::<30>::deserialize(&buf).unwrap();
+        assert_eq!(proof2, proof);
+        let mut other = root;
+        other.randomize();
+        assert!(proof.verify::<Blake3>(&other).is_none());
+        for bit in 0..buf.len() * 8 {
+            let mut copy = buf.clone();
+            flip_bit_in(&mut copy, bit);
+            if let Ok(bad) = BigDataProof::<30>::deserialize(&copy) {
+                assert!(bad.verify::<Blake3>(&root).is_none());
+            }
+        }
+
+        let whole = BigDataProof::new(&mut store, &root, 0, data.len() as u64)
+            .unwrap()
+            .unwrap();
+        assert_eq!(whole.leaves().len(), 4);
+        assert_eq!(whole.verify::<Blake3>(&root), Some(0));
+        assert!(BigDataProof::new(&mut store, &root, 5, 5).is_err());
+        assert!(
+            BigDataProof::new(&mut store, &other, 0, 1)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_range_proof_compact() {
+        let leaves = make_leaves(1024);
+        let proof = RangeProof::new::<Blake3>(&leaves, 500_000, 500_001);
+        assert_eq!(proof.leaves().len(), 1);
+        assert_eq!(proof.nodes.len(), 10);
+        assert!(RangeProof::<30>::deserialize(&[0; 11]).is_err());
+        assert!(RangeProof::<30>::deserialize(&[0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0]).is_err());
+    }
+}
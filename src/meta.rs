@@ -0,0 +1,456 @@
+//! File metadata beyond `File` vs `ExeFile`: mtime, mode, owner, and xattrs.
+//!
+//! Nothing here is kept unless asked for with `MetaOptions`, so by default a
+//! tree is just names and content (and hashes the same no matter who has it).
+
+use std::collections::BTreeMap;
+use std::ffi::{CString, OsStr, OsString};
+use std::fmt;
+use std::fs::{Permissions, set_permissions, symlink_metadata};
+use std::io;
+use std::io::Result as IoResult;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{MetadataExt, PermissionsExt, lchown};
+use std::path::Path;
+
+use crate::dvcs::Reader;
+
+const MTIME: u8 = 1;
+const MODE: u8 = 2;
+const OWNER: u8 = 4;
+const XATTRS: u8 = 8;
+
+/// Which metadata gets saved when scanning a tree.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct MetaOptions {
+    pub mtime: bool,
+    /// All the permission bits (including setuid, setgid, and sticky)
+    pub mode: bool,
+    /// User and group ids, which only root can put back
+    pub owner: bool,
+    /// Extended attributes, which is also where POSIX ACLs live
+    pub xattrs: bool,
+}
+
+impl MetaOptions {
+    pub fn all() -> Self {
+        Self {
+            mtime: true,
+            mode: true,
+            owner: true,
+            xattrs: true,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Parse a comma separated list like `"mtime,mode"` (`"none"` or `""` for
+    /// nothing, `"all"` for everything).
+    pub fn parse(txt: &str) -> Option<Self> {
+        let mut opts = Self::default();
+        for field in txt.split(',').map(|f| f.trim()).filter(|f| !f.is_empty()) {
+            match field {
+                "mtime" => opts.mtime = true,
+                "mode" => opts.mode = true,
+                "owner" => opts.owner = true,
+                "xattrs" => opts.xattrs = true,
+                "all" => opts = Self::all(),
+                "none" => {}
+                _ => return None,
+            }
+        }
+        Some(opts)
+    }
+}
+
+impl fmt::Display for MetaOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields = [
+            (self.mtime, "mtime"),
+            (self.mode, "mode"),
+            (self.owner, "owner"),
+            (self.xattrs, "xattrs"),
+        ];
+        let names = Vec::from_iter(fields.iter().filter(|(on, _)| *on).map(|(_, n)| *n));
+        match names.is_empty() {
+            true => write!(f, "none"),
+            false => write!(f, "{}", names.join(",")),
+        }
+    }
+}
+
+/// Metadata for one `Dir` entry, `None` (or empty) meaning it wasn't saved.
+///
+/// ```text
+/// | Flags 1 | Secs 8 | Nanos 4 | Mode 4 | Uid 4 | Gid 4 |
+/// | Count 2 | (KeyLen 2 | Key | ValLen 4 | Val) * Count |
+/// ```
+///
+/// Only the fields whose bit is set in `Flags` are there (mtime 1, mode 2,
+/// owner 4, and xattrs 8).
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Meta {
+    /// Seconds and nanoseconds since the Unix epoch
+    pub mtime: Option<(i64, u32)>,
+    pub mode: Option<u32>,
+    /// User id and group id
+    pub owner: Option<(u32, u32)>,
+    pub xattrs: BTreeMap<OsString, Vec<u8>>,
+}
+
+impl Meta {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Read what `opts` asks for from `path` (not following symlinks).
+    pub fn read(path: &Path, opts: &MetaOptions) -> IoResult<Self> {
+        let md = symlink_metadata(path)?;
+        let mut meta = Self::default();
+        if opts.mtime {
+            meta.mtime = Some((md.mtime(), md.mtime_nsec() as u32));
+        }
+        if opts.mode && !md.file_type().is_symlink() {
+            meta.mode = Some(md.mode() & 0o7777);
+        }
+        if opts.owner {
+            meta.owner = Some((md.uid(), md.gid()));
+        }
+        if opts.xattrs {
+            meta.xattrs = read_xattrs(path)?;
+        }
+        Ok(meta)
+    }
+
+    /// Put this metadata back on `path` (not following symlinks).
+    ///
+    /// Owners and xattrs we aren't allowed to set are skipped, so restoring as
+    /// a regular user still gets everything else right.  The mtime goes last
+    /// as setting the others can bump it.
+    pub fn apply(&self, path: &Path) -> IoResult<()> {
+        if let Some((uid, gid)) = self.owner {
+            match lchown(path, Some(uid), Some(gid)) {
+                Err(err) if err.kind() != io::ErrorKind::PermissionDenied => return Err(err),
+                _ => {}
+            }
+        }
+        // Before the mode, as setting `user.*` xattrs needs write permission
+        for (key, val) in self.xattrs.iter() {
+            let (path, key) = (cstring(path.as_os_str())?, cstring(key)?);
+            // SAFETY: `path` and `key` are NUL terminated and `val` is good for
+            // `val.len()` bytes, all outliving the call (which only reads them).
+            let ret = unsafe {
+                libc::lsetxattr(
+                    path.as_ptr(),
+                    key.as_ptr(),
+                    val.as_ptr() as *const libc::c_void,
+                    val.len(),
+                    0,
+                )
+            };
+            if ret != 0 {
+                let err = io::Error::last_os_error();
+                match err.raw_os_error() {
+                    Some(libc::EPERM | libc::EOPNOTSUPP) => {}
+                    _ => return Err(err),
+                }
+            }
+        }
+        if let Some(mode) = self.mode {
+            set_permissions(path, Permissions::from_mode(mode))?;
+        }
+        if let Some((secs, nanos)) = self.mtime {
+            let path = cstring(path.as_os_str())?;
+            let times = [
+                libc::timespec {
+                    tv_sec: 0,
+                    tv_nsec: libc::UTIME_OMIT,
+                },
+                libc::timespec {
+                    tv_sec: secs as libc::time_t,
+                    tv_nsec: nanos as libc::c_long,
+                },
+            ];
+            // SAFETY: `path` is NUL terminated and `times` is the two
+            // `timespec`s `utimensat()` reads, both outliving the call.
+            let ret = unsafe {
+                libc::utimensat(
+                    libc::AT_FDCWD,
+                    path.as_ptr(),
+                    times.as_ptr(),
+                    libc::AT_SYMLINK_NOFOLLOW,
+                )
+            };
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn deserialize(rd: &mut Reader) -> Option<Self> {
+        let flags = rd.u8()?;
+        if flags & !(MTIME | MODE | OWNER | XATTRS) != 0 {
+            return None;
+        }
+        let mut meta = Self::default();
+        if flags & MTIME != 0 {
+            let secs = i64::from_le_bytes(rd.array()?);
+            let nanos = rd.u32()?;
+            if nanos >= 1_000_000_000 {
+                return None;
+            }
+            meta.mtime = Some((secs, nanos));
+        }
+        if flags & MODE != 0 {
+            meta.mode = Some(rd.u32()?);
+        }
+        if flags & OWNER != 0 {
+            meta.owner = Some((rd.u32()?, rd.u32()?));
+        }
+        if flags & XATTRS != 0 {
+            for _ in 0..rd.u16()? {
+                let size = rd.u16()? as usize;
+                let key = OsString::from_vec(rd.take(size)?.to_vec());
+                let size = rd.u32()? as usize;
+                meta.xattrs.insert(key, rd.take(size)?.to_vec());
+            }
+        }
+        Some(meta)
+    }
+
+    pub fn serialize(&self, buf: &mut Vec<u8>) -> IoResult<()> {
+        let mut flags = 0;
+        for (on, flag) in [
+            (self.mtime.is_some(), MTIME),
+            (self.mode.is_some(), MODE),
+            (self.owner.is_some(), OWNER),
+            (!self.xattrs.is_empty(), XATTRS),
+        ] {
+            if on {
+                flags |= flag;
+            }
+        }
+        buf.push(flags);
+        if let Some((secs, nanos)) = self.mtime {
+            buf.extend_from_slice(&secs.to_le_bytes());
+            buf.extend_from_slice(&nanos.to_le_bytes());
+        }
+        if let Some(mode) = self.mode {
+            buf.extend_from_slice(&mode.to_le_bytes());
+        }
+        if let Some((uid, gid)) = self.owner {
+            buf.extend_from_slice(&uid.to_le_bytes());
+            buf.extend_from_slice(&gid.to_le_bytes());
+        }
+        if !self.xattrs.is_empty() {
+            let count: u16 = self
+                .xattrs
+                .len()
+                .try_into()
+                .map_err(|_| too_big("xattrs"))?;
+            buf.extend_from_slice(&count.to_le_bytes());
+            for (key, val) in self.xattrs.iter() {
+                let size: u16 = key.len().try_into().map_err(|_| too_big("xattr name"))?;
+                buf.extend_from_slice(&size.to_le_bytes());
+                buf.extend_from_slice(key.as_bytes());
+                let size: u32 = val.len().try_into().map_err(|_| too_big("xattr value"))?;
+                buf.extend_from_slice(&size.to_le_bytes());
+                buf.extend_from_slice(val);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn too_big(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("Too many {}", what))
+}
+
+fn cstring(s: &OsStr) -> IoResult<CString> {
+    CString::new(s.as_bytes()).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+// Some filesystems don't do xattrs at all, which is the same as having none.
+fn read_xattrs(path: &Path) -> IoResult<BTreeMap<OsString, Vec<u8>>> {
+    let mut xattrs = BTreeMap::new();
+    let path = cstring(path.as_os_str())?;
+    // SAFETY: `path` is NUL terminated, and `xattr_buf()` passes either a null
+    // `buf` with a `size` of 0 or a buffer good for `size` bytes.
+    let names = match xattr_buf(|buf, size| unsafe {
+        libc::llistxattr(path.as_ptr(), buf as *mut libc::c_char, size)
+    }) {
+        Err(err) if err.raw_os_error() == Some(libc::EOPNOTSUPP) => return Ok(xattrs),
+        result => result?,
+    };
+    for key in names.split(|b| *b == 0).filter(|k| !k.is_empty()) {
+        let ckey = cstring(OsStr::from_bytes(key))?;
+        // SAFETY: Same as `llistxattr()` above, with `ckey` NUL terminated too.
+        let val = match xattr_buf(|buf, size| unsafe {
+            libc::lgetxattr(path.as_ptr(), ckey.as_ptr(), buf, size)
+        }) {
+            // Removed since we listed it
+            Err(err) if err.raw_os_error() == Some(libc::ENODATA) => continue,
+            result => result?,
+        };
+        xattrs.insert(OsStr::from_bytes(key).to_owned(), val);
+    }
+    Ok(xattrs)
+}
+
+// Call `f` once for the size, then again to fill a buffer that big (retrying
+// if it grew in between).
+fn xattr_buf<F>(f: F) -> IoResult<Vec<u8>>
+where
+    F: Fn(*mut libc::c_void, usize) -> libc::ssize_t,
+{
+    loop {
+        let size = f(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0; size as usize];
+        let got = f(buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+        if got >= 0 {
+            buf.truncate(got as usize);
+            return Ok(buf);
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::TestTempDir;
+
+    #[test]
+    fn test_meta_options() {
+        assert_eq!(MetaOptions::parse(""), Some(MetaOptions::default()));
+        assert_eq!(MetaOptions::parse("none"), Some(MetaOptions::default()));
+        assert_eq!(MetaOptions::parse("all"), Some(MetaOptions::all()));
+        let opts = MetaOptions::parse("mode, mtime").unwrap();
+        assert!(opts.mode && opts.mtime && !opts.owner && !opts.xattrs);
+        assert_eq!(opts.to_string(), "mtime,mode");
+        assert_eq!(MetaOptions::parse(&opts.to_string()), Some(opts));
+        assert_eq!(MetaOptions::all().to_string(), "mtime,mode,owner,xattrs");
+        assert_eq!(MetaOptions::default().to_string(), "none");
+        assert!(MetaOptions::default().is_empty());
+        assert_eq!(MetaOptions::parse("mtime,acls"), None);
+    }
+
+    #[test]
+    fn test_meta_serialize() {
+        let meta = Meta::default();
+        assert!(meta.is_empty());
+        let mut buf = Vec::new();
+        meta.serialize(&mut buf).unwrap();
+        assert_eq!(buf, [0]);
+        assert_eq!(Meta::deserialize(&mut Reader::new(&buf)), Some(meta));
+
+        let mut meta = Meta {
+            mtime: Some((-1, 999_999_999)),
+            mode: Some(0o4755),
+            owner: Some((1000, 100)),
+            xattrs: BTreeMap::new(),
+        };
+        meta.xattrs.insert("user.a".into(), b"b".to_vec());
+        let mut buf = Vec::new();
+        meta.serialize(&mut buf).unwrap();
+        assert_eq!(buf.len(), 1 + 12 + 4 + 8 + 2 + 2 + 6 + 4 + 1);
+        assert_eq!(buf[0], 15);
+        let mut rd = Reader::new(&buf);
+        assert_eq!(Meta::deserialize(&mut rd), Some(meta));
+        assert!(rd.is_done());
+
+        let meta = Meta {
+            mode: Some(0o644),
+            ..Meta::default()
+        };
+        let mut buf = Vec::new();
+        meta.serialize(&mut buf).unwrap();
+        assert_eq!(buf, [2, 0xa4, 1, 0, 0]);
+        assert_eq!(Meta::deserialize(&mut Reader::new(&buf)), Some(meta));
+
+        // Unknown flags, short, or nonsense nanoseconds
+        assert_eq!(Meta::deserialize(&mut Reader::new(&[16])), None);
+        assert_eq!(Meta::deserialize(&mut Reader::new(&[2, 0, 0])), None);
+        let mut bad = vec![1];
+        bad.extend_from_slice(&0i64.to_le_bytes());
+        bad.extend_from_slice(&1_000_000_000u32.to_le_bytes());
+        assert_eq!(Meta::deserialize(&mut Reader::new(&bad)), None);
+    }
+
+    #[test]
+    fn test_meta_read_apply() {
+        let tmp = TestTempDir::new();
+        tmp.write(&["src"], b"hello");
+        tmp.write(&["dst"], b"hello");
+        let (src, dst) = (tmp.build(&["src"]), tmp.build(&["dst"]));
+        set_permissions(&src, Permissions::from_mode(0o640)).unwrap();
+        let have_xattrs = {
+            let path = cstring(src.as_os_str()).unwrap();
+            let key = cstring(OsStr::new("user.tub")).unwrap();
+            // SAFETY: NUL terminated `path` and `key`, and 4 bytes of value.
+            let ret = unsafe {
+                libc::lsetxattr(
+                    path.as_ptr(),
+                    key.as_ptr(),
+                    b"bath".as_ptr() as *const libc::c_void,
+                    4,
+                    0,
+                )
+            };
+            ret == 0
+        };
+        let past = Meta {
+            mtime: Some((1667790900, 123456789)),
+            ..Meta::default()
+        };
+        past.apply(&src).unwrap();
+
+        assert_eq!(
+            Meta::read(&src, &MetaOptions::default()).unwrap(),
+            Meta::default()
+        );
+        let meta = Meta::read(&src, &MetaOptions::all()).unwrap();
+        assert_eq!(meta.mtime, Some((1667790900, 123456789)));
+        assert_eq!(meta.mode, Some(0o640));
+        let md = symlink_metadata(&src).unwrap();
+        assert_eq!(meta.owner, Some((md.uid(), md.gid())));
+        if have_xattrs {
+            assert_eq!(meta.xattrs.get(OsStr::new("user.tub")).unwrap(), b"bath");
+        }
+
+        meta.apply(&dst).unwrap();
+        assert_eq!(Meta::read(&dst, &MetaOptions::all()).unwrap(), meta);
+
+        // Read-only still gets its xattrs (they go on before the mode)
+        tmp.write(&["ro"], b"hello");
+        let ro = tmp.build(&["ro"]);
+        let meta = Meta {
+            mode: Some(0o444),
+            ..meta
+        };
+        meta.apply(&ro).unwrap();
+        assert_eq!(Meta::read(&ro, &MetaOptions::all()).unwrap(), meta);
+
+        // Symlinks get their own mtime, but no mode
+        let link = tmp.build(&["link"]);
+        std::os::unix::fs::symlink("src", &link).unwrap();
+        let meta = Meta::read(&link, &MetaOptions::all()).unwrap();
+        assert_eq!(meta.mode, None);
+        past.apply(&link).unwrap();
+        let meta = Meta::read(&link, &MetaOptions::all()).unwrap();
+        assert_eq!(meta.mtime, past.mtime);
+        assert_eq!(
+            Meta::read(&src, &MetaOptions::all()).unwrap().mode,
+            Some(0o640)
+        );
+    }
+}
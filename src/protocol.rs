@@ -5,7 +5,7 @@ use std::marker::PhantomData;
 
 use blake3;
 
-pub trait Hasher {
+pub trait Hasher: Send {
     fn new() -> Self;
     fn hash_into(&self, data: &[u8], hash: &mut [u8]);
 }
@@ -45,6 +45,7 @@ pub trait Protocol {
     }
 }
 
+#[allow(dead_code)]
 pub struct Hash<const N: usize> {
     buf: [u8; N],
 }
@@ -65,6 +66,7 @@ pub struct Object<P: Protocol, const N: usize> {
 }
 
 impl<P: Protocol, const N: usize> Object<P, N> {
+    #[allow(dead_code)]
     fn reset(&mut self) {
         self.buf.clear();
         self.buf.resize(P::header(), 0);
@@ -85,6 +87,7 @@ pub trait Store<P: Protocol, const N: usize> {
     //fn iter(&self) -> HashIter2<P, N, Store<P, N>>;
 }
 
+#[allow(dead_code)]
 pub struct HashIter2<P: Protocol, const N: usize, S: Store<P, N>> {
     store: S,
     phantom1: PhantomData<P>,
@@ -105,13 +108,13 @@ mod tests {
         let b3 = Blake3::new();
         b3.hash_into(&data, &mut hash);
         let mut set: HashSet<[u8; 30]> = HashSet::new();
-        let og = hash.clone();
-        set.insert(hash.clone());
+        let og = hash;
+        set.insert(hash);
         for bit in 0..data.len() * 8 {
             flip_bit_in(&mut data, bit);
             b3.hash_into(&data, &mut hash);
             assert_ne!(hash, og);
-            assert!(set.insert(hash.clone()));
+            assert!(set.insert(hash));
             flip_bit_in(&mut data, bit); // Flip bit back
             b3.hash_into(&data, &mut hash);
             assert_eq!(hash, og);
@@ -2,16 +2,40 @@
 
 use crate::base::*;
 use crate::blockchain::Chain;
+use crate::cache::DEFAULT_CACHE_CAPACITY;
 use crate::chaos::{Name, Object, Store};
-use crate::dvcs::TrackingList;
+use crate::cipher::{Cipher, SECRET_LEN, Secret};
+use crate::dictionary::Dictionary;
+use crate::dvcs::{TrackingList, os_to_relpath};
+use crate::inception::ContainerCodec;
+use crate::merge::MergeState;
+use crate::meta::MetaOptions;
 use crate::protocol::{DefaultHasher, Hasher};
-use std::fs::{File, create_dir};
+use ed25519_dalek::{SECRET_KEY_LENGTH, SigningKey};
+use rand::rngs::OsRng;
+use std::env;
+use std::fs::{
+    DirBuilder, File, create_dir, create_dir_all, read, read_dir, read_to_string, remove_file,
+    rename, write,
+};
+use std::io;
 use std::io::Result as IoResult;
 use std::io::prelude::*;
-use std::path::{Path, PathBuf};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Component, Path, PathBuf, absolute};
+use std::sync::Arc;
 
 pub type DefaultTub = Tub<DefaultHasher, 30>;
 
+/// The user's tub config directory, `$XDG_CONFIG_HOME/tub` (which defaults to
+/// `~/.config/tub`).
+pub fn config_dir() -> Option<PathBuf> {
+    match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir).join("tub")),
+        _ => env::var_os("HOME").map(|home| PathBuf::from(home).join(".config/tub")),
+    }
+}
+
 pub fn create_dotdir(path: &Path) -> IoResult<PathBuf> {
     let mut pb = PathBuf::from(path);
     pb.push(DOTDIR);
@@ -33,6 +57,48 @@ pub fn find_dotdir(path: &Path) -> Option<PathBuf> {
     }
 }
 
+// Resolve `.` and `..` lexically (so symlinks are left alone).
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut pb = PathBuf::new();
+    for comp in path.components() {
+        match comp {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                pb.pop();
+            }
+            _ => pb.push(comp),
+        }
+    }
+    pb
+}
+
+/// `path` relative to `treedir` (`""` for `treedir` itself).
+///
+/// A relative `path` is relative to the current directory, like the paths
+/// given to `tub add`.
+pub fn tree_relpath(treedir: &Path, path: &Path) -> IoResult<String> {
+    let treedir = normalize_path(&absolute(treedir)?);
+    let path = normalize_path(&absolute(path)?);
+    match path.strip_prefix(&treedir) {
+        Ok(relpath) => Ok(os_to_relpath(relpath.as_os_str())),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} is outside of {:?}", path, treedir),
+        )),
+    }
+}
+
+/// Branch names become file names, so only `[A-Za-z0-9._-]` is allowed (and
+/// no leading `.` or `-`).
+pub fn is_branch_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && !name.starts_with(['.', '-'])
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'_' | b'-'))
+}
+
 pub fn create_for_append(path: &Path) -> IoResult<File> {
     File::options()
         .read(true)
@@ -45,16 +111,12 @@ pub fn open_for_append(path: &Path) -> IoResult<File> {
     File::options().read(true).append(true).open(path)
 }
 
-pub struct HashingFileReaderIter {
-    size: u64,
-    remaining: u64,
-    file: File,
-}
-
 /// Put all your 🏴‍☠️ treasure in here, matey! 💰💵🦓
 pub struct Tub<H: Hasher, const N: usize> {
     dotdir: PathBuf,
     treedir: PathBuf,
+    config: Option<PathBuf>,
+    codec: ContainerCodec<N>,
     pub store: Store<H, N>,
 }
 
@@ -67,31 +129,82 @@ impl<H: Hasher, const N: usize> Tub<H, N> {
         &self.treedir
     }
 
+    /// Where the user's keys live (see `config_dir()`).
+    pub fn config(&self) -> Option<&Path> {
+        self.config.as_deref()
+    }
+
+    pub fn set_config_dir(&mut self, config: Option<PathBuf>) {
+        self.config = config;
+    }
+
     pub fn create(parent: &Path) -> IoResult<Self> {
         let dotdir = create_dotdir(parent)?;
         let mut filename = dotdir.clone();
         filename.push(PACKFILE);
         let file = create_for_append(&filename)?;
-        let store = Store::<H, N>::new(file);
+        let codec = ContainerCodec::new();
+        let mut store = Store::<H, N>::new(file);
+        store.set_codec(Arc::new(codec.clone()));
+        store.enable_cache(DEFAULT_CACHE_CAPACITY);
         Ok(Self {
             dotdir,
             treedir: parent.to_owned(),
+            config: config_dir(),
+            codec,
             store,
         })
     }
 
     pub fn open(dotdir: PathBuf) -> IoResult<Self> {
+        Self::open_with_config(dotdir, config_dir())
+    }
+
+    /// Open with keys looked up in `config` instead of `config_dir()`.
+    pub fn open_with_config(dotdir: PathBuf, config: Option<PathBuf>) -> IoResult<Self> {
         let mut filename = dotdir.clone();
         filename.push(PACKFILE);
         let file = open_for_append(&filename)?;
-        let store = Store::<H, N>::new(file);
+        let mut store = Store::<H, N>::new(file);
+        store.enable_cache(DEFAULT_CACHE_CAPACITY);
         let mut treedir = dotdir.clone();
         treedir.pop();
-        Ok(Self {
+        let mut tub = Self {
             dotdir,
             treedir,
+            config,
+            codec: ContainerCodec::new(),
             store,
-        })
+        };
+        tub.upgrade_legacy_branch()?;
+        tub.remove_legacy_delta_index()?;
+        if let Some((cipher, id)) = tub.load_encryption()? {
+            match tub.load_secret(&id)? {
+                Some(secret) => tub.codec = ContainerCodec::with_secret(secret, cipher),
+                None if cipher == Cipher::None => {}
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("Missing key {} (looked in {:?})", id, tub.keys_dir()?),
+                    ));
+                }
+            }
+        }
+        tub.update_codec();
+        Ok(tub)
+    }
+
+    fn update_codec(&mut self) {
+        self.store.set_codec(Arc::new(self.codec.clone()));
+    }
+
+    // The dictionary object can only be loaded once the store is indexed.
+    fn use_dictionary(&mut self) -> IoResult<()> {
+        let mut obj: Object<H, N> = Object::new();
+        let dict = self.load_dictionary(&mut obj)?;
+        self.codec.set_dictionary(dict);
+        self.update_codec();
+        Ok(())
     }
 
     pub fn idx_file(&self) -> IoResult<File> {
@@ -112,44 +225,332 @@ impl<H: Hasher, const N: usize> Tub<H, N> {
 
     pub fn check(&mut self) -> IoResult<()> {
         let mut obj: Object<H, N> = Object::new();
-        self.store.reindex(&mut obj)
+        self.store.reindex(&mut obj)?;
+        self.use_dictionary()
     }
 
     pub fn reindex(&mut self) -> IoResult<()> {
         let mut obj: Object<H, N> = Object::new();
         self.store.reindex_from(&mut obj, self.idx_file()?)?;
-        Ok(())
+        self.use_dictionary()
     }
 
-    pub fn create_branch(&self) -> IoResult<Chain> {
+    fn branch_path(&self, name: &str, ext: &str) -> IoResult<PathBuf> {
+        if !is_branch_name(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Bad branch name: {:?}", name),
+            ));
+        }
         let mut filename = self.dotdir.clone();
-        filename.push("fixme.branch");
+        filename.push(BRANCHES);
+        filename.push(format!("{}.{}", name, ext));
+        Ok(filename)
+    }
+
+    /// Create branch `name` with a brand new key.
+    pub fn create_branch(&self, name: &str) -> IoResult<Chain> {
+        let filename = self.branch_path(name, "chain")?;
+        create_dir_all(filename.parent().unwrap())?;
         let file = create_for_append(&filename)?;
         let chain = Chain::generate(file)?;
-        // Save secret key:
-        filename.pop();
-        filename.push("omg.fixme.soon");
-        let file = create_for_append(&filename)?;
+        let file = File::options()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(self.branch_path(name, "key")?)?;
         chain.save_secret_key(file)?;
         Ok(chain)
     }
 
-    pub fn open_branch(&self) -> IoResult<Chain> {
-        let mut filename = self.dotdir.clone();
-        filename.push("fixme.branch");
-        let file = open_for_append(&filename)?;
+    pub fn open_branch(&self, name: &str) -> IoResult<Chain> {
+        let file = open_for_append(&self.branch_path(name, "chain")?)?;
         Chain::open(file)
     }
 
-    pub fn load_branch_seckey(&self, chain: &mut Chain) -> IoResult<bool> {
-        let mut filename = self.dotdir.clone();
-        filename.push("omg.fixme.soon");
-        match File::open(&filename) {
+    pub fn load_branch_seckey(&self, name: &str, chain: &mut Chain) -> IoResult<bool> {
+        match File::open(self.branch_path(name, "key")?) {
             Ok(file) => chain.load_secret_key(file),
             _ => Ok(false),
         }
     }
 
+    pub fn has_branch(&self, name: &str) -> bool {
+        match self.branch_path(name, "chain") {
+            Ok(filename) => filename.is_file(),
+            _ => false,
+        }
+    }
+
+    /// Names of all the branches, sorted.
+    pub fn list_branches(&self) -> IoResult<Vec<String>> {
+        let mut dirname = self.dotdir.clone();
+        dirname.push(BRANCHES);
+        let mut names = Vec::new();
+        let entries = match read_dir(&dirname) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(names),
+            Err(err) => return Err(err),
+        };
+        for entry in entries {
+            let name = entry?.file_name();
+            if let Some(name) = name.to_str().and_then(|n| n.strip_suffix(".chain")) {
+                if is_branch_name(name) {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Delete branch `name` (which can't be the current branch).
+    pub fn delete_branch(&self, name: &str) -> IoResult<()> {
+        if name == self.current_branch()? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Cannot delete the current branch {:?}", name),
+            ));
+        }
+        remove_file(self.branch_path(name, "chain")?)?;
+        match remove_file(self.branch_path(name, "key")?) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Name of the branch the working tree is on.
+    pub fn current_branch(&self) -> IoResult<String> {
+        let mut filename = self.dotdir.clone();
+        filename.push(CURRENT_BRANCH);
+        match read_to_string(&filename) {
+            Ok(txt) => Ok(txt.trim().to_owned()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(DEFAULT_BRANCH.to_owned()),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn set_current_branch(&self, name: &str) -> IoResult<()> {
+        if !self.has_branch(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No such branch: {:?}", name),
+            ));
+        }
+        let mut filename = self.dotdir.clone();
+        filename.push(CURRENT_BRANCH);
+        write(&filename, format!("{}\n", name))
+    }
+
+    // Before named branches there was just the one, in `fixme.branch` (with
+    // its key in `omg.fixme.soon`), which now becomes `DEFAULT_BRANCH`.
+    fn upgrade_legacy_branch(&self) -> IoResult<()> {
+        let mut old = self.dotdir.clone();
+        old.push("fixme.branch");
+        if !old.is_file() {
+            return Ok(());
+        }
+        let new = self.branch_path(DEFAULT_BRANCH, "chain")?;
+        create_dir_all(new.parent().unwrap())?;
+        rename(&old, new)?;
+        old.pop();
+        old.push("omg.fixme.soon");
+        if old.is_file() {
+            rename(&old, self.branch_path(DEFAULT_BRANCH, "key")?)?;
+        }
+        Ok(())
+    }
+
+    // Delta containers used to be found through `deltas.tub`, but the store
+    // now indexes them itself (see `inception::ContainerCodec`).
+    fn remove_legacy_delta_index(&self) -> IoResult<()> {
+        let mut filename = self.dotdir.clone();
+        filename.push("deltas.tub");
+        match remove_file(&filename) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    fn keys_dir(&self) -> IoResult<PathBuf> {
+        match &self.config {
+            Some(config) => Ok(config.join("keys")),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "No config directory (set HOME or XDG_CONFIG_HOME)",
+            )),
+        }
+    }
+
+    fn user_key_path(&self) -> IoResult<PathBuf> {
+        match &self.config {
+            Some(config) => Ok(config.join("user.key")),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "No config directory (set HOME or XDG_CONFIG_HOME)",
+            )),
+        }
+    }
+
+    /// The user's own signing key, `user.key` in the config directory.
+    ///
+    /// Commits record its public key as who made them, so it's the same across
+    /// every tub and branch (it's generated the first time it's needed).
+    pub fn user_key(&self) -> IoResult<SigningKey> {
+        let filename = self.user_key_path()?;
+        let mut buf = [0_u8; SECRET_KEY_LENGTH];
+        match File::open(&filename) {
+            Ok(mut file) => {
+                file.read_exact(&mut buf)?;
+                let key = SigningKey::from_bytes(&buf);
+                buf.fill(0);
+                return Ok(key);
+            }
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            Err(_) => {}
+        }
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(filename.parent().unwrap())?;
+        let key = SigningKey::generate(&mut OsRng);
+        let mut file = File::options()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&filename)?;
+        file.write_all(key.as_bytes())?;
+        file.flush()?;
+        Ok(key)
+    }
+
+    /// Generate a new secret from which encryption keys are derived.
+    ///
+    /// The secret is saved as `keys/<id>.key` in the config directory (not in
+    /// the repository, so copying `.tub` around doesn't copy the key).
+    pub fn create_secret(&self) -> IoResult<Secret> {
+        let mut filename = self.keys_dir()?;
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&filename)?;
+        let secret = Secret::generate();
+        filename.push(format!("{}.key", secret.id()));
+        let mut file = File::options()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&filename)?;
+        file.write_all(secret.as_buf())?;
+        file.flush()?;
+        Ok(secret)
+    }
+
+    /// Load the secret with `id` (`None` if we don't have it).
+    pub fn load_secret(&self, id: &str) -> IoResult<Option<Secret>> {
+        let mut filename = self.keys_dir()?;
+        filename.push(format!("{}.key", id));
+        let mut file = match File::open(&filename) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let mut buf = [0_u8; SECRET_LEN];
+        file.read_exact(&mut buf)?;
+        let secret = Secret::from(&buf);
+        buf.fill(0);
+        let secret = secret?;
+        if secret.id() != id {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Key in {:?} is not {}", filename, id),
+            ));
+        }
+        Ok(Some(secret))
+    }
+
+    /// The cipher new objects are encrypted with and the id of the secret
+    /// (`None` if encryption was never turned on).
+    pub fn load_encryption(&self) -> IoResult<Option<(Cipher, String)>> {
+        let mut filename = self.dotdir.clone();
+        filename.push(ENCRYPTION);
+        let txt = match read_to_string(&filename) {
+            Ok(txt) => txt,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let mut words = txt.split_whitespace();
+        match (
+            words.next().and_then(Cipher::parse),
+            words.next(),
+            words.next(),
+        ) {
+            (Some(cipher), Some(id), None) => Ok(Some((cipher, id.to_string()))),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Bad encryption setting in {:?}", filename),
+            )),
+        }
+    }
+
+    /// Encrypt new objects with `cipher` from now on.
+    ///
+    /// The first time this generates the secret, after that the same secret
+    /// is always used (so `Cipher::None` still lets you read old objects).
+    pub fn set_encryption(&mut self, cipher: Cipher) -> IoResult<()> {
+        let secret = match self.load_encryption()? {
+            Some((_, id)) => self.load_secret(&id)?.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("Missing key {}", id))
+            })?,
+            None => self.create_secret()?,
+        };
+        let mut filename = self.dotdir.clone();
+        filename.push(ENCRYPTION);
+        write(&filename, format!("{} {}\n", cipher.name(), secret.id()))?;
+        let dict = self.codec.dictionary().cloned();
+        self.codec = ContainerCodec::with_secret(secret, cipher);
+        self.codec.set_dictionary(dict);
+        self.update_codec();
+        Ok(())
+    }
+
+    /// Train a dictionary from the small objects in the store, save it, and
+    /// make it the repository's current dictionary (which new small objects are
+    /// compressed with, see `inception::ContainerCodec`).
+    pub fn train_dictionary(
+        &mut self,
+        obj: &mut Object<H, N>,
+        max_size: usize,
+    ) -> IoResult<Option<Dictionary<N>>> {
+        let dict = Dictionary::train_from_store(&mut self.store, obj, max_size)?;
+        if let Some(dict) = &dict {
+            dict.save(&mut self.store, obj)?;
+            let mut filename = self.dotdir.clone();
+            filename.push(DICTIONARY);
+            let mut file = File::create(&filename)?;
+            file.write_all(dict.hash().as_buf())?;
+            file.flush()?;
+            self.codec.set_dictionary(Some(dict.clone()));
+            self.update_codec();
+        }
+        Ok(dict)
+    }
+
+    /// Load the repository's current dictionary (if one has been trained).
+    pub fn load_dictionary(&mut self, obj: &mut Object<H, N>) -> IoResult<Option<Dictionary<N>>> {
+        let mut filename = self.dotdir.clone();
+        filename.push(DICTIONARY);
+        match File::open(&filename) {
+            Ok(mut file) => {
+                let mut hash = Name::new();
+                file.read_exact(hash.as_mut_buf())?;
+                Dictionary::load(&mut self.store, &hash, obj)
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
     pub fn load_tracking_list(&self, obj: &mut Object<H, N>) -> IoResult<TrackingList> {
         let mut filename = self.dotdir.clone();
         filename.push("staged.tub");
@@ -166,22 +567,86 @@ impl<H: Hasher, const N: usize> Tub<H, N> {
         Ok(TrackingList::deserialize(obj.as_data()))
     }
 
+    /// Save the tracking list (an empty one removes `staged.tub`).
     pub fn save_tracking_list(&self, obj: &mut Object<H, N>, tl: &TrackingList) -> IoResult<()> {
         let mut filename = self.dotdir.clone();
         filename.push("staged.tub");
+        if tl.is_empty() {
+            return match remove_file(&filename) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+                _ => Ok(()),
+            };
+        }
         let mut file = File::create(&filename)?;
         obj.clear();
-        tl.serialize(obj.as_mut_vec());
+        tl.serialize(obj.as_mut_vec())?;
         obj.finalize_with_kind(0);
         file.write_all(obj.as_buf())?;
         file.flush()
     }
+
+    /// Load the state of an unfinished merge (if there is one).
+    pub fn load_merge_state(&self) -> IoResult<Option<MergeState<N>>> {
+        let mut filename = self.dotdir.clone();
+        filename.push(MERGE_STATE);
+        match read(&filename) {
+            Ok(buf) => match MergeState::deserialize(&buf) {
+                Some(state) => Ok(Some(state)),
+                None => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Bad merge state in {:?}", filename),
+                )),
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn save_merge_state(&self, state: &MergeState<N>) -> IoResult<()> {
+        let mut filename = self.dotdir.clone();
+        filename.push(MERGE_STATE);
+        let mut buf = Vec::new();
+        state.serialize(&mut buf)?;
+        write(&filename, buf)
+    }
+
+    pub fn clear_merge_state(&self) -> IoResult<()> {
+        let mut filename = self.dotdir.clone();
+        filename.push(MERGE_STATE);
+        match remove_file(&filename) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Which file metadata commits keep (none until set).
+    pub fn load_meta_options(&self) -> IoResult<MetaOptions> {
+        let mut filename = self.dotdir.clone();
+        filename.push(META_OPTIONS);
+        match read_to_string(&filename) {
+            Ok(txt) => MetaOptions::parse(txt.trim()).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Bad metadata options in {:?}", filename),
+                )
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(MetaOptions::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn save_meta_options(&self, opts: &MetaOptions) -> IoResult<()> {
+        let mut filename = self.dotdir.clone();
+        filename.push(META_OPTIONS);
+        write(&filename, format!("{}\n", opts))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::helpers::TestTempDir;
+    use crate::merge::{Conflict, ConflictKind};
 
     #[test]
     fn test_create_dotdir() {
@@ -275,6 +740,27 @@ mod tests {
         assert!(find_dotdir(&bar).is_some());
     }
 
+    #[test]
+    fn test_tree_relpath() {
+        let tmp = TestTempDir::new();
+        let treedir = tmp.makedirs(&["tree", "sub"]);
+        let treedir = treedir.parent().unwrap();
+        let relpath = |path: &Path| tree_relpath(treedir, path).unwrap();
+        assert_eq!(relpath(treedir), "");
+        assert_eq!(relpath(&treedir.join("sub/./file")), "sub/file");
+        assert_eq!(relpath(&treedir.join("sub/../other/")), "other");
+        assert_eq!(relpath(&treedir.join("./sub/..")), "");
+        let err = tree_relpath(treedir, tmp.path()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = tree_relpath(treedir, &treedir.join("../tree2/file")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // Relative to the current directory
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(tree_relpath(&cwd, Path::new("a/../b")).unwrap(), "b");
+        assert_eq!(tree_relpath(Path::new("."), Path::new("./c")).unwrap(), "c");
+    }
+
     #[test]
     fn test_tub_create() {
         let tmp = TestTempDir::new();
@@ -288,6 +774,258 @@ mod tests {
         assert!(DefaultTub::open(tmp.build(&[DOTDIR])).is_ok());
     }
 
+    #[test]
+    fn test_tub_secret() {
+        use std::os::unix::fs::PermissionsExt;
+        let tmp = TestTempDir::new();
+        let mut tub = DefaultTub::create(tmp.path()).unwrap();
+        tub.set_config_dir(None);
+        assert!(tub.create_secret().is_err());
+        assert!(tub.load_secret("nope").is_err());
+
+        tub.set_config_dir(Some(tmp.build(&["config"])));
+        assert!(tub.load_secret("nope").unwrap().is_none());
+        let secret = tub.create_secret().unwrap();
+        let id = secret.id();
+        let loaded = tub.load_secret(&id).unwrap().unwrap();
+        assert_eq!(loaded.as_buf(), secret.as_buf());
+        let keyfile = tmp.build(&["config", "keys", &format!("{}.key", id)]);
+        let meta = std::fs::metadata(&keyfile).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        let meta = std::fs::metadata(tmp.build(&["config", "keys"])).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o700);
+        assert!(!tmp.build(&[DOTDIR, &format!("{}.key", id)]).exists());
+
+        // Other errors aren't swallowed
+        std::fs::set_permissions(&keyfile, std::fs::Permissions::from_mode(0o000)).unwrap();
+        if File::open(&keyfile).is_err() {
+            // (root can read it anyway)
+            assert!(tub.load_secret(&id).is_err());
+        }
+        std::fs::set_permissions(&keyfile, std::fs::Permissions::from_mode(0o600)).unwrap();
+        tmp.write(&["config", "keys", "short.key"], b"too short");
+        assert!(tub.load_secret("short").is_err());
+        let other = tub.create_secret().unwrap();
+        std::fs::copy(
+            tmp.build(&["config", "keys", &format!("{}.key", other.id())]),
+            &keyfile,
+        )
+        .unwrap();
+        assert!(tub.load_secret(&id).is_err());
+    }
+
+    #[test]
+    fn test_tub_user_key() {
+        use std::os::unix::fs::PermissionsExt;
+        let tmp = TestTempDir::new();
+        let mut tub = DefaultTub::create(tmp.path()).unwrap();
+        tub.set_config_dir(None);
+        assert!(tub.user_key().is_err());
+
+        tub.set_config_dir(Some(tmp.build(&["config"])));
+        let key = tub.user_key().unwrap();
+        assert_eq!(tub.user_key().unwrap().to_bytes(), key.to_bytes());
+        let keyfile = tmp.build(&["config", "user.key"]);
+        let meta = std::fs::metadata(&keyfile).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+
+        // Not the branch key, and shared by every tub
+        let chain = tub.create_branch("main").unwrap();
+        assert_ne!(chain.header.pubkey(), key.verifying_key());
+        let tmp2 = TestTempDir::new();
+        let mut other = DefaultTub::create(tmp2.path()).unwrap();
+        other.set_config_dir(Some(tmp.build(&["config"])));
+        assert_eq!(other.user_key().unwrap().to_bytes(), key.to_bytes());
+
+        tmp.write(&["config", "user.key"], b"short");
+        assert!(tub.user_key().is_err());
+    }
+
+    #[test]
+    fn test_tub_encryption() {
+        let tmp = TestTempDir::new();
+        let config = Some(tmp.build(&["config"]));
+        let mut tub = DefaultTub::create(tmp.path()).unwrap();
+        tub.set_config_dir(config.clone());
+        assert!(tub.load_encryption().unwrap().is_none());
+
+        tub.set_encryption(Cipher::XChaCha20Poly1305).unwrap();
+        let (cipher, id) = tub.load_encryption().unwrap().unwrap();
+        assert_eq!(cipher, Cipher::XChaCha20Poly1305);
+        let txt = format!("xchacha20-poly1305 {}\n", id);
+        assert_eq!(tmp.read(&[DOTDIR, ENCRYPTION]), txt.as_bytes());
+
+        let mut obj = tub.store.new_object();
+        obj.clear();
+        obj.extend(b"Super secret treasure map");
+        let hash = obj.finalize_with_kind(ObjKind::Data as u8);
+        assert!(tub.store.save(&obj).unwrap());
+        assert!(
+            !tmp.read(&[DOTDIR, PACKFILE])
+                .windows(6)
+                .any(|w| w == b"Super ")
+        );
+        drop(tub);
+
+        let dotdir = tmp.build(&[DOTDIR]);
+        let mut tub = DefaultTub::open_with_config(dotdir.clone(), config.clone()).unwrap();
+        tub.check().unwrap();
+        assert!(tub.store.load(&hash, &mut obj).unwrap());
+        assert_eq!(obj.as_data(), b"Super secret treasure map");
+
+        // Turning it off keeps the secret, so old objects can still be read
+        tub.set_encryption(Cipher::None).unwrap();
+        assert_eq!(
+            tub.load_encryption().unwrap().unwrap(),
+            (Cipher::None, id.clone())
+        );
+        drop(tub);
+        let mut tub = DefaultTub::open_with_config(dotdir.clone(), config.clone()).unwrap();
+        tub.check().unwrap();
+        assert!(tub.store.load(&hash, &mut obj).unwrap());
+        drop(tub);
+
+        // Without the key
+        let other = Some(tmp.build(&["other"]));
+        let mut tub = DefaultTub::open_with_config(dotdir.clone(), other.clone()).unwrap();
+        tub.check().unwrap();
+        let err = tub.store.load(&hash, &mut obj).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        drop(tub);
+        tmp.write(
+            &[DOTDIR, ENCRYPTION],
+            format!("aes-256-gcm {}\n", id).as_bytes(),
+        );
+        assert!(DefaultTub::open_with_config(dotdir.clone(), other).is_err());
+        assert!(DefaultTub::open_with_config(dotdir.clone(), config).is_ok());
+        tmp.write(&[DOTDIR, ENCRYPTION], b"rot13 nope\n");
+        assert!(DefaultTub::open(dotdir).is_err());
+    }
+
+    #[test]
+    fn test_tub_dictionary() {
+        let tmp = TestTempDir::new();
+        let mut tub = DefaultTub::create(tmp.path()).unwrap();
+        let mut obj = tub.store.new_object();
+        assert!(tub.load_dictionary(&mut obj).unwrap().is_none());
+        assert!(tub.train_dictionary(&mut obj, 4096).unwrap().is_none());
+        assert!(tub.load_dictionary(&mut obj).unwrap().is_none());
+        for i in 0..200 {
+            obj.clear();
+            obj.extend(format!("[core]\nname = \"{i}\"\nbare = false\n").as_bytes());
+            obj.finalize_with_kind(ObjKind::Data as u8);
+            tub.store.save(&obj).unwrap();
+        }
+        let dict = tub.train_dictionary(&mut obj, 4096).unwrap().unwrap();
+        assert!(tub.store.contains(dict.hash()));
+        assert_eq!(tub.load_dictionary(&mut obj).unwrap().unwrap(), dict);
+
+        // New small objects are compressed with it
+        obj.clear();
+        obj.extend(
+            b"[core]\nname = \"new\"\nbare = false\n"
+                .repeat(3)
+                .as_slice(),
+        );
+        let hash = obj.finalize_with_kind(ObjKind::Data as u8);
+        let expected = Vec::from(obj.as_buf());
+        tub.store.save(&obj).unwrap();
+        assert!(tub.store.container_of(&hash).is_some());
+        drop(tub);
+        let mut tub = DefaultTub::open(tmp.build(&[DOTDIR])).unwrap();
+        tub.reindex().unwrap();
+        assert!(tub.store.load(&hash, &mut obj).unwrap());
+        assert_eq!(obj.as_buf(), &expected[..]);
+
+        // Encrypted objects are sampled too
+        let tmp = TestTempDir::new();
+        let mut tub = DefaultTub::create(tmp.path()).unwrap();
+        tub.set_config_dir(Some(tmp.build(&["config"])));
+        tub.set_encryption(Cipher::Aes256Gcm).unwrap();
+        for i in 0..200 {
+            obj.clear();
+            obj.extend(format!("[core]\nname = \"{i}\"\nbare = false\n").as_bytes());
+            obj.finalize_with_kind(ObjKind::Data as u8);
+            tub.store.save(&obj).unwrap();
+        }
+        assert!(tub.train_dictionary(&mut obj, 4096).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_tub_tracking_list() {
+        let tmp = TestTempDir::new();
+        let tub = DefaultTub::create(tmp.path()).unwrap();
+        let mut obj = tub.store.new_object();
+        let empty = TrackingList::new();
+        assert_eq!(tub.load_tracking_list(&mut obj).unwrap(), empty);
+        tub.save_tracking_list(&mut obj, &empty).unwrap();
+        assert_eq!(tub.load_tracking_list(&mut obj).unwrap(), empty);
+
+        let mut tl = TrackingList::new();
+        tl.add("foo".to_string());
+        tl.rename("bar".to_string(), "baz".to_string());
+        tub.save_tracking_list(&mut obj, &tl).unwrap();
+        assert_eq!(tub.load_tracking_list(&mut obj).unwrap(), tl);
+        assert!(tmp.build(&[DOTDIR, "staged.tub"]).exists());
+
+        // Clearing removes the file
+        tub.save_tracking_list(&mut obj, &empty).unwrap();
+        assert!(!tmp.build(&[DOTDIR, "staged.tub"]).exists());
+        assert_eq!(tub.load_tracking_list(&mut obj).unwrap(), empty);
+    }
+
+    #[test]
+    fn test_tub_merge_state() {
+        let tmp = TestTempDir::new();
+        let tub = DefaultTub::create(tmp.path()).unwrap();
+        assert_eq!(tub.load_merge_state().unwrap(), None);
+        tub.clear_merge_state().unwrap();
+
+        let mut theirs = Name::new();
+        theirs.randomize();
+        let state = MergeState::new(
+            theirs,
+            vec![
+                Conflict {
+                    path: "foo".to_string(),
+                    kind: ConflictKind::Content,
+                },
+                Conflict {
+                    path: "new\nline\ttab".to_string(),
+                    kind: ConflictKind::ModifyDelete,
+                },
+            ],
+        );
+        tub.save_merge_state(&state).unwrap();
+        assert!(tmp.build(&[DOTDIR, MERGE_STATE]).exists());
+        assert_eq!(tub.load_merge_state().unwrap(), Some(state));
+
+        tub.clear_merge_state().unwrap();
+        assert!(!tmp.build(&[DOTDIR, MERGE_STATE]).exists());
+        assert_eq!(tub.load_merge_state().unwrap(), None);
+
+        tmp.write(&[DOTDIR, MERGE_STATE], b"junk");
+        assert!(tub.load_merge_state().is_err());
+    }
+
+    #[test]
+    fn test_tub_meta_options() {
+        let tmp = TestTempDir::new();
+        let tub = DefaultTub::create(tmp.path()).unwrap();
+        assert_eq!(tub.load_meta_options().unwrap(), MetaOptions::default());
+
+        let opts = MetaOptions::parse("mode,xattrs").unwrap();
+        tub.save_meta_options(&opts).unwrap();
+        assert_eq!(tmp.read(&[DOTDIR, META_OPTIONS]), b"mode,xattrs\n");
+        assert_eq!(tub.load_meta_options().unwrap(), opts);
+
+        tub.save_meta_options(&MetaOptions::default()).unwrap();
+        assert_eq!(tub.load_meta_options().unwrap(), MetaOptions::default());
+
+        tmp.write(&[DOTDIR, META_OPTIONS], b"mtime,colour\n");
+        assert!(tub.load_meta_options().is_err());
+    }
+
     #[test]
     fn test_tub_open() {
         let tmp = TestTempDir::new();
@@ -304,4 +1042,88 @@ mod tests {
         tmp.touch(&[DOTDIR, PACKFILE]);
         assert!(DefaultTub::open(dotdir.clone()).is_ok());
     }
+
+    #[test]
+    fn test_is_branch_name() {
+        for name in [
+            "main",
+            "feature-x",
+            "v1.2",
+            "a_b",
+            "A",
+            "x".repeat(64).as_str(),
+        ] {
+            assert!(is_branch_name(name), "{:?}", name);
+        }
+        for name in [
+            "",
+            ".",
+            "..",
+            ".hidden",
+            "-d",
+            "a/b",
+            "a b",
+            "a\\b",
+            "ünï",
+            "x".repeat(65).as_str(),
+        ] {
+            assert!(!is_branch_name(name), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn test_tub_branches() {
+        let tmp = TestTempDir::new();
+        let tub = DefaultTub::create(tmp.path()).unwrap();
+        assert_eq!(tub.list_branches().unwrap(), Vec::<String>::new());
+        assert_eq!(tub.current_branch().unwrap(), DEFAULT_BRANCH);
+        assert!(!tub.has_branch(DEFAULT_BRANCH));
+        assert!(tub.set_current_branch(DEFAULT_BRANCH).is_err());
+
+        let chain = tub.create_branch(DEFAULT_BRANCH).unwrap();
+        assert!(tub.has_branch(DEFAULT_BRANCH));
+        assert!(tub.create_branch(DEFAULT_BRANCH).is_err());
+        assert!(tub.create_branch("../nope").is_err());
+        assert!(!tub.has_branch("../nope"));
+        tub.set_current_branch(DEFAULT_BRANCH).unwrap();
+        assert_eq!(tub.current_branch().unwrap(), DEFAULT_BRANCH);
+
+        // Each branch has its own key
+        let other = tub.create_branch("other").unwrap();
+        assert_ne!(chain.header.pubkey(), other.header.pubkey());
+        assert_eq!(tub.list_branches().unwrap(), ["main", "other"]);
+        let mut opened = tub.open_branch("other").unwrap();
+        assert_eq!(opened.header.pubkey(), other.header.pubkey());
+        assert!(tub.load_branch_seckey("other", &mut opened).unwrap());
+
+        tub.set_current_branch("other").unwrap();
+        assert_eq!(tub.current_branch().unwrap(), "other");
+        assert!(tub.delete_branch("other").is_err());
+        tub.delete_branch(DEFAULT_BRANCH).unwrap();
+        assert_eq!(tub.list_branches().unwrap(), ["other"]);
+        assert!(tub.open_branch(DEFAULT_BRANCH).is_err());
+        assert!(tub.delete_branch(DEFAULT_BRANCH).is_err());
+    }
+
+    #[test]
+    fn test_tub_upgrade_legacy_branch() {
+        let tmp = TestTempDir::new();
+        let tub = DefaultTub::create(tmp.path()).unwrap();
+        let chain =
+            Chain::generate(create_for_append(&tmp.build(&[DOTDIR, "fixme.branch"])).unwrap())
+                .unwrap();
+        chain
+            .save_secret_key(create_for_append(&tmp.build(&[DOTDIR, "omg.fixme.soon"])).unwrap())
+            .unwrap();
+        drop(tub);
+
+        let tub = DefaultTub::open(tmp.build(&[DOTDIR])).unwrap();
+        assert!(!tmp.build(&[DOTDIR, "fixme.branch"]).exists());
+        assert!(!tmp.build(&[DOTDIR, "omg.fixme.soon"]).exists());
+        assert_eq!(tub.list_branches().unwrap(), [DEFAULT_BRANCH]);
+        assert_eq!(tub.current_branch().unwrap(), DEFAULT_BRANCH);
+        let mut opened = tub.open_branch(DEFAULT_BRANCH).unwrap();
+        assert_eq!(opened.header.pubkey(), chain.header.pubkey());
+        assert!(tub.load_branch_seckey(DEFAULT_BRANCH, &mut opened).unwrap());
+    }
 }
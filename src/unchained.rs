@@ -1,7 +1,5 @@
 //! New blockchain stuffs
 
-use ed25519_dalek::{Signature, SignatureError, Signer, SigningKey, Verifier, VerifyingKey};
-use rand::rngs::OsRng;
 use std::ops::Range;
 
 /*
@@ -123,6 +121,7 @@ impl<'a, const N: usize> MutBlock<'a, N> {
     }
 }
 
+#[allow(dead_code)]
 pub struct Read<'a> {
     buf: &'a [u8],
 }
@@ -133,6 +132,7 @@ impl<'a> Read<'a> {
     }
 }
 
+#[allow(dead_code)]
 pub struct Write<'a> {
     buf: &'a mut [u8],
 }
@@ -153,8 +153,8 @@ mod tests {
     #[test]
     fn test_stuff() {
         let mut buf = [0_u8; 30];
-        let r = Read::new(&buf);
-        let w = Write::new(&mut buf);
+        let _r = Read::new(&buf);
+        let _w = Write::new(&mut buf);
     }
 
     #[test]
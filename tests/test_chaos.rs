@@ -23,7 +23,7 @@ fn test_roundtrip() {
         store.save(&obj).unwrap();
     }
     for (hash, buf) in objects.iter() {
-        store.load(&hash, &mut obj).unwrap();
+        store.load(hash, &mut obj).unwrap();
         assert_eq!(obj.as_mut_vec(), buf);
     }
 }